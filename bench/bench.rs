@@ -2,31 +2,55 @@ extern crate bench;
 extern crate rustfs;
 extern crate rand;
 
-use rustfs::{Proc, O_CREAT, O_RDWR, FileDescriptor};
+use rustfs::{Proc, AllocKind, O_CREAT, O_RDWR, FileDescriptor};
+use std::env;
+use std::io;
+use std::process;
 use std::string::String;
-use bench::{benchmark, Benchmarker};
+use bench::{Bench, Benchmarker, Runner};
+use bench::compare::{self, Verdict};
+use bench::memory::CountingAllocator;
+use bench::report::{Baselines, Estimate, Format};
 use rand::random;
-use std::iter::repeat;
+
+use host::{HostDir, HostFiles, Workload};
+
+mod host;
+mod mix;
+
+// Counts heap use, which each benchmark reports alongside its time.
+#[global_allocator]
+static ALLOC: CountingAllocator = CountingAllocator::new();
 
 static NUM: usize = 100;
+static PAGE_SIZES: [usize; 4] = [512, 4096, 16384, 65536];
+static ALLOC_KINDS: [(AllocKind, &str); 2] = [
+  (AllocKind::Boxed, "box"),
+  (AllocKind::Slab, "slab")
+];
 
+// Benchmark names spell out what an iteration does to each file: O opens, W
+// writes, C closes, and U unlinks it; t does the step to all files at once
+// before moving on. Writes are s(mall), 1 KiB, or b(ig), 40 KiB, or 1 MiB when
+// there are M(any) of them; bb writes grow from 2 bytes to 8 KiB.
 macro_rules! bench {
-  ($wrap:ident, $name:ident, $time:expr, |$p:ident, $filenames:ident| $task:stmt) => ({
+  ($runner:ident, $bench:expr, |$p:ident, $filenames:ident| $task:stmt) => ({
     let $filenames = generate_names(NUM);
-    let $wrap = |b: &mut Benchmarker| {
+    let wrap = |b: &mut Benchmarker| {
       let mut $p = Proc::new();
       b.run(|| {
         $task
       });
     };
-    benchmark(stringify!($name), $wrap, $time);
+    $runner.bench(&$bench, wrap);
   });
 }
 
+// Runs `op` on each of NUM freshly opened files.
 macro_rules! bench_many {
-  ($wrap:ident, $name:ident, $time:expr, |$p:ident, $fd:ident, $filename:ident| $op:stmt) => ({
+  ($runner:ident, $bench:expr, |$p:ident, $fd:ident, $filename:ident| $op:stmt) => ({
     let filenames = generate_names(NUM);
-    let $wrap = |b: &mut Benchmarker| {
+    let wrap = |b: &mut Benchmarker| {
       let mut $p = Proc::new();
       b.run(|| {
         for i_j in 0..NUM {
@@ -36,21 +60,17 @@ macro_rules! bench_many {
         }
       });
     };
-    benchmark(stringify!($name), $wrap, $time);
+    $runner.bench(&$bench.ops(NUM as u64), wrap);
   })
 }
 
-fn ceil_div(x: usize, y: usize) -> usize {
-  return (x + y - 1) / y;
-}
-
 fn rand_array(size: usize) -> Vec<u8> {
   (0..size).map(|_| random::<u8>()).collect()
 }
 
 fn generate_names(n: usize) -> Vec<String> {
-  let name_length = ceil_div(n, 26);
-  let mut name: Vec<_> = repeat('@' as u8).take(name_length).collect();
+  let name_length = n.div_ceil(26);
+  let mut name = vec![b'@'; name_length];
 
   (0..n).map(|i| {
     let next = name[i / 26] + 1;
@@ -64,62 +84,364 @@ fn generate_names(n: usize) -> Vec<String> {
   }).collect()
 }
 
-fn open_many<'a>(p: &mut Proc<'a>, names: &'a Vec<String>) -> Vec<FileDescriptor> {
-  (0..names.len()).map(|i| {
-    let fd = p.open(&names[i], O_CREAT | O_RDWR);
-    fd
-  }).collect()
+fn open_many<'a>(p: &mut Proc<'a>, names: &'a [String]) -> Vec<FileDescriptor> {
+  names.iter().map(|name| p.open(name, O_CREAT | O_RDWR)).collect()
 }
 
-fn close_all(p: &mut Proc, fds: &Vec<FileDescriptor>) {
+fn close_all(p: &mut Proc, fds: &[FileDescriptor]) {
   for fd in fds.iter() {
     p.close(*fd);
   }
 }
 
-fn unlink_all<'a>(p: &mut Proc<'a>, names: &'a Vec<String>) {
+fn unlink_all<'a>(p: &mut Proc<'a>, names: &'a [String]) {
   for filename in names.iter() {
-    p.unlink(&filename);
+    p.unlink(filename);
+  }
+}
+
+// Opens, writes `content` to `many` times, closes, and unlinks NUM files on a
+// file system with the given page size and allocator.
+fn bench_writes(runner: &mut Runner, bench: Bench, content: &[u8], many: usize,
+                page_size: usize, kind: AllocKind) {
+  let bench = bench.bytes((NUM * content.len() * many) as u64).ops(NUM as u64);
+  let filenames = generate_names(NUM);
+  let wrap = |b: &mut Benchmarker| {
+    let mut p = Proc::with_allocator(page_size, kind);
+    b.run(|| {
+      for filename in filenames.iter() {
+        let fd = p.open(filename, O_CREAT | O_RDWR);
+        for _ in 0..many {
+          p.write(fd, content);
+        }
+        p.close(fd);
+        p.unlink(filename);
+      }
+    });
+  };
+  runner.bench(&bench, wrap);
+}
+
+// Opens, writes, closes, and unlinks NUM files like bench_writes, writing
+// `sizes[i]` bytes of `content` the ith time, but times every write on its own.
+// Writes that have to allocate a new page or indirect list show up in the tail.
+fn bench_write_latency(runner: &mut Runner, bench: Bench, content: &[u8], sizes: &[usize]) {
+  let bench = bench.bytes((NUM * sizes.iter().sum::<usize>()) as u64).ops(NUM as u64);
+  let filenames = generate_names(NUM);
+  let wrap = |b: &mut Benchmarker| {
+    let mut p = Proc::new();
+    b.run_measured(|timer| {
+      for filename in filenames.iter() {
+        let fd = p.open(filename, O_CREAT | O_RDWR);
+        for &size in sizes.iter() {
+          timer.measure(|| p.write(fd, &content[..size]));
+        }
+        p.close(fd);
+        p.unlink(filename);
+      }
+    });
+  };
+  runner.bench(&bench, wrap);
+}
+
+fn bench_page_sizes(runner: &mut Runner, bench: &Bench, content: &[u8], many: usize) {
+  for &page_size in PAGE_SIZES.iter() {
+    let name = format!("{}@{}", bench.name, page_size);
+    let description = format!("{}, {}-byte pages", bench.description, page_size);
+    let variant = Bench::new(&name, &description, bench.min_time_ms);
+    bench_writes(runner, variant, content, many, page_size, AllocKind::default());
+  }
+}
+
+fn bench_alloc_kinds(runner: &mut Runner, bench: &Bench, content: &[u8], many: usize) {
+  for &(kind, kind_name) in ALLOC_KINDS.iter() {
+    let name = format!("{}/{}", bench.name, kind_name);
+    let description = format!("{}, {} allocator", bench.description, kind_name);
+    let variant = Bench::new(&name, &description, bench.min_time_ms);
+    bench_writes(runner, variant, content, many, 4096, kind);
+  }
+}
+
+// Runs `workload` on NUM files on RustFS and on the host file system, as
+// NAME/rustfs and NAME/std. `dir` only needs to be there if NAME/std runs.
+// Returns the first error either run hit.
+fn bench_host(runner: &mut Runner, bench: &Bench, workload: &Workload,
+              dir: Option<&HostDir>) -> io::Result<()> {
+  let filenames = generate_names(NUM);
+  let variant = |fs: &str, description: &str| {
+    Bench::new(&format!("{}/{}", bench.name, fs), &format!("{}, {}", bench.description, description),
+               bench.min_time_ms)
+      .bytes((NUM * workload.bytes_per_file()) as u64)
+      .ops(NUM as u64)
+  };
+
+  let mut result = Ok(());
+  runner.bench(&variant("rustfs", "on RustFS"), |b: &mut Benchmarker| {
+    let mut p = Proc::new();
+    b.run(|| if result.is_ok() { result = workload.run(&mut p, &filenames) });
+  });
+
+  result?;
+  let mut result = Ok(());
+  runner.bench(&variant("std", "on the host with std::fs"), |b: &mut Benchmarker| {
+    let mut files = HostFiles::new(dir.expect("no directory for the host benchmarks"));
+    b.run(|| if result.is_ok() { result = workload.run(&mut files, &filenames) });
+  });
+
+  result
+}
+
+// Runs the same workloads on RustFS and on the host. The scratch directory is
+// only made if one of the host runs is going to happen, and is returned so
+// its path can be reported; it's removed if any of the runs fails.
+fn bench_hosts(runner: &mut Runner, options: &Options) -> io::Result<Option<HostDir>> {
+  let content = rand_array(40960);
+  let workload = |writes: Vec<usize>, unlink: bool| Workload { content: &content, writes, unlink };
+  let description = "open each file, write 4096 times growing to 8 KiB, close, and unlink it";
+  let growing = (1..(4096 + 1)).map(|i| i * 2).collect();
+  let host_benches = [
+    (Bench::new("OC", "open and close each file", 100), workload(vec![], false)),
+    (Bench::new("OCU", "open, close, and unlink each file", 500), workload(vec![], true)),
+    (Bench::new("OWsCU", "open each file, write 1 KiB, close, and unlink it", 100), workload(vec![1024], true)),
+    (Bench::new("OWbCU", "open each file, write 40 KiB, close, and unlink it", 100), workload(vec![40960], true)),
+    (Bench::new("OWbbCU", description, 7000), workload(growing, true)),
+  ];
+
+  let on_host = host_benches.iter().any(|(bench, _)| runner.is_selected(&format!("{}/std", bench.name)));
+  let host_dir = match on_host && !options.list {
+    true => Some(HostDir::create(options.host_dir.as_deref())?),
+    false => None
+  };
+
+  for (bench, workload) in host_benches.iter() {
+    bench_host(runner, bench, workload, host_dir.as_ref())?;
+  }
+
+  Ok(host_dir)
+}
+
+// Prints the NAME/rustfs and NAME/std results side by side.
+fn print_host_comparison(runner: &Runner, dir: &HostDir) {
+  let records = runner.records();
+  let pairs: Vec<_> = records.iter().filter_map(|ours| {
+    let name = ours.name.strip_suffix("/rustfs")?;
+    let theirs = records.iter().find(|theirs| theirs.name == format!("{}/std", name))?;
+    Some((name, ours.results.summary.median, theirs.results.summary.median))
+  }).collect();
+
+  if pairs.is_empty() {
+    return;
+  }
+
+  eprintln!("\nRustFS against std::fs in {}:", dir.path.display());
+  eprintln!("{:10}  {:>15}  {:>15}", "", "rustfs ns/iter", "std ns/iter");
+  for (name, ours, theirs) in pairs {
+    let verdict = if ours <= theirs {
+      format!("{:.2}x faster", theirs / ours)
+    } else {
+      format!("{:.2}x slower", ours / theirs)
+    };
+
+    eprintln!("{:10}  {:15.0}  {:15.0}  {}", name, ours, theirs, verdict);
+  }
+}
+
+// Where named baselines are kept unless --baseline-dir says otherwise.
+static BASELINE_DIR: &str = "target/bench-baselines";
+
+static USAGE: &str = "\
+Usage: rustfs-bench [options] [PATTERN...]
+
+Runs the benchmarks whose names match any PATTERN, or all of them. A pattern
+with * or ? in it is a glob matching whole names; any other pattern matches
+names containing it.
+
+Options:
+  --list                 list the benchmarks, with descriptions, and exit
+  --min-time MS          run each benchmark for at least MS ms instead of its
+                         own minimum time
+  --iterations N         time exactly N iterations per sample, skipping the
+                         warmup
+  --no-memory            don't run the extra iteration that measures heap use
+  --host-dir DIR         where the /std benchmarks put their files (default
+                         /dev/shm, or the system's temporary directory)
+  --format FORMAT        print results as text (default), json, or csv
+  --save-baseline NAME   save the results as baseline NAME
+  --baseline NAME        compare the results against baseline NAME, exiting
+                         with status 1 if any benchmark regressed
+  --threshold PERCENT    smallest change that counts as a regression or an
+                         improvement (default 5)
+  --baseline-dir DIR     where baselines are kept (default target/bench-baselines)
+  -h, --help             print this message";
+
+struct Options {
+  patterns: Vec<String>,
+  list: bool,
+  min_time: Option<u64>,
+  iterations: Option<u64>,
+  memory: bool,
+  host_dir: Option<String>,
+  format: Format,
+  save_baseline: Option<String>,
+  baseline: Option<String>,
+  threshold: f64,
+  baseline_dir: String,
+}
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("error: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
+
+fn parse_count(value: &str, what: &str) -> u64 {
+  value.parse().ok().filter(|&n| n > 0)
+    .unwrap_or_else(|| usage_error(&format!("bad {} '{}'", what, value)))
+}
+
+fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Options {
+  let mut options = Options {
+    patterns: Vec::new(),
+    list: false,
+    min_time: None,
+    iterations: None,
+    memory: true,
+    host_dir: None,
+    format: Format::Text,
+    save_baseline: None,
+    baseline: None,
+    threshold: 5.0,
+    baseline_dir: BASELINE_DIR.to_string()
+  };
+
+  while let Some(arg) = args.next() {
+    let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+    match arg.as_str() {
+      "--list" => options.list = true,
+      "--min-time" => options.min_time = Some(parse_count(&value(), "minimum time")),
+      "--iterations" => options.iterations = Some(parse_count(&value(), "iteration count")),
+      "--no-memory" => options.memory = false,
+      "--host-dir" => options.host_dir = Some(value()),
+      "--format" => {
+        let name = value();
+        options.format = Format::parse(&name)
+          .unwrap_or_else(|| usage_error(&format!("unknown format '{}'", name)));
+      }
+      "--save-baseline" => options.save_baseline = Some(value()),
+      "--baseline" => options.baseline = Some(value()),
+      "--threshold" => {
+        let threshold = value();
+        options.threshold = threshold.parse().ok().filter(|t: &f64| *t >= 0.0)
+          .unwrap_or_else(|| usage_error(&format!("bad threshold '{}'", threshold)));
+      }
+      "--baseline-dir" => options.baseline_dir = value(),
+      "-h" | "--help" => {
+        println!("{}", USAGE);
+        process::exit(0);
+      }
+      _ if arg.starts_with('-') => usage_error(&format!("unknown argument '{}'", arg)),
+      _ => options.patterns.push(arg)
+    }
+  }
+
+  options
+}
+
+// Saves and compares against baselines as asked. Returns whether anything
+// regressed.
+fn finish(runner: &Runner, options: &Options) -> bool {
+  if runner.records().is_empty() {
+    eprintln!("No benchmarks match {}.", options.patterns.join(" or "));
+    process::exit(2);
+  }
+
+  if let Some(report) = runner.report() {
+    print!("{}", report);
+  }
+
+  let baselines = Baselines::new(&options.baseline_dir);
+  if let Some(ref name) = options.save_baseline {
+    match baselines.save(name, runner.records()) {
+      Ok(path) => eprintln!("Saved baseline '{}' to {}.", name, path.display()),
+      Err(e) => {
+        eprintln!("error: couldn't save baseline '{}': {}", name, e);
+        process::exit(2);
+      }
+    }
+  }
+
+  let name = match options.baseline {
+    Some(ref name) => name,
+    None => return false
+  };
+
+  let baseline = baselines.load(name).unwrap_or_else(|e| {
+    eprintln!("error: couldn't load baseline '{}': {}", name, e);
+    process::exit(2);
+  });
+
+  let current: Vec<Estimate> = runner.records().iter().map(Estimate::from).collect();
+  let comparisons = compare::compare(&baseline, &current, options.threshold / 100.0, 0.05);
+  eprintln!("\nCompared to baseline '{}':", name);
+  for comparison in comparisons.iter() {
+    eprintln!("{}", comparison);
   }
+
+  let regressions = comparisons.iter().filter(|c| c.verdict == Verdict::Regressed).count();
+  if regressions > 0 {
+    eprintln!("\n{} benchmark(s) regressed by more than {}%.", regressions, options.threshold);
+  }
+
+  regressions > 0
 }
 
-#[allow(non_snake_case)]
 fn main() {
-  bench!(bench_OC1, OC1, 1, |p, _n| {
+  let options = parse_options(env::args().skip(1));
+  let mut runner = Runner::new(options.format)
+    .filter(options.patterns.clone())
+    .min_time(options.min_time)
+    .iterations(options.iterations)
+    .memory(if options.memory { Some(&ALLOC) } else { None })
+    .list(options.list);
+
+  bench!(runner, Bench::new("OC1", "open and close one file", 1).ops(1), |p, _n| {
     let fd = p.open("test", O_CREAT);
     p.close(fd);
   });
 
-  bench!(bench_OtC, OtC, 100, |p, filenames| {
+  let bench = Bench::new("OtC", "open all files, then close them", 100);
+  bench!(runner, bench.ops(NUM as u64), |p, filenames| {
     let fds = open_many(&mut p, &filenames);
     close_all(&mut p, &fds);
   });
 
-  bench_many!(bench_OC, OC, 100, |p, fd, _f| {
+  bench_many!(runner, Bench::new("OC", "open and close each file", 100), |p, fd, _f| {
     p.close(fd);
   });
 
-  bench!(bench_OtCtU, OtCtU, 800, |p, filenames| {
+  let bench = Bench::new("OtCtU", "open all files, close them, then unlink them", 800);
+  bench!(runner, bench.ops(NUM as u64), |p, filenames| {
     let fds = open_many(&mut p, &filenames);
     close_all(&mut p, &fds);
     unlink_all(&mut p, &filenames);
   });
 
-  bench_many!(bench_OCU, OCU, 500, |p, fd, filename| {
+  bench_many!(runner, Bench::new("OCU", "open, close, and unlink each file", 500), |p, fd, filename| {
     p.close(fd);
     p.unlink(filename);
   });
 
   let size = 1024;
   let content = rand_array(size);
-  bench_many!(bench_OWsC, OWsC, 100, |p, fd, filename| {
+  let bench = Bench::new("OWsC", "open each file, write 1 KiB, and close it", 100);
+  bench_many!(runner, bench.bytes((NUM * size) as u64), |p, fd, filename| {
     p.write(fd, &content);
     p.close(fd);
   });
 
   let size = 1024;
   let content = rand_array(size);
-  bench_many!(bench_OWsCU, OWsCU, 100, |p, fd, filename| {
+  let bench = Bench::new("OWsCU", "open each file, write 1 KiB, close, and unlink it", 100);
+  bench_many!(runner, bench.bytes((NUM * size) as u64), |p, fd, filename| {
     p.write(fd, &content);
     p.close(fd);
     p.unlink(filename);
@@ -127,14 +449,16 @@ fn main() {
 
   let size = 40960;
   let content = rand_array(size);
-  bench_many!(bench_OWbC, OWbC, 100, |p, fd, filename| {
+  let bench = Bench::new("OWbC", "open each file, write 40 KiB, and close it", 100);
+  bench_many!(runner, bench.bytes((NUM * size) as u64), |p, fd, filename| {
     p.write(fd, &content);
     p.close(fd);
   });
 
   let size = 40960;
   let content = rand_array(size);
-  bench_many!(bench_OWbCU, OWbCU, 100, |p, fd, filename| {
+  let bench = Bench::new("OWbCU", "open each file, write 40 KiB, close, and unlink it", 100);
+  bench_many!(runner, bench.bytes((NUM * size) as u64), |p, fd, filename| {
     p.write(fd, &content);
     p.close(fd);
     p.unlink(filename);
@@ -142,7 +466,8 @@ fn main() {
 
   let (size, many) = (1024, 4096);
   let content = rand_array(size);
-  bench_many!(bench_OWMsC, OWMsC, 3000, |p, fd, filename| {
+  let bench = Bench::new("OWMsC", "open each file, write 1 KiB 4096 times, and close it", 3000);
+  bench_many!(runner, bench.bytes((NUM * size * many) as u64), |p, fd, filename| {
     for _ in 0..many {
       p.write(fd, &content);
     }
@@ -151,7 +476,8 @@ fn main() {
 
   let (size, many) = (1024, 4096);
   let content = rand_array(size);
-  bench_many!(bench_OWMsCU, OWMsCU, 5000, |p, fd, filename| {
+  let bench = Bench::new("OWMsCU", "open each file, write 1 KiB 4096 times, close, and unlink it", 5000);
+  bench_many!(runner, bench.bytes((NUM * size * many) as u64), |p, fd, filename| {
     for _ in 0..many {
       p.write(fd, &content);
     }
@@ -161,7 +487,8 @@ fn main() {
 
   let (size, many) = (1048576, 32);
   let content = rand_array(size);
-  bench_many!(bench_OWMbC, OWMbC, 5000, |p, fd, filename| {
+  let bench = Bench::new("OWMbC", "open each file, write 1 MiB 32 times, and close it", 5000);
+  bench_many!(runner, bench.bytes((NUM * size * many) as u64), |p, fd, filename| {
     for _ in 0..many {
       p.write(fd, &content);
     }
@@ -170,7 +497,8 @@ fn main() {
 
   let (size, many) = (1048576, 32);
   let content = rand_array(size);
-  bench_many!(bench_OWMbCU, OWMbCU, 7000, |p, fd, filename| {
+  let bench = Bench::new("OWMbCU", "open each file, write 1 MiB 32 times, close, and unlink it", 7000);
+  bench_many!(runner, bench.bytes((NUM * size * many) as u64), |p, fd, filename| {
     for _ in 0..many {
       p.write(fd, &content);
     }
@@ -178,9 +506,13 @@ fn main() {
     p.unlink(filename);
   });
 
+  // Writes of 2, 4, ..., 2 * many bytes.
   let (start_size, many) = (2, 4096);
   let content = rand_array(start_size * many);
-  bench_many!(bench_OWbbC, OWbbC, 5000, |p, fd, filename| {
+  let grown = NUM * start_size * many * (many + 1) / 2;
+  let description = "open each file, write 4096 times growing to 8 KiB, and close it";
+  let bench = Bench::new("OWbbC", description, 5000);
+  bench_many!(runner, bench.bytes(grown as u64), |p, fd, filename| {
     for i in 1..(many + 1) {
       p.write(fd, &content[..(i * start_size)]);
     }
@@ -189,11 +521,137 @@ fn main() {
 
   let (start_size, many) = (2, 4096);
   let content = rand_array(start_size * many);
-  bench_many!(bench_OWbbCU, OWbbCU, 7000, |p, fd, filename| {
+  let description = "open each file, write 4096 times growing to 8 KiB, close, and unlink it";
+  let bench = Bench::new("OWbbCU", description, 7000);
+  bench_many!(runner, bench.bytes(grown as u64), |p, fd, filename| {
     for i in 1..(many + 1) {
       p.write(fd, &content[0..(i * start_size)]);
     }
     p.close(fd);
     p.unlink(filename);
   });
+
+  let content = rand_array(1048576);
+  let bench = Bench::new("OWMsCU/lat", "OWMsCU, timing each write", 5000);
+  bench_write_latency(&mut runner, bench, &content, &vec![1024; 4096]);
+  let bench = Bench::new("OWMbCU/lat", "OWMbCU, timing each write", 7000);
+  bench_write_latency(&mut runner, bench, &content, &[1048576; 32]);
+  let bench = Bench::new("OWbbCU/lat", "OWbbCU, timing each write", 7000);
+  let sizes: Vec<usize> = (1..(4096 + 1)).map(|i| i * 2).collect();
+  bench_write_latency(&mut runner, bench, &content, &sizes);
+
+  let content = rand_array(1024);
+  let bench = Bench::new("OWsCU", "open each file, write 1 KiB, close, and unlink it", 100);
+  bench_page_sizes(&mut runner, &bench, &content, 1);
+  let bench = Bench::new("OWMsCU", "open each file, write 1 KiB 4096 times, close, and unlink it", 5000);
+  bench_page_sizes(&mut runner, &bench, &content, 4096);
+
+  let content = rand_array(1048576);
+  let bench = Bench::new("OWMbCU", "open each file, write 1 MiB 8 times, close, and unlink it", 7000);
+  bench_page_sizes(&mut runner, &bench, &content, 8);
+
+  bench_alloc_kinds(&mut runner, &Bench::new("OCU", "open, close, and unlink each file", 500), &[], 0);
+  let content = rand_array(1024);
+  let bench = Bench::new("OWsCU", "open each file, write 1 KiB, close, and unlink it", 100);
+  bench_alloc_kinds(&mut runner, &bench, &content, 1);
+  let content = rand_array(40960);
+  let bench = Bench::new("OWbCU", "open each file, write 40 KiB, close, and unlink it", 100);
+  bench_alloc_kinds(&mut runner, &bench, &content, 1);
+  let content = rand_array(1048576);
+  let bench = Bench::new("OWMbCU", "open each file, write 1 MiB 8 times, close, and unlink it", 7000);
+  bench_alloc_kinds(&mut runner, &bench, &content, 8);
+
+  mix::bench_mixes(&mut runner);
+
+  let host_dir = match bench_hosts(&mut runner, &options) {
+    Ok(host_dir) => host_dir,
+    Err(e) => {
+      eprintln!("error: {}", e);
+      process::exit(2);
+    }
+  };
+
+  if options.list {
+    return;
+  }
+
+  if let Some(host_dir) = host_dir {
+    print_host_comparison(&runner, &host_dir);
+  }
+
+  if finish(&runner, &options) {
+    process::exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{bench_host, bench_hosts, parse_options, Options};
+  use bench::{Bench, Runner};
+  use bench::report::Format;
+  use host::{HostDir, Workload};
+  use std::env;
+  use std::fs;
+  use std::path::PathBuf;
+  use std::process;
+
+  // An empty directory of this run's own to put host directories in.
+  fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rustfs-bench-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn options(args: &[&str]) -> Options {
+    parse_options(args.iter().map(|arg| arg.to_string()))
+  }
+
+  fn runner(options: &Options) -> Runner {
+    Runner::new(options.format)
+      .filter(options.patterns.clone())
+      .iterations(options.iterations)
+      .list(options.list)
+  }
+
+  #[test]
+  fn test_host_dir_only_when_needed() {
+    let parent = scratch("lazy-dir");
+    let parent_name = parent.to_str().unwrap();
+
+    // Listing, or running only RustFS, leaves the host alone.
+    for args in [vec!["--list", "--host-dir", parent_name], vec!["--host-dir", parent_name, "--iterations", "1", "OC/rustfs"]] {
+      let options = options(&args);
+      let mut runner = runner(&options);
+      assert!(bench_hosts(&mut runner, &options).unwrap().is_none());
+      assert_eq!(fs::read_dir(&parent).unwrap().count(), 0);
+    }
+
+    let options = options(&["--host-dir", parent_name, "--iterations", "1", "OC/std"]);
+    let mut runner = runner(&options);
+    let host_dir = bench_hosts(&mut runner, &options).unwrap().expect("a host directory");
+    assert_eq!(runner.records().len(), 1);
+    assert!(host_dir.path.is_dir());
+
+    drop(host_dir);
+    fs::remove_dir(&parent).unwrap();
+  }
+
+  #[test]
+  fn test_host_errors() {
+    // A scratch directory that's gone fails the std::fs run instead of
+    // exiting the process.
+    let parent = scratch("host-errors");
+    let dir = HostDir::create(parent.to_str()).unwrap();
+    fs::remove_dir(&dir.path).unwrap();
+
+    let content = [7u8; 16];
+    let workload = Workload { content: &content, writes: vec![16], unlink: true };
+    let mut runner = Runner::new(Format::Text).iterations(Some(1));
+    let error = bench_host(&mut runner, &Bench::new("W", "", 1), &workload, Some(&dir)).unwrap_err();
+    assert!(error.to_string().starts_with("couldn't open"), "{}", error);
+
+    drop(dir);
+    fs::remove_dir(&parent).unwrap();
+  }
 }
@@ -0,0 +1,81 @@
+extern crate rustfs;
+
+// Checks, and optionally repairs, a saved file system image.
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+use rustfs::AllocKind;
+use rustfs::check::{self, Problem};
+
+static USAGE: &str = "\
+Usage: rustfs-fsck [options] IMAGE
+
+Checks the file system in IMAGE, printing each problem found. Exits with
+status 0 if there are none, 1 if there are (or, with --repair, if any are left),
+and 2 if IMAGE can't be loaded at all.
+
+Options:
+  --repair        fix what can be fixed and save the image again
+  --output FILE   save the repaired image to FILE instead of over IMAGE
+  -h, --help      print this message";
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("error: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
+
+fn main() {
+  let (mut image, mut output, mut repair) = (None, None, false);
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--repair" => repair = true,
+      "--output" => output = Some(args.next().unwrap_or_else(|| usage_error("--output needs a value"))),
+      "-h" | "--help" => {
+        println!("{}", USAGE);
+        return;
+      }
+      _ if arg.starts_with('-') => usage_error(&format!("unknown argument '{}'", arg)),
+      _ if image.is_some() => usage_error("more than one image given"),
+      _ => image = Some(arg)
+    }
+  }
+
+  let image = image.unwrap_or_else(|| usage_error("no image given"));
+  if output.is_some() && !repair {
+    usage_error("--output only makes sense with --repair");
+  }
+
+  let loaded = File::open(&image).and_then(|file| check::check_image(BufReader::new(file), AllocKind::default()));
+  let (mut p, mut problems) = loaded.unwrap_or_else(|e| {
+    eprintln!("error: couldn't load {}: {}", image, e);
+    process::exit(2);
+  });
+
+  if repair && !problems.is_empty() {
+    // Repairing checks the tree again. Saving fixes the checksum.
+    let checksum_mismatch = problems.contains(&Problem::ChecksumMismatch);
+    problems = p.repair();
+    if checksum_mismatch {
+      problems.insert(0, Problem::ChecksumMismatch);
+    }
+
+    let output = output.as_ref().unwrap_or(&image);
+    if let Err(e) = p.save(output) {
+      eprintln!("error: couldn't save {}: {}", output, e);
+      process::exit(2);
+    }
+  }
+
+  let repaired = |problem: &Problem| repair && (problem.is_repairable() || *problem == Problem::ChecksumMismatch);
+  for problem in problems.iter() {
+    println!("{}{}", problem, if repaired(problem) { " (repaired)" } else { "" });
+  }
+
+  let left = problems.iter().filter(|problem| !repaired(problem)).count();
+  eprintln!("{}: {} problem(s) found, {} left.", image, problems.len(), left);
+  process::exit(if left == 0 { 0 } else { 1 });
+}
@@ -0,0 +1,206 @@
+// Workloads that run the same way on RustFS and on the host file system, to
+// compare the two.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rustfs::{FileDescriptor, Proc, O_CREAT, O_RDWR};
+
+/**
+ * The handful of file operations a workload needs.
+ */
+pub trait Files<'a> {
+  type Fd;
+
+  // Opens `name` for reading and writing, creating it if it doesn't exist.
+  fn open(&mut self, name: &'a str) -> io::Result<Self::Fd>;
+  fn write(&mut self, fd: &mut Self::Fd, data: &[u8]) -> io::Result<()>;
+  fn close(&mut self, fd: Self::Fd);
+  fn unlink(&mut self, name: &'a str) -> io::Result<()>;
+}
+
+impl<'a> Files<'a> for Proc<'a> {
+  type Fd = FileDescriptor;
+
+  fn open(&mut self, name: &'a str) -> io::Result<FileDescriptor> {
+    match Proc::open(self, name, O_CREAT | O_RDWR) {
+      fd if fd < 0 => Err(io::Error::other(format!("couldn't open {}", name))),
+      fd => Ok(fd)
+    }
+  }
+
+  fn write(&mut self, fd: &mut FileDescriptor, data: &[u8]) -> io::Result<()> {
+    match Proc::write(self, *fd, data) == data.len() {
+      true => Ok(()),
+      false => Err(io::Error::new(io::ErrorKind::WriteZero, "short write"))
+    }
+  }
+
+  fn close(&mut self, fd: FileDescriptor) {
+    Proc::close(self, fd);
+  }
+
+  fn unlink(&mut self, name: &'a str) -> io::Result<()> {
+    Proc::unlink(self, name);
+    Ok(())
+  }
+}
+
+/**
+ * Files in a directory on the host, through `std::fs`. Errors say which file
+ * they were about.
+ */
+pub struct HostFiles<'d> {
+  dir: &'d Path,
+}
+
+impl<'d> HostFiles<'d> {
+  pub fn new(dir: &'d HostDir) -> HostFiles<'d> {
+    HostFiles { dir: &dir.path }
+  }
+}
+
+// Adds what was being done, and to what, to `e`.
+fn failed(what: &str, path: &Path, e: io::Error) -> io::Error {
+  io::Error::new(e.kind(), format!("couldn't {} {}: {}", what, path.display(), e))
+}
+
+impl<'a, 'd> Files<'a> for HostFiles<'d> {
+  type Fd = File;
+
+  fn open(&mut self, name: &'a str) -> io::Result<File> {
+    let path = self.dir.join(name);
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+      .map_err(|e| failed("open", &path, e))
+  }
+
+  fn write(&mut self, fd: &mut File, data: &[u8]) -> io::Result<()> {
+    fd.write_all(data).map_err(|e| failed("write to a file in", self.dir, e))
+  }
+
+  fn close(&mut self, fd: File) {
+    drop(fd);
+  }
+
+  fn unlink(&mut self, name: &'a str) -> io::Result<()> {
+    let path = self.dir.join(name);
+    fs::remove_file(&path).map_err(|e| failed("remove", &path, e))
+  }
+}
+
+/**
+ * A scratch directory for the host benchmarks, removed when dropped.
+ */
+pub struct HostDir {
+  pub path: PathBuf,
+}
+
+impl HostDir {
+  // Creates a fresh directory in `parent`, or in /dev/shm, which is a tmpfs
+  // on Linux and so the fairest comparison, or else the system's temporary
+  // directory.
+  pub fn create(parent: Option<&str>) -> io::Result<HostDir> {
+    let parent = match parent {
+      Some(parent) => PathBuf::from(parent),
+      None if Path::new("/dev/shm").is_dir() => PathBuf::from("/dev/shm"),
+      None => env::temp_dir()
+    };
+
+    let path = parent.join(format!("rustfs-bench-{}", process::id()));
+    fs::create_dir_all(&path).map_err(|e| failed("create", &path, e))?;
+    Ok(HostDir { path })
+  }
+}
+
+impl Drop for HostDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.path);
+  }
+}
+
+/**
+ * What a workload does to each file: open it, write `content[..size]` for
+ * every size in `writes`, close it, and maybe unlink it.
+ */
+pub struct Workload<'c> {
+  pub content: &'c [u8],
+  pub writes: Vec<usize>,
+  pub unlink: bool,
+}
+
+impl<'c> Workload<'c> {
+  pub fn bytes_per_file(&self) -> usize {
+    self.writes.iter().sum()
+  }
+
+  // Stops at the first error: a benchmark that silently stopped writing
+  // would look very fast.
+  pub fn run<'a, F: Files<'a>>(&self, files: &mut F, names: &'a [String]) -> io::Result<()> {
+    for name in names.iter() {
+      let mut fd = files.open(name)?;
+      for &size in self.writes.iter() {
+        files.write(&mut fd, &self.content[..size])?;
+      }
+
+      files.close(fd);
+      if self.unlink {
+        files.unlink(name)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{HostDir, HostFiles, Workload};
+  use rustfs::{Proc, O_RDWR};
+  use std::env;
+  use std::fs;
+
+  #[test]
+  fn test_same_files() {
+    let content: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let names: Vec<String> = (0..5).map(|i| format!("file{}", i)).collect();
+    let parent = env::temp_dir().join("rustfs-bench-same-files");
+    let dir = HostDir::create(parent.to_str()).unwrap();
+
+    // Files that are kept, and files that are removed again.
+    let kept = Workload { content: &content, writes: vec![10, 5000, 1], unlink: false };
+    let removed = Workload { content: &content, writes: vec![100], unlink: true };
+    let mut p = Proc::new();
+    kept.run(&mut p, &names[..3]).unwrap();
+    removed.run(&mut p, &names[3..]).unwrap();
+    let mut files = HostFiles::new(&dir);
+    kept.run(&mut files, &names[..3]).unwrap();
+    removed.run(&mut files, &names[3..]).unwrap();
+
+    let mut on_host: Vec<_> = fs::read_dir(&dir.path).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    on_host.sort();
+    let mut on_rustfs = p.read_dir("").unwrap();
+    on_rustfs.sort();
+    assert_eq!(on_host, on_rustfs);
+    assert_eq!(on_host, &names[..3]);
+
+    for name in names[..3].iter() {
+      let expected = fs::read(dir.path.join(name)).unwrap();
+      assert_eq!(expected.len(), kept.bytes_per_file());
+      assert_eq!(p.metadata(name).unwrap().size, expected.len());
+
+      let mut buf = vec![0; expected.len()];
+      let fd = p.open(name, O_RDWR);
+      p.read(fd, &mut buf);
+      p.close(fd);
+      assert!(buf == expected, "{} differs", name);
+    }
+
+    drop(dir);
+    fs::remove_dir(&parent).unwrap();
+  }
+}
@@ -0,0 +1,352 @@
+// Mixed workloads, declared as data: what files there are, what's done to
+// them and how, and by how many workers.
+
+use std::cmp;
+use std::sync::mpsc;
+use std::thread;
+
+use bench::{Bench, Benchmarker, Runner};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use rustfs::{Proc, Whence, O_CREAT, O_RDWR};
+
+/**
+ * How big files are when they're created.
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum Sizes {
+  Fixed(usize),
+  Uniform(usize, usize),
+  // Uniform in the exponent: as many files between 1 and 2 KiB as between 1
+  // and 2 MiB, like the outputs of a build.
+  LogUniform(usize, usize),
+}
+
+impl Sizes {
+  fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+    match *self {
+      Sizes::Fixed(size) => size,
+      Sizes::Uniform(low, high) => rng.gen_range(low, high + 1),
+      Sizes::LogUniform(low, high) if low >= high => low,
+      Sizes::LogUniform(low, high) => {
+        let (low, high) = ((low as f64).ln(), (high as f64).ln());
+        rng.gen_range(low, high).exp() as usize
+      }
+    }
+  }
+
+  fn max(&self) -> usize {
+    match *self {
+      Sizes::Fixed(size) | Sizes::Uniform(_, size) | Sizes::LogUniform(_, size) => size
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+  // Files in turn, each from where the last operation on it stopped.
+  Sequential,
+  // A random file at a random offset.
+  Random,
+}
+
+/**
+ * A mixed workload. Each worker thread gets a file system of its own with
+ * `files` files in it, `depth` directories deep, then does `ops` operations
+ * on them per iteration: reads and writes of `io_size` bytes, or of the whole
+ * file when that's None, and creates, which replace a file with a new one of a
+ * fresh size. `reads`, `writes`, and `creates` are the operations' relative
+ * weights.
+ *
+ * RustFS has a single directory for now, so depth only makes the paths it
+ * hashes and compares longer.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Mix {
+  pub name: &'static str,
+  pub description: &'static str,
+  pub min_time_ms: u64,
+  pub files: usize,
+  pub sizes: Sizes,
+  pub depth: usize,
+  pub reads: u32,
+  pub writes: u32,
+  pub creates: u32,
+  pub io_size: Option<usize>,
+  pub access: Access,
+  pub ops: usize,
+  pub threads: &'static [usize],
+}
+
+pub static MIXES: &[Mix] = &[
+  Mix {
+    name: "build",
+    description: "compiler-like: read sources, replace outputs of very mixed sizes",
+    min_time_ms: 1000,
+    files: 500,
+    sizes: Sizes::LogUniform(256, 1 << 20),
+    depth: 4,
+    reads: 60,
+    writes: 10,
+    creates: 30,
+    io_size: None,
+    access: Access::Random,
+    ops: 500,
+    threads: &[1, 2, 4],
+  },
+  Mix {
+    name: "cache",
+    description: "object cache: mostly whole-file reads of small entries",
+    min_time_ms: 1000,
+    files: 2000,
+    sizes: Sizes::Uniform(1024, 64 * 1024),
+    depth: 2,
+    reads: 90,
+    writes: 0,
+    creates: 10,
+    io_size: None,
+    access: Access::Random,
+    ops: 2000,
+    threads: &[1, 2, 4],
+  },
+  Mix {
+    name: "rw-random",
+    description: "4 KiB reads and writes at random offsets, 70% reads",
+    min_time_ms: 500,
+    files: 100,
+    sizes: Sizes::Fixed(256 * 1024),
+    depth: 1,
+    reads: 70,
+    writes: 30,
+    creates: 0,
+    io_size: Some(4096),
+    access: Access::Random,
+    ops: 5000,
+    threads: &[1, 4],
+  },
+  Mix {
+    name: "rw-seq",
+    description: "4 KiB reads and writes streaming through each file, 70% reads",
+    min_time_ms: 500,
+    files: 100,
+    sizes: Sizes::Fixed(256 * 1024),
+    depth: 1,
+    reads: 70,
+    writes: 30,
+    creates: 0,
+    io_size: Some(4096),
+    access: Access::Sequential,
+    ops: 5000,
+    threads: &[1, 4],
+  },
+];
+
+fn path(n: usize, depth: usize) -> String {
+  let mut path = String::new();
+  for level in 0..depth {
+    path.push_str(&format!("d{}/", (n >> (3 * level)) % 8));
+  }
+
+  path.push_str(&format!("f{}", n));
+  path
+}
+
+// One worker's file system and where it's at.
+struct Worker<'a> {
+  mix: &'a Mix,
+  proc_: Proc<'a>,
+  names: &'a [String],
+  sizes: Vec<usize>,
+  offsets: Vec<usize>,
+  next: usize,
+  rng: XorShiftRng,
+  content: &'a [u8],
+  buffer: Vec<u8>,
+  ops: usize,
+}
+
+impl<'a> Worker<'a> {
+  fn new(mix: &'a Mix, names: &'a [String], content: &'a [u8], seed: u32) -> Worker<'a> {
+    let mut worker = Worker {
+      mix,
+      proc_: Proc::new(),
+      names,
+      sizes: vec![0; names.len()],
+      offsets: vec![0; names.len()],
+      next: 0,
+      rng: XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, seed + 1]),
+      content,
+      buffer: vec![0; cmp::max(mix.sizes.max(), mix.io_size.unwrap_or(0))],
+      ops: 0
+    };
+
+    for i in 0..names.len() {
+      worker.create(i);
+    }
+
+    worker
+  }
+
+  fn create(&mut self, i: usize) {
+    let (size, name) = (self.mix.sizes.sample(&mut self.rng), &self.names[i]);
+    self.proc_.unlink(name);
+    let fd = self.proc_.open(name, O_CREAT | O_RDWR);
+    self.proc_.write(fd, &self.content[..size]);
+    self.proc_.close(fd);
+    self.sizes[i] = size;
+    self.offsets[i] = 0;
+  }
+
+  // The next file to work on, and where in it.
+  fn pick(&mut self) -> (usize, usize) {
+    let i = match self.mix.access {
+      Access::Sequential => { let i = self.next; self.next = (i + 1) % self.names.len(); i }
+      Access::Random => self.rng.gen_range(0, self.names.len())
+    };
+
+    let (size, io_size) = (self.sizes[i], self.mix.io_size.unwrap_or(self.sizes[i]));
+    let offset = match self.mix.access {
+      _ if io_size >= size => 0,
+      Access::Sequential if self.offsets[i] + io_size > size => 0,
+      Access::Sequential => self.offsets[i],
+      Access::Random => self.rng.gen_range(0, size - io_size + 1)
+    };
+
+    self.offsets[i] = offset + io_size;
+    (i, offset)
+  }
+
+  fn op(&mut self) {
+    self.ops += 1;
+    let mix = self.mix;
+    let choice = self.rng.gen_range(0, mix.reads + mix.writes + mix.creates);
+    let (i, offset) = self.pick();
+    if choice >= mix.reads + mix.writes {
+      return self.create(i);
+    }
+
+    let io_size = mix.io_size.unwrap_or(self.sizes[i]);
+    let fd = self.proc_.open(&self.names[i], O_RDWR);
+    self.proc_.seek(fd, offset as isize, Whence::SeekSet);
+    if choice < mix.reads {
+      self.proc_.read(fd, &mut self.buffer[..io_size]);
+    } else {
+      self.proc_.write(fd, &self.content[..io_size]);
+      self.sizes[i] = cmp::max(self.sizes[i], offset + io_size);
+    }
+
+    self.proc_.close(fd);
+  }
+}
+
+// Runs `mix` with `threads` workers, each on its own thread, as NAME/Nt.
+// Returns how many rounds of `mix.ops` operations the workers were asked for,
+// and how many operations each of them did in all, or None if the benchmark
+// wasn't run.
+fn bench_threads(runner: &mut Runner, mix: &Mix, threads: usize) -> Option<(usize, Vec<usize>)> {
+  let bench = Bench::new(&format!("mix/{}/{}t", mix.name, threads), mix.description, mix.min_time_ms)
+    .ops((mix.ops * threads) as u64);
+  if !runner.is_selected(&bench.name) {
+    return None;
+  }
+
+  // Writes can extend a file by up to `io_size` past its largest size.
+  let content: Vec<u8> = (0..(mix.sizes.max() + mix.io_size.unwrap_or(0))).map(|i| i as u8).collect();
+  let (mut rounds, mut ops) = (0, vec![0; threads]);
+  let ran = runner.bench(&bench, |b: &mut Benchmarker| {
+    thread::scope(|scope| {
+      let (done_tx, done) = mpsc::channel();
+      let starts: Vec<_> = (0..threads).map(|t| {
+        let (start, go) = mpsc::channel::<()>();
+        let (done_tx, content) = (done_tx.clone(), &content);
+        scope.spawn(move || {
+          let names: Vec<String> = (0..mix.files).map(|n| path(n, mix.depth)).collect();
+          let mut worker = Worker::new(mix, &names, content, t as u32);
+          done_tx.send((t, 0)).unwrap();
+          for () in go.iter() {
+            worker.ops = 0;
+            for _ in 0..mix.ops {
+              worker.op();
+            }
+
+            done_tx.send((t, worker.ops)).unwrap();
+          }
+        });
+
+        start
+      }).collect();
+
+      // Wait for every worker to set up before timing anything.
+      for _ in 0..threads {
+        done.recv().unwrap();
+      }
+
+      b.run(|| {
+        for start in starts.iter() {
+          start.send(()).unwrap();
+        }
+
+        for _ in 0..threads {
+          let (t, count) = done.recv().unwrap();
+          ops[t] += count;
+        }
+
+        rounds += 1;
+      });
+    });
+  }).is_some();
+
+  if ran { Some((rounds, ops)) } else { None }
+}
+
+pub fn bench_mixes(runner: &mut Runner) {
+  for mix in MIXES.iter() {
+    for &threads in mix.threads.iter() {
+      bench_threads(runner, mix, threads);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{bench_threads, Access, Mix, Sizes};
+  use bench::Runner;
+  use bench::report::Format;
+  use rand::{SeedableRng, XorShiftRng};
+
+  static SMALL: Mix = Mix {
+    name: "small",
+    description: "a little of everything",
+    min_time_ms: 1,
+    files: 10,
+    sizes: Sizes::LogUniform(100, 5000),
+    depth: 2,
+    reads: 50,
+    writes: 30,
+    creates: 20,
+    io_size: Some(64),
+    access: Access::Random,
+    ops: 25,
+    threads: &[1, 3],
+  };
+
+  #[test]
+  fn test_equal_bounds() {
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    for &sizes in [Sizes::Fixed(7), Sizes::Uniform(7, 7), Sizes::LogUniform(7, 7)].iter() {
+      assert_eq!(sizes.sample(&mut rng), 7);
+    }
+  }
+
+  #[test]
+  fn test_ops_per_worker() {
+    for &(mix, threads) in [(SMALL, 1), (SMALL, 3), (Mix { access: Access::Sequential, io_size: None, ..SMALL }, 2)].iter() {
+      let mut runner = Runner::new(Format::Text).iterations(Some(1));
+      let (rounds, ops) = bench_threads(&mut runner, &mix, threads).expect("ran");
+      assert!(rounds > 0);
+      assert_eq!(ops, vec![rounds * mix.ops; threads]);
+    }
+
+    let mut lister = Runner::new(Format::Text).list(true);
+    assert!(bench_threads(&mut lister, &SMALL, 1).is_none());
+  }
+}
@@ -0,0 +1,156 @@
+extern crate bench;
+extern crate rustfs;
+
+// Replays, benchmarks, converts, and prints traces of Proc calls.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::process;
+use std::time::Instant;
+
+use bench::{Bench, Benchmarker, Runner};
+use bench::report::Format;
+use rustfs::Proc;
+use rustfs::strace;
+use rustfs::trace::{self, Event, ReplayStats, Timing, TraceWriter};
+
+static USAGE: &str = "\
+Usage: rustfs-trace replay TRACE [--original-timing]
+       rustfs-trace bench TRACE [--min-time MS] [--format FORMAT]
+       rustfs-trace convert STRACE_LOG TRACE
+       rustfs-trace dump TRACE
+
+replay   runs TRACE once on a fresh file system, as fast as possible or, with
+         --original-timing, with the calls as far apart as they were traced
+bench    times replays of TRACE, each on a fresh file system, for at least MS
+         ms (default 1000), printing results as text (default), json, or csv
+convert  makes a trace from the output of strace -f, with -tt or -ttt for
+         timing
+dump     prints TRACE's calls, one per line";
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("error: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
+
+fn fail<E: ::std::fmt::Display>(what: &str, path: &str, e: E) -> ! {
+  eprintln!("error: couldn't {} {}: {}", what, path, e);
+  process::exit(1);
+}
+
+fn load(path: &str) -> Vec<Event> {
+  let file = File::open(path).unwrap_or_else(|e| fail("open", path, e));
+  trace::read_trace(BufReader::new(file)).unwrap_or_else(|e| fail("read trace", path, e))
+}
+
+fn print_stats(stats: &ReplayStats) {
+  if stats.mismatches > 0 {
+    eprintln!("{} call(s) returned something other than they did when traced.", stats.mismatches);
+  }
+
+  if stats.skipped > 0 {
+    eprintln!("{} call(s) were skipped, being on files that weren't open.", stats.skipped);
+  }
+}
+
+fn replay(path: &str, timing: Timing) {
+  let events = load(path);
+  let start = Instant::now();
+  let stats = trace::replay(&mut Proc::new(), &events, timing);
+  let elapsed = start.elapsed().as_secs_f64();
+  eprintln!("Replayed {} calls in {:.3} ms ({:.0} calls/s).", stats.calls, elapsed * 1e3,
+            stats.calls as f64 / elapsed);
+  print_stats(&stats);
+}
+
+fn bench(path: &str, min_time: u64, format: Format) {
+  let events = load(path);
+  let mut stats = ReplayStats::default();
+  let name = path.rsplit('/').next().unwrap_or(path);
+  let bench = Bench::new(&format!("trace/{}", name), &format!("replay of {}", path), min_time)
+    .ops(events.len() as u64);
+
+  let mut runner = Runner::new(format);
+  runner.bench(&bench, |b: &mut Benchmarker| {
+    b.run(|| stats = trace::replay(&mut Proc::new(), &events, Timing::Fast));
+  });
+
+  if let Some(report) = runner.report() {
+    print!("{}", report);
+  }
+
+  print_stats(&stats);
+}
+
+fn convert(input: &str, output: &str) {
+  let file = File::open(input).unwrap_or_else(|e| fail("open", input, e));
+  let (events, stats) = strace::convert(BufReader::new(file)).unwrap_or_else(|e| fail("read", input, e));
+  let file = File::create(output).unwrap_or_else(|e| fail("create", output, e));
+  let result = TraceWriter::new(BufWriter::new(file)).and_then(|mut writer| {
+    for event in events.iter() {
+      writer.write(event)?;
+    }
+
+    writer.flush()
+  });
+
+  if let Err(e) = result {
+    fail("write", output, e);
+  }
+
+  eprintln!("Converted {} calls from {} lines; ignored {} other calls.", stats.calls, stats.lines,
+            stats.ignored);
+}
+
+fn dump(path: &str) {
+  let stdout = ::std::io::stdout();
+  let mut out = stdout.lock();
+  for event in load(path).iter() {
+    if writeln!(out, "{}", event).is_err() {
+      return;
+    }
+  }
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+    println!("{}", USAGE);
+    return;
+  }
+
+  let (command, rest) = match args.split_first() {
+    Some((command, rest)) => (command.as_str(), rest),
+    None => usage_error("no command given")
+  };
+
+  let (mut paths, mut options) = (Vec::new(), rest.iter());
+  let (mut timing, mut min_time, mut format) = (Timing::Fast, 1000, Format::Text);
+  while let Some(arg) = options.next() {
+    let mut value = || options.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+    match arg.as_str() {
+      "--original-timing" if command == "replay" => timing = Timing::Original,
+      "--min-time" if command == "bench" => {
+        let ms = value();
+        min_time = ms.parse().ok().filter(|&ms| ms > 0)
+          .unwrap_or_else(|| usage_error(&format!("bad minimum time '{}'", ms)));
+      }
+      "--format" if command == "bench" => {
+        let name = value();
+        format = Format::parse(name).unwrap_or_else(|| usage_error(&format!("unknown format '{}'", name)));
+      }
+      _ if arg.starts_with('-') => usage_error(&format!("unknown argument '{}'", arg)),
+      _ => paths.push(arg.as_str())
+    }
+  }
+
+  match (command, paths.as_slice()) {
+    ("replay", &[path]) => replay(path, timing),
+    ("bench", &[path]) => bench(path, min_time, format),
+    ("convert", &[input, output]) => convert(input, output),
+    ("dump", &[path]) => dump(path),
+    ("replay", _) | ("bench", _) | ("convert", _) | ("dump", _) => usage_error("wrong number of files"),
+    _ => usage_error(&format!("unknown command '{}'", command))
+  }
+}
@@ -0,0 +1,68 @@
+// Measures how page allocation scales as more threads allocate at once: the
+// thread-safe slab allocator, the system allocator, and a free list behind a
+// single lock. Run with `cargo bench -p bench`.
+
+extern crate bench;
+extern crate slab;
+
+use std::sync::Mutex;
+use std::thread;
+
+use bench::benchmark;
+use slab::sync::SliceAllocator;
+
+static THREADS: [usize; 4] = [1, 2, 4, 8];
+const PAGE_SIZE: usize = 4096;
+const BATCH: usize = 64;     // pages each thread holds at once
+const ROUNDS: usize = 16;    // batches each thread allocates per iteration
+const TIME: u64 = 300;
+
+// Runs `work` on `threads` threads at once, each allocating and freeing
+// ROUNDS batches of BATCH pages with `alloc`.
+fn contend<P, F: Fn() -> P + Sync>(threads: usize, alloc: F) {
+  thread::scope(|scope| {
+    for _ in 0..threads {
+      scope.spawn(|| {
+        for _ in 0..ROUNDS {
+          let pages: Vec<P> = (0..BATCH).map(|_| alloc()).collect();
+          drop(pages);
+        }
+      });
+    }
+  });
+}
+
+fn main() {
+  for &threads in THREADS.iter() {
+    let allocator = SliceAllocator::new(threads * BATCH, PAGE_SIZE);
+    benchmark(&format!("slab x{}", threads), |b| {
+      b.run(|| contend(threads, || allocator.alloc(0u8)));
+    }, TIME);
+
+    benchmark(&format!("heap x{}", threads), |b| {
+      b.run(|| contend(threads, || vec![0u8; PAGE_SIZE].into_boxed_slice()));
+    }, TIME);
+
+    // A free list shared by every thread, behind one lock. Pages are zeroed
+    // on reuse, like the other two.
+    let free_list: Mutex<Vec<Box<[u8]>>> = Mutex::new(Vec::new());
+    benchmark(&format!("locked x{}", threads), |b| {
+      b.run(|| contend(threads, || {
+        struct Page<'a>(Option<Box<[u8]>>, &'a Mutex<Vec<Box<[u8]>>>);
+
+        impl<'a> Drop for Page<'a> {
+          fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0.take().unwrap());
+          }
+        }
+
+        let page = free_list.lock().unwrap().pop().map(|mut page| {
+          page.fill(0);
+          page
+        });
+
+        Page(Some(page.unwrap_or_else(|| vec![0u8; PAGE_SIZE].into_boxed_slice())), &free_list)
+      }));
+    }, TIME);
+  }
+}
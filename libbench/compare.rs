@@ -0,0 +1,207 @@
+/*!
+ * Comparing benchmark results against a baseline.
+ *
+ * A benchmark's change is only reported as a regression or an improvement if
+ * it's both larger than the threshold and statistically significant under
+ * Welch's t-test, which doesn't assume the two runs have the same variance.
+ */
+
+use std::fmt;
+
+use super::report::Estimate;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+  Improved,
+  Unchanged,
+  Regressed
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+  pub name: String,
+  pub baseline: f64,   // mean ns/iter
+  pub current: f64,    // mean ns/iter
+  pub change: f64,     // relative change in the mean; 0.1 is 10% slower
+  pub p_value: f64,
+  pub verdict: Verdict,
+}
+
+impl fmt::Display for Comparison {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let verdict = match self.verdict {
+      Verdict::Improved => "improved",
+      Verdict::Unchanged => "no change",
+      Verdict::Regressed => "REGRESSED"
+    };
+
+    write!(f, "{:10}: {:12.0} -> {:12.0} ns/iter ({:+6.2}%, p = {:.3}) {}",
+           self.name, self.baseline, self.current, self.change * 100.0, self.p_value, verdict)
+  }
+}
+
+// ln(Γ(x)) for x > 0, using the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+  const COEFFICIENTS: [f64; 6] = [
+    76.18009172947146, -86.50532032941677, 24.01409824083091,
+    -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5
+  ];
+
+  let tmp = x + 5.5;
+  let tmp = tmp - (x + 0.5) * tmp.ln();
+  let mut series = 1.000000000190015;
+  for (i, c) in COEFFICIENTS.iter().enumerate() {
+    series += c / (x + 1.0 + i as f64);
+  }
+
+  -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+// Continued fraction for the incomplete beta function, by Lentz's method.
+fn beta_continued_fraction(a: f64, b: f64, x: f64) -> f64 {
+  const TINY: f64 = 1e-300;
+  let (qab, qap, qam) = (a + b, a + 1.0, a - 1.0);
+  let mut c = 1.0;
+  let mut d = 1.0 - qab * x / qap;
+  if d.abs() < TINY { d = TINY; }
+  d = 1.0 / d;
+  let mut h = d;
+
+  for m in 1..300 {
+    let m = m as f64;
+    let m2 = 2.0 * m;
+    for &aa in [m * (b - m) * x / ((qam + m2) * (a + m2)),
+                -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2))].iter() {
+      d = 1.0 + aa * d;
+      if d.abs() < TINY { d = TINY; }
+      c = 1.0 + aa / c;
+      if c.abs() < TINY { c = TINY; }
+      d = 1.0 / d;
+      h *= d * c;
+    }
+
+    if (d * c - 1.0).abs() < 1e-12 { break }
+  }
+
+  h
+}
+
+// The regularized incomplete beta function I_x(a, b).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+  if x <= 0.0 { return 0.0; }
+  if x >= 1.0 { return 1.0; }
+
+  let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+  if x < (a + 1.0) / (a + b + 2.0) {
+    front * beta_continued_fraction(a, b, x) / a
+  } else {
+    1.0 - front * beta_continued_fraction(b, a, 1.0 - x) / b
+  }
+}
+
+/**
+ * The two-sided p-value of Welch's t-test on two sets of samples, given their
+ * means, sample standard deviations, and sizes.
+ */
+pub fn welch_p_value(a: &Estimate, b: &Estimate) -> f64 {
+  let (va, vb) = (a.stddev * a.stddev / a.samples as f64, b.stddev * b.stddev / b.samples as f64);
+  if va + vb == 0.0 {
+    // No spread at all: any difference is as significant as it gets.
+    return if a.mean == b.mean { 1.0 } else { 0.0 };
+  }
+
+  if a.samples < 2 || b.samples < 2 {
+    return 1.0;
+  }
+
+  let t = (a.mean - b.mean) / (va + vb).sqrt();
+  let df = (va + vb) * (va + vb)
+    / (va * va / (a.samples - 1) as f64 + vb * vb / (b.samples - 1) as f64);
+  incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+/**
+ * Compares every benchmark in `current` that's also in `baseline`. Changes in
+ * the mean larger than `threshold` (0.05 is 5%) with a p-value below `alpha`
+ * count as regressions or improvements. A baseline whose mean is zero has
+ * nothing to measure a relative change against, so it isn't compared.
+ */
+pub fn compare(baseline: &[Estimate], current: &[Estimate], threshold: f64, alpha: f64) -> Vec<Comparison> {
+  current.iter().filter_map(|now| {
+    let then = baseline.iter().find(|then| then.name == now.name && then.mean != 0.0)?;
+    let change = (now.mean - then.mean) / then.mean;
+    let p_value = welch_p_value(then, now);
+    let verdict = if p_value >= alpha || change.abs() <= threshold {
+      Verdict::Unchanged
+    } else if change > 0.0 {
+      Verdict::Regressed
+    } else {
+      Verdict::Improved
+    };
+
+    Some(Comparison { name: now.name.clone(), baseline: then.mean, current: now.mean, change, p_value, verdict })
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{compare, welch_p_value, Verdict};
+  use super::super::report::Estimate;
+  use super::super::Summary;
+
+  fn estimate(name: &str, samples: &[f64]) -> Estimate {
+    let summary = Summary::new(samples);
+    Estimate { name: name.to_string(), samples: samples.len(), mean: summary.mean,
+               stddev: summary.stddev, median: summary.median }
+  }
+
+  #[test]
+  fn test_welch() {
+    // The first example in Welch's t-test's Wikipedia article: t = -2.46,
+    // df = 25.0, p = 0.021.
+    let a = estimate("a", &[27.5, 21.0, 19.0, 23.6, 17.0, 17.9, 16.9, 20.1, 21.9, 22.6, 23.1, 19.6, 19.0, 21.7, 21.4]);
+    let b = estimate("b", &[27.1, 22.0, 20.8, 23.4, 23.4, 23.5, 25.8, 22.0, 24.8, 20.2, 21.9, 22.1, 22.9, 20.5, 24.4]);
+    let p = welch_p_value(&a, &b);
+    assert!((p - 0.021).abs() < 0.001, "p = {}", p);
+    assert_eq!(welch_p_value(&a, &a), 1.0);
+  }
+
+  #[test]
+  fn test_compare() {
+    let noisy: Vec<f64> = (0..20).map(|i| 100.0 + (i % 5) as f64).collect();
+    let slower: Vec<f64> = noisy.iter().map(|x| x * 1.2).collect();
+    let faster: Vec<f64> = noisy.iter().map(|x| x * 0.8).collect();
+    let slightly: Vec<f64> = noisy.iter().map(|x| x * 1.01).collect();
+
+    let baseline = vec![estimate("a", &noisy), estimate("b", &noisy), estimate("c", &noisy)];
+    let current = vec![estimate("a", &slower), estimate("b", &faster), estimate("c", &slightly),
+                       estimate("new", &noisy)];
+    let verdicts: Vec<_> = compare(&baseline, &current, 0.05, 0.05).iter()
+      .map(|comparison| (comparison.name.clone(), comparison.verdict))
+      .collect();
+
+    assert_eq!(verdicts, vec![("a".to_string(), Verdict::Regressed), ("b".to_string(), Verdict::Improved),
+                              ("c".to_string(), Verdict::Unchanged)]);
+  }
+
+  #[test]
+  fn test_insignificant_change() {
+    // A big change in the mean driven by a couple of wild samples isn't
+    // significant.
+    let baseline = estimate("a", &[100.0, 10.0, 190.0, 100.0]);
+    let current = estimate("a", &[130.0, 20.0, 240.0, 130.0]);
+    let comparison = &compare(&[baseline], &[current], 0.05, 0.05)[0];
+    assert!(comparison.change > 0.05);
+    assert_eq!(comparison.verdict, Verdict::Unchanged);
+  }
+
+  #[test]
+  fn test_zero_baseline() {
+    let baseline = vec![estimate("a", &[0.0, 0.0, 0.0]), estimate("b", &[10.0, 11.0, 12.0])];
+    let current = vec![estimate("a", &[5.0, 6.0, 7.0]), estimate("b", &[10.0, 11.0, 12.0])];
+    let comparisons = compare(&baseline, &current, 0.05, 0.05);
+    assert_eq!(comparisons.len(), 1);
+    assert_eq!(comparisons[0].name, "b");
+    assert!(comparisons[0].change.is_finite());
+  }
+}
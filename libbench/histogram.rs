@@ -0,0 +1,237 @@
+/*!
+ * Latency histograms.
+ *
+ * A Histogram counts values, in ns, in logarithmic buckets the way HDR
+ * histograms do: each power of two is split into the same number of linear
+ * sub-buckets, so every recorded value is known to within 1% however large it
+ * is, and recording is a couple of shifts and an increment. Memory grows with
+ * the largest value recorded, up to about 60 KiB for values near u64::MAX.
+ */
+
+use std::cmp;
+use std::fmt;
+
+// Values below 2^SUB_BITS get a bucket each; above, every power of two is
+// split into 2^(SUB_BITS - 1) buckets, for a relative error under 2^-(SUB_BITS - 1).
+const SUB_BITS: u32 = 8;
+const SUB_COUNT: u64 = 1 << SUB_BITS;
+const HALF_COUNT: u64 = SUB_COUNT / 2;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+  counts: Vec<u64>,
+  total: u64,
+  sum: u128,
+  min: u64,
+  max: u64,
+}
+
+// The bucket `value` falls in.
+fn index_of(value: u64) -> usize {
+  if value < SUB_COUNT {
+    return value as usize;
+  }
+
+  let shift = 64 - value.leading_zeros() - SUB_BITS;
+  (shift as u64 * HALF_COUNT + (value >> shift)) as usize
+}
+
+// The smallest and largest values that fall in bucket `index`.
+fn bounds_of(index: usize) -> (u64, u64) {
+  let index = index as u64;
+  if index < SUB_COUNT {
+    return (index, index);
+  }
+
+  let shift = index / HALF_COUNT - 1;
+  let sub = index - shift * HALF_COUNT;
+  (sub << shift, ((sub + 1) << shift).wrapping_sub(1))
+}
+
+impl Histogram {
+  pub fn new() -> Histogram {
+    Histogram::default()
+  }
+
+  pub fn record(&mut self, value: u64) {
+    self.record_n(value, 1);
+  }
+
+  // Records `count` occurrences of `value`.
+  pub fn record_n(&mut self, value: u64, count: u64) {
+    if count == 0 {
+      return;
+    }
+
+    let index = index_of(value);
+    if index >= self.counts.len() {
+      self.counts.resize(index + 1, 0);
+    }
+
+    self.counts[index] += count;
+    self.min = if self.total == 0 { value } else { cmp::min(self.min, value) };
+    self.max = cmp::max(self.max, value);
+    self.total += count;
+    self.sum += value as u128 * count as u128;
+  }
+
+  // Adds everything recorded in `other` to this histogram.
+  pub fn merge(&mut self, other: &Histogram) {
+    if other.total == 0 {
+      return;
+    }
+
+    if other.counts.len() > self.counts.len() {
+      self.counts.resize(other.counts.len(), 0);
+    }
+
+    for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+      *mine += theirs;
+    }
+
+    self.min = if self.total == 0 { other.min } else { cmp::min(self.min, other.min) };
+    self.max = cmp::max(self.max, other.max);
+    self.total += other.total;
+    self.sum += other.sum;
+  }
+
+  pub fn clear(&mut self) {
+    *self = Histogram::new();
+  }
+
+  pub fn len(&self) -> u64 {
+    self.total
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.total == 0
+  }
+
+  pub fn min(&self) -> u64 {
+    self.min
+  }
+
+  pub fn max(&self) -> u64 {
+    self.max
+  }
+
+  pub fn mean(&self) -> f64 {
+    if self.total == 0 { 0.0 } else { self.sum as f64 / self.total as f64 }
+  }
+
+  // The smallest value that `p` percent of recorded values are at most, for
+  // `p` in [0, 100], to within the bucket width. 0 if nothing was recorded.
+  pub fn percentile(&self, p: f64) -> u64 {
+    if self.total == 0 {
+      return 0;
+    }
+
+    // Percentages like 99.9 aren't exact in binary; don't let the rounding
+    // error push the rank past a whole number.
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * self.total as f64 * (1.0 - 1e-12)).ceil() as u64;
+    let rank = cmp::max(rank, 1);
+    let mut seen = 0;
+    for (index, &count) in self.counts.iter().enumerate() {
+      seen += count;
+      if seen >= rank {
+        return bounds_of(index).1.clamp(self.min, self.max);
+      }
+    }
+
+    self.max
+  }
+
+  pub fn latencies(&self) -> Latencies {
+    Latencies {
+      count: self.total,
+      p50: self.percentile(50.0),
+      p90: self.percentile(90.0),
+      p99: self.percentile(99.0),
+      p999: self.percentile(99.9),
+      max: self.max
+    }
+  }
+}
+
+/**
+ * The tail of a latency histogram, in ns.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Latencies {
+  pub count: u64,
+  pub p50: u64,
+  pub p90: u64,
+  pub p99: u64,
+  pub p999: u64,
+  pub max: u64,
+}
+
+impl fmt::Display for Latencies {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "p50 {}, p90 {}, p99 {}, p99.9 {}, max {} ns/op ({} ops)",
+           self.p50, self.p90, self.p99, self.p999, self.max, self.count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{bounds_of, index_of, Histogram};
+
+  #[test]
+  fn test_buckets() {
+    for value in (0..100_000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+      let (low, high) = bounds_of(index_of(value));
+      assert!(low <= value && value <= high, "{} not in [{}, {}]", value, low, high);
+      assert!((high - low) as f64 <= value as f64 / 128.0, "bucket of {} too wide", value);
+    }
+
+    // Buckets are contiguous.
+    for index in 1..2000 {
+      assert_eq!(bounds_of(index).0, bounds_of(index - 1).1 + 1);
+    }
+  }
+
+  #[test]
+  fn test_percentiles() {
+    let mut histogram = Histogram::new();
+    assert_eq!((histogram.percentile(99.0), histogram.mean()), (0, 0.0));
+
+    for value in 1..=1000 {
+      histogram.record(value * 1000);
+    }
+
+    let latencies = histogram.latencies();
+    assert_eq!((latencies.count, histogram.min(), latencies.max), (1000, 1000, 1_000_000));
+    assert_eq!(histogram.mean(), 500_500.0);
+    for &(p, expected) in &[(50.0, 500_000.0), (90.0, 900_000.0), (99.0, 990_000.0), (99.9, 999_000.0)] {
+      let value = histogram.percentile(p) as f64;
+      assert!(value >= expected && value <= expected * 1.01, "p{} = {}", p, value);
+    }
+
+    assert!((1000..1010).contains(&histogram.percentile(0.0)));
+    assert_eq!(histogram.percentile(100.0), 1_000_000);
+  }
+
+  #[test]
+  fn test_spike() {
+    // Five slow operations in a thousand show up at p99.9 but not p99.
+    let mut histogram = Histogram::new();
+    histogram.record_n(100, 995);
+    histogram.record_n(50_000, 5);
+    let latencies = histogram.latencies();
+    assert_eq!((latencies.p50, latencies.p90, latencies.p99), (100, 100, 100));
+    assert_eq!((latencies.p999, latencies.max), (50_000, 50_000));
+    assert_eq!(histogram.mean(), (99_500.0 + 250_000.0) / 1000.0);
+  }
+
+  #[test]
+  fn test_merge() {
+    let (mut a, mut b) = (Histogram::new(), Histogram::new());
+    a.record(10);
+    b.record(1_000_003);
+    b.record(7);
+    a.merge(&b);
+    assert_eq!((a.len(), a.min(), a.max()), (3, 7, 1_000_003));
+    assert_eq!(a.mean(), 1_000_020.0 / 3.0);
+  }
+}
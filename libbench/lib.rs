@@ -1,23 +1,220 @@
-#![feature(asm)]
+use std::cmp;
+use std::fmt;
+use std::hint::black_box;
+use std::mem;
+use std::time::Instant;
 
-extern crate time;
+use histogram::Histogram;
+use memory::{CountingAllocator, Counts, MemoryUse};
+use report::{Format, Record};
 
-use std::cmp;
-use time::precise_time_ns;
+pub mod compare;
+pub mod histogram;
+pub mod memory;
+pub mod report;
+
+pub type IterationCount = u64;
+pub type BenchTimeNS = u64;
+
+// Fewest samples a benchmark is summarized from, however slow it is.
+const MIN_SAMPLES: usize = 5;
+
+/**
+ * How long and how often a benchmark is run.
+ *
+ * A benchmark is first warmed up for `warmup_ms`, which also estimates how
+ * long an iteration takes. It's then run `samples` times, each sample timing
+ * enough iterations for all of them to take about `min_time_ms` together. A
+ * benchmark whose iterations are slower than that runs one iteration per
+ * sample and takes longer.
+ *
+ * Setting `iterations` skips both the warmup and the estimate: every sample
+ * then runs exactly that many iterations, however long they take.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+  pub warmup_ms: u64,
+  pub min_time_ms: u64,
+  pub samples: usize,
+  pub iterations: Option<IterationCount>,
+}
+
+impl Config {
+  // Runs for about `min_time_ms` ms, warming up for a tenth of that.
+  pub fn with_min_time(min_time_ms: u64) -> Config {
+    Config {
+      warmup_ms: cmp::max(min_time_ms / 10, 1),
+      min_time_ms,
+      samples: 20,
+      iterations: None
+    }
+  }
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config::with_min_time(1000)
+  }
+}
+
+/**
+ * The number of samples that are unusually far from the rest, using Tukey's
+ * fences: mild outliers are more than 1.5 interquartile ranges below the first
+ * or above the third quartile, severe ones more than 3.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Outliers {
+  pub low_severe: usize,
+  pub low_mild: usize,
+  pub high_mild: usize,
+  pub high_severe: usize,
+}
+
+impl Outliers {
+  pub fn total(&self) -> usize {
+    self.low_severe + self.low_mild + self.high_mild + self.high_severe
+  }
+}
+
+/**
+ * Summary statistics of a set of samples, each in ns/iter.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+  pub min: f64,
+  pub max: f64,
+  pub mean: f64,
+  pub median: f64,
+  pub stddev: f64,    // sample standard deviation
+  pub outliers: Outliers,
+  sorted: Vec<f64>,
+}
+
+impl Summary {
+  // Panics if `samples` is empty.
+  pub fn new(samples: &[f64]) -> Summary {
+    assert!(!samples.is_empty(), "Can't summarize zero samples.");
+
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let n = sorted.len() as f64;
+    let mean = sorted.iter().sum::<f64>() / n;
+    let variance = if sorted.len() > 1 {
+      sorted.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0)
+    } else {
+      0.0
+    };
+
+    let mut summary = Summary {
+      min: sorted[0],
+      max: sorted[sorted.len() - 1],
+      mean,
+      median: 0.0,
+      stddev: variance.sqrt(),
+      outliers: Outliers::default(),
+      sorted
+    };
+
+    summary.median = summary.percentile(50.0);
+    summary.outliers = summary.count_outliers();
+    summary
+  }
+
+  // The `p`th percentile, for `p` in [0, 100], interpolating linearly between
+  // the closest samples.
+  pub fn percentile(&self, p: f64) -> f64 {
+    let rank = (p.clamp(0.0, 100.0) / 100.0) * (self.sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    let fraction = rank - lower as f64;
+    self.sorted[lower] + (self.sorted[upper] - self.sorted[lower]) * fraction
+  }
+
+  pub fn len(&self) -> usize {
+    self.sorted.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.sorted.is_empty()
+  }
+
+  fn count_outliers(&self) -> Outliers {
+    let (q1, q3) = (self.percentile(25.0), self.percentile(75.0));
+    let iqr = q3 - q1;
+    let mut outliers = Outliers::default();
+    for &x in self.sorted.iter() {
+      if x < q1 - 3.0 * iqr {
+        outliers.low_severe += 1;
+      } else if x < q1 - 1.5 * iqr {
+        outliers.low_mild += 1;
+      } else if x > q3 + 3.0 * iqr {
+        outliers.high_severe += 1;
+      } else if x > q3 + 1.5 * iqr {
+        outliers.high_mild += 1;
+      }
+    }
+
+    outliers
+  }
+}
+
+/**
+ * The outcome of a benchmark: `samples.len()` samples of `iterations` each,
+ * and the latency of every operation measured with `Timer::measure` while
+ * they ran, if there were any.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchResults {
+  pub iterations: IterationCount,   // iterations per sample
+  pub samples: Vec<f64>,            // ns/iter of each sample
+  pub summary: Summary,
+  pub latency: Option<Histogram>,
+}
+
+impl fmt::Display for BenchResults {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = &self.summary;
+    write!(f, "{:12.0} ns/iter (+/- {:.0}) [min {:.0}, mean {:.0}, p95 {:.0}, max {:.0}] ({} x {} it.",
+           s.median, s.stddev, s.min, s.mean, s.percentile(95.0), s.max, s.len(), self.iterations)?;
+
+    match s.outliers.total() {
+      0 => write!(f, ")"),
+      n => write!(f, ", {} outlier{})", n, if n == 1 { "" } else { "s" })
+    }
+  }
+}
+
+/**
+ * Times single operations inside `Benchmarker::run_measured`.
+ */
+pub struct Timer<'b> {
+  latency: &'b mut Histogram,
+}
+
+impl<'b> Timer<'b> {
+  // Runs `op`, recording how long it took.
+  pub fn measure<R, F: FnOnce() -> R>(&mut self, op: F) -> R {
+    let start = Instant::now();
+    let result = black_box(op());
+    self.latency.record(start.elapsed().as_nanos() as u64);
+    result
+  }
+}
 
 pub struct Benchmarker {
   iterations: u64,
   ns_start: u64,
   ns_end: u64,
+  epoch: Instant,
+  latency: Histogram,
+  memory: Option<&'static CountingAllocator>,
+  retained: Option<Counts>,     // the memory counts as `run` returned
 }
 
-pub type IterationCount = u64;
-pub type BenchTimeNS = u64;
-pub type BenchResults = (IterationCount, BenchTimeNS);
-
-fn black_box<T>(dummy: T) {
-  // we need to "use" the argument in some way LLVM can't introspect.
-  unsafe {asm!("" : : "r"(&dummy))}
+impl Default for Benchmarker {
+  fn default() -> Benchmarker {
+    Benchmarker::new()
+  }
 }
 
 impl Benchmarker {
@@ -25,75 +222,418 @@ impl Benchmarker {
     Benchmarker {
       iterations: 0,
       ns_start: 0,
-      ns_end: 0
+      ns_end: 0,
+      epoch: Instant::now(),
+      latency: Histogram::new(),
+      memory: None,
+      retained: None
     }
   }
 
+  fn now_ns(&self) -> u64 {
+    self.epoch.elapsed().as_nanos() as u64
+  }
+
   pub fn run<F: FnMut()>(&mut self, mut f: F) -> BenchTimeNS {
     let k = self.iterations;
-    self.ns_start = precise_time_ns();
+    self.ns_start = self.now_ns();
     for _ in 0..k {
-      black_box(f());
+      black_box(&mut f)();
     }
-    self.ns_end = precise_time_ns();
+    self.ns_end = self.now_ns();
+    self.retained = self.memory.map(CountingAllocator::counts);
     self.ns_end - self.ns_start
   }
 
-  fn ns_per_iter(&self) -> BenchTimeNS {
-    (self.ns_end - self.ns_start) / self.iterations
+  /**
+   * Like `run`, but `f` can also time the operations it's made of, one by one,
+   * with the Timer it's given. Their latencies are reported as a histogram
+   * alongside the usual ns/iter, which then includes the cost of reading the
+   * clock around every measured operation.
+   */
+  pub fn run_measured<F: FnMut(&mut Timer)>(&mut self, mut f: F) -> BenchTimeNS {
+    let k = self.iterations;
+    self.ns_start = self.now_ns();
+    let mut timer = Timer { latency: &mut self.latency };
+    for _ in 0..k {
+      black_box(&mut f)(&mut timer);
+    }
+    self.ns_end = self.now_ns();
+    self.retained = self.memory.map(CountingAllocator::counts);
+    self.ns_end - self.ns_start
   }
 
   pub fn bench_n<F: FnMut(&mut Benchmarker)>(&mut self, n: u64, mut f: F) {
     self.iterations = n;
+    self.ns_start = 0;
+    self.ns_end = 0;
     f(self); // f will call b.run internally
   }
 
-  pub fn bench<F: FnMut(&mut Benchmarker)>(&mut self, mut f: F, min_time: u64) -> BenchResults {
-    // min_time is in ms, convert to ns. start with 1 iteration
-    let min_time = min_time * 1_000_000;
-    let mut n: u64 = 1;
+  // Runs `n` iterations and returns how long they took, in ns.
+  fn time_n<F: FnMut(&mut Benchmarker)>(&mut self, n: u64, f: &mut F) -> u64 {
+    self.bench_n(n, |b| f(b));
+    let elapsed = self.ns_end - self.ns_start;
+    if self.ns_end == 0 { panic!("Must call run in benchmark function."); }
+    elapsed
+  }
 
-    // Keep trying to get enough iterations so as take `min_time`
-    loop {
-      // run for n iterations
-      self.bench_n(n, |x| f(x));
+  // Runs one iteration of `f`, returning the heap it used as counted by
+  // `counter`, which must be the global allocator.
+  pub fn measure_memory<F: FnMut(&mut Benchmarker)>(&mut self, counter: &'static CountingAllocator,
+                                                    mut f: F) -> MemoryUse {
+    self.memory = Some(counter);
+    counter.reset_peak();
+    let before = counter.counts();
+    self.time_n(1, &mut f);
+    let after = counter.counts();
+    self.memory = None;
+    self.latency.clear();
 
-      // If we've done enough, we're done
-      let elapsed = self.ns_end - self.ns_start;
-      if elapsed == 0 { panic!("Must call run in benchmark function."); }
-      if elapsed >= min_time { break }
+    let retained = self.retained.take().unwrap_or(after);
+    MemoryUse::between(&before, &retained, &after)
+  }
 
-      // Otherwise, adjust the number of iterations and try again
-      let new_n = n * (min_time / elapsed);
-      n = cmp::max((new_n as f64 * 1.2) as u64, n + 1);
+  // Runs `f` for the configured warmup time, doubling the iteration count
+  // until it's used up. Returns the estimated ns/iter.
+  fn warm_up<F: FnMut(&mut Benchmarker)>(&mut self, f: &mut F, config: &Config) -> f64 {
+    let warmup_ns = config.warmup_ms * 1_000_000;
+    let (mut total_ns, mut total_iters, mut n) = (0, 0, 1);
+    loop {
+      total_ns += self.time_n(n, f);
+      total_iters += n;
+      if total_ns >= warmup_ns { break }
+      n *= 2;
     }
 
-    (self.iterations, self.ns_per_iter())
+    total_ns as f64 / total_iters as f64
   }
 
-  pub fn print_results(&self, name: &str, results: BenchResults) {
-    let (iter, ns_per_iter) = results;
-    println!("{:10}: {:12} ns/iter ({} it.)", name, ns_per_iter, iter);
+  pub fn bench_with<F: FnMut(&mut Benchmarker)>(&mut self, mut f: F, config: &Config) -> BenchResults {
+    let samples = cmp::max(config.samples, MIN_SAMPLES);
+    let n = match config.iterations {
+      Some(n) => cmp::max(n, 1),
+      None => {
+        // Spread the minimum time over the samples.
+        let ns_per_iter = self.warm_up(&mut f, config).max(1.0);
+        let sample_ns = (config.min_time_ms * 1_000_000) as f64 / samples as f64;
+        cmp::max((sample_ns / ns_per_iter) as u64, 1)
+      }
+    };
+
+    self.latency.clear();
+
+    let samples: Vec<f64> = (0..samples).map(|_| self.time_n(n, &mut f) as f64 / n as f64).collect();
+    let latency = if self.latency.is_empty() { None } else { Some(mem::take(&mut self.latency)) };
+
+    BenchResults { iterations: n, summary: Summary::new(&samples), samples, latency }
+  }
+
+  // Benchmarks `f` for at least `min_time` ms.
+  pub fn bench<F: FnMut(&mut Benchmarker)>(&mut self, f: F, min_time: u64) -> BenchResults {
+    self.bench_with(f, &Config::with_min_time(min_time))
+  }
+
+  pub fn print_results(&self, name: &str, results: &BenchResults) {
+    println!("{:10}: {}", name, results);
+    if let Some(ref latency) = results.latency {
+      println!("{:10}  latency {}", "", latency.latencies());
+    }
   }
 }
 
 // Benchmark `f` for at least `time` ms
 pub fn benchmark<F: FnMut(&mut Benchmarker)>(name: &str, f: F, time: u64) -> BenchResults {
+  benchmark_with(name, f, &Config::with_min_time(time))
+}
+
+pub fn benchmark_with<F: FnMut(&mut Benchmarker)>(name: &str, f: F, config: &Config) -> BenchResults {
   let mut bench = Benchmarker::new();
-  let results = bench.bench(f, time);
-  bench.print_results(name, results);
+  let results = bench.bench_with(f, config);
+  bench.print_results(name, &results);
   results
 }
 
-// #[cfg(bench)]
-// fn main() {
-//   fn insert_1000() {
-//     let mut v = Vec::new();
-//     for i in range(0, 1000) {
-//       v.push(i);
-//     }
-//   }
+/**
+ * A benchmark as a program declares it: its name, what it does, how long it
+ * runs by default, and how much work one iteration does. The last two are
+ * optional; when they're set, results also report throughput in MB/s and
+ * ops/s.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bench {
+  pub name: String,
+  pub description: String,
+  pub min_time_ms: u64,
+  pub bytes: u64,   // bytes processed per iteration
+  pub ops: u64,     // operations per iteration
+}
+
+impl Bench {
+  pub fn new(name: &str, description: &str, min_time_ms: u64) -> Bench {
+    Bench {
+      name: name.to_string(),
+      description: description.to_string(),
+      min_time_ms,
+      bytes: 0,
+      ops: 0
+    }
+  }
+
+  pub fn bytes(mut self, bytes: u64) -> Bench {
+    self.bytes = bytes;
+    self
+  }
+
+  pub fn ops(mut self, ops: u64) -> Bench {
+    self.ops = ops;
+    self
+  }
+}
+
+/**
+ * Whether `name` matches `pattern`. A pattern with a `*` or `?` in it is a
+ * glob that must match the whole name, where `*` matches any run of characters
+ * and `?` any one character. Any other pattern matches names containing it.
+ */
+pub fn matches(pattern: &str, name: &str) -> bool {
+  fn glob(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+      None => name.is_empty(),
+      Some((&'*', rest)) => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+      Some((&'?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+      Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..])
+    }
+  }
+
+  if pattern.contains(['*', '?']) {
+    let (pattern, name): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    glob(&pattern, &name)
+  } else {
+    name.contains(pattern)
+  }
+}
+
+/**
+ * Runs a program's benchmarks and keeps their results. With the Text format
+ * each result is printed as soon as it's in; with the others, results are
+ * printed to stderr as they come in and `report` returns the whole lot.
+ *
+ * Only benchmarks matching one of the runner's filters run, if it has any.
+ * A listing runner runs nothing and prints each benchmark's name and
+ * description instead. A runner given the program's CountingAllocator also
+ * reports each benchmark's heap use; see the `memory` module.
+ */
+pub struct Runner {
+  format: Format,
+  filters: Vec<String>,
+  min_time_ms: Option<u64>,
+  iterations: Option<IterationCount>,
+  list: bool,
+  memory: Option<&'static CountingAllocator>,
+  records: Vec<Record>,
+}
+
+impl Runner {
+  pub fn new(format: Format) -> Runner {
+    Runner {
+      format,
+      filters: Vec::new(),
+      min_time_ms: None,
+      iterations: None,
+      list: false,
+      memory: None,
+      records: Vec::new()
+    }
+  }
+
+  // Runs only benchmarks matching at least one of `patterns`; see `matches`.
+  pub fn filter(mut self, patterns: Vec<String>) -> Runner {
+    self.filters = patterns;
+    self
+  }
+
+  // Runs every benchmark for `min_time_ms` instead of its own minimum time.
+  pub fn min_time(mut self, min_time_ms: Option<u64>) -> Runner {
+    self.min_time_ms = min_time_ms;
+    self
+  }
+
+  // Runs every sample for exactly `iterations` iterations.
+  pub fn iterations(mut self, iterations: Option<IterationCount>) -> Runner {
+    self.iterations = iterations;
+    self
+  }
+
+  pub fn list(mut self, list: bool) -> Runner {
+    self.list = list;
+    self
+  }
+
+  // Measures heap use with `counter`, which must be the global allocator.
+  pub fn memory(mut self, counter: Option<&'static CountingAllocator>) -> Runner {
+    self.memory = counter;
+    self
+  }
+
+  pub fn is_selected(&self, name: &str) -> bool {
+    self.filters.is_empty() || self.filters.iter().any(|pattern| matches(pattern, name))
+  }
+
+  // Runs `bench` if it's selected, returning its results, or None if it was
+  // filtered out or only listed.
+  pub fn bench<F: FnMut(&mut Benchmarker)>(&mut self, bench: &Bench, f: F) -> Option<&BenchResults> {
+    let mut config = Config::with_min_time(self.min_time_ms.unwrap_or(bench.min_time_ms));
+    config.iterations = self.iterations;
+    self.bench_with(bench, f, &config)
+  }
+
+  pub fn bench_with<F>(&mut self, bench: &Bench, mut f: F, config: &Config) -> Option<&BenchResults>
+    where F: FnMut(&mut Benchmarker)
+  {
+    if !self.is_selected(&bench.name) {
+      return None;
+    }
+
+    if self.list {
+      println!("{:14} {} ({} ms)", bench.name, bench.description, bench.min_time_ms);
+      return None;
+    }
+
+    let mut benchmarker = Benchmarker::new();
+    let results = benchmarker.bench_with(&mut f, config);
+    let memory = self.memory.map(|counter| benchmarker.measure_memory(counter, &mut f));
+    let record = Record { name: bench.name.clone(), bytes: bench.bytes, ops: bench.ops, results, memory };
+    match self.format {
+      Format::Text => println!("{}", record),
+      _ => eprintln!("{}", record)
+    }
+
+    self.records.push(record);
+    Some(&self.records[self.records.len() - 1].results)
+  }
+
+  pub fn records(&self) -> &[Record] {
+    &self.records
+  }
+
+  // The results in the runner's format, or None for Text, which has already
+  // been printed.
+  pub fn report(&self) -> Option<String> {
+    match self.format {
+      Format::Text => None,
+      Format::Json => Some(report::to_json(&self.records)),
+      Format::Csv => Some(report::to_csv(&self.records))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{matches, Bench, Benchmarker, Config, Outliers, Runner, Summary};
+  use super::report::Format;
+  use std::hint::black_box;
+
+  #[test]
+  fn test_summary() {
+    let summary = Summary::new(&[4.0, 1.0, 3.0, 2.0, 5.0]);
+    assert_eq!((summary.min, summary.max), (1.0, 5.0));
+    assert_eq!((summary.mean, summary.median), (3.0, 3.0));
+    assert!((summary.stddev - 2.5f64.sqrt()).abs() < 1e-9);
+    assert_eq!(summary.percentile(25.0), 2.0);
+    assert!((summary.percentile(90.0) - 4.6).abs() < 1e-9);
+    assert_eq!(summary.outliers, Outliers::default());
+
+    let single = Summary::new(&[7.0]);
+    assert_eq!((single.median, single.stddev, single.percentile(99.0)), (7.0, 0.0, 7.0));
+  }
+
+  #[test]
+  fn test_outliers() {
+    let mut samples = vec![10.0, 11.0, 10.5, 10.2, 10.8, 10.4, 10.6, 10.1];
+    samples.push(12.5);    // mild: q3 + 1.5 * iqr < 12.5 < q3 + 3 * iqr
+    samples.push(100.0);   // severe
+    samples.push(1.0);     // severe
+    let outliers = Summary::new(&samples).outliers;
+    assert_eq!(outliers, Outliers { low_severe: 1, low_mild: 0, high_mild: 1, high_severe: 1 });
+    assert_eq!(outliers.total(), 3);
+  }
+
+  #[test]
+  fn test_bench() {
+    let config = Config { warmup_ms: 1, min_time_ms: 5, samples: 10, iterations: None };
+    let results = Benchmarker::new().bench_with(|b| {
+      b.run(|| { black_box((0..100u64).sum::<u64>()); });
+    }, &config);
 
-//   let (iter, _) = benchmark("insert_1000", insert_1000, 1);
-//   assert!(iter > 10 && iter < 30);
-// }
+    assert_eq!(results.samples.len(), 10);
+    assert!(results.iterations >= 1);
+    assert!(results.summary.min <= results.summary.median);
+    assert!(results.summary.median <= results.summary.max);
+  }
+
+  #[test]
+  #[should_panic(expected = "Must call run")]
+  fn test_bench_without_run() {
+    Benchmarker::new().bench(|_| {}, 1);
+  }
+
+  #[test]
+  fn test_fixed_iterations() {
+    let mut calls = 0;
+    let config = Config { iterations: Some(3), ..Config::with_min_time(1000) };
+    let results = Benchmarker::new().bench_with(|b| {
+      b.run(|| calls += 1);
+    }, &config);
+
+    assert_eq!(results.iterations, 3);
+    assert_eq!(calls, 3 * results.samples.len());
+  }
+
+  #[test]
+  fn test_run_measured() {
+    let config = Config { iterations: Some(10), ..Config::with_min_time(1) };
+    let results = Benchmarker::new().bench_with(|b| {
+      b.run_measured(|timer| {
+        for i in 0..4u64 {
+          timer.measure(|| black_box((0..i * 100).sum::<u64>()));
+        }
+      });
+    }, &config);
+
+    let latency = results.latency.expect("operations were measured");
+    assert_eq!(latency.len(), 4 * 10 * results.samples.len() as u64);
+    assert!(latency.min() <= latency.percentile(50.0) && latency.percentile(99.0) <= latency.max());
+
+    let plain = Benchmarker::new().bench_with(|b| { b.run(|| ()); }, &config);
+    assert_eq!(plain.latency, None);
+  }
+
+  #[test]
+  fn test_matches() {
+    assert!(matches("OWM", "OWMbCU@4096"));
+    assert!(!matches("OWm", "OWMbCU"));
+    assert!(matches("*CU", "OWsCU"));
+    assert!(!matches("*CU", "OWsCU/slab"));
+    assert!(matches("OW?CU", "OWsCU"));
+    assert!(!matches("OW?CU", "OWMsCU"));
+    assert!(matches("OW*C*", "OWMbCU@512"));
+    assert!(matches("*", ""));
+  }
+
+  #[test]
+  fn test_runner_selection() {
+    let mut runner = Runner::new(Format::Csv).filter(vec!["b*".to_string(), "c".to_string()]).iterations(Some(1));
+    for name in ["a", "b1", "abc", "ab"] {
+      runner.bench(&Bench::new(name, "", 1000).ops(10), |b| { b.run(|| ()); });
+    }
+
+    let names: Vec<_> = runner.records().iter().map(|record| record.name.as_str()).collect();
+    assert_eq!(names, ["b1", "abc"]);
+    assert_eq!((runner.records()[0].ops, runner.records()[0].results.iterations), (10, 1));
+
+    let mut lister = Runner::new(Format::Text).list(true);
+    assert!(lister.bench(&Bench::new("a", "", 1), |_| panic!("listed benchmarks don't run")).is_none());
+    assert!(lister.records().is_empty());
+  }
+}
@@ -0,0 +1,168 @@
+/*!
+ * Heap footprint measurement.
+ *
+ * A benchmark program installs a CountingAllocator as its global allocator and
+ * hands it to its Runner with `Runner::memory`. After timing a benchmark, the
+ * runner then runs one more iteration and reports how much heap it used: the
+ * peak, what was still allocated when `run` returned (so still held by the
+ * file system or whatever else the benchmark built), and how many allocations
+ * it made.
+ */
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/**
+ * The system allocator, counting what goes through it.
+ */
+pub struct CountingAllocator {
+  current: AtomicUsize,
+  peak: AtomicUsize,
+  allocations: AtomicU64,
+  deallocations: AtomicU64,
+}
+
+/**
+ * A CountingAllocator's counters at some point in time. `allocations` counts
+ * reallocations too.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+  pub current: usize,
+  pub peak: usize,
+  pub allocations: u64,
+  pub deallocations: u64,
+}
+
+impl CountingAllocator {
+  pub const fn new() -> CountingAllocator {
+    CountingAllocator {
+      current: AtomicUsize::new(0),
+      peak: AtomicUsize::new(0),
+      allocations: AtomicU64::new(0),
+      deallocations: AtomicU64::new(0)
+    }
+  }
+
+  pub fn counts(&self) -> Counts {
+    Counts {
+      current: self.current.load(Ordering::Relaxed),
+      peak: self.peak.load(Ordering::Relaxed),
+      allocations: self.allocations.load(Ordering::Relaxed),
+      deallocations: self.deallocations.load(Ordering::Relaxed)
+    }
+  }
+
+  // Starts tracking the peak afresh from what's allocated now.
+  pub fn reset_peak(&self) {
+    self.peak.store(self.current.load(Ordering::Relaxed), Ordering::Relaxed);
+  }
+
+  fn grew(&self, bytes: usize) {
+    let current = self.current.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    self.peak.fetch_max(current, Ordering::Relaxed);
+    self.allocations.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl Default for CountingAllocator {
+  fn default() -> CountingAllocator {
+    CountingAllocator::new()
+  }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let ptr = System.alloc(layout);
+    if !ptr.is_null() {
+      self.grew(layout.size());
+    }
+
+    ptr
+  }
+
+  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+    let ptr = System.alloc_zeroed(layout);
+    if !ptr.is_null() {
+      self.grew(layout.size());
+    }
+
+    ptr
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout);
+    self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+    self.deallocations.fetch_add(1, Ordering::Relaxed);
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_ptr = System.realloc(ptr, layout, new_size);
+    if !new_ptr.is_null() {
+      self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+      self.grew(new_size);
+    }
+
+    new_ptr
+  }
+}
+
+/**
+ * The heap used by one iteration of a benchmark, in bytes.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUse {
+  pub peak: usize,        // most allocated at once, above what was before
+  pub retained: usize,    // still allocated when `run` returned
+  pub allocations: u64,
+}
+
+impl MemoryUse {
+  // What was used between `before` and `after`, with `retained` taken at the
+  // end of `run`. `before` must be taken right after a `reset_peak`.
+  pub fn between(before: &Counts, retained: &Counts, after: &Counts) -> MemoryUse {
+    MemoryUse {
+      peak: after.peak.saturating_sub(before.current),
+      retained: retained.current.saturating_sub(before.current),
+      allocations: after.allocations - before.allocations
+    }
+  }
+}
+
+// `bytes` in B, KiB, MiB, or GiB, whichever reads best.
+pub fn human_bytes(bytes: f64) -> String {
+  const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+  let (mut value, mut unit) = (bytes, 0);
+  while value.abs() >= 1024.0 && unit + 1 < UNITS.len() {
+    value /= 1024.0;
+    unit += 1;
+  }
+
+  match unit {
+    0 => format!("{:.0} {}", value, UNITS[unit]),
+    _ => format!("{:.1} {}", value, UNITS[unit])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{human_bytes, Counts, MemoryUse};
+
+  #[test]
+  fn test_human_bytes() {
+    assert_eq!(human_bytes(0.0), "0 B");
+    assert_eq!(human_bytes(1023.0), "1023 B");
+    assert_eq!(human_bytes(1536.0), "1.5 KiB");
+    assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0), "3.0 MiB");
+    assert_eq!(human_bytes(5.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0), "5120.0 GiB");
+  }
+
+  #[test]
+  fn test_between() {
+    let before = Counts { current: 1000, peak: 1000, allocations: 10, deallocations: 5 };
+    let retained = Counts { current: 1500, peak: 4000, allocations: 30, deallocations: 20 };
+    let after = Counts { current: 900, peak: 4000, allocations: 31, deallocations: 30 };
+    let used = MemoryUse::between(&before, &retained, &after);
+    assert_eq!(used, MemoryUse { peak: 3000, retained: 500, allocations: 21 });
+  }
+}
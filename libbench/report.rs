@@ -0,0 +1,429 @@
+/*!
+ * Machine-readable benchmark results, and baselines to compare against.
+ *
+ * Results can be written as JSON, with every sample, or as CSV, with one
+ * summary row per benchmark. A baseline is a saved CSV report; reading one back
+ * gives an Estimate per benchmark, which is all a comparison needs.
+ */
+
+use std::fmt::{self, Write as FmtWrite};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::BenchResults;
+use super::histogram::Histogram;
+use super::memory::{human_bytes, MemoryUse};
+
+/**
+ * A finished benchmark, with the bytes and operations one of its iterations
+ * processes, or 0 if it didn't say, and the heap one iteration used, if that
+ * was measured.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+  pub name: String,
+  pub bytes: u64,
+  pub ops: u64,
+  pub results: BenchResults,
+  pub memory: Option<MemoryUse>,
+}
+
+impl Record {
+  // Iterations per second at the median ns/iter, times `per_iter`.
+  fn rate(&self, per_iter: u64) -> Option<f64> {
+    match (per_iter, self.results.summary.median) {
+      (0, _) => None,
+      (n, median) => Some(n as f64 * 1e9 / median)
+    }
+  }
+
+  // Throughput in MB/s (10^6 bytes), if the benchmark counts bytes.
+  pub fn mb_per_s(&self) -> Option<f64> {
+    self.rate(self.bytes).map(|bytes| bytes / 1e6)
+  }
+
+  pub fn ops_per_s(&self) -> Option<f64> {
+    self.rate(self.ops)
+  }
+
+  // Retained heap bytes per operation, such as per file.
+  pub fn retained_per_op(&self) -> Option<f64> {
+    match (self.memory, self.ops) {
+      (Some(memory), ops) if ops > 0 => Some(memory.retained as f64 / ops as f64),
+      _ => None
+    }
+  }
+
+  // Retained heap bytes per byte processed, such as per byte stored.
+  pub fn retained_per_byte(&self) -> Option<f64> {
+    match (self.memory, self.bytes) {
+      (Some(memory), bytes) if bytes > 0 => Some(memory.retained as f64 / bytes as f64),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for Record {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:10}: {}", self.name, self.results)?;
+    if let Some(mb_per_s) = self.mb_per_s() {
+      write!(f, " {:.1} MB/s", mb_per_s)?;
+    }
+
+    if let Some(ops_per_s) = self.ops_per_s() {
+      write!(f, " {:.0} ops/s", ops_per_s)?;
+    }
+
+    if let Some(ref latency) = self.results.latency {
+      write!(f, "\n{:10}  latency {}", "", latency.latencies())?;
+    }
+
+    if let Some(memory) = self.memory {
+      write!(f, "\n{:10}  memory peak {}, retained {}", "",
+             human_bytes(memory.peak as f64), human_bytes(memory.retained as f64))?;
+      if let Some(per_op) = self.retained_per_op() {
+        write!(f, " ({}/op", human_bytes(per_op))?;
+        match self.retained_per_byte() {
+          Some(per_byte) => write!(f, ", {:.2} B/byte)", per_byte)?,
+          None => write!(f, ")")?
+        }
+      }
+
+      write!(f, ", {} allocations", memory.allocations)?;
+    }
+
+    Ok(())
+  }
+}
+
+/**
+ * What's known about a benchmark from a baseline: its mean and standard
+ * deviation in ns/iter, and the number of samples they were computed from.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+  pub name: String,
+  pub samples: usize,
+  pub mean: f64,
+  pub stddev: f64,
+  pub median: f64,
+}
+
+impl<'a> From<&'a Record> for Estimate {
+  fn from(record: &'a Record) -> Estimate {
+    let summary = &record.results.summary;
+    Estimate {
+      name: record.name.clone(),
+      samples: summary.len(),
+      mean: summary.mean,
+      stddev: summary.stddev,
+      median: summary.median
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+  Text,
+  Json,
+  Csv
+}
+
+impl Format {
+  pub fn parse(name: &str) -> Option<Format> {
+    match name {
+      "text" => Some(Format::Text),
+      "json" => Some(Format::Json),
+      "csv" => Some(Format::Csv),
+      _ => None
+    }
+  }
+}
+
+fn json_string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+      c => out.push(c)
+    }
+  }
+
+  out.push('"');
+  out
+}
+
+// JSON has no representation for NaN or infinities.
+fn json_number(x: f64) -> String {
+  if x.is_finite() { format!("{}", x) } else { "null".to_string() }
+}
+
+fn json_option(x: Option<f64>) -> String {
+  x.map_or_else(|| "null".to_string(), json_number)
+}
+
+fn json_memory(record: &Record) -> String {
+  match record.memory {
+    Some(m) => format!("{{\"peak\": {}, \"retained\": {}, \"allocations\": {}, \"retained_per_op\": {}, \
+                        \"retained_per_byte\": {}}}", m.peak, m.retained, m.allocations,
+                       json_option(record.retained_per_op()), json_option(record.retained_per_byte())),
+    None => "null".to_string()
+  }
+}
+
+fn json_latency(latency: Option<&Histogram>) -> String {
+  match latency.map(Histogram::latencies) {
+    Some(l) => format!("{{\"count\": {}, \"p50\": {}, \"p90\": {}, \"p99\": {}, \"p99.9\": {}, \"max\": {}}}",
+                       l.count, l.p50, l.p90, l.p99, l.p999, l.max),
+    None => "null".to_string()
+  }
+}
+
+pub fn to_json(records: &[Record]) -> String {
+  let mut out = String::from("[\n");
+  for (i, record) in records.iter().enumerate() {
+    let (results, s) = (&record.results, &record.results.summary);
+    let samples: Vec<_> = results.samples.iter().map(|&x| json_number(x)).collect();
+    let _ = write!(out,
+      "  {{\"name\": {}, \"iterations\": {}, \"min\": {}, \"max\": {}, \"mean\": {}, \
+       \"median\": {}, \"stddev\": {}, \"p95\": {}, \"outliers\": {}, \"bytes_per_iter\": {}, \
+       \"ops_per_iter\": {}, \"mb_per_s\": {}, \"ops_per_s\": {}, \"latency\": {}, \"memory\": {}, \"samples\": [{}]}}",
+      json_string(&record.name), results.iterations, json_number(s.min), json_number(s.max),
+      json_number(s.mean), json_number(s.median), json_number(s.stddev),
+      json_number(s.percentile(95.0)), s.outliers.total(), record.bytes, record.ops,
+      json_option(record.mb_per_s()), json_option(record.ops_per_s()),
+      json_latency(results.latency.as_ref()), json_memory(record), samples.join(", "));
+    out.push_str(if i + 1 < records.len() { ",\n" } else { "\n" });
+  }
+
+  out.push_str("]\n");
+  out
+}
+
+const CSV_HEADER: &str = "name,samples,iterations,min,max,mean,median,stddev,p95,outliers,mb_per_s,ops_per_s,\
+                          op_p50,op_p90,op_p99,op_p99.9,op_max,mem_peak,mem_retained,mem_allocations";
+
+fn csv_field(s: &str) -> String {
+  if s.contains([',', '"', '\n']) {
+    format!("\"{}\"", s.replace('"', "\"\""))
+  } else {
+    s.to_string()
+  }
+}
+
+pub fn to_csv(records: &[Record]) -> String {
+  let mut out = String::from(CSV_HEADER);
+  out.push('\n');
+  for record in records {
+    let (results, s) = (&record.results, &record.results.summary);
+    let rate = |x: Option<f64>| x.map_or_else(String::new, |x| x.to_string());
+    let latency = match results.latency.as_ref().map(Histogram::latencies) {
+      Some(l) => format!("{},{},{},{},{}", l.p50, l.p90, l.p99, l.p999, l.max),
+      None => ",,,,".to_string()
+    };
+
+    let memory = match record.memory {
+      Some(m) => format!("{},{},{}", m.peak, m.retained, m.allocations),
+      None => ",,".to_string()
+    };
+
+    let _ = writeln!(out, "{},{},{},{},{},{},{},{},{},{},{},{},{},{}", csv_field(&record.name), s.len(),
+                     results.iterations, s.min, s.max, s.mean, s.median, s.stddev,
+                     s.percentile(95.0), s.outliers.total(), rate(record.mb_per_s()),
+                     rate(record.ops_per_s()), latency, memory);
+  }
+
+  out
+}
+
+// Splits a CSV line into its fields, undoing csv_field's quoting.
+fn split_csv(line: &str) -> Vec<String> {
+  let (mut fields, mut field, mut quoted) = (Vec::new(), String::new(), false);
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => { chars.next(); field.push('"'); }
+      '"' => quoted = !quoted,
+      ',' if !quoted => fields.push(std::mem::take(&mut field)),
+      c => field.push(c)
+    }
+  }
+
+  fields.push(field);
+  fields
+}
+
+fn invalid(line: usize, what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, what))
+}
+
+// Reads the estimates back out of a CSV report. Columns are found by name, so
+// reports written with fewer or more columns than today's still read.
+pub fn parse_csv(csv: &str) -> io::Result<Vec<Estimate>> {
+  let mut lines = csv.lines().enumerate();
+  let header = match lines.next() {
+    Some((_, header)) => split_csv(header.trim()),
+    None => return Err(invalid(1, "not a benchmark report"))
+  };
+
+  let column = |name: &str| header.iter().position(|field| field == name);
+  let columns = ["name", "samples", "mean", "median", "stddev"].map(column);
+  let [name, samples, mean, median, stddev] = match columns {
+    [Some(a), Some(b), Some(c), Some(d), Some(e)] => [a, b, c, d, e],
+    _ => return Err(invalid(1, "not a benchmark report"))
+  };
+
+  lines.filter(|&(_, line)| !line.trim().is_empty()).map(|(i, line)| {
+    let fields = split_csv(line);
+    if fields.len() != header.len() {
+      return Err(invalid(i + 1, "wrong number of fields"));
+    }
+
+    let number = |field: &str| field.parse::<f64>().map_err(|_| invalid(i + 1, "bad number"));
+    Ok(Estimate {
+      name: fields[name].clone(),
+      samples: fields[samples].parse().map_err(|_| invalid(i + 1, "bad sample count"))?,
+      mean: number(&fields[mean])?,
+      stddev: number(&fields[stddev])?,
+      median: number(&fields[median])?
+    })
+  }).collect()
+}
+
+/**
+ * A directory of named baselines, each a CSV report.
+ */
+pub struct Baselines {
+  dir: PathBuf
+}
+
+impl Baselines {
+  pub fn new<P: AsRef<Path>>(dir: P) -> Baselines {
+    Baselines { dir: dir.as_ref().to_path_buf() }
+  }
+
+  fn path(&self, name: &str) -> PathBuf {
+    self.dir.join(format!("{}.csv", name))
+  }
+
+  pub fn save(&self, name: &str, records: &[Record]) -> io::Result<PathBuf> {
+    fs::create_dir_all(&self.dir)?;
+    let path = self.path(name);
+    fs::write(&path, to_csv(records))?;
+    Ok(path)
+  }
+
+  pub fn load(&self, name: &str) -> io::Result<Vec<Estimate>> {
+    let path = self.path(name);
+    let csv = fs::read_to_string(&path).map_err(|e| {
+      io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    })?;
+
+    parse_csv(&csv)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_csv, split_csv, to_csv, to_json, Histogram, MemoryUse, Record};
+  use super::super::{BenchResults, Summary};
+
+  fn record(name: &str, samples: &[f64]) -> Record {
+    let results = BenchResults { iterations: 10, samples: samples.to_vec(), summary: Summary::new(samples),
+                                 latency: None };
+    Record { name: name.to_string(), bytes: 0, ops: 0, results, memory: None }
+  }
+
+  #[test]
+  fn test_csv_round_trip() {
+    let records = vec![record("OCU/slab", &[1.0, 2.0, 3.0]), record("odd, \"name\"", &[5.0])];
+    let estimates = parse_csv(&to_csv(&records)).unwrap();
+    assert_eq!(estimates.len(), 2);
+    assert_eq!(estimates[0].name, "OCU/slab");
+    assert_eq!((estimates[0].samples, estimates[0].mean, estimates[0].stddev), (3, 2.0, 1.0));
+    assert_eq!(estimates[1].name, "odd, \"name\"");
+    assert_eq!(estimates[1].median, 5.0);
+  }
+
+  #[test]
+  fn test_bad_csv() {
+    assert!(parse_csv("").is_err());
+    assert!(parse_csv("name,ns\nOC1,10\n").is_err());
+    let mut csv = to_csv(&[record("OC1", &[1.0])]);
+    csv = csv.replace(",1,", ",x,");
+    assert!(parse_csv(&csv).is_err());
+  }
+
+  #[test]
+  fn test_old_csv() {
+    // Reports from before the throughput columns were added.
+    let csv = "name,samples,iterations,min,max,mean,median,stddev,p95,outliers\nOC1,5,10,1,3,2,2,1,3,0\n";
+    let estimates = parse_csv(csv).unwrap();
+    assert_eq!((estimates[0].name.as_str(), estimates[0].samples, estimates[0].mean), ("OC1", 5, 2.0));
+  }
+
+  #[test]
+  fn test_throughput() {
+    let mut record = record("OWsC", &[2000.0, 1000.0, 4000.0]);
+    assert_eq!((record.mb_per_s(), record.ops_per_s()), (None, None));
+    assert!(to_csv(&[record.clone()]).ends_with(",0,,,,,,,,,,\n"));
+
+    // 2 µs per iteration of 10 files and 1000 bytes
+    record.bytes = 1000;
+    record.ops = 10;
+    assert_eq!((record.mb_per_s(), record.ops_per_s()), (Some(500.0), Some(5_000_000.0)));
+    assert!(to_csv(&[record.clone()]).ends_with(",500,5000000,,,,,,,,\n"));
+    assert!(to_json(&[record.clone()]).contains("\"mb_per_s\": 500, \"ops_per_s\": 5000000,"));
+    assert!(record.to_string().ends_with(" 500.0 MB/s 5000000 ops/s"));
+  }
+
+  #[test]
+  fn test_latency() {
+    let mut record = record("OWMsCU/lat", &[1.0]);
+    let mut latency = Histogram::new();
+    latency.record_n(100, 99);
+    latency.record(5000);
+    record.results.latency = Some(latency);
+
+    assert!(to_csv(&[record.clone()]).ends_with(",,,100,100,100,5000,5000,,,\n"));
+    assert!(to_json(&[record.clone()]).contains(
+      "\"latency\": {\"count\": 100, \"p50\": 100, \"p90\": 100, \"p99\": 100, \"p99.9\": 5000, \"max\": 5000}"));
+    assert!(record.to_string().ends_with("latency p50 100, p90 100, p99 100, p99.9 5000, max 5000 ns/op (100 ops)"));
+    assert_eq!(parse_csv(&to_csv(&[record])).unwrap()[0].median, 1.0);
+  }
+
+  #[test]
+  fn test_memory() {
+    let mut record = record("OtC", &[1.0]);
+    record.memory = Some(MemoryUse { peak: 300_000, retained: 204_800, allocations: 700 });
+    assert_eq!((record.retained_per_op(), record.retained_per_byte()), (None, None));
+    assert!(to_csv(&[record.clone()]).ends_with(",300000,204800,700\n"));
+    assert!(record.to_string().ends_with("memory peak 293.0 KiB, retained 200.0 KiB, 700 allocations"));
+
+    record.ops = 100;
+    record.bytes = 102_400;
+    assert_eq!((record.retained_per_op(), record.retained_per_byte()), (Some(2048.0), Some(2.0)));
+    assert!(to_json(&[record.clone()]).contains("\"memory\": {\"peak\": 300000, \"retained\": 204800, \
+                                                 \"allocations\": 700, \"retained_per_op\": 2048, \
+                                                 \"retained_per_byte\": 2}"));
+    assert!(record.to_string().ends_with("retained 200.0 KiB (2.0 KiB/op, 2.00 B/byte), 700 allocations"));
+  }
+
+  #[test]
+  fn test_split_csv() {
+    assert_eq!(split_csv("a,\"b,c\",\"d\"\"e\""), vec!["a", "b,c", "d\"e"]);
+  }
+
+  #[test]
+  fn test_json() {
+    let json = to_json(&[record("a\"b", &[1.0, 3.0]), record("c", &[2.0])]);
+    assert!(json.starts_with("[\n  {\"name\": \"a\\\"b\", \"iterations\": 10, \"min\": 1, \"max\": 3"));
+    assert!(json.contains("\"samples\": [1, 3]}"));
+    assert!(json.ends_with("\"samples\": [2]}\n]\n"));
+  }
+}
@@ -0,0 +1,43 @@
+// Runs with the counting allocator as the global allocator, the way a
+// benchmark program measuring heap use does.
+
+extern crate bench;
+
+use std::hint::black_box;
+
+use bench::{Bench, Benchmarker, Runner};
+use bench::memory::CountingAllocator;
+use bench::report::Format;
+
+#[global_allocator]
+static ALLOC: CountingAllocator = CountingAllocator::new();
+
+#[test]
+fn test_memory() {
+  let before = ALLOC.counts();
+  let data = black_box(vec![1u8; 10_000]);
+  let during = ALLOC.counts();
+  drop(data);
+  let after = ALLOC.counts();
+  assert!(during.current >= before.current + 10_000);
+  assert!(during.allocations > before.allocations && after.deallocations > during.deallocations);
+
+  // Each iteration keeps 100 KiB until `run` returns and briefly needs
+  // another 1 MiB.
+  let mut runner = Runner::new(Format::Csv).iterations(Some(1)).memory(Some(&ALLOC));
+  let bench = Bench::new("keep", "", 1).ops(100);
+  runner.bench(&bench, |b: &mut Benchmarker| {
+    let mut kept = Vec::new();
+    b.run(|| {
+      kept.push(vec![0u8; 100 * 1024]);
+      black_box(vec![0u8; 1024 * 1024]);
+    });
+  });
+
+  let record = &runner.records()[0];
+  let memory = record.memory.expect("memory was measured");
+  assert!(memory.retained >= 100 * 1024 && memory.retained < 1024 * 1024, "{:?}", memory);
+  assert!(memory.peak >= 1024 * 1024 + 100 * 1024, "{:?}", memory);
+  assert!(memory.allocations >= 3);
+  assert!(record.retained_per_op().unwrap() >= 1024.0);
+}
@@ -0,0 +1,503 @@
+/*!
+ * An untyped slab allocator for variable-size byte buffers.
+ *
+ * To use:
+ *
+ * let s = SizeClassAllocator::new();
+ * let page = s.alloc(4096); // Type is ByteBox, 4096 zeroes
+ *
+ * let mut name = ByteBuf::new(&s); // A Vec<u8> backed by `s`
+ * name.extend_from_slice(b"hello"); // Stored in a 16 byte slot
+ *
+ * Sizes are rounded up to a power-of-two size class between MIN_CLASS and
+ * MAX_CLASS bytes. Each class is served by its own pool, using the same chunks
+ * as the typed allocators; anything larger goes straight to the heap. Every
+ * slot is aligned to MIN_CLASS bytes.
+ *
+ * GlobalSizeClassAllocator serves all of a program's allocations the same way:
+ *
+ * #[global_allocator]
+ * static ALLOC: GlobalSizeClassAllocator = GlobalSizeClassAllocator::new();
+ */
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::cmp;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+use std::slice;
+use std::sync::{Mutex, PoisonError};
+
+use super::{Pool, Stats};
+
+pub const MIN_CLASS: usize = 16;
+pub const MAX_CLASS: usize = 64 * 1024;
+
+// Number of classes from MIN_CLASS to MAX_CLASS.
+const NUM_CLASSES: usize = 13;
+
+// Slots are runs of Units, so every slot is MIN_CLASS aligned.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct Unit([u8; MIN_CLASS]);
+
+// Index of the smallest class that holds `size` bytes aligned to `align`, if
+// there is one. Empty allocations don't need a slot at all.
+fn class_of(size: usize, align: usize) -> Option<usize> {
+  if size == 0 || size > MAX_CLASS || align > MIN_CLASS {
+    return None;
+  }
+
+  let class = cmp::max(size, MIN_CLASS).next_power_of_two();
+  Some((class.trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize)
+}
+
+#[inline(always)]
+fn class_size(class: usize) -> usize {
+  MIN_CLASS << class
+}
+
+fn new_pool(class: usize) -> Pool<Unit> {
+  // Start empty; classes that are never used never allocate a chunk.
+  Pool::new(0, class_size(class) / MIN_CLASS)
+}
+
+// Returns the size of the slot `size` bytes are allocated in, or None if they
+// aren't allocated from a pool.
+pub fn size_class(size: usize) -> Option<usize> {
+  class_of(size, 1).map(class_size)
+}
+
+enum Storage {
+  Slab { pools: Rc<Vec<Pool<Unit>>>, class: usize, ptr: NonNull<Unit> },
+  Heap(Box<[u8]>)
+}
+
+/**
+ * A uniquely owned run of bytes, allocated from a SizeClassAllocator and
+ * returned to it on drop.
+ */
+pub struct ByteBox {
+  storage: Storage,
+  len: usize
+}
+
+impl ByteBox {
+  // Number of bytes the box could hold without moving.
+  pub fn capacity(&self) -> usize {
+    match self.storage {
+      Storage::Slab { class, .. } => class_size(class),
+      Storage::Heap(ref bytes) => bytes.len()
+    }
+  }
+
+  fn as_ptr(&self) -> *const u8 {
+    match self.storage {
+      Storage::Slab { ptr, .. } => ptr.as_ptr() as *const u8,
+      Storage::Heap(ref bytes) => bytes.as_ptr()
+    }
+  }
+
+  fn as_mut_ptr(&mut self) -> *mut u8 {
+    match self.storage {
+      Storage::Slab { ptr, .. } => ptr.as_ptr() as *mut u8,
+      Storage::Heap(ref mut bytes) => bytes.as_mut_ptr()
+    }
+  }
+}
+
+impl Deref for ByteBox {
+  type Target = [u8];
+
+  #[inline(always)]
+  fn deref(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+  }
+}
+
+impl DerefMut for ByteBox {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+  }
+}
+
+impl Drop for ByteBox {
+  fn drop(&mut self) {
+    if let Storage::Slab { ref pools, class, ptr } = self.storage {
+      // Units are Copy, so there's nothing to drop.
+      unsafe { pools[class].free(ptr, 0); }
+    }
+  }
+}
+
+impl fmt::Debug for ByteBox {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    (**self).fmt(f)
+  }
+}
+
+#[derive(Clone)]
+pub struct SizeClassAllocator {
+  pools: Rc<Vec<Pool<Unit>>>
+}
+
+impl Default for SizeClassAllocator {
+  fn default() -> SizeClassAllocator {
+    SizeClassAllocator::new()
+  }
+}
+
+impl fmt::Debug for SizeClassAllocator {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_list().entries(self.pools.iter()).finish()
+  }
+}
+
+impl SizeClassAllocator {
+  pub fn new() -> SizeClassAllocator {
+    SizeClassAllocator { pools: Rc::new((0..NUM_CLASSES).map(new_pool).collect()) }
+  }
+
+  // Allocates `len` bytes without clearing them: they hold zeroes or whatever
+  // the slot's previous owner left behind.
+  fn alloc_dirty(&self, len: usize) -> ByteBox {
+    let storage = match class_of(len, 1) {
+      Some(class) => {
+        let ptr = self.pools[class].take();
+        Storage::Slab { pools: self.pools.clone(), class, ptr }
+      }
+      None => Storage::Heap(vec![0; len].into_boxed_slice())
+    };
+
+    ByteBox { storage, len }
+  }
+
+  // Allocates `len` zeroed bytes.
+  pub fn alloc(&self, len: usize) -> ByteBox {
+    let mut bytes = self.alloc_dirty(len);
+    bytes.fill(0);
+    bytes
+  }
+
+  // Allocates a copy of `src`.
+  pub fn alloc_from(&self, src: &[u8]) -> ByteBox {
+    let mut bytes = self.alloc_dirty(src.len());
+    bytes.copy_from_slice(src);
+    bytes
+  }
+
+  // The stats of every class's pool, along with the class's size.
+  pub fn stats(&self) -> Vec<(usize, Stats)> {
+    self.pools.iter().enumerate().map(|(class, pool)| (class_size(class), pool.stats())).collect()
+  }
+
+  // See SliceAllocator::set_low_water_mark. Applies to every class.
+  pub fn set_low_water_mark(&self, mark: Option<usize>) {
+    for pool in self.pools.iter() {
+      pool.low_water.set(mark);
+    }
+  }
+
+  pub fn shrink_to_fit(&self) {
+    for pool in self.pools.iter() {
+      pool.shrink_to_fit();
+    }
+  }
+}
+
+/**
+ * A growable byte buffer, like a Vec<u8>, whose storage comes from a
+ * SizeClassAllocator. Growing moves the bytes to the next class up.
+ */
+pub struct ByteBuf {
+  allocator: SizeClassAllocator,
+  bytes: ByteBox
+}
+
+impl ByteBuf {
+  pub fn new(allocator: &SizeClassAllocator) -> ByteBuf {
+    ByteBuf::with_capacity(allocator, 0)
+  }
+
+  pub fn with_capacity(allocator: &SizeClassAllocator, capacity: usize) -> ByteBuf {
+    let mut bytes = allocator.alloc_dirty(capacity);
+    bytes.len = 0;
+    ByteBuf { allocator: allocator.clone(), bytes }
+  }
+
+  pub fn len(&self) -> usize {
+    self.bytes.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bytes.len == 0
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.bytes.capacity()
+  }
+
+  // Makes room for at least `additional` more bytes.
+  pub fn reserve(&mut self, additional: usize) {
+    let len = self.len();
+    let needed = len.checked_add(additional).expect("capacity overflow");
+    if needed <= self.capacity() {
+      return;
+    }
+
+    let mut bytes = self.allocator.alloc_dirty(cmp::max(needed, self.capacity() * 2));
+    bytes[..len].copy_from_slice(&self.bytes);
+    bytes.len = len;
+    self.bytes = bytes;
+  }
+
+  pub fn push(&mut self, byte: u8) {
+    self.extend_from_slice(&[byte]);
+  }
+
+  pub fn extend_from_slice(&mut self, src: &[u8]) {
+    self.reserve(src.len());
+    let len = self.len();
+    unsafe { ptr::copy_nonoverlapping(src.as_ptr(), self.bytes.as_mut_ptr().add(len), src.len()); }
+    self.bytes.len += src.len();
+  }
+
+  pub fn truncate(&mut self, len: usize) {
+    self.bytes.len = cmp::min(len, self.bytes.len);
+  }
+
+  pub fn clear(&mut self) {
+    self.truncate(0);
+  }
+
+  // Gives up any unused capacity, returning the bytes.
+  pub fn into_boxed_bytes(self) -> ByteBox {
+    self.allocator.alloc_from(&self.bytes)
+  }
+}
+
+impl Deref for ByteBuf {
+  type Target = [u8];
+
+  #[inline(always)]
+  fn deref(&self) -> &[u8] {
+    &self.bytes
+  }
+}
+
+impl DerefMut for ByteBuf {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut [u8] {
+    &mut self.bytes
+  }
+}
+
+impl Clone for ByteBuf {
+  fn clone(&self) -> ByteBuf {
+    let mut clone = ByteBuf::with_capacity(&self.allocator, self.len());
+    clone.extend_from_slice(self);
+    clone
+  }
+}
+
+impl fmt::Debug for ByteBuf {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    (**self).fmt(f)
+  }
+}
+
+// A pool behind a GlobalSizeClassAllocator's lock. Pools aren't tied to the
+// thread that created them; they just can't be used from two at once.
+struct SendPool(Pool<Unit>);
+
+unsafe impl Send for SendPool {}
+
+thread_local!(static IN_ALLOCATOR: Cell<bool> = const { Cell::new(false) });
+
+// Runs `f` unless this thread is already inside a GlobalSizeClassAllocator,
+// which happens when a pool allocates memory for its own bookkeeping. That
+// memory, and only that memory, comes from and goes back to the system.
+fn reentrancy_guarded<R, F: FnOnce() -> R>(f: F) -> Option<R> {
+  struct Exit;
+
+  impl Drop for Exit {
+    fn drop(&mut self) {
+      let _ = IN_ALLOCATOR.try_with(|inside| inside.set(false));
+    }
+  }
+
+  let entered = IN_ALLOCATOR.try_with(|inside| !inside.replace(true)).unwrap_or(false);
+  if !entered {
+    return None;
+  }
+
+  let _exit = Exit;
+  Some(f())
+}
+
+/**
+ * A thread-safe size-class allocator meant to be a program's global allocator.
+ * Each class is a pool behind its own lock. Allocations that don't fit a class
+ * go to the system allocator.
+ */
+pub struct GlobalSizeClassAllocator {
+  classes: [Mutex<Option<SendPool>>; NUM_CLASSES]
+}
+
+impl Default for GlobalSizeClassAllocator {
+  fn default() -> GlobalSizeClassAllocator {
+    GlobalSizeClassAllocator::new()
+  }
+}
+
+impl GlobalSizeClassAllocator {
+  pub const fn new() -> GlobalSizeClassAllocator {
+    GlobalSizeClassAllocator { classes: [const { Mutex::new(None) }; NUM_CLASSES] }
+  }
+
+  // The stats of every class's pool that's been used, along with the class's
+  // size.
+  pub fn stats(&self) -> Vec<(usize, Stats)> {
+    (0..NUM_CLASSES).filter_map(|class| {
+      let pool = self.classes[class].lock().unwrap_or_else(PoisonError::into_inner);
+      pool.as_ref().map(|pool| (class_size(class), pool.0.stats()))
+    }).collect()
+  }
+}
+
+unsafe impl GlobalAlloc for GlobalSizeClassAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    if let Some(class) = class_of(layout.size(), layout.align()) {
+      let ptr = reentrancy_guarded(|| {
+        let mut pool = self.classes[class].lock().unwrap_or_else(PoisonError::into_inner);
+        let pool = pool.get_or_insert_with(|| SendPool(new_pool(class)));
+        pool.0.take().as_ptr() as *mut u8
+      });
+
+      if let Some(ptr) = ptr {
+        return ptr;
+      }
+    }
+
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    if let Some(class) = class_of(layout.size(), layout.align()) {
+      let freed = reentrancy_guarded(|| {
+        let pool = self.classes[class].lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = NonNull::new_unchecked(ptr as *mut Unit);
+        match *pool {
+          Some(ref pool) if pool.0.owns(slot) => {
+            pool.0.free(slot, 0);
+            true
+          }
+          _ => false
+        }
+      });
+
+      if freed == Some(true) {
+        return;
+      }
+    }
+
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{size_class, ByteBuf, SizeClassAllocator, MAX_CLASS};
+
+  #[test]
+  fn test_size_classes() {
+    assert_eq!(size_class(0), None);
+    assert_eq!(size_class(1), Some(16));
+    assert_eq!(size_class(16), Some(16));
+    assert_eq!(size_class(17), Some(32));
+    assert_eq!(size_class(1000), Some(1024));
+    assert_eq!(size_class(MAX_CLASS), Some(MAX_CLASS));
+    assert_eq!(size_class(MAX_CLASS + 1), None);
+  }
+
+  #[test]
+  fn test_alloc() {
+    let allocator = SizeClassAllocator::new();
+    let mut small = allocator.alloc(5);
+    small.copy_from_slice(b"hello");
+    assert_eq!(&small[..], b"hello");
+    assert_eq!(small.capacity(), 16);
+    assert_eq!(small.as_ptr() as usize % 16, 0);
+
+    let page = allocator.alloc(4096);
+    assert_eq!(page.len(), 4096);
+    assert!(page.iter().all(|&byte| byte == 0));
+
+    let huge = allocator.alloc(MAX_CLASS * 2);
+    assert_eq!(huge.len(), MAX_CLASS * 2);
+
+    // Only the two class allocations came from pools
+    let used: Vec<_> = allocator.stats().into_iter()
+      .filter(|&(_, stats)| stats.alloc > 0)
+      .map(|(class, stats)| (class, stats.alloc))
+      .collect();
+    assert_eq!(used, vec![(16, 1), (4096, 1)]);
+  }
+
+  #[test]
+  fn test_slots_reused_and_cleared() {
+    let allocator = SizeClassAllocator::new();
+    let ptr = {
+      let mut bytes = allocator.alloc_from(&[7; 30]);
+      bytes[0] = 8;
+      bytes.as_ptr()
+    };
+
+    let bytes = allocator.alloc(20);
+    assert_eq!(bytes.as_ptr(), ptr);
+    assert!(bytes.iter().all(|&byte| byte == 0));
+  }
+
+  #[test]
+  fn test_byte_buf() {
+    let allocator = SizeClassAllocator::new();
+    let mut buf = ByteBuf::new(&allocator);
+    assert!(buf.is_empty());
+    assert_eq!(buf.capacity(), 0);
+
+    for i in 0..1000u32 {
+      buf.push(i as u8);
+    }
+
+    assert_eq!(buf.len(), 1000);
+    assert_eq!(buf.capacity(), 1024);
+    assert!(buf.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+    // Only the final slot is still held
+    let held: usize = allocator.stats().iter().map(|&(_, stats)| stats.alloc).sum();
+    assert_eq!(held, 1);
+
+    buf.truncate(3);
+    buf.extend_from_slice(b"abc");
+    assert_eq!(&buf[..], &[0, 1, 2, b'a', b'b', b'c']);
+
+    let copy = buf.clone();
+    let boxed = buf.into_boxed_bytes();
+    assert_eq!(&boxed[..], &copy[..]);
+    assert_eq!(boxed.capacity(), 16);
+  }
+
+  #[test]
+  fn test_shrink_to_fit() {
+    let allocator = SizeClassAllocator::new();
+    let names: Vec<_> = (0..100).map(|i| allocator.alloc_from(format!("file{}", i).as_bytes())).collect();
+    assert_eq!(&names[42][..], b"file42");
+    drop(names);
+
+    allocator.shrink_to_fit();
+    assert!(allocator.stats().iter().all(|&(_, stats)| stats.bytes == 0));
+  }
+}
@@ -0,0 +1,97 @@
+/*!
+ * Bookkeeping for the `debug` feature.
+ *
+ * Every pool tracks its outstanding slots along with a backtrace of where each
+ * was allocated. Freed slots are filled with POISON; a slot that's no longer
+ * fully poisoned when it's handed out again was written to after it was freed.
+ * Freeing a slot that isn't outstanding is a double free. Both panic.
+ *
+ * When the last allocator handle for a pool is dropped, every slot that's
+ * still outstanding is reported on stderr. Boxes are allowed to outlive their
+ * allocator, so this is only a report, not an error.
+ */
+
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ptr;
+use std::slice;
+
+// What freed slots are filled with.
+pub const POISON: u8 = 0xdb;
+
+pub struct Tracker {
+  live: RefCell<HashMap<usize, Backtrace>>,   // outstanding slots by address
+  handles: Cell<usize>                        // number of allocator handles
+}
+
+impl Tracker {
+  // Every pool starts out with the allocator that created it.
+  pub fn new() -> Tracker {
+    Tracker { live: RefCell::new(HashMap::new()), handles: Cell::new(1) }
+  }
+
+  // Checks that the `size` byte slot hasn't been written to since it was
+  // freed, then zeroes it and records where it was allocated from.
+  pub unsafe fn alloc(&self, slot: *mut u8, size: usize) {
+    if size == 0 { return; }
+
+    let bytes = slice::from_raw_parts_mut(slot, size);
+    if let Some(offset) = bytes.iter().position(|&byte| byte != POISON) {
+      panic!("slab: use after free: slot {:p} was written to at offset {} after it was freed",
+             slot, offset);
+    }
+
+    bytes.fill(0);
+    self.live.borrow_mut().insert(slot as usize, Backtrace::force_capture());
+  }
+
+  // Checks that the slot is outstanding and forgets about it. Must be called
+  // before the slot's contents are dropped.
+  pub fn free(&self, slot: *mut u8, size: usize) {
+    if size == 0 { return; }
+
+    if self.live.borrow_mut().remove(&(slot as usize)).is_none() {
+      panic!("slab: double free of slot {:p}", slot);
+    }
+  }
+
+  // Fills a freed (or brand new) `size` byte slot with POISON.
+  pub unsafe fn poison(&self, slot: *mut u8, size: usize) {
+    ptr::write_bytes(slot, POISON, size);
+  }
+
+  pub fn handle_created(&self) {
+    self.handles.set(self.handles.get() + 1);
+  }
+
+  // Prints the leak report once the last handle is gone.
+  pub fn handle_dropped(&self) {
+    self.handles.set(self.handles.get() - 1);
+    if self.handles.get() == 0 {
+      if let Some(report) = self.report() {
+        eprint!("{}", report);
+      }
+    }
+  }
+
+  // Describes every outstanding slot, or returns None if there are none.
+  pub fn report(&self) -> Option<String> {
+    let live = self.live.borrow();
+    if live.is_empty() {
+      return None;
+    }
+
+    let mut slots: Vec<_> = live.iter().collect();
+    slots.sort_by_key(|&(&addr, _)| addr);
+
+    let mut report = format!("slab: {} allocation(s) outstanding after the allocator was dropped\n",
+                             slots.len());
+    for (addr, backtrace) in slots {
+      let _ = write!(report, "\nslot {:#x}, allocated at:\n{}\n", addr, backtrace);
+    }
+
+    Some(report)
+  }
+}
@@ -1,211 +1,643 @@
-#![allow(raw_pointer_deriving)]
 #![crate_type = "lib"]
-#![crate_id = "slab"]
 
-/**
- * A growing (not yet shrinking), typed slab allocator.
+/*!
+ * A growing and shrinking, typed slab allocator.
  *
  * To use:
  *
  * let s = SlabAllocator::new(10);
  * {
- *   let mut first = s.alloc(0); // Type is SlabBox<'a, int>
- *   *first = 10;
- *   assert_eq!(*first, 10);
+ *   let first = s.alloc(0); // Type is SlabBox<isize>
+ *   *first.borrow_mut() = 10;
+ *   assert_eq!(*first.borrow(), 10);
  *
- *   let mut second = s.alloc(0);
- *   *second = 20;
- *   assert_eq!(*first, 20);
- *   assert_eq!(*second, *first);
+ *   let second = s.alloc(0);
+ *   *second.borrow_mut() = 20;
+ *   assert_eq!(*first.borrow(), 10);
+ *   assert_eq!(*second.borrow(), 20);
  *
  *   let third = first.clone(); // Referencing same as first.
- *   assert!(*first == *third);
+ *   assert!(*first.borrow() == *third.borrow());
  *
- *   *second = 30;
- *   assert_eq!(*first == 30);
- *   assert_eq!(*first == *third);
- *   assert_eq!(*second == *third);
+ *   *first.borrow_mut() = 30;
+ *   assert_eq!(*third.borrow(), 30);
  * } // first, second, third returned to allocator.
+ *
+ * SlabBoxes share their value like an Rc<RefCell<T>> does: `borrow` and
+ * `borrow_mut` hand out guards, and panic if the value is already mutably
+ * borrowed, or borrowed at all, respectively.
+ *
+ * Allocators are cheap handles to a shared pool, so boxes can outlive the
+ * handle they were allocated from; the pool's memory is released once the
+ * last of them is dropped.
+ *
+ * A SliceAllocator does the same for fixed-length slices, handing out
+ * uniquely owned SliceBoxes:
+ *
+ * let s = SliceAllocator::new(10, 4096);
+ * let mut page = s.alloc(0u8); // Type is SliceBox<u8>, 4096 zeroes
+ * page[10] = 1;
+ *
+ * Allocators grow by doubling and keep their memory by default. Set a
+ * low-water mark to have chunks returned to the system as they empty out, or
+ * call `shrink_to_fit` to release every empty chunk at once:
+ *
+ * s.set_low_water_mark(Some(10)); // keep room for at least 10 slices
+ * s.shrink_to_fit();
+ * println!("{:?}", s.stats()); // Stats { alloc, capacity, chunks, .. }
+ *
+ * None of the above can be shared between threads. See the `sync` module for
+ * slab and slice allocators that can, and the `classes` module for an untyped
+ * allocator of variable-size byte buffers.
  */
 
-extern crate libc;
-
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::fmt;
 use std::mem;
-use std::mem::transmute;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
 use std::rc::Rc;
-use libc::{size_t, malloc, free};
-use libc::types::common::c95::c_void;
-use std::cell::{Cell, RefCell};
-use std::intrinsics;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::slice;
+
+pub mod sync;
+pub mod classes;
+
+#[cfg(feature = "debug")]
+mod debug;
+
+/**
+ * A snapshot of an allocator's bookkeeping.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+  pub alloc: usize,        // number of outstanding items
+  pub capacity: usize,     // number of items pre-allocated, outstanding or not
+  pub chunks: usize,       // number of chunks held
+  pub empty_chunks: usize, // number of chunks with no outstanding items
+  pub bytes: usize,        // number of bytes held in chunks
+}
+
+// A single allocation from the system, split into `items` items.
+struct Chunk<T> {
+  ptr: NonNull<T>,
+  layout: Layout,
+  items: usize,
+  live: usize   // number of outstanding items from this chunk
+}
 
-#[deriving(Show)]
-struct Slab<'a, T> {
-  parent: &'a SlabAllocator<T>, // Would RC be better to get rid of 'a? How to?
-  ptr: *mut T
+// The chunks backing an allocator. Every item is a slot of `len` contiguous
+// `T`s; items[alloc..capacity] are the free ones. Chunks are zeroed when
+// they're allocated, so slots never hold uninitialized memory.
+struct Pool<T> {
+  len: usize,                             // number of T in each item
+  items: RefCell<Vec<NonNull<T>>>,        // holds pointers to allocations
+  alloc: Cell<usize>,                     // number of outstanding items
+  capacity: Cell<usize>,                  // number of items pre-allocated (valid in items)
+  chunks: RefCell<Vec<Chunk<T>>>,         // every chunk, sorted by address
+  low_water: Cell<Option<usize>>,         // see set_low_water_mark
+  #[cfg(feature = "debug")]
+  debug: debug::Tracker,
 }
 
-impl<'a, T> Slab<'a, T> {
-  fn borrow<'r>(&'r self) -> &'r T {
-    unsafe { &*self.ptr }
+impl<T> Pool<T> {
+  fn new(initial_size: usize, len: usize) -> Pool<T> {
+    let pool = Pool {
+      len,
+      items: RefCell::new(Vec::with_capacity(initial_size)),
+      alloc: Cell::new(0),
+      capacity: Cell::new(0),
+      chunks: RefCell::new(Vec::with_capacity(20)),
+      low_water: Cell::new(None),
+      #[cfg(feature = "debug")]
+      debug: debug::Tracker::new()
+    };
+
+    pool.expand(initial_size);
+    pool
   }
 
-  fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
-    unsafe { &mut*self.ptr }
+  // pre-allocates and additional new_items and adds them to the end of
+  // self.items, increasing self.capacity with the new size
+  fn expand(&self, new_items: usize) {
+    let layout = self.len.checked_mul(new_items)
+      .and_then(|n| Layout::array::<T>(n).ok())
+      .expect("chunk too large");
+
+    // Zero-sized slots don't need any memory at all.
+    let memory = if layout.size() == 0 {
+      NonNull::dangling()
+    } else {
+      let memory = unsafe { alloc::alloc_zeroed(layout) as *mut T };
+      let memory = NonNull::new(memory).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+      #[cfg(feature = "debug")]
+      unsafe { self.debug.poison(memory.as_ptr() as *mut u8, layout.size()); }
+
+      let mut chunks = self.chunks.borrow_mut();
+      let position = chunks.binary_search_by_key(&(memory.as_ptr() as usize), |chunk| {
+        chunk.ptr.as_ptr() as usize
+      }).unwrap_err();
+
+      let chunk = Chunk { ptr: memory, layout, items: new_items, live: 0 };
+      chunks.insert(position, chunk);
+      memory
+    };
+
+    let mut items = self.items.borrow_mut();
+    for i in 0..new_items {
+      items.push(unsafe { NonNull::new_unchecked(memory.as_ptr().add(i * self.len)) });
+    }
+
+    self.capacity.set(self.capacity.get() + new_items);
+  }
+
+  // Index of the chunk `ptr` was allocated from, if it's from a chunk at all.
+  fn chunk_of(&self, chunks: &[Chunk<T>], ptr: NonNull<T>) -> Option<usize> {
+    let addr = ptr.as_ptr() as usize;
+    match chunks.binary_search_by_key(&addr, |chunk| chunk.ptr.as_ptr() as usize) {
+      Ok(i) => Some(i),
+      Err(0) => None,
+      Err(i) => Some(i - 1)
+    }
+  }
+
+  // Number of bytes in an item.
+  #[cfg(feature = "debug")]
+  fn item_size(&self) -> usize {
+    self.len * mem::size_of::<T>()
   }
-}
 
-impl<'a, T: Eq> Eq for Slab<'a, T> {
-  fn eq(&self, other: &Slab<T>) -> bool {
-    self.borrow() == other.borrow()
+  // Whether `ptr` points into one of the pool's chunks.
+  fn owns(&self, ptr: NonNull<T>) -> bool {
+    let chunks = self.chunks.borrow();
+    self.chunk_of(&chunks, ptr).is_some_and(|i| {
+      let start = chunks[i].ptr.as_ptr() as usize;
+      (ptr.as_ptr() as usize) < start + chunks[i].layout.size()
+    })
+  }
+
+  // Returns a free item, growing the pool if it's full. The item holds zeroes
+  // or whatever its previous owner left behind.
+  fn take(&self) -> NonNull<T> {
+    let (alloc, capacity) = (self.alloc.get(), self.capacity.get());
+    if alloc >= capacity {
+      self.expand(cmp::max(capacity, 1));
+    }
+
+    let ptr = self.items.borrow()[alloc];
+    #[cfg(feature = "debug")]
+    unsafe { self.debug.alloc(ptr.as_ptr() as *mut u8, self.item_size()); }
+    self.alloc.set(alloc + 1);
+
+    let mut chunks = self.chunks.borrow_mut();
+    if let Some(i) = self.chunk_of(&chunks, ptr) {
+      chunks[i].live += 1;
+    }
+
+    ptr
+  }
+
+  // Returns an item to the pool without touching its contents. Releases the
+  // item's chunk if that leaves it empty and the low-water mark allows it.
+  fn put_back(&self, ptr: NonNull<T>) {
+    let alloc = self.alloc.get();
+    if alloc == 0 { panic!("Over-freeing....somehow"); }
+
+    let (ptr, empty_chunk) = {
+      let mut chunks = self.chunks.borrow_mut();
+      match self.chunk_of(&chunks, ptr) {
+        Some(i) => {
+          // Rederive the pointer from the chunk's: whoever is freeing the item
+          // may only have had access to part of it.
+          let offset = ptr.as_ptr() as usize - chunks[i].ptr.as_ptr() as usize;
+          let base = chunks[i].ptr.as_ptr() as *mut u8;
+          let ptr = unsafe { NonNull::new_unchecked(base.add(offset) as *mut T) };
+
+          chunks[i].live -= 1;
+          (ptr, if chunks[i].live == 0 { Some(i) } else { None })
+        }
+        None => (ptr, None)
+      }
+    };
+
+    #[cfg(feature = "debug")]
+    unsafe { self.debug.poison(ptr.as_ptr() as *mut u8, self.item_size()); }
+
+    self.alloc.set(alloc - 1);
+    self.items.borrow_mut()[alloc - 1] = ptr;
+
+    if let (Some(i), Some(low_water)) = (empty_chunk, self.low_water.get()) {
+      // Only release a chunk if the pool stays at or above the low-water mark
+      // and at most half full, so that allocating and freeing right at a chunk
+      // boundary doesn't allocate and release a chunk every time.
+      let remaining = self.capacity.get() - self.chunks.borrow()[i].items;
+      if remaining >= low_water && self.alloc.get() <= remaining / 2 {
+        self.release(i);
+      }
+    }
+  }
+
+  // Returns the (empty) chunk at index `i` to the system.
+  fn release(&self, i: usize) {
+    let chunk = self.chunks.borrow_mut().remove(i);
+    debug_assert_eq!(chunk.live, 0);
+
+    // Every item in the chunk is free; drop them from the free list.
+    let (start, end) = (chunk.ptr.as_ptr() as usize, chunk.ptr.as_ptr() as usize + chunk.layout.size());
+    let mut items = self.items.borrow_mut();
+    let alloc = self.alloc.get();
+    let mut free = items.split_off(alloc);
+    free.retain(|item| {
+      let addr = item.as_ptr() as usize;
+      addr < start || addr >= end
+    });
+
+    items.append(&mut free);
+    self.capacity.set(items.len());
+    unsafe { alloc::dealloc(chunk.ptr.as_ptr() as *mut u8, chunk.layout); }
+  }
+
+  // Releases every chunk that has no outstanding items.
+  fn shrink_to_fit(&self) {
+    loop {
+      let empty = self.chunks.borrow().iter().position(|chunk| chunk.live == 0);
+      match empty {
+        Some(i) => self.release(i),
+        None => break
+      }
+    }
+  }
+
+  // Drops the first `init` values in the item and returns it to the pool. The
+  // item is returned even if one of the drops panics.
+  unsafe fn free(&self, ptr: NonNull<T>, init: usize) {
+    struct PutBack<'a, T: 'a>(&'a Pool<T>, NonNull<T>);
+
+    impl<'a, T> Drop for PutBack<'a, T> {
+      fn drop(&mut self) {
+        self.0.put_back(self.1);
+      }
+    }
+
+    #[cfg(feature = "debug")]
+    self.debug.free(ptr.as_ptr() as *mut u8, self.item_size());
+
+    let _put_back = PutBack(self, ptr);
+    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(ptr.as_ptr(), init));
+  }
+
+  fn stats(&self) -> Stats {
+    let chunks = self.chunks.borrow();
+    Stats {
+      alloc: self.alloc.get(),
+      capacity: self.capacity.get(),
+      chunks: chunks.len(),
+      empty_chunks: chunks.iter().filter(|chunk| chunk.live == 0).count(),
+      bytes: chunks.iter().map(|chunk| chunk.layout.size()).sum()
+    }
   }
 }
 
-#[unsafe_destructor]
-impl<'a, T> Drop for Slab<'a, T> {
+impl<T> Drop for Pool<T> {
   fn drop(&mut self) {
-    self.parent.free(self.ptr);
+    for chunk in self.chunks.borrow().iter() {
+      unsafe { alloc::dealloc(chunk.ptr.as_ptr() as *mut u8, chunk.layout); }
+    }
   }
 }
 
-#[deriving(Clone)]
-pub struct SlabBox<'a, T>(Rc<RefCell<Slab<'a, T>>>);
+impl<T> fmt::Debug for Pool<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Pool {{ len: {}, {:?} }}", self.len, self.stats())
+  }
+}
 
-impl<'a, T> SlabBox<'a, T> {
+// What a SlabAllocator's slots hold: the value, along with the reference
+// count and borrow state that its SlabBoxes share.
+struct Shared<T> {
+  boxes: Cell<usize>,  // number of SlabBoxes pointing to this value
+  borrow: Cell<isize>, // number of live SlabRefs, or -1 for a SlabRefMut
+  value: UnsafeCell<T>
+}
+
+pub struct SlabBox<T> {
+  parent: Rc<Pool<Shared<T>>>,
+  ptr: NonNull<Shared<T>>
+}
+
+impl<T> SlabBox<T> {
+  #[inline(always)]
+  fn shared(&self) -> &Shared<T> {
+    unsafe { self.ptr.as_ref() }
+  }
+
+  // Immutably borrows the value. Panics if it's currently mutably borrowed.
   #[inline(always)]
-  pub fn borrow<'r>(&'r self) -> &'r T {
-    let SlabBox(ref rc) = *self;
-    unsafe { transmute(rc.borrow().borrow()) }
+  pub fn borrow(&self) -> SlabRef<'_, T> {
+    let shared = self.shared();
+    let borrow = shared.borrow.get();
+    if borrow < 0 { panic!("SlabBox value is already mutably borrowed"); }
+
+    shared.borrow.set(borrow + 1);
+    SlabRef { shared }
   }
 
+  // Mutably borrows the value. Panics if it's currently borrowed.
   #[inline(always)]
-  pub fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
-    let SlabBox(ref rc) = *self;
-    unsafe { transmute(rc.borrow_mut().borrow_mut()) }
+  pub fn borrow_mut(&self) -> SlabRefMut<'_, T> {
+    let shared = self.shared();
+    if shared.borrow.get() != 0 { panic!("SlabBox value is already borrowed"); }
+
+    shared.borrow.set(-1);
+    SlabRefMut { shared }
   }
 }
 
-impl<'a, T> Deref<T> for SlabBox<'a, T> {
+impl<T> Clone for SlabBox<T> {
+  fn clone(&self) -> SlabBox<T> {
+    let shared = self.shared();
+    shared.boxes.set(shared.boxes.get() + 1);
+    SlabBox { parent: self.parent.clone(), ptr: self.ptr }
+  }
+}
+
+impl<T> Drop for SlabBox<T> {
+  fn drop(&mut self) {
+    let shared = self.shared();
+    let boxes = shared.boxes.get() - 1;
+    shared.boxes.set(boxes);
+    if boxes == 0 {
+      unsafe { self.parent.free(self.ptr, 1); }
+    }
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.shared().borrow.get() < 0 {
+      f.write_str("SlabBox(<borrowed>)")
+    } else {
+      f.debug_tuple("SlabBox").field(&*self.borrow()).finish()
+    }
+  }
+}
+
+pub struct SlabRef<'b, T: 'b> {
+  shared: &'b Shared<T>
+}
+
+impl<'b, T> Deref for SlabRef<'b, T> {
+  type Target = T;
+
   #[inline(always)]
-  fn deref<'r>(&'r self) -> &'r T {
-    self.borrow()
+  fn deref(&self) -> &T {
+    unsafe { &*self.shared.value.get() }
   }
 }
 
-impl<'a, T> DerefMut<T> for SlabBox<'a, T> {
+impl<'b, T> Drop for SlabRef<'b, T> {
+  fn drop(&mut self) {
+    self.shared.borrow.set(self.shared.borrow.get() - 1);
+  }
+}
+
+pub struct SlabRefMut<'b, T: 'b> {
+  shared: &'b Shared<T>
+}
+
+impl<'b, T> Deref for SlabRefMut<'b, T> {
+  type Target = T;
+
+  #[inline(always)]
+  fn deref(&self) -> &T {
+    unsafe { &*self.shared.value.get() }
+  }
+}
+
+impl<'b, T> DerefMut for SlabRefMut<'b, T> {
   #[inline(always)]
-  fn deref_mut<'r>(&'r mut self) -> &'r mut T {
-    self.borrow_mut()
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.shared.value.get() }
   }
 }
 
-#[deriving(Show)]
+impl<'b, T> Drop for SlabRefMut<'b, T> {
+  fn drop(&mut self) {
+    self.shared.borrow.set(0);
+  }
+}
+
+#[derive(Debug)]
 pub struct SlabAllocator<T> {
-  items: Vec<*mut T>,       // holds pointers to allocations
-  alloc: Cell<usize>,        // number of outstanding items
-  capacity: Cell<usize>,     // number of items pre-allocated (valid in items)
-  chunks: Vec<*mut T>,      // holds pointers to each chunk for freeing
+  pool: Rc<Pool<Shared<T>>>
+}
+
+impl<T> Clone for SlabAllocator<T> {
+  fn clone(&self) -> SlabAllocator<T> {
+    #[cfg(feature = "debug")]
+    self.pool.debug.handle_created();
+    SlabAllocator { pool: self.pool.clone() }
+  }
+}
+
+#[cfg(feature = "debug")]
+impl<T> Drop for SlabAllocator<T> {
+  fn drop(&mut self) {
+    self.pool.debug.handle_dropped();
+  }
 }
 
 impl<T> SlabAllocator<T> {
   pub fn new(initial_size: usize) -> SlabAllocator<T> {
-    let mut allocator = SlabAllocator {
-      items: Vec::with_capacity(initial_size),
-      alloc: Cell::new(0),
-      capacity: Cell::new(0),
-      chunks: Vec::with_capacity(20)
+    SlabAllocator { pool: Rc::new(Pool::new(initial_size, 1)) }
+  }
+
+  /// Allocates a box without initializing its value: it holds whatever the
+  /// last box in its slot held, or zeroes if the slot has never been used.
+  /// With the `debug` feature, it always holds zeroes.
+  ///
+  /// # Safety
+  ///
+  /// All zeroes must be a valid `T`.
+  pub unsafe fn dirty_alloc(&self) -> SlabBox<T> where T: Copy {
+    let ptr = self.pool.take();
+    let shared = ptr.as_ptr();
+    ptr::addr_of_mut!((*shared).boxes).write(Cell::new(1));
+    ptr::addr_of_mut!((*shared).borrow).write(Cell::new(0));
+    SlabBox { parent: self.pool.clone(), ptr }
+  }
+
+  pub fn alloc(&self, value: T) -> SlabBox<T> {
+    let ptr = self.pool.take();
+    let shared = Shared {
+      boxes: Cell::new(1),
+      borrow: Cell::new(0),
+      value: UnsafeCell::new(value)
     };
 
-    allocator.expand(initial_size);
-    allocator
+    unsafe { ptr.as_ptr().write(shared); }
+    SlabBox { parent: self.pool.clone(), ptr }
   }
-  
-  // pre-allocates and additional new_items and adds them to the end of
-  // self.items, increasing self.capacity with the new size
-  fn expand(&mut self, new_items: usize) {
-    unsafe {
-      let memory = malloc((mem::size_of::<T>() * new_items) as size_t) as *mut T;
-      assert!(!memory.is_null());
-
-      self.chunks.push(memory);
-      for i in range(0, new_items as isize) {
-        self.items.push(memory.offset(i));
-      }
-    }
 
-    self.capacity.set(self.capacity.get() + new_items);
+  pub fn stats(&self) -> Stats {
+    self.pool.stats()
   }
 
-  pub unsafe fn dirty_alloc<'r>(&'r self) -> SlabBox<'r, T> {
-    self.all_alloc(None)
+  // Describes every outstanding allocation and where it was made, or returns
+  // None if there are none. This is printed when the last handle is dropped.
+  #[cfg(feature = "debug")]
+  pub fn leak_report(&self) -> Option<String> {
+    self.pool.debug.report()
   }
 
-  pub fn alloc<'r>(&'r self, value: T) -> SlabBox<'r, T> {
-    self.all_alloc(Some(value))
+  // Once set, chunks are returned to the system as soon as they're empty,
+  // as long as the allocator keeps room for at least `mark` items. `None`,
+  // the default, never releases chunks on its own.
+  pub fn set_low_water_mark(&self, mark: Option<usize>) {
+    self.pool.low_water.set(mark);
   }
 
-  fn all_alloc<'r>(&'r self, value: Option<T>) -> SlabBox<'r, T> {
-    let (alloc, capacity) = (self.alloc.get(), self.capacity.get());
-    if alloc >= capacity {
-      unsafe {
-        // is there a safe/better way to do something like this?
-        let mut_self: &mut SlabAllocator<T> = transmute(self);
-        mut_self.expand(capacity * 2 - capacity);
-      }
-    }
+  // Returns every chunk with no outstanding items to the system.
+  pub fn shrink_to_fit(&self) {
+    self.pool.shrink_to_fit();
+  }
+}
 
-    let ptr: *mut T = *self.items.get(alloc);
-    match value {
-      Some(val) => unsafe { mem::overwrite(&mut *ptr, val); },
-      None => { /* Giving back dirty value. */ }
-    }
+/**
+ * A uniquely owned slice of `len` items, allocated from a SliceAllocator and
+ * returned to it on drop.
+ */
+pub struct SliceBox<T> {
+  parent: Rc<Pool<T>>,
+  ptr: NonNull<T>
+}
 
-    self.alloc.set(alloc + 1);
-    let slab = Slab { parent: self, ptr: ptr }; 
-    SlabBox(Rc::new(RefCell::new(slab)))
+impl<T> Deref for SliceBox<T> {
+  type Target = [T];
+
+  #[inline(always)]
+  fn deref(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.parent.len) }
   }
+}
 
-  fn free(&self, ptr: *mut T) {
-    let alloc = self.alloc.get();
-    if alloc <= 0 { panic!("Over-freeing....somehow"); }
+impl<T> DerefMut for SliceBox<T> {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.parent.len) }
+  }
+}
 
-    self.alloc.set(alloc - 1);
-    unsafe {
-      // Dropping if needed
-      if intrinsics::needs_drop::<T>() {
-        let ty = intrinsics::get_tydesc::<T>();
-        ((*ty).drop_glue)(ptr as *i8);
-      }
+impl<T> Drop for SliceBox<T> {
+  fn drop(&mut self) {
+    unsafe { self.parent.free(self.ptr, self.parent.len); }
+  }
+}
 
-      // Letting an immutable slice be mutable, unsafely
-      let items: &mut [*mut T] = transmute(self.items.as_slice());
-      items[alloc - 1] = ptr;
-    }
+impl<T: fmt::Debug> fmt::Debug for SliceBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    (**self).fmt(f)
   }
+}
+
+#[derive(Debug)]
+pub struct SliceAllocator<T> {
+  pool: Rc<Pool<T>>
+}
 
-  pub fn stats(&self) -> (usize, usize) {
-    (self.alloc.get(), self.capacity.get())
+impl<T> Clone for SliceAllocator<T> {
+  fn clone(&self) -> SliceAllocator<T> {
+    #[cfg(feature = "debug")]
+    self.pool.debug.handle_created();
+    SliceAllocator { pool: self.pool.clone() }
   }
 }
 
-#[unsafe_destructor]
-impl<T> Drop for SlabAllocator<T> {
+#[cfg(feature = "debug")]
+impl<T> Drop for SliceAllocator<T> {
   fn drop(&mut self) {
-    for chunk in self.chunks.iter() {
-      unsafe { free(*chunk as *mut c_void); }
+    self.pool.debug.handle_dropped();
+  }
+}
+
+impl<T> SliceAllocator<T> {
+  // Pre-allocates `initial_size` slices of `len` items each.
+  pub fn new(initial_size: usize, len: usize) -> SliceAllocator<T> {
+    SliceAllocator { pool: Rc::new(Pool::new(initial_size, len)) }
+  }
+
+  pub fn len(&self) -> usize {
+    self.pool.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pool.len == 0
+  }
+
+  // Allocates a slice, initializing each item with the next value from `f`.
+  // If `f` panics, the items it already produced are dropped and the slice is
+  // returned to the pool.
+  pub fn alloc_with<F: FnMut() -> T>(&self, mut f: F) -> SliceBox<T> {
+    struct Partial<'a, T: 'a> {
+      pool: &'a Pool<T>,
+      ptr: NonNull<T>,
+      init: usize
+    }
+
+    impl<'a, T> Drop for Partial<'a, T> {
+      fn drop(&mut self) {
+        unsafe { self.pool.free(self.ptr, self.init); }
+      }
     }
+
+    let mut partial = Partial { pool: &self.pool, ptr: self.pool.take(), init: 0 };
+    while partial.init < self.pool.len {
+      unsafe { partial.ptr.as_ptr().add(partial.init).write(f()); }
+      partial.init += 1;
+    }
+
+    let ptr = partial.ptr;
+    mem::forget(partial);
+    SliceBox { parent: self.pool.clone(), ptr }
+  }
+
+  pub fn alloc(&self, value: T) -> SliceBox<T> where T: Clone {
+    self.alloc_with(|| value.clone())
+  }
+
+  pub fn stats(&self) -> Stats {
+    self.pool.stats()
+  }
+
+  // Describes every outstanding allocation and where it was made, or returns
+  // None if there are none. This is printed when the last handle is dropped.
+  #[cfg(feature = "debug")]
+  pub fn leak_report(&self) -> Option<String> {
+    self.pool.debug.report()
+  }
+
+  // Once set, chunks are returned to the system as soon as they're empty,
+  // as long as the allocator keeps room for at least `mark` items. `None`,
+  // the default, never releases chunks on its own.
+  pub fn set_low_water_mark(&self, mark: Option<usize>) {
+    self.pool.low_water.set(mark);
+  }
+
+  // Returns every chunk with no outstanding items to the system.
+  pub fn shrink_to_fit(&self) {
+    self.pool.shrink_to_fit();
   }
 }
 
 #[cfg(test)]
 mod tests {
-  extern crate test;
-  use super::{SlabAllocator, SlabBox};
-  use std::mem;
+  use super::{SlabAllocator, SlabBox, SliceAllocator, Stats};
 
   // Used to test that deallocation works.
-  #[deriving(Eq, Show, Clone)]
+  #[derive(PartialEq, Debug, Clone)]
   struct MyThing {
-    field1: Option<Box<int>>,
+    field1: Option<Box<isize>>,
     done: bool
   }
 
@@ -220,26 +652,26 @@ mod tests {
   fn test_one_mut_alloc() {
     let slab_allocator = SlabAllocator::new(20);
     let object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
-    assert_eq!(*object, *slab_allocator.alloc(239));
+    assert_eq!(*object.borrow(), 239);
+    assert_eq!(*object.borrow(), *slab_allocator.alloc(239).borrow());
   }
 
   #[test]
   fn test_one_struct_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let struct_obj = MyThing { field1: Some(box 20), done: false };
-    let mut object = slab_allocator.alloc(struct_obj);
+    let struct_obj = MyThing { field1: Some(Box::new(20)), done: false };
+    let object = slab_allocator.alloc(struct_obj);
 
-    assert_eq!(object.field1, Some(box 20));
-    object.done = true;
+    assert_eq!(object.borrow().field1, Some(Box::new(20)));
+    object.borrow_mut().done = true;
 
     // Now with a boxed type
     let slab_allocator = SlabAllocator::new(20);
-    let struct_obj = box MyThing { field1: Some(box 40), done: false };
-    let mut object = slab_allocator.alloc(struct_obj);
+    let struct_obj = Box::new(MyThing { field1: Some(Box::new(40)), done: false });
+    let object = slab_allocator.alloc(struct_obj);
 
-    assert_eq!(object.field1, Some(box 40)); // same as (*object).field1
-    object.done = true;
+    assert_eq!(object.borrow().field1, Some(Box::new(40))); // same as (*object.borrow()).field1
+    object.borrow_mut().done = true;
   }
 
   #[test]
@@ -247,9 +679,9 @@ mod tests {
   fn test_struct_dealloc() {
     let slab_allocator = SlabAllocator::new(20);
     {
-      let struct_obj = MyThing { field1: Some(box 20), done: false };
+      let struct_obj = MyThing { field1: Some(Box::new(20)), done: false };
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.field1, Some(box 20));
+      assert_eq!(object.borrow().field1, Some(Box::new(20)));
     }
     // Will cause double-fail if deallocation didn't occur.
     panic!("Did not dallocate struct in time.");
@@ -260,9 +692,9 @@ mod tests {
   fn test_box_struct_dealloc() {
     let slab_allocator = SlabAllocator::new(20);
     {
-      let struct_obj = box MyThing { field1: Some(box 2445), done: false };
+      let struct_obj = Box::new(MyThing { field1: Some(Box::new(2445)), done: false });
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.field1, Some(box 2445));
+      assert_eq!(object.borrow().field1, Some(Box::new(2445)));
     }
     // Will cause double-fail if deallocation didn't occur.
     panic!("Did not dallocate struct in time.");
@@ -271,80 +703,81 @@ mod tests {
   #[test]
   fn test_two_allocs_with_boxes() {
     let slab_allocator = SlabAllocator::new(20);
-    let object = slab_allocator.alloc(box 239);
-    let object2 = slab_allocator.alloc(box 23089);
+    let object = slab_allocator.alloc(Box::new(239));
+    let object2 = slab_allocator.alloc(Box::new(23089));
     let object3 = object.clone();
 
-    assert!(**object == 239);
-    assert_eq!(*object2, box 23089);
-    assert!(*object2 != *object);
-    assert!(*object2 != *object);
-    assert_eq!(*object3, *object);
+    assert!(**object.borrow() == 239);
+    assert_eq!(*object2.borrow(), Box::new(23089));
+    assert!(*object2.borrow() != *object.borrow());
+    assert!(*object2.borrow() != *object.borrow());
+    assert_eq!(*object3.borrow(), *object.borrow());
   }
 
   #[test]
   fn test_mut_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
+    let object = slab_allocator.alloc(239);
+    assert_eq!(*object.borrow(), 239);
 
-    *object = 500;
-    assert_eq!(*object, 500);
+    *object.borrow_mut() = 500;
+    assert_eq!(*object.borrow(), 500);
 
-    *object = 50;
-    assert_eq!(*object, 50);
+    *object.borrow_mut() = 50;
+    assert_eq!(*object.borrow(), 50);
   }
 
   #[test]
   fn test_mut_alloc_clone() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(239);
+    let object = slab_allocator.alloc(239);
     let object2 = object.clone();
     let object3 = slab_allocator.alloc(77);
 
-    assert_eq!(*object, 239);
-    assert_eq!(*object, *object2);
-    assert_eq!(*object3, 77);
+    assert_eq!(*object.borrow(), 239);
+    assert_eq!(*object.borrow(), *object2.borrow());
+    assert_eq!(*object3.borrow(), 77);
 
-    *object = 349;
-    assert_eq!(*object, 349);
-    assert_eq!(*object, *object2);
-    assert_eq!(*object3, 77);
+    *object.borrow_mut() = 349;
+    assert_eq!(*object.borrow(), 349);
+    assert_eq!(*object.borrow(), *object2.borrow());
+    assert_eq!(*object3.borrow(), 77);
   }
 
   #[test]
+  #[allow(clippy::replace_box)]
   fn test_mut_box_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(box 1111);
-    assert_eq!(*object, box 1111);
-    assert_eq!(*object, *slab_allocator.alloc(box 1111));
+    let object = slab_allocator.alloc(Box::new(1111));
+    assert_eq!(*object.borrow(), Box::new(1111));
+    assert_eq!(*object.borrow(), *slab_allocator.alloc(Box::new(1111)).borrow());
 
     let object2 = object.clone();
-    assert_eq!(*object, *object2);
-    let init_ptr: *int;
-    unsafe {
-      let ptr1: *int = mem::transmute(object.deref());
-      let ptr2: *int = mem::transmute(object2.deref());
+    assert_eq!(*object.borrow(), *object2.borrow());
+    let init_ptr: *const Box<isize>;
+    {
+      let ptr1: *const Box<isize> = &*object.borrow();
+      let ptr2: *const Box<isize> = &*object2.borrow();
       init_ptr = ptr1;
       assert_eq!(ptr1, ptr2); // make sure we're not allocating new internal box
     }
 
-    *object = box 2222;
-    assert_eq!(*object, *object2);
-    assert_eq!(*object2, box 2222);
-    assert_eq!(**object2, 2222);
+    *object.borrow_mut() = Box::new(2222);
+    assert_eq!(*object.borrow(), *object2.borrow());
+    assert_eq!(*object2.borrow(), Box::new(2222));
+    assert_eq!(**object2.borrow(), 2222);
 
-    let mut object3 = object2.clone();
-    *object3 = box 3333;
-    assert_eq!(*object, box 3333);
-    assert_eq!(*object2, *object);
-    assert_eq!(*object, *object3);
+    let object3 = object2.clone();
+    *object3.borrow_mut() = Box::new(3333);
+    assert_eq!(*object.borrow(), Box::new(3333));
+    assert_eq!(*object2.borrow(), *object.borrow());
+    assert_eq!(*object.borrow(), *object3.borrow());
 
-    unsafe {
-      let ptr1: *int = mem::transmute(object.deref());
-      let ptr2: *int = mem::transmute(object2.deref());
-      let ptr3: *int = mem::transmute(object3.deref());
-      assert_eq!(ptr1, ptr2); 
+    {
+      let ptr1: *const Box<isize> = &*object.borrow();
+      let ptr2: *const Box<isize> = &*object2.borrow();
+      let ptr3: *const Box<isize> = &*object3.borrow();
+      assert_eq!(ptr1, ptr2);
       assert_eq!(ptr2, ptr3);
 
       // this one is a bit interesting. after doing some tests, it looks like if
@@ -357,9 +790,9 @@ mod tests {
 
   #[test]
   fn test_one_alloc_boxed() {
-    let slab_allocator = box SlabAllocator::new(20);
+    let slab_allocator = Box::new(SlabAllocator::new(20));
     let object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
+    assert_eq!(*object.borrow(), 239);
   }
 
   #[test]
@@ -368,42 +801,40 @@ mod tests {
 
     // Alloacting and verifying 20 items and putting into vector.
     let mut vec = Vec::new();
-    for i in range(0, 20) {
+    for i in 0..20 {
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
+      assert_eq!(*obj.borrow(), i);
       vec.push(obj);
     }
 
     // Making sure they're all still there and different.
-    let mut i = 0;
-    for obj in vec.iter() {
-      assert_eq!(**obj, i);
-      i += 1;
+    for (i, obj) in vec.iter().enumerate() {
+      assert_eq!(*obj.borrow(), i);
     }
   }
 
   #[test]
   fn test_alloc_return() {
-    let slab_allocator = box SlabAllocator::new(20);
+    let slab_allocator = Box::new(SlabAllocator::new(20));
 
     // Drop should be called for each object after each loop
-    for i in range(0, 50) {
+    for i in 0..50 {
       // Making sure nothing's in there
-      let (alloc, _) = slab_allocator.stats();
+      let alloc = slab_allocator.stats().alloc;
       assert_eq!(alloc, 0);
 
       let object = slab_allocator.alloc(i);
-      assert_eq!(*object, i);
+      assert_eq!(*object.borrow(), i);
     }
 
     // Just in case some weird business is happenning
-    for i in range(-239, -180) {
+    for i in -239..-180 {
       let object = slab_allocator.alloc(i);
-      assert_eq!(*object, i);
+      assert_eq!(*object.borrow(), i);
     }
 
     // Should still be empty
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(alloc, 0);
   }
 
@@ -415,14 +846,14 @@ mod tests {
     // Alloacting more then the capacity
     // Testing reference counting (by using clone), shouldn't drop
     let mut vec = Vec::new();
-    for i in range(0, 100) {
+    for i in 0..100 {
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
+      assert_eq!(*obj.borrow(), i);
       vec.push(obj.clone());
     }
 
     // Make sure the allocator performed as expected
-    let (allocated, capacity) = slab_allocator.stats();
+    let Stats { alloc: allocated, capacity, .. } = slab_allocator.stats();
     assert_eq!(allocated, 100);
     assert_eq!(capacity, 160); // 20 -> 40 -> 80 -> 160
 
@@ -430,7 +861,7 @@ mod tests {
     vec.truncate(0);
 
     // Make sure they were deallocated
-    let (allocated, capacity) = slab_allocator.stats();
+    let Stats { alloc: allocated, capacity, .. } = slab_allocator.stats();
     assert_eq!(allocated, 0);
     assert_eq!(capacity, 160); // 20 -> 40 -> 80 -> 160
   }
@@ -442,31 +873,31 @@ mod tests {
     // Drop should be called for each object after each loop since we're only
     // storing the value of the object and not the object itself
     let mut vec = Vec::new();
-    for i in range(0, 25) {
-      let (alloc, _) = slab_allocator.stats();
+    for i in 0..25 {
+      let alloc = slab_allocator.stats().alloc;
       assert_eq!(alloc, 0);
 
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
-      vec.push(*obj);
+      assert_eq!(*obj.borrow(), i);
+      vec.push(*obj.borrow());
     }
 
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(alloc, 0);
   }
 
   #[test]
   fn test_usage_external_allocator() {
     struct MyThing<'r> {
-      item: SlabBox<'r, int>,
-      allocator: &'r SlabAllocator<int>,
+      item: SlabBox<isize>,
+      allocator: &'r SlabAllocator<isize>,
     }
 
     impl<'r> MyThing<'r> {
-      fn new(allocator: &'r SlabAllocator<int>, num: isize) -> MyThing<'r> {
+      fn new(allocator: &'r SlabAllocator<isize>, num: isize) -> MyThing<'r> {
         MyThing {
           item: allocator.alloc(num),
-          allocator: allocator,
+          allocator,
         }
       }
 
@@ -479,75 +910,80 @@ mod tests {
     let mut thing = MyThing::new(&allocator, 120);
     let thing2 = MyThing::new(&allocator, 130);
 
-    assert_eq!(*thing.item, 120);
-    assert_eq!(*thing2.item, 130);
+    assert_eq!(*thing.item.borrow(), 120);
+    assert_eq!(*thing2.item.borrow(), 130);
 
-    let (alloc, _) = allocator.stats();
+    let alloc = allocator.stats().alloc;
     assert_eq!(alloc, 2);
 
     thing.set_num(434);
-    assert_eq!(*thing.item, 434);
-    assert_eq!(*thing2.item, 130);
+    assert_eq!(*thing.item.borrow(), 434);
+    assert_eq!(*thing2.item.borrow(), 130);
 
-    let (alloc, _) = allocator.stats();
+    let alloc = allocator.stats().alloc;
     assert_eq!(alloc, 2);
   }
 
-  // Not sure if this is possible.
   #[test]
   fn test_usage_internal_allocator() {
     use std::cell::RefCell;
 
-    struct MyThing<'r> {
-      item: RefCell<Option<SlabBox<'r, int>>>,
-      allocator: SlabAllocator<int>
+    struct MyThing {
+      item: RefCell<Option<SlabBox<isize>>>,
+      allocator: SlabAllocator<isize>
     }
 
-    impl<'r> MyThing<'r> {
+    impl MyThing {
       fn new() -> MyThing {
-        let thing = MyThing {
+        MyThing {
           item: RefCell::new(None),
           allocator: SlabAllocator::new(10)
-        };
+        }
+      }
 
+      fn with(num: isize) -> MyThing {
+        let thing = MyThing::new();
+        thing.set(num);
         thing
       }
 
-      fn set(&'r self, num: isize) {
+      fn set(&self, num: isize) {
         *self.item.borrow_mut() = Some(self.allocator.alloc(num));
       }
 
-      fn item(&'r self) -> SlabBox<'r, int> {
-        self.item.borrow().get_ref().clone()
+      fn item(&self) -> SlabBox<isize> {
+        self.item.borrow().as_ref().unwrap().clone()
       }
     }
 
-    // Ideally, this would work.
-    // let thing = MyThing::new(120);
-    // assert!(**thing.item() == 120);
-    
+    // Now that boxes don't borrow their allocator, this works.
+    let thing = MyThing::with(120);
+    assert!(*thing.item().borrow() == 120);
+
     let thing = MyThing::new();
 
     thing.set(127);
-    assert!(*thing.item() == 127);
+    assert!(*thing.item().borrow() == 127);
 
     thing.set(50);
-    assert!(*thing.item() == 50);
+    assert!(*thing.item().borrow() == 50);
 
-    let (alloc, _) = thing.allocator.stats();
+    let alloc = thing.allocator.stats().alloc;
     assert_eq!(alloc, 1);
 
     let oldthing = thing.item();
     thing.set(120);
-    assert_eq!(*oldthing, 50);
-    assert_eq!(*thing.item(), 120);
+    assert_eq!(*oldthing.borrow(), 50);
+    assert_eq!(*thing.item().borrow(), 120);
 
-    let (alloc, _) = thing.allocator.stats();
+    let alloc = thing.allocator.stats().alloc;
     assert_eq!(alloc, 2);
   }
 
   #[test]
+  #[cfg(not(feature = "debug"))]
   fn test_dirty_alloc() {
+    #[derive(Clone, Copy)]
     struct ValHolder {
       value: isize,
       value2: isize
@@ -559,23 +995,23 @@ mod tests {
     {
       let struct_obj = ValHolder { value: 3490, value2: 871 };
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.value, 3490);
-      assert_eq!(object.value2, 871);
+      assert_eq!(object.borrow().value, 3490);
+      assert_eq!(object.borrow().value2, 871);
     }
 
     // Making sure object is returned back.
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(0, alloc);
 
     // Making sure dirty_alloc returns the same structure.
     {
       let object = unsafe { slab_allocator.dirty_alloc() };
-      assert_eq!(object.value, 3490);
-      assert_eq!(object.value2, 871);
+      assert_eq!(object.borrow().value, 3490);
+      assert_eq!(object.borrow().value2, 871);
     }
 
     // Again, should be deallocated.
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(0, alloc);
 
     // Allocating two objects. First should be dirty with same values, second
@@ -583,9 +1019,292 @@ mod tests {
     let object1 = unsafe { slab_allocator.dirty_alloc() };
     let object2 = unsafe { slab_allocator.dirty_alloc() };
 
-    assert_eq!(object1.value, 3490);
-    assert_eq!(object1.value2, 871);
-    assert!(object2.value != 3490);
-    assert!(object2.value2 != 871);
+    assert_eq!(object1.borrow().value, 3490);
+    assert_eq!(object1.borrow().value2, 871);
+    assert!(object2.borrow().value != 3490);
+    assert!(object2.borrow().value2 != 871);
+  }
+
+  #[test]
+  fn test_slice_alloc() {
+    let slice_allocator = SliceAllocator::new(2, 100);
+    let mut first = slice_allocator.alloc(0u8);
+    let mut second = slice_allocator.alloc(7u8);
+    assert_eq!(first.len(), 100);
+    assert!(first.iter().all(|&x| x == 0));
+    assert!(second.iter().all(|&x| x == 7));
+
+    first[99] = 1;
+    second[0] = 2;
+    assert_eq!(first[99], 1);
+    assert_eq!(second[99], 7);
+    assert_eq!(second[0], 2);
+
+    // Growing past the initial size
+    let third = slice_allocator.alloc(3u8);
+    assert_eq!((slice_allocator.stats().alloc, slice_allocator.stats().capacity), (3, 4));
+    assert_eq!(first[0], 0);
+    assert_eq!(third[99], 3);
+
+    drop(first);
+    drop(second);
+    assert_eq!((slice_allocator.stats().alloc, slice_allocator.stats().capacity), (1, 4));
+  }
+
+  #[test]
+  fn test_slice_alloc_with() {
+    use std::rc::Rc;
+
+    let counter = Rc::new(());
+    let slice_allocator = SliceAllocator::new(4, 10);
+    {
+      let mut n = 0;
+      let slice = slice_allocator.alloc_with(|| { n += 1; (n, counter.clone()) });
+      assert_eq!(slice[0].0, 1);
+      assert_eq!(slice[9].0, 10);
+      assert_eq!(Rc::strong_count(&counter), 11);
+    }
+
+    // Every item was dropped when the slice was returned
+    assert_eq!(Rc::strong_count(&counter), 1);
+    assert_eq!((slice_allocator.stats().alloc, slice_allocator.stats().capacity), (0, 4));
+  }
+
+  #[test]
+  fn test_box_outlives_allocator() {
+    let object = {
+      let slab_allocator = SlabAllocator::new(1);
+      slab_allocator.alloc(Box::new(239))
+    };
+
+    let slice = SliceAllocator::new(1, 3).alloc(Box::new(7));
+    assert_eq!(*object.borrow(), Box::new(239));
+    assert_eq!(*slice[2], 7);
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_borrow_mut_while_borrowed() {
+    let slab_allocator = SlabAllocator::new(1);
+    let object = slab_allocator.alloc(1);
+    let object2 = object.clone();
+
+    let _value = object.borrow();
+    *object2.borrow_mut() = 2;
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_borrow_while_borrowed_mut() {
+    let slab_allocator = SlabAllocator::new(1);
+    let object = slab_allocator.alloc(1);
+
+    let _value = object.borrow_mut();
+    let _ = *object.clone().borrow();
+  }
+
+  #[test]
+  fn test_borrows_released() {
+    let slab_allocator = SlabAllocator::new(1);
+    let object = slab_allocator.alloc(1);
+    {
+      let (a, b) = (object.borrow(), object.borrow());
+      assert_eq!(*a + *b, 2);
+    }
+
+    *object.borrow_mut() += 1;
+    assert_eq!(*object.borrow(), 2);
+    assert_eq!(format!("{:?}", object), "SlabBox(2)");
+  }
+
+  #[test]
+  fn test_zero_sized_alloc() {
+    let slab_allocator = SlabAllocator::new(0);
+    let objects: Vec<_> = (0..5).map(|_| slab_allocator.alloc(())).collect();
+    assert_eq!((slab_allocator.stats().alloc, slab_allocator.stats().capacity), (5, 8));
+    drop(objects);
+
+    let slice_allocator = SliceAllocator::new(2, 0);
+    assert!(slice_allocator.alloc(1u8).is_empty());
+    let slice = SliceAllocator::new(2, 3).alloc(());
+    assert_eq!(slice.len(), 3);
+  }
+
+  #[test]
+  fn test_panicking_alloc_with() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    let counter = Rc::new(());
+    let slice_allocator = SliceAllocator::new(1, 10);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+      let mut n = 0;
+      slice_allocator.alloc_with(|| {
+        n += 1;
+        if n == 5 { panic!("Out of values."); }
+        counter.clone()
+      })
+    }));
+
+    // The four values that were produced got dropped, and the slot is free
+    assert!(result.is_err());
+    assert_eq!(Rc::strong_count(&counter), 1);
+    assert_eq!((slice_allocator.stats().alloc, slice_allocator.stats().capacity), (0, 1));
+    assert_eq!(slice_allocator.alloc_with(|| counter.clone()).len(), 10);
+  }
+
+  #[test]
+  fn test_panicking_drop() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let slab_allocator = SlabAllocator::new(2);
+    let keep = slab_allocator.alloc(MyThing { field1: None, done: true });
+    let result = catch_unwind(AssertUnwindSafe(|| {
+      slab_allocator.alloc(MyThing { field1: Some(Box::new(1)), done: false });
+    }));
+
+    // The slot came back even though dropping its value panicked, and the
+    // live object's slot wasn't handed out again.
+    assert!(result.is_err());
+    assert_eq!((slab_allocator.stats().alloc, slab_allocator.stats().capacity), (1, 2));
+    let other = slab_allocator.alloc(MyThing { field1: Some(Box::new(2)), done: true });
+    assert_eq!(keep.borrow().field1, None);
+    assert_eq!(other.borrow().field1, Some(Box::new(2)));
+  }
+
+  #[test]
+  fn test_stats() {
+    let slice_allocator = SliceAllocator::<u64>::new(4, 8);
+    let stats = slice_allocator.stats();
+    assert_eq!(stats, Stats { alloc: 0, capacity: 4, chunks: 1, empty_chunks: 1, bytes: 4 * 64 });
+
+    let boxes: Vec<_> = (0..5).map(|_| slice_allocator.alloc(0)).collect();
+    let stats = slice_allocator.stats();
+    assert_eq!(stats, Stats { alloc: 5, capacity: 8, chunks: 2, empty_chunks: 0, bytes: 8 * 64 });
+
+    // Without a low-water mark, nothing is ever released on its own
+    drop(boxes);
+    let stats = slice_allocator.stats();
+    assert_eq!(stats, Stats { alloc: 0, capacity: 8, chunks: 2, empty_chunks: 2, bytes: 8 * 64 });
+  }
+
+  #[test]
+  fn test_shrink_to_fit() {
+    let slab_allocator = SlabAllocator::new(2);
+    let mut objects: Vec<_> = (0..16).map(|i| slab_allocator.alloc(i)).collect();
+    assert_eq!(slab_allocator.stats().chunks, 4); // 2 + 2 + 4 + 8
+
+    // Keep one object alive in the first chunk
+    objects.truncate(1);
+    slab_allocator.shrink_to_fit();
+    let stats = slab_allocator.stats();
+    assert_eq!((stats.alloc, stats.capacity, stats.chunks, stats.empty_chunks), (1, 2, 1, 0));
+    assert_eq!(*objects[0].borrow(), 0);
+
+    // The allocator grows again as usual
+    objects.extend((1..10).map(|i| slab_allocator.alloc(i)));
+    for (i, object) in objects.iter().enumerate() {
+      assert_eq!(*object.borrow(), i);
+    }
+
+    drop(objects);
+    slab_allocator.shrink_to_fit();
+    let stats = slab_allocator.stats();
+    assert_eq!((stats.alloc, stats.capacity, stats.chunks, stats.bytes), (0, 0, 0, 0));
+    assert_eq!(*slab_allocator.alloc(7).borrow(), 7);
+  }
+
+  #[test]
+  fn test_low_water_mark() {
+    let slice_allocator = SliceAllocator::<u8>::new(4, 16);
+    slice_allocator.set_low_water_mark(Some(4));
+    let mut slices: Vec<_> = (0..32).map(|_| slice_allocator.alloc(0)).collect();
+    assert_eq!(slice_allocator.stats().capacity, 32);
+
+    // Chunks are only released once the allocator is at most half full
+    slices.truncate(17);
+    assert_eq!(slice_allocator.stats().capacity, 32);
+
+    // ...and never below the low-water mark
+    slices.clear();
+    let stats = slice_allocator.stats();
+    assert_eq!(stats.alloc, 0);
+    assert!(stats.capacity >= 4 && stats.capacity < 32);
+    assert_eq!(stats.bytes, stats.capacity * 16);
+
+    // Freeing right at a chunk boundary doesn't release and reallocate
+    // the same chunk over and over.
+    let slices: Vec<_> = (0..stats.capacity).map(|_| slice_allocator.alloc(0)).collect();
+    drop(slice_allocator.alloc(0));
+    let grown = slice_allocator.stats();
+    assert_eq!((grown.chunks, grown.empty_chunks), (stats.chunks + 1, 1));
+    for _ in 0..10 {
+      drop(slice_allocator.alloc(0));
+      assert_eq!(slice_allocator.stats(), grown);
+    }
+
+    drop(slices);
+  }
+
+  #[test]
+  fn test_released_chunks_with_zero_sized() {
+    let slab_allocator = SlabAllocator::new(1);
+    slab_allocator.set_low_water_mark(Some(0));
+    let units: Vec<_> = (0..8).map(|_| slab_allocator.alloc(())).collect();
+    drop(units);
+    slab_allocator.shrink_to_fit();
+    assert_eq!(slab_allocator.stats().alloc, 0);
+  }
+
+  #[cfg(feature = "debug")]
+  mod debug {
+    use super::super::{debug, SlabAllocator, SliceAllocator};
+
+    #[test]
+    fn test_poisoned_on_free() {
+      let slice_allocator = SliceAllocator::new(2, 16);
+      let ptr = slice_allocator.alloc(7u8).ptr;
+      let bytes = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), 16) };
+      assert!(bytes.iter().all(|&byte| byte == debug::POISON));
+    }
+
+    #[test]
+    fn test_dirty_alloc_zeroes() {
+      let slab_allocator = SlabAllocator::new(2);
+      drop(slab_allocator.alloc((3490isize, 871isize)));
+      let object = unsafe { slab_allocator.dirty_alloc() };
+      assert_eq!(*object.borrow(), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "use after free")]
+    fn test_use_after_free() {
+      let slice_allocator = SliceAllocator::new(1, 8);
+      let ptr = slice_allocator.alloc(0u64).ptr;
+      unsafe { *ptr.as_ptr().add(3) = 1; }
+      slice_allocator.alloc(0u64);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_double_free() {
+      let slice_allocator = SliceAllocator::new(1, 8);
+      let ptr = slice_allocator.alloc(0u64).ptr;
+      unsafe { slice_allocator.pool.free(ptr, 0); }
+    }
+
+    #[test]
+    fn test_leak_report() {
+      let slab_allocator = SlabAllocator::new(4);
+      assert_eq!(slab_allocator.leak_report(), None);
+
+      let kept = slab_allocator.alloc(1);
+      let report = slab_allocator.leak_report().unwrap();
+      assert!(report.starts_with("slab: 1 allocation(s) outstanding"));
+      assert!(report.contains("test_leak_report"));
+
+      drop(kept);
+      assert_eq!(slab_allocator.leak_report(), None);
+    }
   }
 }
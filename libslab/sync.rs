@@ -0,0 +1,632 @@
+/*!
+ * Thread-safe slab and slice allocators.
+ *
+ * To use:
+ *
+ * let s = sync::SliceAllocator::new(64, 4096);
+ * let pages = s.clone(); // Allocators can be shared between threads
+ * thread::spawn(move || {
+ *   let mut page = pages.alloc(0u8); // Type is sync::SliceBox<u8>
+ *   page[10] = 1;
+ * });
+ *
+ * A sync::SlabAllocator does the same for single values. Unlike the
+ * single-threaded SlabBox, its boxes are uniquely owned, like a Box<T>:
+ *
+ * let s = sync::SlabAllocator::new(64);
+ * let mut name = s.alloc(String::new()); // Type is sync::SlabBox<String>
+ * name.push_str("slab");
+ *
+ * Free slots live in magazines, fixed-size stacks of slots. Each thread keeps
+ * two magazines per allocator and allocates from and frees to them without
+ * any synchronization. Only when both of its magazines are empty (or full)
+ * does a thread go to the allocator's depot, under a lock, to swap a whole
+ * magazine for a full (or empty) one. The depot carves new chunks into full
+ * magazines when it runs out.
+ *
+ * Unlike the single-threaded allocators, chunks are never released before the
+ * allocator and all of its boxes are gone.
+ */
+
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+// Number of free slots in a full magazine.
+const MAGAZINE_SIZE: usize = 32;
+
+// Hands out a distinct id to every depot, for finding a thread's cache.
+static NEXT_DEPOT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static CACHES: RefCell<HashMap<usize, Cache>> = RefCell::new(HashMap::new()));
+
+// A free slot. Free slots are only ever touched by whoever holds them, so they
+// can move between threads.
+#[derive(Clone, Copy)]
+struct Slot(NonNull<u8>);
+
+unsafe impl Send for Slot {}
+
+type Magazine = Vec<Slot>;
+
+struct Chunk {
+  ptr: NonNull<u8>,
+  layout: Layout
+}
+
+unsafe impl Send for Chunk {}
+
+/**
+ * A snapshot of a depot's bookkeeping. Slots cached by threads count as
+ * allocated: only the depot's own free slots are counted as free.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepotStats {
+  pub capacity: usize,   // number of slots pre-allocated, outstanding or not
+  pub free: usize,       // number of free slots in the depot
+  pub chunks: usize,     // number of chunks held
+  pub bytes: usize,      // number of bytes held in chunks
+}
+
+// The part of a depot that's behind its lock.
+struct Shelves {
+  full: Vec<Magazine>,    // magazines with at least one free slot
+  empty: Vec<Magazine>,   // magazines with none, kept for their memory
+  chunks: Vec<Chunk>,
+  capacity: usize,
+}
+
+// The slots shared by every thread, and the memory behind them.
+struct Depot {
+  id: usize,
+  slot: Layout,
+  shelves: Mutex<Shelves>
+}
+
+impl Depot {
+  fn new(slot: Layout, initial_size: usize) -> Depot {
+    let depot = Depot {
+      id: NEXT_DEPOT_ID.fetch_add(1, Ordering::Relaxed),
+      slot,
+      shelves: Mutex::new(Shelves {
+        full: Vec::new(),
+        empty: Vec::new(),
+        chunks: Vec::new(),
+        capacity: 0
+      })
+    };
+
+    depot.grow(&mut depot.lock(), initial_size);
+    depot
+  }
+
+  fn lock(&self) -> MutexGuard<'_, Shelves> {
+    // Nothing that can panic runs under the lock, but don't cascade if it does.
+    self.shelves.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  // Allocates a chunk of `new_slots` slots and shelves them as full magazines.
+  fn grow(&self, shelves: &mut Shelves, new_slots: usize) {
+    let layout = self.slot.size().checked_mul(new_slots)
+      .and_then(|size| Layout::from_size_align(size, self.slot.align()).ok())
+      .expect("chunk too large");
+
+    // Zero-sized slots don't need any memory at all.
+    let memory = if layout.size() == 0 {
+      NonNull::new(ptr::without_provenance_mut(layout.align())).unwrap()
+    } else {
+      let memory = unsafe { alloc::alloc_zeroed(layout) };
+      let memory = NonNull::new(memory).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+      shelves.chunks.push(Chunk { ptr: memory, layout });
+      memory
+    };
+
+    for start in (0..new_slots).step_by(MAGAZINE_SIZE) {
+      let mut magazine = Vec::with_capacity(MAGAZINE_SIZE);
+      for i in start..cmp::min(start + MAGAZINE_SIZE, new_slots) {
+        let slot = unsafe { memory.as_ptr().add(i * self.slot.size()) };
+        magazine.push(Slot(unsafe { NonNull::new_unchecked(slot) }));
+      }
+
+      shelves.full.push(magazine);
+    }
+
+    shelves.capacity += new_slots;
+  }
+
+  // Returns a magazine with at least one free slot, growing if needed.
+  fn full_magazine(&self, shelves: &mut Shelves) -> Magazine {
+    if shelves.full.is_empty() {
+      let new_slots = cmp::max(shelves.capacity, MAGAZINE_SIZE);
+      self.grow(shelves, new_slots);
+    }
+
+    shelves.full.pop().expect("depot grew")
+  }
+
+  // Trades an empty magazine for one with free slots.
+  fn exchange_empty(&self, empty: Magazine) -> Magazine {
+    let mut shelves = self.lock();
+    let full = self.full_magazine(&mut shelves);
+    shelves.empty.push(empty);
+    full
+  }
+
+  // Trades a full magazine for an empty one.
+  fn exchange_full(&self, full: Magazine) -> Magazine {
+    let mut shelves = self.lock();
+    shelves.full.push(full);
+    shelves.empty.pop().unwrap_or_else(|| Vec::with_capacity(MAGAZINE_SIZE))
+  }
+
+  // Takes back a thread's magazines when it exits or drops its cache.
+  fn return_magazines(&self, magazines: [Magazine; 2]) {
+    let mut shelves = self.lock();
+    for magazine in magazines {
+      if magazine.is_empty() {
+        shelves.empty.push(magazine);
+      } else {
+        shelves.full.push(magazine);
+      }
+    }
+  }
+
+  // Takes a single slot, for threads that can't use their cache.
+  fn take(&self) -> Slot {
+    let mut shelves = self.lock();
+    let mut magazine = self.full_magazine(&mut shelves);
+    let slot = magazine.pop().expect("magazine has a slot");
+    if magazine.is_empty() {
+      shelves.empty.push(magazine);
+    } else {
+      shelves.full.push(magazine);
+    }
+
+    slot
+  }
+
+  // Returns a single slot, for threads that can't use their cache.
+  fn put_back(&self, slot: Slot) {
+    let mut shelves = self.lock();
+    match shelves.full.last_mut() {
+      Some(magazine) if magazine.len() < MAGAZINE_SIZE => magazine.push(slot),
+      _ => {
+        let mut magazine = shelves.empty.pop().unwrap_or_else(|| Vec::with_capacity(MAGAZINE_SIZE));
+        magazine.push(slot);
+        shelves.full.push(magazine);
+      }
+    }
+  }
+
+  fn stats(&self) -> DepotStats {
+    let shelves = self.lock();
+    DepotStats {
+      capacity: shelves.capacity,
+      free: shelves.full.iter().map(|magazine| magazine.len()).sum(),
+      chunks: shelves.chunks.len(),
+      bytes: shelves.chunks.iter().map(|chunk| chunk.layout.size()).sum()
+    }
+  }
+}
+
+impl Drop for Depot {
+  fn drop(&mut self) {
+    for chunk in self.lock().chunks.iter() {
+      unsafe { alloc::dealloc(chunk.ptr.as_ptr(), chunk.layout); }
+    }
+  }
+}
+
+// A thread's magazines for a single depot. `loaded` is allocated from and
+// freed to first; `previous` is either full or empty, so that a thread
+// alternating between allocating and freeing at a magazine boundary doesn't
+// go to the depot every time.
+struct Cache {
+  depot: Weak<Depot>,
+  loaded: Magazine,
+  previous: Magazine
+}
+
+impl Cache {
+  fn take(&mut self, depot: &Depot) -> Slot {
+    if self.loaded.is_empty() {
+      if self.previous.is_empty() {
+        self.loaded = depot.exchange_empty(mem::take(&mut self.loaded));
+      } else {
+        mem::swap(&mut self.loaded, &mut self.previous);
+      }
+    }
+
+    self.loaded.pop().expect("loaded magazine has a slot")
+  }
+
+  fn put_back(&mut self, depot: &Depot, slot: Slot) {
+    if self.loaded.len() >= MAGAZINE_SIZE {
+      if self.previous.len() >= MAGAZINE_SIZE {
+        self.previous = depot.exchange_full(mem::take(&mut self.previous));
+      }
+
+      mem::swap(&mut self.loaded, &mut self.previous);
+    }
+
+    self.loaded.push(slot);
+  }
+}
+
+impl Drop for Cache {
+  fn drop(&mut self) {
+    // If the depot is gone, so is the memory the slots point into.
+    if let Some(depot) = self.depot.upgrade() {
+      depot.return_magazines([mem::take(&mut self.loaded), mem::take(&mut self.previous)]);
+    }
+  }
+}
+
+// Runs `f` with this thread's cache for `depot`. Returns `None` if the cache
+// isn't available, which happens while the thread's locals are being torn
+// down.
+fn with_cache<R, F: FnOnce(&mut Cache) -> R>(depot: &Arc<Depot>, f: F) -> Option<R> {
+  CACHES.try_with(|caches| {
+    let mut caches = caches.try_borrow_mut().ok()?;
+    if !caches.contains_key(&depot.id) {
+      // Forget the caches of depots that have since been dropped.
+      caches.retain(|_, cache| cache.depot.strong_count() > 0);
+      caches.insert(depot.id, Cache {
+        depot: Arc::downgrade(depot),
+        loaded: Vec::with_capacity(MAGAZINE_SIZE),
+        previous: Vec::with_capacity(MAGAZINE_SIZE)
+      });
+    }
+
+    caches.get_mut(&depot.id).map(f)
+  }).ok().flatten()
+}
+
+fn take(depot: &Arc<Depot>) -> Slot {
+  with_cache(depot, |cache| cache.take(depot)).unwrap_or_else(|| depot.take())
+}
+
+fn put_back(depot: &Arc<Depot>, slot: Slot) {
+  if with_cache(depot, |cache| cache.put_back(depot, slot)).is_none() {
+    depot.put_back(slot);
+  }
+}
+
+// Drops the first `init` values in the slot and returns it. The slot is
+// returned even if one of the drops panics.
+unsafe fn free<T>(depot: &Arc<Depot>, ptr: NonNull<T>, init: usize) {
+  struct PutBack<'a>(&'a Arc<Depot>, Slot);
+
+  impl<'a> Drop for PutBack<'a> {
+    fn drop(&mut self) {
+      put_back(self.0, self.1);
+    }
+  }
+
+  let _put_back = PutBack(depot, Slot(ptr.cast()));
+  ptr::drop_in_place(ptr::slice_from_raw_parts_mut(ptr.as_ptr(), init));
+}
+
+/**
+ * A uniquely owned slice of `len` items, allocated from a sync::SliceAllocator
+ * and returned to it on drop, from whichever thread drops it.
+ */
+pub struct SliceBox<T> {
+  depot: Arc<Depot>,
+  ptr: NonNull<T>,
+  len: usize,
+  _marker: PhantomData<T>
+}
+
+unsafe impl<T: Send> Send for SliceBox<T> {}
+unsafe impl<T: Sync> Sync for SliceBox<T> {}
+
+impl<T> Deref for SliceBox<T> {
+  type Target = [T];
+
+  #[inline(always)]
+  fn deref(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+  }
+}
+
+impl<T> DerefMut for SliceBox<T> {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+  }
+}
+
+impl<T> Drop for SliceBox<T> {
+  fn drop(&mut self) {
+    unsafe { free(&self.depot, self.ptr, self.len); }
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SliceBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    (**self).fmt(f)
+  }
+}
+
+pub struct SliceAllocator<T> {
+  depot: Arc<Depot>,
+  len: usize,
+  _marker: PhantomData<T>
+}
+
+impl<T> Clone for SliceAllocator<T> {
+  fn clone(&self) -> SliceAllocator<T> {
+    SliceAllocator { depot: self.depot.clone(), len: self.len, _marker: PhantomData }
+  }
+}
+
+impl<T> fmt::Debug for SliceAllocator<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "SliceAllocator {{ len: {}, {:?} }}", self.len, self.stats())
+  }
+}
+
+impl<T> SliceAllocator<T> {
+  // Pre-allocates `initial_size` slices of `len` items each.
+  pub fn new(initial_size: usize, len: usize) -> SliceAllocator<T> {
+    let slot = Layout::array::<T>(len).expect("slice too large");
+    let depot = Arc::new(Depot::new(slot, initial_size));
+    SliceAllocator { depot, len, _marker: PhantomData }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  // Allocates a slice, initializing each item with the next value from `f`.
+  // If `f` panics, the items it already produced are dropped and the slice is
+  // returned to the allocator.
+  pub fn alloc_with<F: FnMut() -> T>(&self, mut f: F) -> SliceBox<T> {
+    struct Partial<'a, T> {
+      depot: &'a Arc<Depot>,
+      ptr: NonNull<T>,
+      init: usize
+    }
+
+    impl<'a, T> Drop for Partial<'a, T> {
+      fn drop(&mut self) {
+        unsafe { free(self.depot, self.ptr, self.init); }
+      }
+    }
+
+    let ptr = take(&self.depot).0.cast::<T>();
+    let mut partial = Partial { depot: &self.depot, ptr, init: 0 };
+    while partial.init < self.len {
+      unsafe { partial.ptr.as_ptr().add(partial.init).write(f()); }
+      partial.init += 1;
+    }
+
+    mem::forget(partial);
+    SliceBox { depot: self.depot.clone(), ptr, len: self.len, _marker: PhantomData }
+  }
+
+  pub fn alloc(&self, value: T) -> SliceBox<T> where T: Clone {
+    self.alloc_with(|| value.clone())
+  }
+
+  pub fn stats(&self) -> DepotStats {
+    self.depot.stats()
+  }
+}
+
+/**
+ * A uniquely owned value allocated from a sync::SlabAllocator and returned to
+ * it on drop, from whichever thread drops it.
+ */
+pub struct SlabBox<T>(SliceBox<T>);
+
+impl<T> Deref for SlabBox<T> {
+  type Target = T;
+
+  #[inline(always)]
+  fn deref(&self) -> &T {
+    &self.0[0]
+  }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.0[0]
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    (**self).fmt(f)
+  }
+}
+
+// A SliceAllocator of one-item slices.
+pub struct SlabAllocator<T>(SliceAllocator<T>);
+
+impl<T> Clone for SlabAllocator<T> {
+  fn clone(&self) -> SlabAllocator<T> {
+    SlabAllocator(self.0.clone())
+  }
+}
+
+impl<T> fmt::Debug for SlabAllocator<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "SlabAllocator {{ {:?} }}", self.stats())
+  }
+}
+
+impl<T> SlabAllocator<T> {
+  // Pre-allocates room for `initial_size` values.
+  pub fn new(initial_size: usize) -> SlabAllocator<T> {
+    SlabAllocator(SliceAllocator::new(initial_size, 1))
+  }
+
+  pub fn alloc(&self, value: T) -> SlabBox<T> {
+    let mut value = Some(value);
+    SlabBox(self.0.alloc_with(|| value.take().expect("one value per slot")))
+  }
+
+  pub fn stats(&self) -> DepotStats {
+    self.0.stats()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SlabAllocator, SliceAllocator, MAGAZINE_SIZE};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  #[test]
+  fn test_alloc() {
+    let allocator = SliceAllocator::new(4, 16);
+    let mut first = allocator.alloc(0u8);
+    let mut second = allocator.alloc(1u8);
+    first[0] = 2;
+    second[15] = 3;
+    assert_eq!(&first[..2], &[2, 0]);
+    assert_eq!(&second[14..], &[1, 3]);
+    assert_eq!(allocator.len(), 16);
+  }
+
+  #[test]
+  fn test_thread_cache_reuse() {
+    let allocator = SliceAllocator::new(4, 8);
+    let ptr = allocator.alloc(0u64).as_ptr();
+
+    // The slot freed above is on top of this thread's loaded magazine
+    assert_eq!(allocator.alloc(0u64).as_ptr(), ptr);
+  }
+
+  #[test]
+  fn test_depot_grows() {
+    let allocator = SliceAllocator::new(1, 4);
+    let slices: Vec<_> = (0..(MAGAZINE_SIZE * 3)).map(|i| allocator.alloc(i)).collect();
+    for (i, slice) in slices.iter().enumerate() {
+      assert_eq!(&slice[..], &[i; 4]);
+    }
+
+    let stats = allocator.stats();
+    assert!(stats.capacity >= MAGAZINE_SIZE * 3);
+    assert_eq!(stats.bytes, stats.capacity * 4 * std::mem::size_of::<usize>());
+  }
+
+  #[test]
+  fn test_threads() {
+    let allocator = SliceAllocator::new(8, 32);
+    let handles: Vec<_> = (0..4u8).map(|t| {
+      let allocator = allocator.clone();
+      thread::spawn(move || {
+        for round in 0..10 {
+          let slices: Vec<_> = (0..(MAGAZINE_SIZE + 5)).map(|_| allocator.alloc(t)).collect();
+          for slice in slices.iter() {
+            assert!(slice.iter().all(|&byte| byte == t), "round {}", round);
+          }
+        }
+      })
+    }).collect();
+
+    for handle in handles {
+      handle.join().unwrap();
+    }
+
+    // Exited threads hand their magazines back
+    let stats = allocator.stats();
+    assert_eq!(stats.free, stats.capacity);
+  }
+
+  #[test]
+  fn test_free_on_other_thread() {
+    let allocator = SliceAllocator::new(2, 1);
+    let boxes: Vec<_> = (0..10).map(|i| allocator.alloc(Arc::new(i))).collect();
+    let counter = boxes[3][0].clone();
+    thread::spawn(move || drop(boxes)).join().unwrap();
+    assert_eq!(Arc::strong_count(&counter), 1);
+  }
+
+  #[test]
+  fn test_box_outlives_allocator() {
+    let allocator = SliceAllocator::new(1, 3);
+    let slice = allocator.alloc(String::from("slab"));
+    drop(allocator);
+    let slice = thread::spawn(move || slice).join().unwrap();
+    assert_eq!(&slice[..], &["slab", "slab", "slab"]);
+  }
+
+  #[test]
+  fn test_panicking_alloc_with() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+      fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+
+    let allocator = SliceAllocator::new(1, 10);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+      let mut n = 0;
+      allocator.alloc_with(|| {
+        n += 1;
+        if n == 5 { panic!("Out of values."); }
+        Counted(drops.clone())
+      })
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(drops.load(Ordering::SeqCst), 4);
+    assert_eq!(allocator.alloc_with(|| Counted(drops.clone())).len(), 10);
+  }
+
+  #[test]
+  fn test_zero_sized() {
+    let allocator = SliceAllocator::new(2, 5);
+    let units: Vec<_> = (0..100).map(|_| allocator.alloc(())).collect();
+    assert!(units.iter().all(|unit| unit.len() == 5));
+    assert_eq!(allocator.stats().bytes, 0);
+  }
+
+  #[test]
+  fn test_slab_threads() {
+    let allocator = SlabAllocator::new(4);
+    let handles: Vec<_> = (0..4usize).map(|t| {
+      let allocator = allocator.clone();
+      thread::spawn(move || {
+        let mut boxes: Vec<_> = (0..(MAGAZINE_SIZE + 5)).map(|i| allocator.alloc(vec![t; i])).collect();
+        for (i, value) in boxes.iter_mut().enumerate() {
+          value.push(t);
+          assert_eq!(value.len(), i + 1);
+          assert!(value.iter().all(|&item| item == t));
+        }
+
+        boxes
+      })
+    }).collect();
+
+    // Boxes can be dropped on a different thread than the one that made them
+    let boxes: Vec<_> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+    assert_eq!(boxes.len(), 4 * (MAGAZINE_SIZE + 5));
+    drop(boxes);
+    assert_eq!(*allocator.alloc(vec![7]), [7]);
+  }
+}
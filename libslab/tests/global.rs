@@ -0,0 +1,64 @@
+// Runs with every allocation in the binary served by a size-class allocator.
+
+extern crate slab;
+
+use std::collections::HashMap;
+use std::thread;
+
+use slab::classes::GlobalSizeClassAllocator;
+
+#[global_allocator]
+static ALLOC: GlobalSizeClassAllocator = GlobalSizeClassAllocator::new();
+
+#[test]
+fn test_collections() {
+  let mut names = HashMap::new();
+  for i in 0..1000 {
+    names.insert(format!("file{}", i), vec![i as u8; i % 100]);
+  }
+
+  for i in (0..1000).step_by(2) {
+    names.remove(&format!("file{}", i));
+  }
+
+  assert_eq!(names.len(), 500);
+  assert_eq!(names["file999"], vec![231; 99]);
+
+  // Small allocations were served by the pools
+  let pooled: usize = ALLOC.stats().iter().map(|&(_, stats)| stats.alloc).sum();
+  assert!(pooled >= 1000);
+}
+
+#[test]
+fn test_large_and_aligned() {
+  #[repr(align(64))]
+  struct Aligned([u8; 64]);
+
+  let big = vec![1u8; 1 << 20];
+  let aligned = Box::new(Aligned([2; 64]));
+  assert_eq!(big.iter().map(|&byte| byte as usize).sum::<usize>(), 1 << 20);
+  assert_eq!(&*aligned as *const Aligned as usize % 64, 0);
+  assert_eq!(aligned.0[63], 2);
+}
+
+#[test]
+fn test_threads() {
+  let handles: Vec<_> = (0..4).map(|t| {
+    thread::spawn(move || {
+      let mut strings = Vec::new();
+      for i in 0..500 {
+        strings.push(format!("{}-{}", t, i));
+        if i % 3 == 0 {
+          strings.swap_remove(0);
+        }
+      }
+
+      strings
+    })
+  }).collect();
+
+  for (t, handle) in handles.into_iter().enumerate() {
+    let strings = handle.join().unwrap();
+    assert!(strings.iter().all(|s| s.starts_with(&format!("{}-", t))));
+  }
+}
@@ -0,0 +1,273 @@
+extern crate slab;
+
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use inode::{Inode, Geometry, Page, EntryList, DoubleEntryList};
+use self::slab::{SliceAllocator, SliceBox, Stats};
+
+// Number of objects each pool starts out with room for. Pools double in size
+// whenever they run out, and give memory back as files are removed, down to
+// this many objects.
+const INITIAL_POOL_SIZE: usize = 16;
+
+/**
+ * Where a file system gets the memory for its inodes, block lists, and pages
+ * from: straight from the heap, or from slab pools owned by the file system.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AllocKind {
+  Boxed,
+  #[default]
+  Slab
+}
+
+// A fixed-length run of `T`s: a page, a block list, or an inode.
+pub enum Block<T> {
+  Boxed(Box<[T]>),
+  Slab(SliceBox<T>)
+}
+
+impl<T> Deref for Block<T> {
+  type Target = [T];
+
+  #[inline(always)]
+  fn deref(&self) -> &[T] {
+    match *self {
+      Block::Boxed(ref boxed) => boxed,
+      Block::Slab(ref slab) => slab
+    }
+  }
+}
+
+impl<T> DerefMut for Block<T> {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut [T] {
+    match *self {
+      Block::Boxed(ref mut boxed) => boxed,
+      Block::Slab(ref mut slab) => slab
+    }
+  }
+}
+
+// An inode allocated by an Allocator. Derefs to the inode itself.
+pub struct InodeBox(Block<Inode>);
+
+impl Deref for InodeBox {
+  type Target = Inode;
+
+  #[inline(always)]
+  fn deref(&self) -> &Inode {
+    &self.0[0]
+  }
+}
+
+impl DerefMut for InodeBox {
+  #[inline(always)]
+  fn deref_mut(&mut self) -> &mut Inode {
+    &mut self.0[0]
+  }
+}
+
+pub struct Pools {
+  inodes: SliceAllocator<Inode>,
+  pages: SliceAllocator<u8>,
+  singles: SliceAllocator<Option<Page>>,
+  doubles: SliceAllocator<Option<EntryList>>,
+  triples: SliceAllocator<Option<DoubleEntryList>>,
+}
+
+impl Pools {
+  // Every pool, as something that can be shrunk and report its stats.
+  fn all(&self) -> [&dyn Pool; 5] {
+    [&self.inodes, &self.pages, &self.singles, &self.doubles, &self.triples]
+  }
+}
+
+// The parts of a SliceAllocator that don't depend on what it holds.
+trait Pool {
+  fn set_low_water_mark(&self, mark: Option<usize>);
+  fn shrink_to_fit(&self);
+  fn stats(&self) -> Stats;
+}
+
+impl<T> Pool for SliceAllocator<T> {
+  fn set_low_water_mark(&self, mark: Option<usize>) { SliceAllocator::set_low_water_mark(self, mark) }
+  fn shrink_to_fit(&self) { SliceAllocator::shrink_to_fit(self) }
+  fn stats(&self) -> Stats { SliceAllocator::stats(self) }
+}
+
+// Picks the pool a block list's entries come from.
+pub trait ListEntry: Sized {
+  fn pool(pools: &Pools) -> &SliceAllocator<Self>;
+}
+
+impl ListEntry for Option<Page> {
+  fn pool(pools: &Pools) -> &SliceAllocator<Self> { &pools.singles }
+}
+
+impl ListEntry for Option<EntryList> {
+  fn pool(pools: &Pools) -> &SliceAllocator<Self> { &pools.doubles }
+}
+
+impl ListEntry for Option<DoubleEntryList> {
+  fn pool(pools: &Pools) -> &SliceAllocator<Self> { &pools.triples }
+}
+
+/**
+ * A handle to a file system's allocator. Every inode holds one so that it can
+ * allocate pages and block lists as it grows. Cloning is cheap.
+ */
+#[derive(Clone)]
+pub struct Allocator {
+  geometry: Geometry,
+  pools: Option<Rc<Pools>>
+}
+
+impl Allocator {
+  pub fn new(geometry: Geometry, kind: AllocKind) -> Allocator {
+    let pools = match kind {
+      AllocKind::Boxed => None,
+      AllocKind::Slab => {
+        let (page_size, list_size) = (geometry.page_size(), geometry.list_size());
+        let pools = Rc::new(Pools {
+          inodes: SliceAllocator::new(INITIAL_POOL_SIZE, 1),
+          pages: SliceAllocator::new(INITIAL_POOL_SIZE, page_size),
+          singles: SliceAllocator::new(INITIAL_POOL_SIZE, list_size),
+          doubles: SliceAllocator::new(INITIAL_POOL_SIZE, list_size),
+          triples: SliceAllocator::new(INITIAL_POOL_SIZE, list_size),
+        });
+
+        for pool in pools.all() {
+          pool.set_low_water_mark(Some(INITIAL_POOL_SIZE));
+        }
+
+        Some(pools)
+      }
+    };
+
+    Allocator { geometry, pools }
+  }
+
+  #[inline(always)]
+  pub fn geometry(&self) -> Geometry {
+    self.geometry
+  }
+
+  pub fn kind(&self) -> AllocKind {
+    match self.pools {
+      None => AllocKind::Boxed,
+      Some(_) => AllocKind::Slab
+    }
+  }
+
+  // Returns every empty chunk in the pools to the system.
+  pub fn shrink_to_fit(&self) {
+    if let Some(ref pools) = self.pools {
+      for pool in pools.all() {
+        pool.shrink_to_fit();
+      }
+    }
+  }
+
+  // Number of bytes the pools hold, in use or not. Always 0 for boxed
+  // allocation.
+  pub fn bytes_held(&self) -> usize {
+    match self.pools {
+      None => 0,
+      Some(ref pools) => pools.all().iter().map(|pool| pool.stats().bytes).sum()
+    }
+  }
+
+  pub fn alloc_page(&self) -> Page {
+    match self.pools {
+      None => Block::Boxed(vec![0u8; self.geometry.page_size()].into_boxed_slice()),
+      Some(ref pools) => Block::Slab(pools.pages.alloc(0))
+    }
+  }
+
+  pub fn alloc_list<T>(&self) -> Block<Option<T>> where Option<T>: ListEntry {
+    match self.pools {
+      None => {
+        let list_size = self.geometry.list_size();
+        Block::Boxed((0..list_size).map(|_| None).collect())
+      }
+      Some(ref pools) => Block::Slab(Option::<T>::pool(pools).alloc_with(|| None))
+    }
+  }
+
+  pub fn alloc_inode(&self) -> InodeBox {
+    let mut inode = Some(Inode::with_allocator(self.clone()));
+    let mut next = || inode.take().expect("inodes are allocated one at a time");
+    match self.pools {
+      None => InodeBox(Block::Boxed(Box::new([next()]))),
+      Some(ref pools) => InodeBox(Block::Slab(pools.inodes.alloc_with(next)))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Allocator, AllocKind};
+  use inode::Geometry;
+
+  #[test]
+  fn test_slab_reuse() {
+    let allocator = Allocator::new(Geometry::new(512), AllocKind::Slab);
+    let pools = allocator.pools.clone().unwrap();
+
+    let page = allocator.alloc_page();
+    let ptr = page.as_ptr();
+    assert_eq!(page.len(), 512);
+    assert_eq!(pools.pages.stats().alloc, 1);
+
+    // A freed page is handed right back out, zeroed
+    drop(page);
+    let mut page = allocator.alloc_page();
+    assert_eq!(page.as_ptr(), ptr);
+    page[0] = 1;
+    drop(page);
+    assert!(allocator.alloc_page().iter().all(|&byte| byte == 0));
+  }
+
+  #[test]
+  fn test_inode_returns_blocks() {
+    let allocator = Allocator::new(Geometry::new(512), AllocKind::Slab);
+    let pools = allocator.pools.clone().unwrap();
+
+    {
+      let mut inode = allocator.alloc_inode();
+      inode.write(0, &[1; 512 * 300]);
+      assert_eq!(pools.inodes.stats().alloc, 1);
+      assert_eq!(pools.pages.stats().alloc, 300);
+      assert_eq!(pools.singles.stats().alloc, 2); // plus the list under `double`
+      assert_eq!(pools.doubles.stats().alloc, 1);
+      assert_eq!(pools.triples.stats().alloc, 0);
+    }
+
+    assert_eq!(pools.inodes.stats().alloc, 0);
+    assert_eq!(pools.pages.stats().alloc, 0);
+    assert_eq!(pools.singles.stats().alloc, 0);
+    assert_eq!(pools.doubles.stats().alloc, 0);
+  }
+
+  #[test]
+  fn test_pools_shrink() {
+    let allocator = Allocator::new(Geometry::new(512), AllocKind::Slab);
+    let pools = allocator.pools.clone().unwrap();
+    let initial = allocator.bytes_held();
+
+    // Pools grow with the file and give the memory back once it's gone,
+    // keeping their initial chunks.
+    let mut inode = allocator.alloc_inode();
+    inode.write(0, &[1; 512 * 200]);
+    assert!(allocator.bytes_held() > initial);
+    assert_eq!(pools.pages.stats().chunks, 5);
+
+    drop(inode);
+    assert_eq!(pools.pages.stats().alloc, 0);
+    assert!(pools.pages.stats().capacity >= 16);
+
+    allocator.shrink_to_fit();
+    assert_eq!(allocator.bytes_held(), 0);
+  }
+}
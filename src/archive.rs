@@ -0,0 +1,81 @@
+/*!
+ * What the archive formats share: mapping names in an archive to names in the
+ * file system and back, and filling files with data while leaving holes.
+ */
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+
+use file::{DirectoryContent, File};
+use inode::Inode;
+
+/**
+ * `name`'s components under `dest`, joined with '/', without any empty or "."
+ * ones. None if `name` would lead outside `dest`.
+ */
+pub fn clean(dest: &str, name: &str) -> Option<String> {
+  let mut parts: Vec<&str> = dest.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+  for part in name.split('/') {
+    match part {
+      "" | "." => {}
+      ".." => return None,
+      _ => parts.push(part)
+    }
+  }
+
+  Some(parts.join("/"))
+}
+
+/**
+ * Every entry under `src`, by its name relative to `src`, in order. Directories
+ * that are only part of other entries' names are there too, with no file.
+ */
+pub fn entries<'a, 'r>(content: &'a DirectoryContent<'r>, src: &str) -> Vec<(String, Option<&'a File<'r>>)> {
+  let mut entries = BTreeMap::new();
+  for (name, file) in content.entries.iter() {
+    let relative = match src {
+      "" => &name[..],
+      _ => match name.strip_prefix(src).and_then(|rest| rest.strip_prefix('/')) {
+        Some(rest) => rest,
+        None => continue
+      }
+    };
+
+    let relative = match clean("", relative) {
+      Some(ref relative) if relative.is_empty() => continue,
+      Some(relative) => relative,
+      None => continue
+    };
+
+    let mut parent = relative.as_str();
+    while let Some((dir, _)) = parent.rsplit_once('/') {
+      entries.entry(dir.to_string()).or_insert(None);
+      parent = dir;
+    }
+
+    entries.insert(relative, Some(file));
+  }
+
+  entries.into_iter().collect()
+}
+
+/**
+ * Reads `size` bytes from `input` into `inode`, a page at a time, leaving out
+ * pages that are all zeroes. The inode's size isn't changed past what's
+ * written.
+ */
+pub fn fill<R: Read>(input: &mut R, inode: &mut Inode, size: u64, page_size: usize) -> io::Result<()> {
+  let mut page = vec![0u8; page_size];
+  let mut offset = 0;
+  while offset < size {
+    let len = (size - offset).min(page_size as u64) as usize;
+    input.read_exact(&mut page[..len])?;
+    if page[..len].iter().any(|&byte| byte != 0) {
+      inode.write(offset as usize, &page[..len]);
+    }
+
+    offset += len as u64;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,349 @@
+/*!
+ * Checking a file system's consistency, and repairing it.
+ *
+ * `Proc::check` walks every directory from the root and reports what's wrong
+ * with the tree and the files in it; `Proc::repair` does the same, fixing
+ * what it can as it goes. `check_image` also checks an image's checksum.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use file::File;
+use image;
+use {AllocKind, Proc};
+
+/**
+ * Something wrong with a file system, and where: `path` is the entry's name,
+ * with the names of the directories it's in before it, separated by '/'.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+  // An image's checksum doesn't match its contents.
+  ChecksumMismatch,
+  // An entry that's neither a file nor a directory.
+  EmptyEntry { path: String },
+  // A directory that's already in the tree somewhere else, possibly above
+  // itself.
+  DirectoryLinkedTwice { path: String },
+  // A file larger than the page size allows.
+  TooLarge { path: String, size: usize, max: usize },
+  // Pages allocated past the end of a file.
+  PagesPastEnd { path: String, pages: usize },
+  // Block lists allocated with nothing in them.
+  EmptyBlockLists { path: String, lists: usize },
+  // An inode with more or fewer references than directory entries and open
+  // file descriptors: something is holding on to it, or let it go twice.
+  LinkCount { path: String, links: usize, references: usize },
+}
+
+impl Problem {
+  // Whether `Proc::repair` fixes this problem. A checksum mismatch is fixed by
+  // saving the image again.
+  pub fn is_repairable(&self) -> bool {
+    !matches!(*self, Problem::ChecksumMismatch | Problem::LinkCount { .. })
+  }
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Problem::ChecksumMismatch => write!(f, "image checksum doesn't match"),
+      Problem::EmptyEntry { ref path } => write!(f, "{}: entry is neither a file nor a directory", path),
+      Problem::DirectoryLinkedTwice { ref path } => write!(f, "{}: directory is already in the tree", path),
+      Problem::TooLarge { ref path, size, max } => {
+        write!(f, "{}: size {} is more than the maximum of {}", path, size, max)
+      }
+      Problem::PagesPastEnd { ref path, pages } => write!(f, "{}: {} page(s) past the end of the file", path, pages),
+      Problem::EmptyBlockLists { ref path, lists } => write!(f, "{}: {} empty block list(s)", path, lists),
+      Problem::LinkCount { ref path, links, references } => {
+        write!(f, "{}: {} link(s) and open descriptor(s) but {} reference(s)", path, links, references)
+      }
+    }
+  }
+}
+
+fn join(directory: &str, name: &str) -> String {
+  match directory {
+    "" => name.to_string(),
+    _ => format!("{}/{}", directory, name)
+  }
+}
+
+// An inode seen in the walk: where first, how many entries name it, and how
+// many references there were to it.
+struct Seen {
+  path: String,
+  links: usize,
+  references: usize,
+}
+
+// Checks `p`, repairing what it can if `repair` is set.
+fn scan(p: &Proc, repair: bool) -> Vec<Problem> {
+  let mut problems = Vec::new();
+  let max = p.geometry().max_file_size();
+
+  // References held by open descriptors, and by the lower files they have
+  // open in a union, one each however many descriptors share it.
+  let mut open: HashMap<*const _, usize> = HashMap::new();
+  let mut lower_files = HashSet::new();
+  for handle in p.fd_table.values() {
+    match *handle.file() {
+      File::DataFile(ref inode) => *open.entry(Rc::as_ptr(inode)).or_insert(0) += 1,
+      File::Lower(ref lower) if lower_files.insert(Rc::as_ptr(lower)) => {
+        if let Some(inode) = lower.tree_inode() {
+          *open.entry(Rc::as_ptr(inode)).or_insert(0) += 1;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  let root = p.cwd.get_dir_rc().clone();
+  let mut directories = HashSet::new();
+  directories.insert(Rc::as_ptr(&root));
+
+  let mut inodes: HashMap<*const _, Seen> = HashMap::new();
+  let mut order = Vec::new();
+  let mut queue = vec![(String::new(), root)];
+  let mut next = 0;
+  while next < queue.len() {
+    let (ref dir_path, ref directory) = queue[next].clone();
+    next += 1;
+
+    let mut remove = Vec::new();
+    let content = directory.borrow();
+    let mut names: Vec<_> = content.entries.keys().collect();
+    names.sort();
+    for name in names {
+      let path = join(dir_path, name);
+      match content.entries[name] {
+        File::EmptyFile | File::Lower(_) => {
+          problems.push(Problem::EmptyEntry { path });
+          remove.push(name.clone());
+        }
+        File::Directory(ref child) => {
+          if directories.insert(Rc::as_ptr(child)) {
+            queue.push((path, child.clone()));
+          } else {
+            problems.push(Problem::DirectoryLinkedTwice { path });
+            remove.push(name.clone());
+          }
+        }
+        File::DataFile(ref inode) => {
+          let ptr = Rc::as_ptr(inode);
+          if let Some(seen) = inodes.get_mut(&ptr) {
+            seen.links += 1;
+            continue;
+          }
+
+          let (size, pages, lists) = {
+            let inode = inode.borrow();
+            (inode.size(), inode.pages_past_end(), inode.empty_lists())
+          };
+
+          if size > max {
+            problems.push(Problem::TooLarge { path: path.clone(), size, max });
+          }
+
+          if pages > 0 {
+            problems.push(Problem::PagesPastEnd { path: path.clone(), pages });
+          }
+
+          if lists > 0 {
+            problems.push(Problem::EmptyBlockLists { path: path.clone(), lists });
+          }
+
+          if repair {
+            let mut inode = inode.borrow_mut();
+            if size > max {
+              let stat = inode.stat();
+              inode.set_stat(max, stat);
+            }
+
+            inode.trim();
+          }
+
+          let references = Rc::strong_count(inode);
+          inodes.insert(ptr, Seen { path, links: 1, references });
+          order.push(ptr);
+        }
+      }
+    }
+
+    drop(content);
+    if repair {
+      let mut content = directory.borrow_mut();
+      for name in remove {
+        content.entries.remove(&name);
+      }
+    }
+  }
+
+  for ptr in order {
+    let seen = &inodes[&ptr];
+    let links = seen.links + open.get(&ptr).cloned().unwrap_or(0);
+    if links != seen.references {
+      problems.push(Problem::LinkCount { path: seen.path.clone(), links, references: seen.references });
+    }
+  }
+
+  problems
+}
+
+impl<'r> Proc<'r> {
+  // Checks the file system's consistency. Returns every problem found, or
+  // nothing if it's consistent.
+  pub fn check(&self) -> Vec<Problem> {
+    scan(self, false)
+  }
+
+  // Checks the file system like `check`, fixing the problems it finds that
+  // are repairable: entries that shouldn't be there are removed, files too
+  // large are cut down to size, and pages past the end of a file and empty
+  // block lists are freed. Returns every problem found, repaired or not.
+  pub fn repair(&mut self) -> Vec<Problem> {
+    scan(self, true)
+  }
+}
+
+/**
+ * Loads the image from `input` and checks it, including its checksum.
+ * Returns the file system, to repair or look at further, and the problems
+ * found. Fails if the image is too damaged to load at all.
+ */
+pub fn check_image<'r, R: Read>(input: R, kind: AllocKind) -> io::Result<(Proc<'r>, Vec<Problem>)> {
+  let (p, checksum_matched) = image::read_image_unverified(input, kind)?;
+  let mut problems = Vec::new();
+  if !checksum_matched {
+    problems.push(Problem::ChecksumMismatch);
+  }
+
+  problems.extend(p.check());
+  Ok((p, problems))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{check_image, Problem};
+  use directory::DirectoryHandle;
+  use file::File;
+  use image::write_image;
+  use {AllocKind, Filesystem, Proc, Whence, O_CREAT, O_RDWR};
+
+  fn write_file(p: &mut Proc<'static>, name: &'static str, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    p.write(fd, data);
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_consistent() {
+    let mut p = Proc::with_page_size(512);
+    write_file(&mut p, "small", b"tiny");
+    write_file(&mut p, "large", &[7; 5000]);
+    let fd = p.open("sparse", O_CREAT | O_RDWR);
+    p.seek(fd, 1 << 20, Whence::SeekSet);
+    p.write(fd, b"end");
+
+    // Open descriptors count as references, even to unlinked files.
+    let large = p.open("large", O_RDWR);
+    p.unlink("large");
+    assert_eq!(p.check(), vec![]);
+    p.close(fd);
+    p.close(large);
+    assert_eq!(p.check(), vec![]);
+  }
+
+  #[test]
+  fn test_union_lower_files() {
+    // Files open in a union's lower layer hold their inodes, once for each
+    // file however many descriptors it has, before and after copying up.
+    let mut p = Proc::new();
+    assert!(p.mkdir("lower"));
+    assert!(p.mkdir("merged"));
+    write_file(&mut p, "lower/file", b"lower");
+    p.mount("merged", Filesystem::Union { upper: "upper".to_string(), lowers: vec!["lower".to_string()] }).unwrap();
+
+    let first = p.open("merged/file", O_RDWR);
+    let second = p.open("merged/file", O_RDWR);
+    assert_eq!(p.check(), vec![]);
+    p.seek(second, 0, Whence::SeekEnd);
+    assert_eq!(p.write(second, b"!"), 1);
+    assert_eq!(p.check(), vec![]);
+
+    // Even with the lower file unlinked from under the union.
+    p.unlink("lower/file");
+    let mut buf = [0u8; 6];
+    assert_eq!(p.read(first, &mut buf), 6);
+    assert_eq!(&buf, b"lower!");
+    assert_eq!(p.check(), vec![]);
+    p.close(first);
+    p.close(second);
+    assert_eq!(p.check(), vec![]);
+  }
+
+  #[test]
+  fn test_repair() {
+    let mut p = Proc::with_page_size(512);
+    write_file(&mut p, "a", &[1; 2000]);
+    write_file(&mut p, "b", b"fine");
+
+    let mut root = p.cwd.clone();
+    root.insert("ghost", File::EmptyFile);
+    root.insert("loop", root.clone());
+    let held = match root.get("b") {
+      Some(File::DataFile(inode)) => inode,
+      _ => panic!("b is missing")
+    };
+
+    match root.get("a") {
+      Some(File::DataFile(ref inode)) => {
+        let stat = inode.borrow().stat();
+        inode.borrow_mut().set_stat(100, stat);
+      }
+      _ => panic!("a is missing")
+    }
+
+    let problems = vec![
+      Problem::PagesPastEnd { path: "a".to_string(), pages: 3 },
+      Problem::EmptyEntry { path: "ghost".to_string() },
+      Problem::DirectoryLinkedTwice { path: "loop".to_string() },
+      Problem::LinkCount { path: "b".to_string(), links: 1, references: 2 },
+    ];
+
+    assert_eq!(p.check(), problems);
+    assert_eq!(p.repair(), problems);
+    assert_eq!(p.check(), vec![problems[3].clone()]);
+    assert!(!problems[3].is_repairable());
+
+    drop(held);
+    assert_eq!(p.check(), vec![]);
+    assert_eq!(problems[0].to_string(), "a: 3 page(s) past the end of the file");
+  }
+
+  #[test]
+  fn test_check_image() {
+    let mut p = Proc::new();
+    write_file(&mut p, "file", &[3; 10000]);
+    let mut bytes = Vec::new();
+    write_image(&p, &mut bytes).unwrap();
+
+    let (_, problems) = check_image(&bytes[..], AllocKind::default()).unwrap();
+    assert_eq!(problems, vec![]);
+
+    // The last 4 bytes are the checksum; the ones before, the file's last.
+    let last = bytes.len() - 100;
+    bytes[last] ^= 0xff;
+    let (mut p, problems) = check_image(&bytes[..], AllocKind::default()).unwrap();
+    assert_eq!(problems, vec![Problem::ChecksumMismatch]);
+
+    let mut buf = [0u8; 1];
+    let fd = p.open("file", O_RDWR);
+    p.seek(fd, 10000 - 96, Whence::SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq!(buf[0], 3 ^ 0xff);
+  }
+}
@@ -0,0 +1,590 @@
+/*!
+ * Raw DEFLATE (RFC 1951) compression and decompression, for zip archives.
+ *
+ * Inflating handles all three kinds of block. Deflating finds matches with
+ * hash chains and writes a block with the fixed Huffman codes for every
+ * BLOCK bytes of input, which does well enough on the text and code zips
+ * mostly hold without the work of building codes per block. Blocks that
+ * wouldn't get any smaller are stored instead.
+ */
+
+use std::io;
+use std::mem;
+
+// The window matches can reach back through, and the longest match.
+const WINDOW: usize = 32768;
+const MAX_MATCH: usize = 258;
+const MIN_MATCH: usize = 3;
+
+// How many earlier positions with the same hash to try for each match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+// How much input goes in each block.
+const BLOCK: usize = 32768;
+
+// The base value and number of extra bits of each length and distance code.
+static LENGTH_BASE: [u16; 29] = [
+  3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+static LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+static DISTANCE_BASE: [u16; 30] = [
+  1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+  6145, 8193, 12289, 16385, 24577
+];
+static DISTANCE_EXTRA: [u8; 30] = [
+  0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+// The order code length code lengths come in, in a dynamic block's header.
+static CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad deflate data: {}", what))
+}
+
+struct BitReader<'a> {
+  input: &'a [u8],
+  pos: usize,
+  bits: u32,
+  count: u32,
+}
+
+impl<'a> BitReader<'a> {
+  fn bits(&mut self, n: u32) -> io::Result<u32> {
+    while self.count < n {
+      let byte = *self.input.get(self.pos).ok_or_else(|| invalid("unexpected end"))?;
+      self.bits |= (byte as u32) << self.count;
+      self.pos += 1;
+      self.count += 8;
+    }
+
+    let value = self.bits & ((1u64 << n) - 1) as u32;
+    self.bits >>= n;
+    self.count -= n;
+    Ok(value)
+  }
+
+  // Drops what's left of the current byte.
+  fn align(&mut self) {
+    self.bits = 0;
+    self.count = 0;
+  }
+}
+
+// A canonical Huffman code, decoded a bit at a time: how many codes there are
+// of each length, and the symbols in code order.
+struct Huffman {
+  counts: [u16; 16],
+  symbols: Vec<u16>,
+}
+
+impl Huffman {
+  fn new(lengths: &[u8]) -> io::Result<Huffman> {
+    let mut counts = [0u16; 16];
+    for &len in lengths {
+      counts[len as usize] += 1;
+    }
+
+    // Codes can be left unused, but not promised twice over.
+    let mut left: i32 = 1;
+    for &count in counts[1..].iter() {
+      left = (left << 1) - count as i32;
+      if left < 0 {
+        return Err(invalid("over-subscribed code"));
+      }
+    }
+
+    let mut offsets = [0u16; 16];
+    for len in 1..15 {
+      offsets[len + 1] = offsets[len] + counts[len];
+    }
+
+    let mut symbols = vec![0; lengths.len()];
+    for (symbol, &len) in lengths.iter().enumerate() {
+      if len != 0 {
+        symbols[offsets[len as usize] as usize] = symbol as u16;
+        offsets[len as usize] += 1;
+      }
+    }
+
+    counts[0] = 0;
+    Ok(Huffman { counts, symbols })
+  }
+
+  fn decode(&self, input: &mut BitReader) -> io::Result<u16> {
+    let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+    for len in 1..16 {
+      code |= input.bits(1)? as i32;
+      let count = self.counts[len] as i32;
+      if code - first < count {
+        return Ok(self.symbols[(index + code - first) as usize]);
+      }
+
+      index += count;
+      first = (first + count) << 1;
+      code <<= 1;
+    }
+
+    Err(invalid("no such code"))
+  }
+}
+
+fn fixed_lengths() -> ([u8; 288], [u8; 30]) {
+  let mut literals = [8u8; 288];
+  for (symbol, len) in literals.iter_mut().enumerate() {
+    *len = match symbol {
+      0..=143 => 8,
+      144..=255 => 9,
+      256..=279 => 7,
+      _ => 8
+    };
+  }
+
+  (literals, [5; 30])
+}
+
+/**
+ * Decompresses `input`, appending what it holds to `out`. Fails if it isn't
+ * valid DEFLATE data, ends before its last block does, or holds more than
+ * `limit` bytes.
+ */
+pub fn inflate(input: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+  let mut input = BitReader { input, pos: 0, bits: 0, count: 0 };
+  let start = out.len();
+  let end = start.saturating_add(limit);
+  loop {
+    let last = input.bits(1)? == 1;
+    match input.bits(2)? {
+      0 => {
+        input.align();
+        let header = input.input.get(input.pos..input.pos + 4).ok_or_else(|| invalid("unexpected end"))?;
+        let len = header[0] as usize | (header[1] as usize) << 8;
+        if len != !(header[2] as usize | (header[3] as usize) << 8) & 0xffff {
+          return Err(invalid("stored block length doesn't match its complement"));
+        }
+
+        input.pos += 4;
+        let data = input.input.get(input.pos..input.pos + len).ok_or_else(|| invalid("unexpected end"))?;
+        if out.len() + len > end {
+          return Err(invalid("longer than expected"));
+        }
+
+        out.extend_from_slice(data);
+        input.pos += len;
+      }
+      1 => {
+        let (literals, distances) = fixed_lengths();
+        inflate_block(&mut input, out, (start, end), &Huffman::new(&literals)?, &Huffman::new(&distances)?)?;
+      }
+      2 => {
+        let (literals, distances) = read_dynamic_codes(&mut input)?;
+        inflate_block(&mut input, out, (start, end), &literals, &distances)?;
+      }
+      _ => return Err(invalid("reserved block type"))
+    }
+
+    if last {
+      return Ok(());
+    }
+  }
+}
+
+// Reads the codes a dynamic block's header describes.
+fn read_dynamic_codes(input: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+  let literal_count = input.bits(5)? as usize + 257;
+  let distance_count = input.bits(5)? as usize + 1;
+  let length_count = input.bits(4)? as usize + 4;
+  if literal_count > 286 || distance_count > 30 {
+    return Err(invalid("too many codes"));
+  }
+
+  let mut length_lengths = [0u8; 19];
+  for &i in CODE_LENGTH_ORDER[..length_count].iter() {
+    length_lengths[i] = input.bits(3)? as u8;
+  }
+
+  let length_code = Huffman::new(&length_lengths)?;
+  let mut lengths = Vec::with_capacity(literal_count + distance_count);
+  while lengths.len() < literal_count + distance_count {
+    let (len, repeat) = match length_code.decode(input)? {
+      symbol @ 0..=15 => (symbol as u8, 1),
+      16 => (*lengths.last().ok_or_else(|| invalid("repeat with nothing before"))?, 3 + input.bits(2)?),
+      17 => (0, 3 + input.bits(3)?),
+      _ => (0, 11 + input.bits(7)?)
+    };
+
+    if lengths.len() + repeat as usize > literal_count + distance_count {
+      return Err(invalid("too many code lengths"));
+    }
+
+    lengths.extend((0..repeat).map(|_| len));
+  }
+
+  if lengths[256] == 0 {
+    return Err(invalid("no end of block code"));
+  }
+
+  Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+// Inflates a block's symbols up to its end, with matches reaching no further
+// back than `start` in `out`, and `out` growing no longer than `end`.
+fn inflate_block(input: &mut BitReader, out: &mut Vec<u8>, (start, end): (usize, usize), literals: &Huffman,
+                 distances: &Huffman) -> io::Result<()> {
+  loop {
+    let symbol = literals.decode(input)? as usize;
+    if symbol != 256 && out.len() >= end {
+      return Err(invalid("longer than expected"));
+    }
+
+    match symbol {
+      0..=255 => out.push(symbol as u8),
+      256 => return Ok(()),
+      257..=285 => {
+        let code = symbol - 257;
+        let len = LENGTH_BASE[code] as usize + input.bits(LENGTH_EXTRA[code] as u32)? as usize;
+        let code = distances.decode(input)? as usize;
+        if code >= 30 {
+          return Err(invalid("bad distance code"));
+        }
+
+        let distance = DISTANCE_BASE[code] as usize + input.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+        if distance > out.len() - start {
+          return Err(invalid("distance too far back"));
+        } else if out.len() + len > end {
+          return Err(invalid("longer than expected"));
+        }
+
+        // Matches can overlap what they copy, so a byte at a time.
+        let from = out.len() - distance;
+        for i in 0..len {
+          let byte = out[from + i];
+          out.push(byte);
+        }
+      }
+      _ => return Err(invalid("bad length code"))
+    }
+  }
+}
+
+struct BitWriter {
+  out: Vec<u8>,
+  bits: u64,
+  count: u32,
+}
+
+impl BitWriter {
+  fn put(&mut self, value: u32, n: u32) {
+    self.bits |= (value as u64) << self.count;
+    self.count += n;
+    while self.count >= 8 {
+      self.out.push(self.bits as u8);
+      self.bits >>= 8;
+      self.count -= 8;
+    }
+  }
+
+  // Pads the last byte out with zeroes.
+  fn align(&mut self) {
+    if self.count > 0 {
+      self.out.push(self.bits as u8);
+      self.bits = 0;
+      self.count = 0;
+    }
+  }
+
+  // Huffman codes go most significant bit first.
+  fn put_code(&mut self, code: u32, n: u32) {
+    self.put(code.reverse_bits() >> (32 - n), n);
+  }
+
+  fn literal(&mut self, symbol: usize) {
+    let symbol = symbol as u32;
+    match symbol {
+      0..=143 => self.put_code(0x30 + symbol, 8),
+      144..=255 => self.put_code(0x190 + symbol - 144, 9),
+      256..=279 => self.put_code(symbol - 256, 7),
+      _ => self.put_code(0xc0 + symbol - 280, 8)
+    }
+  }
+
+  fn copy(&mut self, len: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= len).unwrap_or(0);
+    self.literal(257 + code);
+    self.put((len - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    self.put_code(code as u32, 5);
+    self.put((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+  }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+  let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+  (value.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+}
+
+/**
+ * Compresses data handed to it a piece at a time. What's been compressed so
+ * far can be taken out between pieces, so neither the input nor the output
+ * has to be held whole.
+ */
+pub struct Deflater {
+  out: BitWriter,
+  // The input from `base` on: the window matches can reach back through,
+  // then from `pending` what hasn't been compressed yet. Positions are from
+  // the start of the input.
+  data: Vec<u8>,
+  base: usize,
+  pending: usize,
+  // The last position each hash was seen at, plus one, and for each position
+  // in the window the one before it with the same hash.
+  head: Vec<usize>,
+  prev: Vec<usize>,
+}
+
+impl Deflater {
+  pub fn new() -> Deflater {
+    Deflater {
+      out: BitWriter { out: Vec::new(), bits: 0, count: 0 },
+      data: Vec::with_capacity(WINDOW + 2 * BLOCK),
+      base: 0,
+      pending: 0,
+      head: vec![0; 1 << HASH_BITS],
+      prev: vec![0; WINDOW]
+    }
+  }
+
+  // Compresses whatever whole blocks of input there are.
+  pub fn write(&mut self, input: &[u8]) {
+    self.data.extend_from_slice(input);
+    while self.end() - self.pending >= BLOCK {
+      self.block(BLOCK, false);
+    }
+  }
+
+  // Takes what's been compressed so far.
+  pub fn take(&mut self) -> Vec<u8> {
+    mem::take(&mut self.out.out)
+  }
+
+  // Compresses the rest of the input. Returns the output that hasn't been
+  // taken yet.
+  pub fn finish(mut self) -> Vec<u8> {
+    let len = self.end() - self.pending;
+    self.block(len, true);
+    self.out.align();
+    self.out.out
+  }
+
+  fn end(&self) -> usize {
+    self.base + self.data.len()
+  }
+
+  fn insert(&mut self, pos: usize) {
+    if pos + MIN_MATCH <= self.end() {
+      let h = hash(&self.data[pos - self.base..]);
+      self.prev[pos % WINDOW] = self.head[h];
+      self.head[h] = pos + 1;
+    }
+  }
+
+  // The longest match for `pos` that doesn't go past `end`, as its length
+  // and distance.
+  fn find_match(&self, pos: usize, end: usize) -> (usize, usize) {
+    let (mut best_len, mut best_distance) = (0, 0);
+    if pos + MIN_MATCH > end {
+      return (best_len, best_distance);
+    }
+
+    let data = &self.data[..end - self.base];
+    let at = pos - self.base;
+    let max = (end - pos).min(MAX_MATCH);
+    let mut candidate = self.head[hash(&data[at..])];
+    for _ in 0..MAX_CHAIN {
+      if candidate == 0 || pos - (candidate - 1) > WINDOW {
+        break;
+      }
+
+      let from = candidate - 1;
+      let len = data[from - self.base..].iter().zip(data[at..at + max].iter()).take_while(|&(a, b)| a == b).count();
+      if len > best_len {
+        best_len = len;
+        best_distance = pos - from;
+        if len == max {
+          break;
+        }
+      }
+
+      let next = self.prev[from % WINDOW];
+      if next >= candidate {
+        break;
+      }
+
+      candidate = next;
+    }
+
+    (best_len, best_distance)
+  }
+
+  // Compresses the next `len` bytes of input as a block, with the fixed
+  // codes or stored, whichever is shorter.
+  fn block(&mut self, len: usize, last: bool) {
+    let end = self.pending + len;
+    let saved = (self.out.out.len(), self.out.bits, self.out.count);
+    self.out.put(last as u32, 1);
+    self.out.put(1, 2);
+
+    let mut pos = self.pending;
+    while pos < end {
+      let (len, distance) = self.find_match(pos, end);
+      if len >= MIN_MATCH {
+        self.out.copy(len, distance);
+        for i in pos..pos + len {
+          self.insert(i);
+        }
+
+        pos += len;
+      } else {
+        self.out.literal(self.data[pos - self.base] as usize);
+        self.insert(pos);
+        pos += 1;
+      }
+    }
+
+    self.out.literal(256);
+
+    let fixed = (self.out.out.len() - saved.0) * 8 + self.out.count as usize - saved.2 as usize;
+    let stored = 3 + (8 - (saved.2 as usize + 3) % 8) % 8 + 32 + 8 * len;
+    if stored < fixed {
+      self.out.out.truncate(saved.0);
+      self.out.bits = saved.1;
+      self.out.count = saved.2;
+      self.out.put(last as u32, 1);
+      self.out.put(0, 2);
+      self.out.align();
+      self.out.put(len as u32, 16);
+      self.out.put(!len as u32 & 0xffff, 16);
+      let at = self.pending - self.base;
+      self.out.out.extend_from_slice(&self.data[at..at + len]);
+    }
+
+    // Keep only the window before what's left.
+    self.pending = end;
+    if self.pending - self.base > WINDOW + BLOCK {
+      let drop = self.pending - WINDOW - self.base;
+      self.data.drain(..drop);
+      self.base += drop;
+    }
+  }
+}
+
+/**
+ * The most a Deflater can turn `len` bytes into: each block can be stored,
+ * at five bytes more than its input, and the last one can be empty.
+ */
+pub fn max_len(len: u64) -> u64 {
+  len + 5 * (len / BLOCK as u64 + 1) + 2
+}
+
+/**
+ * Compresses `input` all at once.
+ */
+#[cfg(test)]
+pub fn deflate(input: &[u8]) -> Vec<u8> {
+  let mut deflater = Deflater::new();
+  deflater.write(input);
+  let mut out = deflater.take();
+  out.extend(deflater.finish());
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{deflate, inflate, max_len, Deflater, BLOCK};
+
+  fn round_trip(data: &[u8]) {
+    let compressed = deflate(data);
+    assert!(compressed.len() as u64 <= max_len(data.len() as u64));
+    let mut out = Vec::new();
+    inflate(&compressed, &mut out, data.len()).unwrap();
+    assert_eq!(out, data);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    round_trip(b"");
+    round_trip(b"a");
+    round_trip(&[0; 100000]);
+    round_trip("the quick brown fox jumps over the lazy dog. ".repeat(1000).as_bytes());
+
+    let random: Vec<u8> = (0..70000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    round_trip(&random);
+
+    // Text compresses, and what doesn't compress is stored.
+    let text = "fn main() { println!(\"hello, world\"); }\n".repeat(100);
+    assert!(deflate(text.as_bytes()).len() < text.len() / 10);
+    assert!(deflate(&random).len() as u64 <= max_len(random.len() as u64));
+    assert!(deflate(&random).len() < random.len() + 20);
+  }
+
+  #[test]
+  fn test_pieces() {
+    // Input handed over in pieces of any size comes out the same, and matches
+    // reach back into earlier pieces.
+    let text = "the quick brown fox jumps over the lazy dog. ".repeat(5000);
+    for &piece in [1, 100, 4096, BLOCK + 1].iter() {
+      let mut deflater = Deflater::new();
+      let mut compressed = Vec::new();
+      for chunk in text.as_bytes().chunks(piece) {
+        deflater.write(chunk);
+        compressed.extend(deflater.take());
+      }
+
+      compressed.extend(deflater.finish());
+      assert!(compressed.len() < text.len() / 20, "{}", piece);
+      let mut out = Vec::new();
+      inflate(&compressed, &mut out, text.len()).unwrap();
+      assert_eq!(out, text.as_bytes());
+    }
+  }
+
+  #[test]
+  fn test_inflate_limit() {
+    let data = [7u8; 100000];
+    let compressed = deflate(&data);
+    let error = |limit| inflate(&compressed, &mut Vec::new(), limit).unwrap_err().to_string();
+    assert_eq!(error(99999), "bad deflate data: longer than expected");
+    assert_eq!(error(0), "bad deflate data: longer than expected");
+
+    // Stored blocks too.
+    let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'e', b'n', b'd'];
+    assert!(inflate(&stored, &mut Vec::new(), 2).is_err());
+    assert!(inflate(&stored, &mut Vec::new(), 3).is_ok());
+  }
+
+  #[test]
+  fn test_inflate() {
+    // From zlib: a dynamic block, then (by hand) a stored one.
+    let dynamic = [
+      0xd5, 0x8c, 0x57, 0x01, 0x80, 0x20, 0x14, 0x45, 0xab, 0xdc, 0x04, 0x16, 0xb0, 0x86, 0x05, 0x40, 0x59, 0x0e,
+      0x1e, 0x1b, 0x21, 0xbd, 0xaf, 0x86, 0xdf, 0x67, 0x6c, 0x56, 0x21, 0x56, 0xb7, 0x5f, 0x90, 0x89, 0xba, 0x87,
+      0xa6, 0x17, 0x67, 0x7d, 0x42, 0x06, 0x35, 0x95, 0x50, 0x18, 0xdf, 0x62, 0x0e, 0x1c, 0x64, 0x56, 0x04, 0xc1,
+      0xde, 0x33, 0x20, 0x59, 0xea, 0xae, 0x58, 0x68, 0xd7, 0x14, 0xa3, 0xa9, 0x3c, 0x6e, 0x17, 0x2b, 0x25, 0x6e,
+      0x4d, 0x5e, 0xb0, 0xfd, 0xe8, 0xfa, 0x01
+    ];
+    let mut out = Vec::new();
+    inflate(&dynamic, &mut out, 1000).unwrap();
+    let mut stored = vec![0x01, 0x03, 0x00, 0xfc, 0xff];
+    stored.extend_from_slice(b"end");
+    inflate(&stored, &mut out, 3).unwrap();
+    let text = "The quick brown fox jumps over the lazy dog; pack my box with five dozen liquor jugs. ";
+    assert_eq!(out, [text.repeat(3).as_bytes(), b"end"].concat());
+
+    let error = |data: &[u8]| inflate(data, &mut Vec::new(), 1000).unwrap_err().to_string();
+    assert_eq!(error(&dynamic[..5]), "bad deflate data: unexpected end");
+    assert_eq!(error(&[0x07]), "bad deflate data: reserved block type");
+    assert_eq!(error(&[0x01, 0x03, 0x00, 0xfc, 0xfe]), "bad deflate data: stored block length doesn't match its complement");
+  }
+}
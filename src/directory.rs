@@ -1,39 +1,35 @@
+use std::borrow::Cow;
 use file::File;
 use file::File::Directory;
 
 pub trait DirectoryHandle<'r>: Sized {
+  #[allow(dead_code)]
   fn is_dir(&self) -> bool;
-  fn insert(&mut self, name: &'r str, file: Self);
-  fn remove(&mut self, name: &'r str);
-  fn get(&self, name: &'r str) -> Option<Self>;
+  fn insert<N: Into<Cow<'r, str>>>(&mut self, name: N, file: Self);
+  fn remove(&mut self, name: &str);
+  fn get(&self, name: &str) -> Option<Self>;
 }
 
 impl<'r> DirectoryHandle<'r> for File<'r> {
   fn is_dir(&self) -> bool {
-    match self {
-      &Directory(_) => true,
-      _ => false
-    }
+    matches!(*self, Directory(_))
   }
 
-  fn insert(&mut self, name: &'r str, file: File<'r>) {
+  fn insert<N: Into<Cow<'r, str>>>(&mut self, name: N, file: File<'r>) {
     let rc = self.get_dir_rc();
     let mut content = rc.borrow_mut();
-    content.entries.insert(name, file);
+    content.entries.insert(name.into(), file);
   }
 
-  fn remove(&mut self, name: &'r str) {
+  fn remove(&mut self, name: &str) {
     let rc = self.get_dir_rc();
     let mut content = rc.borrow_mut();
-    content.entries.remove(&name);
+    content.entries.remove(name);
   }
 
-  fn get(&self, name: &'r str) -> Option<File<'r>> {
+  fn get(&self, name: &str) -> Option<File<'r>> {
     let rc = self.get_dir_rc();
     let content = rc.borrow();
-    match content.entries.get(&name) {
-      None => None,
-      Some(ref file) => Some((*file).clone()) // It's RC
-    }
+    content.entries.get(name).cloned() // It's RC
   }
 }
@@ -1,22 +1,28 @@
 extern crate time;
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::{Cell, RefCell};
-use inode::{Inode};
-use self::File::{DataFile, Directory};
+use alloc::InodeBox;
+use overlay::LowerFile;
+use self::File::{DataFile, Directory, Lower};
 
 pub type RcDirContent<'r> = Rc<RefCell<Box<DirectoryContent<'r>>>>;
-pub type RcInode = Rc<RefCell<Box<Inode>>>;
+pub type RcInode = Rc<RefCell<InodeBox>>;
 
 // File is a thing wrapper around Inodes and Directories. The whole point is to
 // provide a layer of indirection. FileHandle's and Directory entries, then,
 // point to these guys instead of directly to Inodes/Directories
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum File<'r> {
   DataFile(RcInode),
   Directory(RcDirContent<'r>),
-  EmptyFile
+  EmptyFile,
+  // A file in a host directory mounted underneath, not yet copied up. Only
+  // ever looked up or in a FileHandle, never in a directory.
+  Lower(Rc<LowerFile>)
 }
 
 #[derive(Clone)]
@@ -25,11 +31,14 @@ pub struct FileHandle<'r> {
   seek: Cell<usize>
 }
 
+// Names are usually borrowed from the caller; files loaded from an image own
+// theirs.
 #[derive(Clone)]
 pub struct DirectoryContent<'r> {
-  pub entries: HashMap<&'r str, File<'r>>
+  pub entries: HashMap<Cow<'r, str>, File<'r>>
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Whence {
   SeekSet,
   SeekCur,
@@ -61,16 +70,16 @@ impl<'r> File<'r> {
     DataFile(inode)
   }
 
-  pub fn get_dir_rc<'a>(&'a self) -> &'a RcDirContent<'r> {
-    match self {
-      &Directory(ref rc) => rc,
+  pub fn get_dir_rc(&self) -> &RcDirContent<'r> {
+    match *self {
+      Directory(ref rc) => rc,
       _ => panic!("not a directory")
     }
   }
 
-  pub fn get_inode_rc<'a>(&'a self) -> &'a RcInode {
-    match self {
-      &DataFile(ref rc) => rc,
+  pub fn get_inode_rc(&self) -> &RcInode {
+    match *self {
+      DataFile(ref rc) => rc,
       _ => panic!("not a directory")
     }
   }
@@ -80,35 +89,59 @@ impl<'r> FileHandle<'r> {
   // Probably not the right type.
   pub fn new(file: File<'r>) -> FileHandle<'r> {
     FileHandle {
-      file: file,
+      file,
       seek: Cell::new(0)
     }
   }
 
+  // The file this is a handle to.
+  pub fn file(&self) -> &File<'r> {
+    &self.file
+  }
+
+  // Where the next read or write starts.
+  pub fn position(&self) -> usize {
+    self.seek.get()
+  }
+
+  // Points the handle at another file, keeping its position: the copy of a
+  // lower file made when it's first written to.
+  pub fn set_file(&mut self, file: File<'r>) {
+    self.file = file;
+  }
+
   pub fn read(&self, dst: &mut [u8]) -> usize {
     let offset = self.seek.get();
-    let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow().read(offset, dst);
+    let changed = match self.file {
+      Lower(ref lower) => lower.read(offset, dst),
+      _ => self.file.get_inode_rc().borrow().read(offset, dst)
+    };
+
     self.seek.set(offset + changed);
     changed
   }
 
   pub fn write(&mut self, src: &[u8]) -> usize {
     let offset = self.seek.get();
-    let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow_mut().write(offset, src);
+    let changed = match self.file {
+      Lower(ref lower) => lower.write(offset, src),
+      _ => self.file.get_inode_rc().borrow_mut().write(offset, src)
+    };
     self.seek.set(offset + changed);
     changed
   }
 
   pub fn seek(&mut self, offset: isize, whence: Whence) -> usize {
-    let inode_rc = self.file.get_inode_rc();
+    let size = match self.file {
+      Lower(ref lower) => lower.size(),
+      _ => self.file.get_inode_rc().borrow().size()
+    };
 
     let seek = self.seek.get();
     let new_seek = match whence {
       Whence::SeekSet => offset as usize,
       Whence::SeekCur => (seek as isize + offset) as usize,
-      Whence::SeekEnd => (inode_rc.borrow().size() as isize + offset) as usize
+      Whence::SeekEnd => (size as isize + offset) as usize
     };
 
     self.seek.set(new_seek);
@@ -0,0 +1,633 @@
+/*!
+ * Copying trees between the host's file system and RustFS.
+ *
+ * `Proc::copy_in` mirrors a host file or directory into the file system, and
+ * `Proc::copy_out` mirrors files back out to the host. Modes, access and
+ * modification times, symbolic links (which are copied, never followed), and
+ * hard links between copied files are kept, and so are holes: pages of
+ * zeroes aren't allocated going in, and only allocated pages are written going
+ * out. Owners and extended attributes aren't copied.
+ *
+ * `Glob`s in `CopyOptions` pick what's copied, by names relative to the top of
+ * the copy.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use archive;
+use directory::DirectoryHandle;
+use file::File;
+use time::Timespec;
+use {Proc, S_IFLNK, S_IFREG};
+
+/**
+ * A shell-style pattern for names relative to the top of a copy. `*` matches
+ * anything but '/', and `**` anything at all; a `**` between two '/' matches
+ * any number of directories, even none. `?` matches any one character but
+ * '/', and `[...]` any one character in it (`[!...]` any not in it); `\`
+ * makes the next character match itself. A pattern without a '/' in it matches the last
+ * component of a name, at any depth, like in a .gitignore.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+  pattern: Vec<char>,
+  anywhere: bool,
+}
+
+impl Glob {
+  // Fails if a '[' isn't closed, or the pattern ends with a '\'.
+  pub fn new(pattern: &str) -> io::Result<Glob> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+      match chars[i] {
+        '\\' if i + 1 == chars.len() => return Err(bad_glob(pattern, "ends with '\\'")),
+        '\\' => i += 1,
+        '[' => i = class_end(&chars, i).ok_or_else(|| bad_glob(pattern, "'[' isn't closed"))?,
+        _ => {}
+      }
+
+      i += 1;
+    }
+
+    Ok(Glob { anywhere: !chars.contains(&'/'), pattern: chars })
+  }
+
+  pub fn matches(&self, name: &str) -> bool {
+    let name = match self.anywhere {
+      true => name.rsplit('/').next().unwrap_or(name),
+      false => name
+    };
+
+    let name: Vec<char> = name.chars().collect();
+    glob_match(&self.pattern, &name)
+  }
+}
+
+fn bad_glob(pattern: &str, what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, format!("bad pattern '{}': {}", pattern, what))
+}
+
+// Where the class starting at `pattern[start]` ends. A ']' first in the class
+// (after any '!') is one of its characters.
+fn class_end(pattern: &[char], start: usize) -> Option<usize> {
+  let mut i = start + 1;
+  if pattern.get(i) == Some(&'!') {
+    i += 1;
+  }
+
+  if pattern.get(i) == Some(&']') {
+    i += 1;
+  }
+
+  pattern[i.min(pattern.len())..].iter().position(|&c| c == ']').map(|end| i + end)
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+  match pattern.first() {
+    None => name.is_empty(),
+    Some('*') if pattern.get(1) == Some(&'*') => {
+      if pattern.get(2) == Some(&'/') {
+        (0..=name.len()).filter(|&i| i == 0 || name[i - 1] == '/').any(|i| glob_match(&pattern[3..], &name[i..]))
+      } else {
+        (0..=name.len()).any(|i| glob_match(&pattern[2..], &name[i..]))
+      }
+    }
+    Some('*') => {
+      let component = name.iter().position(|&c| c == '/').unwrap_or(name.len());
+      (0..=component).any(|i| glob_match(&pattern[1..], &name[i..]))
+    }
+    Some('?') => !name.is_empty() && name[0] != '/' && glob_match(&pattern[1..], &name[1..]),
+    Some('[') => {
+      let end = class_end(pattern, 0).unwrap_or(pattern.len() - 1);
+      let (negated, class) = match pattern[1] {
+        '!' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end])
+      };
+
+      match name.first() {
+        Some(&c) if c != '/' && in_class(class, c) != negated => glob_match(&pattern[end + 1..], &name[1..]),
+        _ => false
+      }
+    }
+    Some('\\') => name.first() == pattern.get(1) && glob_match(&pattern[2..], &name[1..]),
+    Some(c) => name.first() == Some(c) && glob_match(&pattern[1..], &name[1..])
+  }
+}
+
+fn in_class(class: &[char], c: char) -> bool {
+  let mut i = 0;
+  while i < class.len() {
+    if i + 2 < class.len() && class[i + 1] == '-' {
+      if class[i] <= c && c <= class[i + 2] {
+        return true;
+      }
+
+      i += 3;
+    } else {
+      if class[i] == c {
+        return true;
+      }
+
+      i += 1;
+    }
+  }
+
+  false
+}
+
+/**
+ * What `Proc::copy_in` and `Proc::copy_out` copy. With no `include` patterns,
+ * everything is; with some, only files matching one of them are, along with
+ * directories matching one. Anything matching an `exclude` pattern isn't, and
+ * neither is anything under a directory that matches one.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyOptions {
+  pub include: Vec<Glob>,
+  pub exclude: Vec<Glob>,
+}
+
+impl CopyOptions {
+  // Whether the directory `name` is copied, and whether what's under it can be.
+  fn dir_wanted(&self, name: &str) -> (bool, bool) {
+    let excluded = self.exclude.iter().any(|glob| glob.matches(name));
+    let included = self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name));
+    (!excluded && included, !excluded)
+  }
+
+  fn file_wanted(&self, name: &str) -> bool {
+    self.dir_wanted(name).0
+  }
+}
+
+/**
+ * How many of each kind of thing were copied.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+  pub files: usize,
+  pub directories: usize,
+  pub symlinks: usize,
+  pub hard_links: usize,
+  // Devices, FIFOs, sockets, and names that aren't UTF-8.
+  pub skipped: usize,
+}
+
+fn to_timespec(sec: i64, nsec: i64) -> Timespec {
+  Timespec::new(sec, nsec as i32)
+}
+
+fn to_system_time(time: Timespec) -> SystemTime {
+  match time.sec {
+    sec if sec >= 0 => UNIX_EPOCH + Duration::new(sec as u64, time.nsec as u32),
+    sec => UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + Duration::from_nanos(time.nsec as u64)
+  }
+}
+
+fn join(dir: &str, name: &str) -> String {
+  match dir {
+    "" => name.to_string(),
+    _ => format!("{}/{}", dir, name)
+  }
+}
+
+// Makes the directories `relative` names under `top` on the host, one at a
+// time. Fails if something on the way is anything but a directory, such as a
+// symbolic link, so nothing can be written through it outside `top`.
+fn make_dirs(top: &Path, relative: &str) -> io::Result<()> {
+  let mut path = top.to_path_buf();
+  for part in relative.split('/').filter(|part| !part.is_empty()) {
+    path.push(part);
+    match fs::symlink_metadata(&path) {
+      Ok(ref metadata) if metadata.is_dir() => {}
+      Ok(_) => {
+        return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{}: isn't a directory", path.display())));
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+      Err(e) => return Err(e)
+    }
+  }
+
+  Ok(())
+}
+
+// Removes whatever is at `path` on the host, unless it's a directory, so
+// writing there can't follow a symbolic link somewhere else. The directories
+// above it have to have been checked with `make_dirs`.
+fn clear(path: &Path) -> io::Result<()> {
+  match fs::symlink_metadata(path) {
+    Ok(ref metadata) if !metadata.is_dir() => fs::remove_file(path),
+    _ => Ok(())
+  }
+}
+
+// State kept through a copy in.
+struct CopyIn<'o> {
+  options: &'o CopyOptions,
+  stats: CopyStats,
+  // Files with more than one link, by device and inode number, and the name
+  // the first was copied to.
+  linked: HashMap<(u64, u64), String>,
+}
+
+impl<'r> Proc<'r> {
+  /**
+   * Copies `host_path`, and everything under it if it's a directory, to
+   * `fs_path`, which is "" for the top. Files already there with the same
+   * names are replaced. Fails if `fs_path` would lead outside the top or into
+   * a mounted file system, on files too large for the page size, or on the
+   * first error from the host; whatever was copied before then stays.
+   */
+  pub fn copy_in<P: AsRef<Path>>(&mut self, host_path: P, fs_path: &str,
+                                 options: &CopyOptions) -> io::Result<CopyStats> {
+    let dest = archive::clean("", fs_path).ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is outside the top", fs_path))
+    })?;
+
+    let mut copy = CopyIn { options, stats: CopyStats::default(), linked: HashMap::new() };
+    self.copy_in_entry(&mut copy, host_path.as_ref(), &dest, "")?;
+    Ok(copy.stats)
+  }
+
+  // Copies `host` to `name`; `relative` is `name` relative to the top of the
+  // copy, "" for the top itself.
+  fn copy_in_entry(&mut self, copy: &mut CopyIn, host: &Path, name: &str, relative: &str) -> io::Result<()> {
+    if !name.is_empty() {
+      self.check_unmounted(name)?;
+    }
+
+    let metadata = fs::symlink_metadata(host)?;
+    let kind = metadata.file_type();
+    if kind.is_dir() {
+      let (wanted, descend) = match relative {
+        "" => (true, true),
+        _ => copy.options.dir_wanted(relative)
+      };
+
+      if wanted && !name.is_empty() {
+        if !matches!(self.cwd.get(name), Some(File::Directory(_))) {
+          self.cwd.insert(name.to_string(), File::new_dir(None));
+        }
+
+        copy.stats.directories += 1;
+      }
+
+      if descend {
+        let mut children = fs::read_dir(host)?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+          match child.file_name().to_str() {
+            Some(child_name) => {
+              let (name, relative) = (join(name, child_name), join(relative, child_name));
+              self.copy_in_entry(copy, &child.path(), &name, &relative)?;
+            }
+            None => copy.stats.skipped += 1
+          }
+        }
+      }
+
+      return Ok(());
+    }
+
+    if !relative.is_empty() && !copy.options.file_wanted(relative) {
+      return Ok(());
+    }
+
+    if name.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "only a directory can be copied to the top"));
+    }
+
+    if !kind.is_file() && !kind.is_symlink() {
+      copy.stats.skipped += 1;
+      return Ok(());
+    }
+
+    let id = (metadata.dev(), metadata.ino());
+    if metadata.nlink() > 1 {
+      if let Some(first) = copy.linked.get(&id) {
+        if let Some(file @ File::DataFile(_)) = self.cwd.get(first) {
+          self.cwd.insert(name.to_string(), file);
+          copy.stats.hard_links += 1;
+          return Ok(());
+        }
+      }
+    }
+
+    if kind.is_file() && metadata.len() > self.geometry().max_file_size() as u64 {
+      return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("{}: too large for the page size", host.display())));
+    }
+
+    let mut inode = self.allocator.alloc_inode();
+    let permissions = metadata.mode() & 0o7777;
+    if kind.is_symlink() {
+      let target = fs::read_link(host)?;
+      let target = target.to_str().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: link target isn't UTF-8", host.display()))
+      })?;
+
+      inode.set_mode(S_IFLNK | permissions);
+      inode.write(0, target.as_bytes());
+      copy.stats.symlinks += 1;
+    } else {
+      inode.set_mode(S_IFREG | permissions);
+      let page_size = self.geometry().page_size();
+      archive::fill(&mut fs::File::open(host)?, &mut inode, metadata.len(), page_size)?;
+      copy.stats.files += 1;
+    }
+
+    let (created, _, _) = inode.stat();
+    let accessed = to_timespec(metadata.atime(), metadata.atime_nsec());
+    let modified = to_timespec(metadata.mtime(), metadata.mtime_nsec());
+    let size = inode.size().max(metadata.len() as usize);
+    inode.set_stat(size, (created, accessed, modified));
+    self.cwd.insert(name.to_string(), File::new_data_file(Rc::new(RefCell::new(inode))));
+    if metadata.nlink() > 1 {
+      copy.linked.insert(id, name.to_string());
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Copies `fs_path`, and everything under it if it's a directory, to
+   * `host_path`, which is created if it isn't there. `fs_path` is "" for
+   * everything. Files on the host with the same names are replaced. Fails on
+   * the first error from the host, or if a file would go under anything but a
+   * directory, such as a symbolic link copied out before it; whatever was
+   * copied before then stays.
+   */
+  pub fn copy_out<P: AsRef<Path>>(&self, fs_path: &str, host_path: P,
+                                  options: &CopyOptions) -> io::Result<CopyStats> {
+    let host_path = host_path.as_ref();
+    let src = archive::clean("", fs_path).ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is outside the top", fs_path))
+    })?;
+
+    let mut stats = CopyStats::default();
+    let mut linked = HashMap::new();
+    if let Some(file @ File::DataFile(_)) = self.cwd.get(&src) {
+      self.copy_out_file(&file, host_path, &mut linked, &mut stats)?;
+      return Ok(stats);
+    }
+
+    fs::create_dir_all(host_path)?;
+    let content = self.cwd.get_dir_rc().borrow();
+    let mut pruned: Vec<String> = Vec::new();
+    for (relative, file) in archive::entries(&content, &src) {
+      if pruned.iter().any(|dir| relative.strip_prefix(dir.as_str()).is_some_and(|rest| rest.starts_with('/'))) {
+        continue;
+      }
+
+      let host = relative.split('/').fold(host_path.to_path_buf(), |path, part| path.join(part));
+      match file {
+        Some(file @ File::DataFile(_)) => {
+          if options.file_wanted(&relative) {
+            make_dirs(host_path, relative.rsplit_once('/').map(|(parent, _)| parent).unwrap_or(""))?;
+            self.copy_out_file(file, &host, &mut linked, &mut stats)?;
+          }
+        }
+        _ => {
+          let (wanted, descend) = options.dir_wanted(&relative);
+          if wanted {
+            make_dirs(host_path, &relative)?;
+            stats.directories += 1;
+          }
+
+          if !descend {
+            pruned.push(relative);
+          }
+        }
+      }
+    }
+
+    Ok(stats)
+  }
+
+  // Copies a file to `host`, writing only its allocated pages that aren't all
+  // zeroes.
+  fn copy_out_file(&self, file: &File<'r>, host: &Path, linked: &mut HashMap<*const RefCell<::alloc::InodeBox>, PathBuf>,
+                   stats: &mut CopyStats) -> io::Result<()> {
+    let inode = match *file {
+      File::DataFile(ref inode) => inode,
+      _ => return Ok(())
+    };
+
+    clear(host)?;
+    if let Some(first) = linked.get(&Rc::as_ptr(inode)) {
+      stats.hard_links += 1;
+      return fs::hard_link(first, host);
+    }
+
+    let inode_ref = inode.borrow();
+    if let Some(target) = inode_ref.link_target() {
+      symlink(target, host)?;
+      stats.symlinks += 1;
+      return Ok(());
+    }
+
+    let mut out = fs::File::create(host)?;
+    let mut result = Ok(());
+    inode_ref.extents(|offset, data| {
+      if result.is_ok() && data.iter().any(|&byte| byte != 0) {
+        result = out.seek(SeekFrom::Start(offset as u64)).and_then(|_| out.write_all(data));
+      }
+    });
+
+    result?;
+    out.set_len(inode_ref.size() as u64)?;
+    let (_, accessed, modified) = inode_ref.stat();
+    out.set_times(fs::FileTimes::new().set_accessed(to_system_time(accessed)).set_modified(to_system_time(modified)))?;
+    out.set_permissions(fs::Permissions::from_mode(inode_ref.mode() & 0o7777))?;
+    linked.insert(Rc::as_ptr(inode), host.to_path_buf());
+    stats.files += 1;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::io::ErrorKind;
+  use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+  use std::path::PathBuf;
+  use std::process;
+
+  use super::{CopyOptions, CopyStats, Glob};
+  use directory::DirectoryHandle;
+  use file::File;
+  use {Proc, O_CREAT, O_RDWR};
+
+  // A fresh directory for a test, under the system's temporary directory.
+  fn scratch(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("rustfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn contents(p: &mut Proc, name: &'static str) -> Vec<u8> {
+    let size = p.metadata(name).expect("missing").size;
+    let fd = p.open(name, O_RDWR);
+    let mut buf = vec![0; size];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  fn globs(patterns: &[&str]) -> Vec<Glob> {
+    patterns.iter().map(|pattern| Glob::new(pattern).unwrap()).collect()
+  }
+
+  #[test]
+  fn test_globs() {
+    let cases: &[(&str, &[&str], &[&str])] = &[
+      ("*.rs", &["main.rs", "src/lib.rs", "a/b/c.rs"], &["main.rs.bak", "rs"]),
+      ("src/*.rs", &["src/lib.rs"], &["src/a/lib.rs", "lib.rs", "x/src/lib.rs"]),
+      ("src/**/*.rs", &["src/lib.rs", "src/a/b/lib.rs"], &["lib.rs", "src/lib.c"]),
+      ("target/**", &["target/debug/x", "target/"], &["target", "src/target/x"]),
+      ("file?.[ch]", &["file1.c", "dir/fileX.h"], &["file.c", "file12.c", "file1.o"]),
+      ("[!a-c]*", &["dog", "zebra"], &["apple", "cat"]),
+      ("[]x]", &["]", "x"], &["y"]),
+      ("\\*", &["*"], &["a"]),
+    ];
+
+    for &(pattern, matching, other) in cases {
+      let glob = Glob::new(pattern).unwrap();
+      for name in matching {
+        assert!(glob.matches(name), "{} should match {}", pattern, name);
+      }
+
+      for name in other {
+        assert!(!glob.matches(name), "{} shouldn't match {}", pattern, name);
+      }
+    }
+
+    assert_eq!(Glob::new("[abc").unwrap_err().to_string(), "bad pattern '[abc': '[' isn't closed");
+    assert_eq!(Glob::new("a\\").unwrap_err().to_string(), "bad pattern 'a\\': ends with '\\'");
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let host = scratch("copy");
+    let src = host.join("src");
+    fs::create_dir_all(src.join("sub/empty")).unwrap();
+    fs::write(src.join("main.rs"), b"fn main() {}\n").unwrap();
+    fs::set_permissions(src.join("main.rs"), fs::Permissions::from_mode(0o751)).unwrap();
+    fs::hard_link(src.join("main.rs"), src.join("sub/hard.rs")).unwrap();
+    symlink("../main.rs", src.join("sub/link")).unwrap();
+    let sparse = fs::File::create(src.join("sparse")).unwrap();
+    sparse.set_len(1 << 20).unwrap();
+    fs::write(src.join("target"), b"skipped").unwrap();
+
+    let mut p = Proc::new();
+    let options = CopyOptions { exclude: globs(&["target"]), ..CopyOptions::default() };
+    let stats = p.copy_in(&src, "./in/", &options).unwrap();
+    assert_eq!(stats, CopyStats { files: 2, directories: 3, symlinks: 1, hard_links: 1, skipped: 0 });
+
+    let metadata = p.metadata("in/main.rs").unwrap();
+    let host_metadata = fs::metadata(src.join("main.rs")).unwrap();
+    assert_eq!(metadata.mode, 0o100751);
+    assert_eq!((metadata.modified.sec, metadata.modified.nsec as i64), (host_metadata.mtime(), host_metadata.mtime_nsec()));
+    assert_eq!(p.readlink("in/sub/link").unwrap(), "../main.rs");
+    assert!(p.metadata("in/sub/empty").unwrap().is_dir());
+    assert!(p.metadata("in/target").is_none());
+    assert_eq!(contents(&mut p, "in/sparse"), vec![0; 1 << 20]);
+
+    // Holes stay holes, and hard links stay linked.
+    let mut allocated = 0;
+    match p.cwd.get("in/sparse") {
+      Some(File::DataFile(ref inode)) => inode.borrow().extents(|_, data| allocated += data.len()),
+      _ => panic!("in/sparse is missing")
+    }
+
+    assert!(allocated < p.geometry().page_size());
+
+    let fd = p.open("in/sub/hard.rs", O_RDWR);
+    p.write(fd, b"//");
+    p.close(fd);
+    assert_eq!(&contents(&mut p, "in/main.rs")[..2], b"//");
+
+    let out = host.join("out");
+    let stats = p.copy_out("in", &out, &CopyOptions::default()).unwrap();
+    assert_eq!(stats, CopyStats { files: 2, directories: 2, symlinks: 1, hard_links: 1, skipped: 0 });
+    let copied = fs::metadata(out.join("main.rs")).unwrap();
+    assert_eq!(copied.mode() & 0o7777, 0o751);
+    let modified = p.metadata("in/main.rs").unwrap().modified;
+    assert_eq!((copied.mtime(), copied.mtime_nsec()), (modified.sec, modified.nsec as i64));
+    assert_eq!(copied.ino(), fs::metadata(out.join("sub/hard.rs")).unwrap().ino());
+    assert_eq!(fs::read(out.join("sub/link")).unwrap(), b"// main() {}\n");
+    assert_eq!(fs::metadata(out.join("sparse")).unwrap().len(), 1 << 20);
+    assert!(fs::metadata(out.join("sparse")).unwrap().blocks() < 8);
+    assert!(out.join("sub/empty").is_dir());
+
+    // Copying out again replaces the files, and a symbolic link in the way
+    // isn't followed.
+    fs::remove_file(out.join("main.rs")).unwrap();
+    symlink(src.join("main.rs"), out.join("main.rs")).unwrap();
+    let options = CopyOptions { include: globs(&["*.rs"]), exclude: globs(&["sub"]) };
+    let stats = p.copy_out("in", &out, &options).unwrap();
+    assert_eq!(stats, CopyStats { files: 1, ..CopyStats::default() });
+    assert!(!fs::symlink_metadata(out.join("main.rs")).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read(src.join("main.rs")).unwrap(), b"fn main() {}\n");
+
+    // A single file goes by itself.
+    p.copy_in(src.join("main.rs"), "single", &CopyOptions::default()).unwrap();
+    p.copy_out("single", out.join("single"), &CopyOptions::default()).unwrap();
+    assert_eq!(fs::read(out.join("single")).unwrap(), b"fn main() {}\n");
+    fs::remove_dir_all(&host).unwrap();
+  }
+
+  #[test]
+  fn test_copy_out_through_symlink() {
+    // Names are flat, so a file can be under a symbolic link. Copying it out
+    // mustn't write wherever the link points.
+    let host = scratch("escape");
+    let outside = host.join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    let target = outside.to_str().unwrap().to_string();
+
+    let mut p = Proc::new();
+    assert!(p.symlink(&target, "a"));
+    let fd = p.open("a/secret", O_RDWR | O_CREAT);
+    p.write(fd, b"secret");
+    p.close(fd);
+    assert!(p.mkdir("a/dir"));
+
+    let error = p.copy_out("", host.join("out"), &CopyOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotADirectory);
+    assert!(fs::symlink_metadata(host.join("out/a")).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+    // Nor where a link already on the host points.
+    let mut p = Proc::new();
+    let fd = p.open("sub/file", O_RDWR | O_CREAT);
+    p.close(fd);
+    let out = host.join("out2");
+    fs::create_dir_all(&out).unwrap();
+    symlink(&outside, out.join("sub")).unwrap();
+    let error = p.copy_out("", &out, &CopyOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotADirectory);
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    fs::remove_dir_all(&host).unwrap();
+  }
+
+  #[test]
+  fn test_copy_in_too_large() {
+    let host = scratch("large");
+    let mut p = Proc::with_page_size(512);
+    let large = fs::File::create(host.join("large")).unwrap();
+    large.set_len(p.geometry().max_file_size() as u64 + 1).unwrap();
+    let error = p.copy_in(host.join("large"), "large", &CopyOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::FileTooLarge);
+    assert!(p.metadata("large").is_none());
+    fs::remove_dir_all(&host).unwrap();
+  }
+}
@@ -0,0 +1,628 @@
+/*!
+ * Saving a file system to an image and loading it back.
+ *
+ * An image holds a file system's files, their names, sizes, and times, and
+ * their data, with holes left out. Open file descriptors aren't saved. Every
+ * integer is little-endian; times are seconds (i64) and nanoseconds (i32). In
+ * order, an image is:
+ *
+ *   superblock    "RFSIMAGE", then the version (u32, currently 2), the page
+ *                 size (u32), and the number of inodes, directories, and
+ *                 extents that follow (u64 each)
+ *   inode table   for each inode: its size (u64), its create, access, and
+ *                 modify times, how many extents of data it has (u64), its
+ *                 mode, user id, and group id (u32 each), and how many
+ *                 extended attributes it has (u32), then each attribute's
+ *                 name and value (u32 length, then bytes, each). A symbolic
+ *                 link's data is its target.
+ *   directories   for each directory, the root first: how many entries it
+ *                 has (u64), then for each entry its kind (u8: 1 for a file, 2
+ *                 for a directory), the index of its inode or directory (u64),
+ *                 and its name (u32 length, then UTF-8 bytes). Entries are
+ *                 sorted by name. A directory's index is always the next one
+ *                 not yet used, so directories come in breadth-first order
+ *                 and can't form cycles.
+ *   data          for each inode, in table order, each of its extents: the
+ *                 offset (u64) and length (u32) of a stretch of the file that
+ *                 isn't a hole, then its bytes. Extents are in order and don't
+ *                 overlap; they're whole pages, cut off at the end of the file,
+ *                 or a small file's data.
+ *   checksum      the CRC-32 (IEEE) of everything before it (u32)
+ *
+ * Version 1 images, whose inode table stops at the extent count, load as
+ * regular files with mode 0644 owned by root. Loading an image rejects
+ * anything that doesn't follow these rules.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use directory::DirectoryHandle;
+use file::{File, RcDirContent, RcInode};
+use inode::Geometry;
+use time::Timespec;
+use {AllocKind, Proc};
+
+const MAGIC: &[u8; 8] = b"RFSIMAGE";
+const VERSION: u32 = 2;
+
+const FILE: u8 = 1;
+const DIRECTORY: u8 = 2;
+
+// Caps how much is reserved up front from counts read from an image, which
+// may be garbage.
+const MAX_RESERVE: u64 = 1 << 16;
+
+const fn crc_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+      bit += 1;
+    }
+
+    table[i] = crc;
+    i += 1;
+  }
+
+  table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+// A reader or writer that keeps the CRC-32 of everything through it. Zip
+// archives use the same CRC.
+pub(crate) struct Checksummed<T> {
+  inner: T,
+  crc: u32,
+}
+
+impl<T> Checksummed<T> {
+  pub(crate) fn new(inner: T) -> Checksummed<T> {
+    Checksummed { inner, crc: !0 }
+  }
+
+  pub(crate) fn update(&mut self, bytes: &[u8]) {
+    for &byte in bytes {
+      self.crc = CRC_TABLE[((self.crc ^ byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+    }
+  }
+
+  pub(crate) fn checksum(&self) -> u32 {
+    !self.crc
+  }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.update(&buf[..n]);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.update(&buf[..n]);
+    Ok(n)
+  }
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad image: {}", what))
+}
+
+fn write_times<W: Write>(out: &mut W, times: &[Timespec; 3]) -> io::Result<()> {
+  for time in times.iter() {
+    out.write_all(&time.sec.to_le_bytes())?;
+    out.write_all(&time.nsec.to_le_bytes())?;
+  }
+
+  Ok(())
+}
+
+fn write_blob<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+  out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+  out.write_all(bytes)
+}
+
+fn read_bytes<R: Read, const N: usize>(input: &mut R) -> io::Result<[u8; N]> {
+  let mut bytes = [0u8; N];
+  input.read_exact(&mut bytes).map_err(|e| match e.kind() {
+    io::ErrorKind::UnexpectedEof => invalid("truncated"),
+    _ => e
+  })?;
+
+  Ok(bytes)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+  Ok(u32::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+  Ok(u64::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_blob<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+  let len = read_u32(input)? as u64;
+  let mut bytes = Vec::new();
+  input.take(len).read_to_end(&mut bytes)?;
+  if bytes.len() as u64 != len {
+    return Err(invalid("truncated"));
+  }
+
+  Ok(bytes)
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+  String::from_utf8(read_blob(input)?).map_err(|_| invalid("name isn't UTF-8"))
+}
+
+fn read_times<R: Read>(input: &mut R) -> io::Result<[Timespec; 3]> {
+  let mut times = [Timespec::new(0, 0); 3];
+  for time in times.iter_mut() {
+    let sec = i64::from_le_bytes(read_bytes(input)?);
+    let nsec = i32::from_le_bytes(read_bytes(input)?);
+    if !(0..1_000_000_000).contains(&nsec) {
+      return Err(invalid("bad time"));
+    }
+
+    *time = Timespec::new(sec, nsec);
+  }
+
+  Ok(times)
+}
+
+// Every inode and directory reachable from the root, numbered in the order
+// they're saved in.
+struct Tree<'r> {
+  inodes: Vec<RcInode>,
+  directories: Vec<RcDirContent<'r>>,
+  // Each directory's entries, sorted by name: kind, index, and name.
+  entries: Vec<Vec<(u8, u64, String)>>,
+}
+
+impl<'r> Tree<'r> {
+  fn walk(root: &RcDirContent<'r>) -> io::Result<Tree<'r>> {
+    let mut tree = Tree { inodes: Vec::new(), directories: vec![root.clone()], entries: Vec::new() };
+    let mut inode_index: HashMap<*const RefCell<_>, u64> = HashMap::new();
+    let mut i = 0;
+    while i < tree.directories.len() {
+      let directory = tree.directories[i].clone();
+      let content = directory.borrow();
+      let mut names: Vec<_> = content.entries.keys().collect();
+      names.sort();
+
+      let mut entries = Vec::with_capacity(names.len());
+      for name in names {
+        let entry = match content.entries[name] {
+          File::DataFile(ref inode) => {
+            let next = tree.inodes.len() as u64;
+            let index = *inode_index.entry(Rc::as_ptr(inode)).or_insert(next);
+            if index == next {
+              tree.inodes.push(inode.clone());
+            }
+
+            (FILE, index)
+          }
+          File::Directory(ref child) => {
+            if tree.directories.iter().any(|seen| Rc::ptr_eq(seen, child)) {
+              let message = format!("directory '{}' is linked more than once", name);
+              return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+
+            tree.directories.push(child.clone());
+            (DIRECTORY, tree.directories.len() as u64 - 1)
+          }
+          File::EmptyFile | File::Lower(_) => continue
+        };
+
+        entries.push((entry.0, entry.1, name.to_string()));
+      }
+
+      tree.entries.push(entries);
+      i += 1;
+    }
+
+    Ok(tree)
+  }
+}
+
+/**
+ * Writes `p`'s files to `out` as an image.
+ */
+pub fn write_image<W: Write>(p: &Proc, out: W) -> io::Result<()> {
+  let tree = Tree::walk(p.cwd.get_dir_rc())?;
+  let extent_counts: Vec<u64> = tree.inodes.iter().map(|inode| {
+    let mut count = 0;
+    inode.borrow().extents(|_, _| count += 1);
+    count
+  }).collect();
+
+  let mut out = Checksummed::new(out);
+  out.write_all(MAGIC)?;
+  out.write_all(&VERSION.to_le_bytes())?;
+  out.write_all(&(p.geometry().page_size() as u32).to_le_bytes())?;
+  out.write_all(&(tree.inodes.len() as u64).to_le_bytes())?;
+  out.write_all(&(tree.directories.len() as u64).to_le_bytes())?;
+  out.write_all(&extent_counts.iter().sum::<u64>().to_le_bytes())?;
+
+  for (inode, extents) in tree.inodes.iter().zip(extent_counts.iter()) {
+    let inode = inode.borrow();
+    let (create, access, modify) = inode.stat();
+    out.write_all(&(inode.size() as u64).to_le_bytes())?;
+    write_times(&mut out, &[create, access, modify])?;
+    out.write_all(&extents.to_le_bytes())?;
+
+    let (uid, gid) = inode.owner();
+    for field in [inode.mode(), uid, gid, inode.xattrs().map_or(0, |xattrs| xattrs.len() as u32)].iter() {
+      out.write_all(&field.to_le_bytes())?;
+    }
+
+    for (name, value) in inode.xattrs().into_iter().flatten() {
+      write_blob(&mut out, name.as_bytes())?;
+      write_blob(&mut out, value)?;
+    }
+  }
+
+  for entries in tree.entries.iter() {
+    out.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for &(kind, index, ref name) in entries.iter() {
+      out.write_all(&[kind])?;
+      out.write_all(&index.to_le_bytes())?;
+      write_blob(&mut out, name.as_bytes())?;
+    }
+  }
+
+  for inode in tree.inodes.iter() {
+    let mut result = Ok(());
+    inode.borrow().extents(|offset, data| {
+      if result.is_ok() {
+        result = out.write_all(&(offset as u64).to_le_bytes())
+          .and_then(|_| out.write_all(&(data.len() as u32).to_le_bytes()))
+          .and_then(|_| out.write_all(data));
+      }
+    });
+
+    result?;
+  }
+
+  let checksum = out.checksum();
+  out.write_all(&checksum.to_le_bytes())?;
+  out.flush()
+}
+
+/**
+ * Reads an image written by `write_image` into a new file system whose
+ * inodes, block lists, and pages come from `kind` of allocator.
+ */
+pub fn read_image<'r, R: Read>(input: R, kind: AllocKind) -> io::Result<Proc<'r>> {
+  match read_image_unverified(input, kind)? {
+    (p, true) => Ok(p),
+    (_, false) => Err(invalid("checksum mismatch"))
+  }
+}
+
+/**
+ * Like `read_image`, but loads an image whose checksum doesn't match instead
+ * of failing. Also returns whether it matched.
+ */
+pub fn read_image_unverified<'r, R: Read>(input: R, kind: AllocKind) -> io::Result<(Proc<'r>, bool)> {
+  let mut input = Checksummed::new(input);
+  if &read_bytes::<_, 8>(&mut input).map_err(|_| invalid("not an image"))? != MAGIC {
+    return Err(invalid("not an image"));
+  }
+
+  let version = read_u32(&mut input)?;
+  if version == 0 || version > VERSION {
+    return Err(invalid(&format!("unsupported version {}", version)));
+  }
+
+  let page_size = read_u32(&mut input)? as usize;
+  let geometry = match Geometry::try_new(page_size) {
+    Ok(geometry) => geometry,
+    Err(_) => return Err(invalid(&format!("bad page size {}", page_size)))
+  };
+
+  let (inode_count, directory_count) = (read_u64(&mut input)?, read_u64(&mut input)?);
+  let extent_count = read_u64(&mut input)?;
+  if directory_count == 0 {
+    return Err(invalid("no root directory"));
+  }
+
+  let p = Proc::with_allocator(page_size, kind);
+  let max_size = geometry.max_file_size() as u64;
+  let mut inodes = Vec::with_capacity(inode_count.min(MAX_RESERVE) as usize);
+  for _ in 0..inode_count {
+    let size = read_u64(&mut input)?;
+    let times = read_times(&mut input)?;
+    let extents = read_u64(&mut input)?;
+    if size > max_size {
+      return Err(invalid("file too large for the page size"));
+    }
+
+    let mut inode = p.allocator.alloc_inode();
+    if version >= 2 {
+      let (mode, uid, gid) = (read_u32(&mut input)?, read_u32(&mut input)?, read_u32(&mut input)?);
+      inode.set_mode(mode);
+      inode.set_owner(uid, gid);
+      for _ in 0..read_u32(&mut input)? {
+        let name = read_string(&mut input)?;
+        inode.set_xattr(&name, &read_blob(&mut input)?);
+      }
+    }
+
+    inodes.push((Rc::new(RefCell::new(inode)), size as usize, times, extents));
+  }
+
+  if inodes.iter().map(|inode| inode.3).sum::<u64>() != extent_count {
+    return Err(invalid("extent count doesn't match the inode table"));
+  }
+
+  let mut directories = vec![p.cwd.clone()];
+  for i in 0..directory_count as usize {
+    let mut directory = directories.get(i).cloned().ok_or_else(|| invalid("unreachable directory"))?;
+    for _ in 0..read_u64(&mut input)? {
+      let kind = read_bytes::<_, 1>(&mut input)?[0];
+      let index = read_u64(&mut input)?;
+      let name = read_string(&mut input)?;
+      if name.is_empty() || directory.get(&name).is_some() {
+        return Err(invalid(&format!("bad or repeated name '{}'", name)));
+      }
+
+      let file = match kind {
+        FILE => {
+          let inode = inodes.get(index as usize).ok_or_else(|| invalid("no such inode"))?;
+          File::new_data_file(inode.0.clone())
+        }
+        DIRECTORY if index == directories.len() as u64 && index < directory_count => {
+          let child = File::new_dir(Some(directory.clone()));
+          directories.push(child.clone());
+          child
+        }
+        DIRECTORY => return Err(invalid("directories out of order")),
+        _ => return Err(invalid(&format!("unknown entry kind {}", kind)))
+      };
+
+      directory.insert(name, file);
+    }
+  }
+
+  let mut buffer = Vec::new();
+  for &(ref inode, size, times, extents) in inodes.iter() {
+    let mut inode = inode.borrow_mut();
+    let mut end = 0;
+    for _ in 0..extents {
+      let (offset, len) = (read_u64(&mut input)?, read_u32(&mut input)? as u64);
+      let extent_end = match offset.checked_add(len) {
+        Some(extent_end) if offset >= end && extent_end <= size as u64 && len <= page_size as u64 => extent_end,
+        _ => return Err(invalid("extent out of place"))
+      };
+
+      buffer.resize(len as usize, 0);
+      input.read_exact(&mut buffer).map_err(|_| invalid("truncated"))?;
+      inode.write(offset as usize, &buffer);
+      end = extent_end;
+    }
+
+    inode.set_stat(size, (times[0], times[1], times[2]));
+  }
+
+  let checksum = input.checksum();
+  let matched = u32::from_le_bytes(read_bytes(&mut input.inner)?) == checksum;
+  if input.inner.read(&mut [0u8])? != 0 {
+    return Err(invalid("trailing data"));
+  }
+
+  Ok((p, matched))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::process;
+
+  use std::io::Write;
+
+  use super::{read_image, read_u64, write_image, Checksummed};
+  use file::File;
+  use {AllocKind, Proc, Whence, O_CREAT, O_RDWR};
+
+  fn image(p: &Proc) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_image(p, &mut bytes).unwrap();
+    bytes
+  }
+
+  fn contents(p: &mut Proc<'static>, name: &'static str, len: usize) -> Vec<u8> {
+    let fd = p.open(name, O_RDWR);
+    assert!(fd >= 0, "{} is missing", name);
+    let mut buf = vec![0; len];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  fn extents(p: &mut Proc, name: &'static str) -> Vec<(usize, usize)> {
+    let mut extents = Vec::new();
+    match p.cwd.get_dir_rc().borrow().entries[name] {
+      File::DataFile(ref inode) => inode.borrow().extents(|offset, data| extents.push((offset, data.len()))),
+      _ => panic!("not a file")
+    }
+
+    extents
+  }
+
+  fn fixture() -> Proc<'static> {
+    let mut p = Proc::with_page_size(512);
+    let small = p.open("small", O_CREAT | O_RDWR);
+    p.write(small, b"tiny");
+    p.close(small);
+
+    let sparse = p.open("sparse", O_CREAT | O_RDWR);
+    p.write(sparse, &[1; 700]);
+    p.seek(sparse, 512 * 5000 + 3, Whence::SeekSet);
+    p.write(sparse, &[2; 100]);
+    p.close(sparse);
+
+    let empty = p.open("empty", O_CREAT | O_RDWR);
+    p.close(empty);
+    p
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let original = fixture();
+    let bytes = image(&original);
+    for &kind in [AllocKind::Slab, AllocKind::Boxed].iter() {
+      let mut p = read_image(&bytes[..], kind).unwrap();
+      assert_eq!(p.geometry().page_size(), 512);
+      assert_eq!(p.alloc_kind(), kind);
+
+      assert_eq!(contents(&mut p, "small", 4), b"tiny");
+      assert_eq!(contents(&mut p, "empty", 0), b"");
+      let sparse = contents(&mut p, "sparse", 512 * 5000 + 103);
+      assert!(sparse[..700].iter().all(|&byte| byte == 1));
+      assert!(sparse[700..512 * 5000 + 3].iter().all(|&byte| byte == 0));
+      assert!(sparse[512 * 5000 + 3..].iter().all(|&byte| byte == 2));
+
+      // Holes stay holes, and the image comes out the same again.
+      assert_eq!(extents(&mut p, "sparse"), vec![(0, 512), (512, 512), (512 * 5000, 103)]);
+      assert_eq!(image(&p), bytes);
+    }
+  }
+
+  #[test]
+  fn test_stat_preserved() {
+    let original = fixture();
+    let mut p = read_image(&image(&original)[..], AllocKind::default()).unwrap();
+    for &name in ["small", "sparse", "empty"].iter() {
+      let stat = |p: &Proc| match p.cwd.get_dir_rc().borrow().entries[name] {
+        File::DataFile(ref inode) => (inode.borrow().size(), inode.borrow().stat()),
+        _ => panic!("not a file")
+      };
+
+      assert_eq!(stat(&p), stat(&original));
+    }
+
+    // Loaded files can be written to like any other.
+    let fd = p.open("small", O_RDWR);
+    p.seek(fd, 0, Whence::SeekEnd);
+    p.write(fd, b" no more");
+    p.close(fd);
+    assert_eq!(contents(&mut p, "small", 12), b"tiny no more");
+  }
+
+  #[test]
+  fn test_bad_images() {
+    let bytes = image(&fixture());
+    let error = |bytes: &[u8]| read_image(bytes, AllocKind::default()).err().expect("loaded").to_string();
+
+    assert_eq!(error(b"RFSTRACE\x01"), "bad image: not an image");
+    assert_eq!(error(&bytes[..bytes.len() - 10]), "bad image: truncated");
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.len() - 5;
+    corrupt[last] ^= 1;
+    assert_eq!(error(&corrupt), "bad image: checksum mismatch");
+
+    let mut newer = bytes.clone();
+    newer[8] = 3;
+    assert_eq!(error(&newer), "bad image: unsupported version 3");
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(error(&trailing), "bad image: trailing data");
+  }
+
+  #[test]
+  fn test_extent_overflow() {
+    let mut p = Proc::new();
+    let fd = p.open("f", O_CREAT | O_RDWR);
+    p.write(fd, b"0123456789");
+    p.close(fd);
+
+    // An extent whose offset plus length wraps around is rejected, not read.
+    let mut bytes = image(&p);
+    let data = bytes.windows(10).position(|window| window == b"0123456789").unwrap();
+    bytes[(data - 12)..(data - 4)].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+    let error = read_image(&bytes[..], AllocKind::default()).err().expect("loaded");
+    assert_eq!(error.to_string(), "bad image: extent out of place");
+  }
+
+  #[test]
+  fn test_metadata() {
+    let mut original = fixture();
+    assert!(original.symlink("small", "link"));
+    assert!(original.link("sparse", "hard").is_ok());
+    assert!(original.mkdir("dir"));
+    if let File::DataFile(ref inode) = original.cwd.get_dir_rc().borrow().entries["small"] {
+      let mut inode = inode.borrow_mut();
+      inode.set_mode(0o100600);
+      inode.set_owner(1000, 100);
+      inode.set_xattr("user.note", b"hello");
+    }
+
+    let mut p = read_image(&image(&original)[..], AllocKind::default()).unwrap();
+    for &name in ["small", "sparse", "hard", "link", "dir"].iter() {
+      assert_eq!(p.symlink_metadata(name), original.symlink_metadata(name), "{}", name);
+    }
+
+    assert_eq!(p.readlink("link").unwrap(), "small");
+    assert_eq!(contents(&mut p, "link", 4), b"tiny");
+
+    // Hard links still share an inode.
+    let fd = p.open("hard", O_RDWR);
+    p.write(fd, b"shared");
+    p.close(fd);
+    assert_eq!(contents(&mut p, "sparse", 6), b"shared");
+  }
+
+  #[test]
+  fn test_version_1() {
+    let original = fixture();
+    let v2 = image(&original);
+
+    // A version 1 inode is 52 bytes, without the 16 bytes of mode, owner, and
+    // attribute count that version 2 adds, all defaults here.
+    let mut v1 = Checksummed::new(Vec::new());
+    v1.write_all(&v2[..8]).unwrap();
+    v1.write_all(&1u32.to_le_bytes()).unwrap();
+    v1.write_all(&v2[12..40]).unwrap();
+    let inodes = read_u64(&mut &v2[16..24]).unwrap() as usize;
+    for i in 0..inodes {
+      let start = 40 + i * 68;
+      assert_eq!(&v2[start + 52..start + 68], &[0o100644u32.to_le_bytes(), [0; 4], [0; 4], [0; 4]].concat()[..]);
+      v1.write_all(&v2[start..start + 52]).unwrap();
+    }
+
+    v1.write_all(&v2[40 + inodes * 68..v2.len() - 4]).unwrap();
+    let checksum = v1.checksum();
+    v1.write_all(&checksum.to_le_bytes()).unwrap();
+
+    let mut p = read_image(&v1.inner[..], AllocKind::default()).unwrap();
+    assert_eq!(contents(&mut p, "small", 4), b"tiny");
+    assert_eq!(image(&p), v2);
+  }
+
+  #[test]
+  fn test_save_load() {
+    let path = env::temp_dir().join(format!("rustfs-image-{}", process::id()));
+    fixture().save(&path).unwrap();
+    let mut p = Proc::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(contents(&mut p, "small", 4), b"tiny");
+  }
+}
@@ -1,114 +1,337 @@
 use time;
 use time::Timespec;
-use std::mem;
-use std::ptr;
+use std::collections::BTreeMap;
+use std::io;
 use std::ptr::copy_nonoverlapping;
+use alloc::{Allocator, AllocKind, Block};
 
-const PAGE_SIZE: usize = 4096;
-const LIST_SIZE: usize = 256;
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 65536;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
 
-type Page = Box<([u8; PAGE_SIZE])>;
+// Every block list has 2^LIST_SHIFT = 256 entries, whatever the page size, so
+// even 512 B pages reach 8 GiB files.
+const LIST_SHIFT: u32 = 8;
+
+// Files no larger than this are stored inside the inode itself.
+const INLINE_SIZE: usize = 60;
+
+// The file type bits of a mode, and the types there are.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFLNK: u32 = 0o120000;
+
+// Modes new files, directories, and symbolic links get.
+pub const DEFAULT_FILE_MODE: u32 = S_IFREG | 0o644;
+pub const DEFAULT_DIR_MODE: u32 = S_IFDIR | 0o755;
+pub const DEFAULT_LINK_MODE: u32 = S_IFLNK | 0o777;
+
+// Extended attributes, by name.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+pub type Page = Block<u8>;
 type Entry = Page;
-type EntryList = TList<Entry>; // TODO: Option<TList> for lazy loading
-type DoubleEntryList = TList<EntryList>;
-pub type TList<T> = Box<([Option<T>; LIST_SIZE])>;
+pub type EntryList = TList<Entry>;
+pub type DoubleEntryList = TList<EntryList>;
+type TripleEntryList = TList<DoubleEntryList>;
+pub type TList<T> = Block<Option<T>>;
+
+/**
+ * The page size of a file system and the block counts derived from it. Page
+ * sizes are powers of two between MIN_PAGE_SIZE and MAX_PAGE_SIZE.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+  page_shift: u32
+}
 
-#[inline(always)]
-fn ceil_div(x: usize, y: usize) -> usize {
-  return (x + y - 1) / y;
+impl Default for Geometry {
+  fn default() -> Geometry {
+    Geometry::new(DEFAULT_PAGE_SIZE)
+  }
+}
+
+impl Geometry {
+  // Panics if `page_size` isn't a valid page size. See `try_new`.
+  pub fn new(page_size: usize) -> Geometry {
+    match Geometry::try_new(page_size) {
+      Ok(geometry) => geometry,
+      Err(_) => panic!("Invalid page size: {}", page_size)
+    }
+  }
+
+  // Fails with InvalidInput if `page_size` isn't a power of two between
+  // MIN_PAGE_SIZE and MAX_PAGE_SIZE.
+  pub fn try_new(page_size: usize) -> io::Result<Geometry> {
+    let valid_sizes = MIN_PAGE_SIZE..=MAX_PAGE_SIZE;
+    if !page_size.is_power_of_two() || !valid_sizes.contains(&page_size) {
+      let message = format!("invalid page size {}", page_size);
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+
+    Ok(Geometry { page_shift: page_size.trailing_zeros() })
+  }
+
+  #[inline(always)]
+  pub fn page_size(&self) -> usize {
+    1 << self.page_shift
+  }
+
+  #[inline(always)]
+  fn list_shift(&self) -> u32 {
+    LIST_SHIFT
+  }
+
+  // Number of entries in each singly, doubly, and triply-indirect list.
+  #[inline(always)]
+  pub fn list_size(&self) -> usize {
+    1 << self.list_shift()
+  }
+
+  // Number of pages addressable through the singly and doubly-indirect lists.
+  #[inline(always)]
+  fn single_pages(&self) -> usize {
+    self.list_size()
+  }
+
+  #[inline(always)]
+  fn double_pages(&self) -> usize {
+    1 << (2 * self.list_shift())
+  }
+
+  // Number of pages a file can hold: what the singly, doubly, and
+  // triply-indirect lists reach between them. Writes past the last one panic.
+  pub fn max_pages(&self) -> usize {
+    self.single_pages() + self.double_pages() + (1 << (3 * self.list_shift()))
+  }
+
+  // The largest a file can grow, in bytes: about 8 GiB with 512 B pages, 64 GiB
+  // with 4 KiB pages, and 1 TiB with 64 KiB pages.
+  pub fn max_file_size(&self) -> usize {
+    self.max_pages() << self.page_shift
+  }
 }
 
-#[inline(always)]
-pub fn create_tlist<T>() -> TList<T> {
-  let mut list: TList<T> = Box::new(unsafe { mem::uninitialized() });
-  for x in list.iter_mut() { unsafe { ptr::write(x, None); } };
-  list
+// Every list is allocated on first write to a page it covers, so an empty
+// file costs nothing beyond the inode itself.
+#[derive(Default)]
+struct BlockLists {
+  single: Option<EntryList>, // Block<Option<Page>>
+  double: Option<DoubleEntryList>, // Block<Option<Block<Option<Page>>>>
+  triple: Option<TripleEntryList>,
+}
+
+enum Blocks {
+  Inline([u8; INLINE_SIZE]),
+  Lists(BlockLists)
 }
 
 pub struct Inode {
-  single: EntryList, // Box<([Option<Page>, ..256])>
-  double: DoubleEntryList, // Box<[Option<Box<([Option<Page>>, ..256])>, ..256]
+  blocks: Blocks,
+  allocator: Allocator,
   size: usize,
 
   mod_time: Timespec,
   access_time: Timespec,
   create_time: Timespec,
+
+  // The type and permission bits, as in `st_mode`. A symbolic link's target
+  // is its data.
+  mode: u32,
+  uid: u32,
+  gid: u32,
+  // Most files have none, so they don't cost more than a pointer.
+  xattrs: Option<Box<Xattrs>>,
 }
 
-impl Inode {
-  pub fn new() -> Inode {
-    let time_now = time::get_time();
+// The entries of a block list that have been allocated, with their indices.
+fn present<T>(list: &TList<T>) -> impl Iterator<Item = (usize, &T)> {
+  list.iter().enumerate().filter_map(|(i, entry)| entry.as_ref().map(|entry| (i, entry)))
+}
 
-    Inode {
-      single: create_tlist(),
-      double: create_tlist(),
-      size: 0,
+fn is_empty<T>(list: &TList<T>) -> bool {
+  list.iter().all(Option::is_none)
+}
 
-      mod_time: time_now,
-      access_time: time_now,
-      create_time: time_now
-    }
-  }
+// Frees the pages in `list` from the `keep`th on. Returns how many there were.
+fn free_pages(list: &mut EntryList, keep: usize) -> usize {
+  let start = keep.min(list.len());
+  list[start..].iter_mut().filter_map(Option::take).count()
+}
 
-  fn get_or_alloc_page<'a>(&'a mut self, num: usize) -> &'a mut Page {
-    if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-      panic!("Maximum file size exceeded!")
-    };
+impl BlockLists {
+  fn get_or_alloc_page(&mut self, a: &Allocator, num: usize) -> &mut Page {
+    let g = a.geometry();
+    // `Inode::write` never writes past the largest file.
+    assert!(num < g.max_pages(), "page {} is past the largest file", num);
 
-    // Getting a pointer to the page
-    let page = if num < LIST_SIZE {
+    let (list_size, list_shift) = (g.list_size(), g.list_shift());
+
+    // Getting a pointer to the page. Entry lists are allocated where necessary.
+    let page = if num < g.single_pages() {
       // if the page num is in the singly-indirect list
-      &mut self.single[num]
+      let single = self.single.get_or_insert_with(|| a.alloc_list());
+      &mut single[num]
+    } else if num < g.single_pages() + g.double_pages() {
+      // if the page num is in the doubly-indirect list
+      let double_entry = num - g.single_pages();
+      let double = self.double.get_or_insert_with(|| a.alloc_list());
+
+      let slot = double_entry >> list_shift;
+      let entry_list = double[slot].get_or_insert_with(|| a.alloc_list());
+
+      let entry_offset = double_entry & (list_size - 1);
+      &mut entry_list[entry_offset]
     } else {
-      // if the page num is in the doubly-indirect list. We allocate a new
-      // entry list where necessary (*entry_list = ...)
-      let double_entry = num - LIST_SIZE;
-      let slot = double_entry / LIST_SIZE;
-      let entry_list = &mut self.double[slot];
+      // if the page num is in the triply-indirect list
+      let triple_entry = num - g.single_pages() - g.double_pages();
+      let triple = self.triple.get_or_insert_with(|| a.alloc_list());
 
-      match *entry_list {
-        None => *entry_list = Some(create_tlist()),
-        _ => { /* Do nothing */ }
-      }
+      let slot = triple_entry >> (2 * list_shift);
+      let double_list = triple[slot].get_or_insert_with(|| a.alloc_list());
 
-      let entry_offset = double_entry % LIST_SIZE;
-      &mut entry_list.as_mut().unwrap()[entry_offset]
+      let double_slot = (triple_entry >> list_shift) & (list_size - 1);
+      let entry_list = double_list[double_slot].get_or_insert_with(|| a.alloc_list());
+
+      let entry_offset = triple_entry & (list_size - 1);
+      &mut entry_list[entry_offset]
     };
 
-    match *page {
-      None => *page = Some(Box::new([0u8; 4096])),
-      _ => { /* Do Nothing */ }
+    page.get_or_insert_with(|| a.alloc_page())
+  }
+
+  // Returns `None` for pages that were never written, ie, holes in the file.
+  fn get_page(&self, g: Geometry, num: usize) -> Option<&Page> {
+    if num >= g.max_pages() {
+      return None;
     }
 
-    page.as_mut().unwrap()
+    let (list_size, list_shift) = (g.list_size(), g.list_shift());
+    if num < g.single_pages() {
+      self.single.as_ref()
+        .and_then(|single| single[num].as_ref())
+    } else if num < g.single_pages() + g.double_pages() {
+      let double_entry = num - g.single_pages();
+      let slot = double_entry >> list_shift;
+      let entry_offset = double_entry & (list_size - 1);
+
+      self.double.as_ref()
+        .and_then(|double| double[slot].as_ref())
+        .and_then(|entry_list| entry_list[entry_offset].as_ref())
+    } else {
+      let triple_entry = num - g.single_pages() - g.double_pages();
+      let slot = triple_entry >> (2 * list_shift);
+      let double_slot = (triple_entry >> list_shift) & (list_size - 1);
+      let entry_offset = triple_entry & (list_size - 1);
+
+      self.triple.as_ref()
+        .and_then(|triple| triple[slot].as_ref())
+        .and_then(|double_list| double_list[double_slot].as_ref())
+        .and_then(|entry_list| entry_list[entry_offset].as_ref())
+    }
   }
 
-  fn get_page<'a>(&'a self, num: usize) -> &'a Option<Page> {
-    if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-      panic!("Page does not exist.")
-    };
+  // Calls `f` with the number and contents of every page that's been written,
+  // in order.
+  fn for_each_page<F: FnMut(usize, &Page)>(&self, g: Geometry, mut f: F) {
+    let list_size = g.list_size();
+    if let Some(ref single) = self.single {
+      for (i, page) in present(single) { f(i, page) }
+    }
 
-    if num < LIST_SIZE {
-      &self.single[num]
-    } else {
-      let double_entry = num - LIST_SIZE;
-      let slot = double_entry / LIST_SIZE;
-      let entry_offset = double_entry % LIST_SIZE;
-      let entry_list = &self.double[slot];
+    let base = g.single_pages();
+    if let Some(ref double) = self.double {
+      for (i, list) in present(double) {
+        for (j, page) in present(list) { f(base + i * list_size + j, page) }
+      }
+    }
 
-      match *entry_list {
-        None => panic!("Page does not exist."),
-        _ => &entry_list.as_ref().unwrap()[entry_offset]
+    let base = g.single_pages() + g.double_pages();
+    if let Some(ref triple) = self.triple {
+      for (i, double) in present(triple) {
+        for (j, list) in present(double) {
+          for (k, page) in present(list) { f(base + (i * list_size + j) * list_size + k, page) }
+        }
       }
     }
   }
 
-  pub fn write(&mut self, offset: usize, data: &[u8]) -> usize {
+  // Number of lists that are allocated but hold nothing.
+  fn empty_lists(&self) -> usize {
+    let mut count = 0;
+    if let Some(ref single) = self.single {
+      count += is_empty(single) as usize;
+    }
+
+    if let Some(ref double) = self.double {
+      count += is_empty(double) as usize;
+      count += present(double).filter(|&(_, list)| is_empty(list)).count();
+    }
+
+    if let Some(ref triple) = self.triple {
+      count += is_empty(triple) as usize;
+      for (_, double) in present(triple) {
+        count += is_empty(double) as usize;
+        count += present(double).filter(|&(_, list)| is_empty(list)).count();
+      }
+    }
+
+    count
+  }
+
+  // Frees every page from the `pages`th on, then every list left empty.
+  // Returns how many pages and lists were freed.
+  fn trim(&mut self, g: Geometry, pages: usize) -> (usize, usize) {
+    let list_size = g.list_size();
+    let (mut freed_pages, mut freed_lists) = (0, 0);
+    if let Some(ref mut single) = self.single {
+      freed_pages += free_pages(single, pages);
+    }
+
+    let base = g.single_pages();
+    if let Some(ref mut double) = self.double {
+      for (i, slot) in double.iter_mut().enumerate() {
+        if let Some(ref mut list) = *slot {
+          freed_pages += free_pages(list, pages.saturating_sub(base + i * list_size));
+        }
+
+        if slot.as_ref().is_some_and(is_empty) { *slot = None; freed_lists += 1; }
+      }
+    }
+
+    let base = g.single_pages() + g.double_pages();
+    if let Some(ref mut triple) = self.triple {
+      for (i, double_slot) in triple.iter_mut().enumerate() {
+        if let Some(ref mut double) = *double_slot {
+          for (j, slot) in double.iter_mut().enumerate() {
+            if let Some(ref mut list) = *slot {
+              freed_pages += free_pages(list, pages.saturating_sub(base + (i * list_size + j) * list_size));
+            }
+
+            if slot.as_ref().is_some_and(is_empty) { *slot = None; freed_lists += 1; }
+          }
+        }
+
+        if double_slot.as_ref().is_some_and(is_empty) { *double_slot = None; freed_lists += 1; }
+      }
+    }
+
+    if self.single.as_ref().is_some_and(is_empty) { self.single = None; freed_lists += 1; }
+    if self.double.as_ref().is_some_and(is_empty) { self.double = None; freed_lists += 1; }
+    if self.triple.as_ref().is_some_and(is_empty) { self.triple = None; freed_lists += 1; }
+
+    (freed_pages, freed_lists)
+  }
+
+  fn write(&mut self, a: &Allocator, offset: usize, data: &[u8]) -> usize {
+    let g = a.geometry();
+    let page_size = g.page_size();
     let mut written = 0;
-    let mut block_offset = offset % PAGE_SIZE; // offset from first block
+    let mut block_offset = offset & (page_size - 1); // offset from first block
 
-    let start = offset / PAGE_SIZE; // first block to act on
-    let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+    let start = offset >> g.page_shift; // first block to act on
+    let blocks_to_act_on = (block_offset + data.len()).div_ceil(page_size);
 
     for i in 0..blocks_to_act_on {
       // Resetting the block offset after first pass since we want to read from
@@ -119,11 +342,11 @@ impl Inode {
       let num_bytes = if i == blocks_to_act_on - 1 {
         data.len() - written
       } else {
-        PAGE_SIZE - block_offset
+        page_size - block_offset
       };
 
       // Finding our block, writing to it
-      let mut page = self.get_or_alloc_page(start + i);
+      let page = self.get_or_alloc_page(a, start + i);
       let slice = &mut page[block_offset..(block_offset + num_bytes)];
       // written += slice.copy_from(data.slice(written, written + num_bytes));
       unsafe {
@@ -135,21 +358,15 @@ impl Inode {
       written += num_bytes;
     }
 
-    let last_byte = offset + written;
-    if self.size < last_byte { self.size = last_byte; }
-
-    let time_now = time::get_time();
-    self.mod_time = time_now;
-    self.access_time = time_now;
-
     written
   }
 
-  pub fn read(&self, offset: usize, data: &mut [u8]) -> usize {
+  fn read(&self, g: Geometry, offset: usize, data: &mut [u8]) -> usize {
+    let page_size = g.page_size();
     let mut read = 0;
-    let mut block_offset = offset % PAGE_SIZE; // offset from first block
-    let start = offset / PAGE_SIZE; // first block to act on
-    let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+    let mut block_offset = offset & (page_size - 1); // offset from first block
+    let start = offset >> g.page_shift; // first block to act on
+    let blocks_to_act_on = (block_offset + data.len()).div_ceil(page_size);
 
     for i in 0..blocks_to_act_on {
       // Resetting the block offset after first pass since we want to read from
@@ -160,22 +377,20 @@ impl Inode {
       let num_bytes = if i == blocks_to_act_on - 1 {
         data.len() - read
       } else {
-        PAGE_SIZE - block_offset
-      };
-
-      // Finding our block, reading from it
-      let page = match self.get_page(start + i) {
-        &None => panic!("Empty data."),
-        &Some(ref pg) => pg
+        page_size - block_offset
       };
 
+      // Finding our block, reading from it. Holes read back as zeroes.
       let slice = &mut data[read..(read + num_bytes)];
-      // read += slice.copy_from(page.slice(block_offset,
-      // block_offset + num_bytes));
-      unsafe {
-        // copy_from is extremely slow! use copy_memory instead
-        let src = page[block_offset..(block_offset + num_bytes)].as_ptr();
-        copy_nonoverlapping(src, slice.as_mut_ptr(), num_bytes);
+      match self.get_page(g, start + i) {
+        None => for byte in slice.iter_mut() { *byte = 0 },
+        Some(page) => unsafe {
+          // read += slice.copy_from(page.slice(block_offset,
+          // block_offset + num_bytes));
+          // copy_from is extremely slow! use copy_memory instead
+          let src = page[block_offset..(block_offset + num_bytes)].as_ptr();
+          copy_nonoverlapping(src, slice.as_mut_ptr(), num_bytes);
+        }
       }
 
       read += num_bytes;
@@ -183,21 +398,241 @@ impl Inode {
 
     read
   }
+}
+
+impl Default for Inode {
+  fn default() -> Inode {
+    Inode::new()
+  }
+}
+
+impl Inode {
+  pub fn new() -> Inode {
+    Inode::with_geometry(Geometry::default())
+  }
+
+  // An inode whose pages and block lists come straight from the heap.
+  pub fn with_geometry(geometry: Geometry) -> Inode {
+    Inode::with_allocator(Allocator::new(geometry, AllocKind::Boxed))
+  }
+
+  pub fn with_allocator(allocator: Allocator) -> Inode {
+    let time_now = time::get_time();
+
+    Inode {
+      blocks: Blocks::Inline([0u8; INLINE_SIZE]),
+      allocator,
+      size: 0,
+
+      mod_time: time_now,
+      access_time: time_now,
+      create_time: time_now,
+
+      mode: DEFAULT_FILE_MODE,
+      uid: 0,
+      gid: 0,
+      xattrs: None
+    }
+  }
+
+  // Moves inline data into the first page so the file can grow past
+  // INLINE_SIZE. Does nothing if the file is already using block lists.
+  fn spill<'a>(blocks: &'a mut Blocks, a: &Allocator, size: usize) -> &'a mut BlockLists {
+    if let Blocks::Inline(inline) = *blocks {
+      let mut lists = BlockLists::default();
+      if size > 0 {
+        lists.write(a, 0, &inline[..size]);
+      }
+
+      *blocks = Blocks::Lists(lists);
+    }
+
+    match *blocks {
+      Blocks::Lists(ref mut lists) => lists,
+      Blocks::Inline(_) => unreachable!()
+    }
+  }
+
+  // Writes `data` at `offset`, as much of it as fits below the largest a
+  // file can grow, and returns how much that was.
+  pub fn write(&mut self, offset: usize, data: &[u8]) -> usize {
+    let max = self.allocator.geometry().max_file_size();
+    let last_byte = offset.checked_add(data.len()).map_or(max, |end| end.min(max));
+    if offset >= last_byte {
+      return 0;
+    }
+
+    let data = &data[..last_byte - offset];
+    let written = match self.blocks {
+      Blocks::Inline(ref mut inline) if last_byte <= INLINE_SIZE => {
+        inline[offset..last_byte].copy_from_slice(data);
+        data.len()
+      }
+      _ => {
+        let lists = Inode::spill(&mut self.blocks, &self.allocator, self.size);
+        lists.write(&self.allocator, offset, data)
+      }
+    };
+
+    if self.size < last_byte { self.size = last_byte; }
+
+    let time_now = time::get_time();
+    self.mod_time = time_now;
+    self.access_time = time_now;
+
+    written
+  }
+
+  pub fn read(&self, offset: usize, data: &mut [u8]) -> usize {
+    match self.blocks {
+      Blocks::Lists(ref lists) => lists.read(self.allocator.geometry(), offset, data),
+      Blocks::Inline(ref inline) => {
+        // Anything past the inline buffer is a hole.
+        let start = offset.min(INLINE_SIZE);
+        let end = offset.saturating_add(data.len()).min(INLINE_SIZE);
+        let (head, tail) = data.split_at_mut(end - start);
+        head.copy_from_slice(&inline[start..end]);
+        for byte in tail.iter_mut() { *byte = 0 }
+        data.len()
+      }
+    }
+  }
 
   pub fn size(&self) -> usize {
     self.size
   }
 
+  // Calls `f` with the offset and contents of every stretch of the file that
+  // isn't a hole, in order: the inline data, or each page that's been written,
+  // cut off at the end of the file.
+  pub fn extents<F: FnMut(usize, &[u8])>(&self, mut f: F) {
+    match self.blocks {
+      Blocks::Inline(ref inline) => {
+        let len = self.size.min(INLINE_SIZE);
+        if len > 0 { f(0, &inline[..len]) }
+      }
+      Blocks::Lists(ref lists) => {
+        let (g, size) = (self.allocator.geometry(), self.size);
+        lists.for_each_page(g, |num, page| {
+          let offset = num << g.page_shift;
+          if offset < size {
+            f(offset, &page[..g.page_size().min(size - offset)]);
+          }
+        });
+      }
+    }
+  }
+
+  // Number of pages allocated past the end of the file. There should be none.
+  pub fn pages_past_end(&self) -> usize {
+    match self.blocks {
+      Blocks::Inline(_) => 0,
+      Blocks::Lists(ref lists) => {
+        let g = self.allocator.geometry();
+        let pages = self.size.div_ceil(g.page_size());
+        let mut count = 0;
+        lists.for_each_page(g, |num, _| if num >= pages { count += 1 });
+        count
+      }
+    }
+  }
+
+  // Number of block lists allocated with nothing in them. There should be
+  // none: lists are only allocated to hold a page.
+  pub fn empty_lists(&self) -> usize {
+    match self.blocks {
+      Blocks::Inline(_) => 0,
+      Blocks::Lists(ref lists) => lists.empty_lists()
+    }
+  }
+
+  // Frees any pages past the end of the file and any empty block lists.
+  // Returns how many pages and lists were freed.
+  pub fn trim(&mut self) -> (usize, usize) {
+    match self.blocks {
+      Blocks::Inline(_) => (0, 0),
+      Blocks::Lists(ref mut lists) => {
+        let g = self.allocator.geometry();
+        lists.trim(g, self.size.div_ceil(g.page_size()))
+      }
+    }
+  }
+
+  // Sets the size and times, as from `size` and `stat`, without touching the
+  // data. For restoring an inode that's been saved.
+  pub fn set_stat(&mut self, size: usize, (create, access, modify): (Timespec, Timespec, Timespec)) {
+    self.size = size;
+    self.create_time = create;
+    self.access_time = access;
+    self.mod_time = modify;
+  }
+
+  pub fn geometry(&self) -> Geometry {
+    self.allocator.geometry()
+  }
+
   pub fn stat(&self) -> (Timespec, Timespec, Timespec) {
     (self.create_time, self.access_time, self.mod_time)
   }
+
+  pub fn mode(&self) -> u32 {
+    self.mode
+  }
+
+  pub fn set_mode(&mut self, mode: u32) {
+    self.mode = mode;
+  }
+
+  pub fn is_symlink(&self) -> bool {
+    self.mode & S_IFMT == S_IFLNK
+  }
+
+  // The target, if this is a symbolic link.
+  pub fn link_target(&self) -> Option<String> {
+    if !self.is_symlink() {
+      return None;
+    }
+
+    let mut target = vec![0; self.size];
+    self.read(0, &mut target);
+    Some(String::from_utf8_lossy(&target).into_owned())
+  }
+
+  // The owning user and group ids.
+  pub fn owner(&self) -> (u32, u32) {
+    (self.uid, self.gid)
+  }
+
+  pub fn set_owner(&mut self, uid: u32, gid: u32) {
+    self.uid = uid;
+    self.gid = gid;
+  }
+
+  pub fn xattrs(&self) -> Option<&Xattrs> {
+    self.xattrs.as_deref()
+  }
+
+  pub fn set_xattr(&mut self, name: &str, value: &[u8]) {
+    self.xattrs.get_or_insert_with(Default::default).insert(name.to_string(), value.to_vec());
+  }
+
+  pub fn remove_xattr(&mut self, name: &str) -> Option<Vec<u8>> {
+    let xattrs = self.xattrs.as_mut()?;
+    let value = xattrs.remove(name);
+    if xattrs.is_empty() {
+      self.xattrs = None;
+    }
+
+    value
+  }
 }
 
 #[cfg(test)]
 mod tests {
   extern crate rand;
 
-  use super::{Inode};
+  use super::{Inode, Blocks, Geometry, INLINE_SIZE};
+  use std::io;
   use self::rand::random;
   use time;
 
@@ -229,4 +664,152 @@ mod tests {
     let (create, _, _) = inode.stat();
     assert_eq!(create.sec, time_now.sec);
   }
+
+  #[test]
+  fn test_sparse_read() {
+    const SIZE: usize = 4096 * 2 + 1234;
+    const OFFSET: usize = 4096 * (256 + 256 * 256) + 77;
+
+    let original_data = rand_array(SIZE);
+    let mut inode = Inode::new();
+    let mut buf = vec![0u8; SIZE];
+
+    // Write into the triply-indirect range, leaving everything before a hole
+    inode.write(OFFSET, &original_data);
+    assert_eq!(OFFSET + SIZE, inode.size());
+
+    inode.read(OFFSET, &mut buf);
+    assert_eq!(buf, original_data);
+
+    // Holes, in every level of indirection, read back as zeroes
+    for &offset in [0, 4096 * 256, OFFSET - SIZE].iter() {
+      inode.read(offset, &mut buf);
+      assert!(buf.iter().all(|&byte| byte == 0));
+    }
+  }
+
+  #[test]
+  fn test_inline_write() {
+    let original_data = rand_array(INLINE_SIZE + 1);
+    let mut inode = Inode::new();
+    let mut buf = [0u8; INLINE_SIZE + 1];
+
+    // An empty file, and one that fits, doesn't allocate any block lists
+    assert!(matches!(inode.blocks, Blocks::Inline(_)));
+    inode.write(0, &original_data[..10]);
+    inode.write(10, &original_data[10..INLINE_SIZE]);
+    assert!(matches!(inode.blocks, Blocks::Inline(_)));
+    assert_eq!(INLINE_SIZE, inode.size());
+
+    inode.read(0, &mut buf);
+    assert_eq!(&buf[..INLINE_SIZE], &original_data[..INLINE_SIZE]);
+    assert_eq!(buf[INLINE_SIZE], 0);
+
+    // One more byte moves the data out into a page
+    inode.write(INLINE_SIZE, &original_data[INLINE_SIZE..]);
+    match inode.blocks {
+      Blocks::Lists(ref lists) => {
+        assert!(lists.single.is_some());
+        assert!(lists.double.is_none() && lists.triple.is_none());
+      }
+      Blocks::Inline(_) => panic!("data should have been moved out of the inode")
+    }
+
+    inode.read(0, &mut buf);
+    assert_eq!(&buf[..], &original_data[..]);
+    assert_eq!(INLINE_SIZE + 1, inode.size());
+  }
+
+  #[test]
+  fn test_trim() {
+    let g = Geometry::new(512);
+    let mut inode = Inode::with_geometry(g);
+    let (single_end, list_size) = (256 * 512, 256);
+    inode.write(0, &[1; 1000]);
+    inode.write(single_end + list_size * 512 * 3, &[2; 10]);
+    inode.write(g.max_file_size() - 10, &[3; 10]);
+    assert_eq!((inode.pages_past_end(), inode.empty_lists()), (0, 0));
+
+    // Shrinking the file leaves the pages past the new end allocated.
+    let stat = inode.stat();
+    inode.set_stat(600, stat);
+    assert_eq!(inode.pages_past_end(), 2);
+    assert_eq!(inode.trim(), (2, 5));
+    assert_eq!((inode.pages_past_end(), inode.empty_lists()), (0, 0));
+
+    let mut buf = [0u8; 600];
+    inode.read(0, &mut buf);
+    assert!(buf.iter().all(|&byte| byte == 1));
+
+    // Lists that hold nothing are counted and freed too.
+    if let Blocks::Lists(ref mut lists) = inode.blocks {
+      lists.double = Some(inode.allocator.alloc_list());
+    }
+
+    assert_eq!(inode.empty_lists(), 1);
+    assert_eq!(inode.trim(), (0, 1));
+    assert_eq!(inode.empty_lists(), 0);
+  }
+
+  #[test]
+  fn test_geometry() {
+    let default = Geometry::default();
+    assert_eq!(default.page_size(), 4096);
+    assert_eq!(default.list_size(), 256);
+    assert_eq!(default.max_file_size(), 4096 * (256 + 256 * 256 + 256 * 256 * 256));
+
+    // The fan-out doesn't shrink with the page size, so the smallest pages
+    // still reach well past the 257 MiB two levels of 4 KiB pages did.
+    let small = Geometry::new(512);
+    assert_eq!(small.list_size(), 256);
+    assert_eq!(small.max_pages(), 256 + 256 * 256 + 256 * 256 * 256);
+    assert!(small.max_file_size() > 4096 * (256 + 256 * 256));
+
+    let large = Geometry::new(65536);
+    assert_eq!(large.list_size(), 256);
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_bad_page_size() {
+    Geometry::new(3000);
+  }
+
+  #[test]
+  fn test_try_new() {
+    assert_eq!(Geometry::try_new(512).unwrap().page_size(), 512);
+    for &size in [0, 256, 3000, 131072].iter() {
+      assert_eq!(Geometry::try_new(size).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+  }
+
+  #[test]
+  fn test_page_sizes() {
+    const SIZE: usize = 3 * 512 + 123;
+    let original_data = rand_array(SIZE);
+    let mut buf = vec![0u8; SIZE];
+
+    for shift in 9..17 {
+      let g = Geometry::new(1 << shift);
+      let mut inode = Inode::with_geometry(g);
+
+      // Straddle the end of each level of indirection
+      let single_end = g.list_size() * g.page_size();
+      let double_end = single_end + g.list_size() * single_end;
+      for &end in [single_end, double_end, g.max_file_size()].iter() {
+        let offset = end - SIZE / 2;
+        let data = if end == g.max_file_size() {
+          &original_data[..SIZE / 2]
+        } else {
+          &original_data[..]
+        };
+
+        inode.write(offset, data);
+        inode.read(offset, &mut buf[..data.len()]);
+        assert_eq!(&buf[..data.len()], data);
+      }
+
+      assert_eq!(g.max_file_size(), inode.size());
+    }
+  }
 }
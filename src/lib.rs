@@ -1,101 +1,616 @@
 extern crate time;
 
+mod alloc;
+mod deflate;
+mod archive;
 mod directory;
 mod file;
 mod inode;
+mod mount;
+mod overlay;
+pub mod check;
+#[cfg(unix)]
+pub mod host;
+pub mod image;
+pub mod trace;
+pub mod strace;
+pub mod tar;
+pub mod zip;
 
 use file::{File, FileHandle};
-use file::File::{EmptyFile, DataFile, Directory};
+use file::File::{EmptyFile, DataFile, Directory, Lower};
+use std::borrow::Cow;
 use std::rc::Rc;
 use std::cell::{RefCell};
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
 use directory::DirectoryHandle;
+use alloc::Allocator;
+use mount::{Mount, Mounted};
+use trace::{Call, Recorder};
+pub use alloc::AllocKind;
 pub use file::Whence;
-pub use inode::Inode;
+pub use mount::Filesystem;
+pub use inode::{Inode, Geometry, Xattrs, MIN_PAGE_SIZE, MAX_PAGE_SIZE, DEFAULT_PAGE_SIZE};
+pub use inode::{S_IFMT, S_IFREG, S_IFDIR, S_IFLNK, DEFAULT_FILE_MODE, DEFAULT_DIR_MODE, DEFAULT_LINK_MODE};
+use time::Timespec;
 
 pub type FileDescriptor = isize;
 
-pub const O_RDONLY: u32 =   (1 << 0);
-pub const O_WRONLY: u32 =   (1 << 1);
-pub const O_RDWR: u32 =     (1 << 2);
-pub const O_NONBLOCK: u32 = (1 << 3);
-pub const O_APPEND: u32 =   (1 << 4);
-pub const O_CREAT: u32 =    (1 << 5);
+pub const O_RDONLY: u32 =   1 << 0;
+pub const O_WRONLY: u32 =   1 << 1;
+pub const O_RDWR: u32 =     1 << 2;
+pub const O_NONBLOCK: u32 = 1 << 3;
+pub const O_APPEND: u32 =   1 << 4;
+pub const O_CREAT: u32 =    1 << 5;
+
+// How many symbolic links `open` follows before giving up on a path.
+const MAX_SYMLINKS: usize = 40;
+
+/**
+ * What there is to know about a file, directory, or symbolic link besides
+ * its contents. Directories have no times; theirs are all zero.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+  pub mode: u32,
+  pub size: usize,
+  pub uid: u32,
+  pub gid: u32,
+  pub created: Timespec,
+  pub accessed: Timespec,
+  pub modified: Timespec,
+  pub xattrs: Xattrs,
+}
+
+impl Metadata {
+  pub fn is_file(&self) -> bool {
+    self.mode & S_IFMT == S_IFREG
+  }
+
+  pub fn is_dir(&self) -> bool {
+    self.mode & S_IFMT == S_IFDIR
+  }
+
+  pub fn is_symlink(&self) -> bool {
+    self.mode & S_IFMT == S_IFLNK
+  }
+}
+
+// Where the symbolic link at `link` points when its target is `target`. Names
+// are flat, but targets are resolved as if the slashes in them were
+// directories, so that links unpacked from an archive keep working.
+fn resolve_link(link: &str, target: &str) -> String {
+  let mut parts: Vec<&str> = match target.starts_with('/') {
+    true => Vec::new(),
+    false => link.split('/').collect()
+  };
+
+  parts.pop();
+  for part in target.split('/') {
+    match part {
+      "" | "." => {}
+      ".." => { parts.pop(); }
+      _ => parts.push(part)
+    }
+  }
+
+  parts.join("/")
+}
 
 pub struct Proc<'r> {
+  allocator: Allocator,
   cwd: File<'r>,
   fd_table: HashMap<FileDescriptor, FileHandle<'r>>,
-  fds: Vec<FileDescriptor>
+  fds: Vec<FileDescriptor>,
+  mounts: Vec<Mount<'r>>,
+  recorder: RefCell<Option<Recorder>>
+}
+
+impl<'r> Default for Proc<'r> {
+  fn default() -> Proc<'r> {
+    Proc::new()
+  }
 }
 
 impl<'r> Proc<'r> {
   pub fn new() -> Proc<'r> {
+    Proc::with_page_size(DEFAULT_PAGE_SIZE)
+  }
+
+  // Creates a file system whose files are stored in `page_size` byte pages.
+  // Panics if `page_size` isn't a power of two between MIN_PAGE_SIZE and
+  // MAX_PAGE_SIZE.
+  pub fn with_page_size(page_size: usize) -> Proc<'r> {
+    Proc::with_allocator(page_size, AllocKind::default())
+  }
+
+  // Like `with_page_size`, but also picks where the file system's inodes,
+  // block lists, and pages are allocated from.
+  pub fn with_allocator(page_size: usize, kind: AllocKind) -> Proc<'r> {
+    Proc::with_geometry(Geometry::new(page_size), kind)
+  }
+
+  // Like `with_allocator`, but fails with InvalidInput on a bad page size
+  // instead of panicking.
+  pub fn try_with_allocator(page_size: usize, kind: AllocKind) -> io::Result<Proc<'r>> {
+    Ok(Proc::with_geometry(Geometry::try_new(page_size)?, kind))
+  }
+
+  fn with_geometry(geometry: Geometry, kind: AllocKind) -> Proc<'r> {
     Proc {
+      allocator: Allocator::new(geometry, kind),
       cwd: File::new_dir(None),
       fd_table: HashMap::new(),
       fds: (0..(256 - 2)).map(|i| 256 - i).collect(),
+      mounts: Vec::new(),
+      recorder: RefCell::new(None)
+    }
+  }
+
+  pub fn geometry(&self) -> Geometry {
+    self.allocator.geometry()
+  }
+
+  pub fn alloc_kind(&self) -> AllocKind {
+    self.allocator.kind()
+  }
+
+  // Returns the memory the file system's pools hold but aren't using to the
+  // system.
+  pub fn shrink_to_fit(&self) {
+    self.allocator.shrink_to_fit()
+  }
+
+  // Saves every file to an image at `path`; see the `image` module.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    image::write_image(self, BufWriter::new(fs::File::create(path)?))
+  }
+
+  // Loads the image at `path` into a new file system with the image's page
+  // size and the default allocator.
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Proc<'r>> {
+    image::read_image(BufReader::new(fs::File::open(path)?), AllocKind::default())
+  }
+
+  // Records every call from now on to `out` as a trace; see the `trace`
+  // module. Replaces any recording already going on without finishing it.
+  pub fn start_recording<W: Write + 'static>(&mut self, out: W) -> io::Result<()> {
+    *self.recorder.get_mut() = Some(Recorder::new(Box::new(out))?);
+    Ok(())
+  }
+
+  // Stops recording, flushing the trace. Returns the first error writing it.
+  pub fn stop_recording(&mut self) -> io::Result<()> {
+    match self.recorder.get_mut().take() {
+      Some(recorder) => recorder.finish(),
+      None => Ok(())
+    }
+  }
+
+  #[inline(always)]
+  fn record<F: FnOnce() -> Call>(&self, call: F) {
+    if let Some(ref mut recorder) = *self.recorder.borrow_mut() {
+      recorder.record(call());
     }
   }
 
   #[inline(always)]
   fn extract_fd(fd_opt: &Option<FileDescriptor>) -> FileDescriptor {
-    match fd_opt {
-      &Some(fd) => fd,
-      &None => panic!("Error in FD allocation.")
+    match *fd_opt {
+      Some(fd) => fd,
+      None => panic!("Error in FD allocation.")
     }
   }
 
+  // What's at `name`: what's in the file system mounted there, if there is
+  // one; what a union's layers have, if it's in one; or what's in memory, or
+  // else what's in a lower layer underneath.
+  fn lookup(&self, name: &str) -> Option<File<'r>> {
+    match self.mount_of(name) {
+      Some((i, at)) => self.mounts[i].lookup(&self.allocator, &name[at..]),
+      None if self.in_union(name) => self.lookup_lower(name),
+      None => self.cwd.get(name).or_else(|| self.lookup_lower(name))
+    }
+  }
+
+  // Creates an empty file at `name`, in the file system mounted there if
+  // there is one. None if it can't be created there.
+  fn create_file(&mut self, name: Cow<'r, str>) -> Option<File<'r>> {
+    if let Some((i, at)) = self.mount_of(&name) {
+      let relative = match name {
+        Cow::Borrowed(name) => Cow::Borrowed(&name[at..]),
+        Cow::Owned(name) => Cow::Owned(name[at..].to_string())
+      };
+
+      return self.mounts[i].create(&self.allocator, relative);
+    }
+
+    let file = File::new_data_file(Rc::new(RefCell::new(self.allocator.alloc_inode())));
+    let name = self.upper_name(name, false);
+    self.cwd.insert(name, file.clone());
+    Some(file)
+  }
+
+  // Follows symbolic links from `path`. Returns the name it ends up at and
+  // what's there, or None if there are too many links to follow.
+  fn follow<'p>(&self, path: &'p str) -> Option<(Cow<'p, str>, Option<File<'r>>)> {
+    let mut name = Cow::Borrowed(path);
+    for _ in 0..=MAX_SYMLINKS {
+      let file = self.lookup(&name);
+      let target = match file {
+        Some(DataFile(ref inode)) => inode.borrow().link_target(),
+        _ => None
+      };
+
+      match target {
+        Some(target) => name = Cow::Owned(resolve_link(&name, &target)),
+        None => return Some((name, file))
+      }
+    }
+
+    None
+  }
+
   pub fn open(&mut self, path: &'r str, flags: u32) -> FileDescriptor {
-    let lookup = self.cwd.get(path);
-    let file = match lookup {
-      Some(f) => f,
-      None => {
-        if (flags & O_CREAT) != 0 {
-          // FIXME: Fetch from allocator
-          let rcinode = Rc::new(RefCell::new(Box::new(Inode::new())));
-          let file = File::new_data_file(rcinode);
-          self.cwd.insert(path, file.clone());
-          file
-        } else {
-          EmptyFile
-        }
+    let (name, file) = match self.follow(path) {
+      Some((name, Some(f))) => (name, f),
+      Some((name, None)) if (flags & O_CREAT) != 0 => {
+        let file = self.create_file(name.clone()).unwrap_or(EmptyFile);
+        (name, file)
       }
+      _ => (Cow::Borrowed(path), EmptyFile)
     };
 
-    match file {
-      DataFile(_) => {
+    let fd = match file {
+      DataFile(_) | Lower(_) => {
         let fd = Proc::extract_fd(&self.fds.pop());
         let handle = FileHandle::new(file);
         self.fd_table.insert(fd, handle);
+        if let Some(i) = self.mount_of(&name).map(|(i, _)| i).or_else(|| self.union_of(&name)) {
+          self.mounts[i].fds.insert(fd);
+        }
+
         fd
       }
       Directory(_) => -1,
       EmptyFile => -2,
-    }
+    };
+
+    self.record(|| Call::Open { path: path.to_string(), flags, fd });
+    fd
   }
 
   pub fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> usize {
     let handle = self.fd_table.get(&fd).expect("fd does not exist");
-    handle.read(dst)
+    let offset = handle.position();
+    let result = handle.read(dst);
+    self.record(|| Call::Read { fd, offset, len: dst.len(), result });
+    result
   }
 
   pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> usize {
+    let lower = match *self.fd_table.get(&fd).expect("fd does not exist").file() {
+      Lower(ref lower) if !lower.writes_in_place() => Some(lower.clone()),
+      _ => None
+    };
+
+    // A union's files are copied up in the file system it's mounted in.
+    let mount = self.mounts.iter().position(|mount| mount.fds.contains(&fd) && !matches!(mount.mounted, Mounted::Layers(_)));
+    let read_only = mount.is_some_and(|i| self.mounts[i].read_only);
+    let copied = match (lower, mount) {
+      _ if read_only => Err(()),
+      (Some(lower), Some(i)) => self.mounts[i].copy_up(&lower).map(|inode| Some(DataFile(inode))).map_err(|_| ()),
+      (Some(lower), None) => self.copy_up(&lower).map(|inode| Some(DataFile(inode))).map_err(|_| ()),
+      (None, _) => Ok(None)
+    };
+
     let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-    handle.write(src)
+    let offset = handle.position();
+    let result = match copied {
+      Ok(Some(file)) => { handle.set_file(file); handle.write(src) }
+      Ok(None) => handle.write(src),
+      Err(()) => 0
+    };
+    self.record(|| Call::Write { fd, offset, len: src.len(), result });
+    result
   }
 
   pub fn seek(&mut self, fd: FileDescriptor, o: isize, whence: Whence) -> usize {
     let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-    handle.seek(o, whence)
+    let result = handle.seek(o, whence);
+    self.record(|| Call::Seek { fd, offset: o, whence, result });
+    result
   }
 
   pub fn close(&mut self, fd: FileDescriptor) {
     self.fd_table.remove(&fd);
     self.fds.push(fd);
+    for mount in self.mounts.iter_mut() {
+      mount.fds.remove(&fd);
+    }
+
+    self.record(|| Call::Close { fd });
   }
 
   pub fn unlink(&mut self, path: &'r str) {
-    self.cwd.remove(path);
+    match self.mount_of(path) {
+      Some((i, at)) => self.mounts[i].unlink(&path[at..]),
+      None => {
+        if !self.in_union(path) {
+          self.cwd.remove(path);
+        }
+
+        self.whiteout(path);
+      }
+    }
+
+    self.record(|| Call::Unlink { path: path.to_string() });
+  }
+
+  // Creates a symbolic link at `path` pointing to `target`. Returns false if
+  // there's already something at `path`.
+  pub fn symlink(&mut self, target: &str, path: &'r str) -> bool {
+    let ok = self.make_symlink(target, path);
+    self.record(|| Call::Symlink { target: target.to_string(), path: path.to_string(), ok });
+    ok
+  }
+
+  fn make_symlink(&mut self, target: &str, path: &'r str) -> bool {
+    if self.lookup(path).is_some() {
+      return false;
+    } else if let Some((i, at)) = self.mount_of(path) {
+      return self.mounts[i].symlink(target, &path[at..]);
+    }
+
+    let mut inode = self.allocator.alloc_inode();
+    inode.set_mode(DEFAULT_LINK_MODE);
+    inode.write(0, target.as_bytes());
+    let path = self.upper_name(path.into(), false);
+    self.cwd.insert(path, File::new_data_file(Rc::new(RefCell::new(inode))));
+    true
+  }
+
+  // Gives the file or symbolic link at `existing` another name, `path`.
+  // Fails if there's nothing to link to, a directory, or something at `path`
+  // already, and with CrossesDevices if they're on different file systems.
+  pub fn link(&mut self, existing: &str, path: &'r str) -> io::Result<()> {
+    let result = self.make_link(existing, path);
+    self.record(|| Call::Link { existing: existing.to_string(), path: path.to_string(), ok: result.is_ok() });
+    result
+  }
+
+  fn make_link(&mut self, existing: &str, path: &'r str) -> io::Result<()> {
+    match (self.mount_of(existing), self.mount_of(path)) {
+      (Some((i, from)), Some((j, at))) if i == j => return self.mounts[i].link(&existing[from..], &path[at..]),
+      (None, None) => {}
+      _ => return Err(Proc::cross_devices(existing, path))
+    }
+
+    let file = match (self.lookup(existing), self.lookup(path)) {
+      (None, _) | (Some(EmptyFile), _) => {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("there's nothing at '{}'", existing)));
+      }
+      (_, Some(_)) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists", path))),
+      (Some(Directory(_)), None) => {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("'{}' is a directory", existing)));
+      }
+      (Some(Lower(lower)), None) => DataFile(self.copy_up(&lower)?),
+      (Some(file), None) => file
+    };
+
+    let path = self.upper_name(path.into(), false);
+    self.cwd.insert(path, file);
+    Ok(())
+  }
+
+  // Moves whatever's at `from` to `to`, replacing what's there unless it's a
+  // directory with something in it. Moving a directory moves every name
+  // under it. Fails with CrossesDevices if they're on different file
+  // systems, or if a directory is in an overlay, as overlayfs does, and with
+  // ResourceBusy if something is mounted under a directory.
+  pub fn rename(&mut self, from: &'r str, to: &'r str) -> io::Result<()> {
+    let result = self.move_name(from, to);
+    self.record(|| Call::Rename { from: from.to_string(), to: to.to_string(), ok: result.is_ok() });
+    result
+  }
+
+  fn move_name(&mut self, from: &'r str, to: &'r str) -> io::Result<()> {
+    match (self.mount_of(from), self.mount_of(to)) {
+      (Some((i, at_from)), Some((j, at_to))) if i == j => return self.mounts[i].rename(&from[at_from..], &to[at_to..]),
+      (None, None) => {}
+      _ => return Err(Proc::cross_devices(from, to))
+    }
+
+    let file = match self.lookup(from) {
+      Some(EmptyFile) | None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("there's nothing at '{}'", from))),
+      Some(file) => file
+    };
+
+    let is_dir = |file: &File| matches!(*file, Directory(_));
+    match self.lookup(to) {
+      _ if from == to => return Ok(()),
+      Some(ref existing) if is_dir(existing) && !is_dir(&file) => {
+        return Err(io::Error::new(io::ErrorKind::IsADirectory, format!("'{}' is a directory", to)));
+      }
+      Some(ref existing) if !is_dir(existing) && is_dir(&file) => {
+        return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("'{}' isn't a directory", to)));
+      }
+      Some(ref existing) if is_dir(existing) && !self.read_dir(to).unwrap_or_default().is_empty() => {
+        return Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, format!("'{}' isn't empty", to)));
+      }
+      _ => {}
+    }
+
+    let file = match file {
+      Lower(lower) => DataFile(self.copy_up(&lower)?),
+      Directory(_) if to.starts_with(&format!("{}/", from)) => {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' can't be moved into itself", from)));
+      }
+      Directory(_) if self.overlaid(from) || self.overlaid(to) => {
+        return Err(Proc::cross_devices(from, to));
+      }
+      Directory(_) if self.mounts.iter().any(|mount| mount.point.starts_with(&format!("{}/", from))) => {
+        return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("something is mounted under '{}'", from)));
+      }
+      Directory(_) => {
+        let content = self.cwd.get_dir_rc().clone();
+        let names: Vec<String> = content.borrow().entries.keys()
+          .filter(|name| name.strip_prefix(from).is_some_and(|rest| rest.starts_with('/')))
+          .map(|name| name.to_string())
+          .collect();
+
+        for name in names {
+          let file = content.borrow_mut().entries.remove(name.as_str()).expect("listed");
+          self.cwd.insert(format!("{}{}", to, &name[from.len()..]), file);
+        }
+
+        self.cwd.remove(from);
+        self.cwd.insert(to, File::new_dir(None));
+        return Ok(());
+      }
+      file => file
+    };
+
+    if !self.in_union(from) {
+      self.cwd.remove(from);
+    }
+
+    self.whiteout(from);
+    let to = self.upper_name(to.into(), false);
+    self.cwd.insert(to, file);
+    Ok(())
+  }
+
+  fn cross_devices(from: &str, to: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::CrossesDevices, format!("'{}' and '{}' are on different file systems", from, to))
+  }
+
+  // The target of the symbolic link at `path`.
+  pub fn readlink(&self, path: &str) -> Option<String> {
+    let target = match self.lookup(path) {
+      Some(DataFile(ref inode)) => inode.borrow().link_target(),
+      _ => None
+    };
+
+    self.record(|| Call::Readlink { path: path.to_string(), ok: target.is_some() });
+    target
+  }
+
+  // Creates an empty directory at `path`. Names are flat, so it's only a
+  // marker: files named `path/...` aren't in it. Returns false if there's
+  // already something at `path`.
+  pub fn mkdir(&mut self, path: &'r str) -> bool {
+    let ok = self.make_dir(path);
+    self.record(|| Call::Mkdir { path: path.to_string(), ok });
+    ok
+  }
+
+  fn make_dir(&mut self, path: &'r str) -> bool {
+    if self.lookup(path).is_some() {
+      return false;
+    } else if let Some((i, at)) = self.mount_of(path) {
+      return self.mounts[i].mkdir(&path[at..]);
+    }
+
+    let path = self.upper_name(path.into(), true);
+    self.cwd.insert(path, File::new_dir(None));
+    true
+  }
+
+  // The names in the directory at `path` ("" for the top), sorted, or None
+  // if there's no directory there. Names are flat, so these are only the
+  // names one component longer than `path`.
+  pub fn read_dir(&self, path: &str) -> Option<Vec<String>> {
+    if let Some((i, at)) = self.mount_of(path) {
+      return self.mounts[i].read_dir(&path[at..]);
+    } else if !path.is_empty() {
+      match self.lookup(path) {
+        Some(Directory(_)) => {}
+        _ => return None
+      }
+    }
+
+    let mut names: Vec<String> = match self.in_union(path) {
+      true => Vec::new(),
+      false => self.children(path)
+    };
+    names.extend(self.lower_children(path));
+    names.sort();
+    names.dedup();
+    names.retain(|name| match path {
+      "" => self.lookup(name).is_some(),
+      _ => self.lookup(&format!("{}/{}", path, name)).is_some()
+    });
+
+    Some(names)
+  }
+
+  // The names one component longer than `directory` in memory.
+  fn children(&self, directory: &str) -> Vec<String> {
+    let content = self.cwd.get_dir_rc().borrow();
+    content.entries.keys().filter_map(|name| {
+      let child = match directory {
+        "" => &name[..],
+        _ => name.strip_prefix(directory)?.strip_prefix('/')?
+      };
+
+      match child.is_empty() || child.contains('/') {
+        true => None,
+        false => Some(child.to_string())
+      }
+    }).collect()
+  }
+
+  // The metadata of whatever's at `path`, following symbolic links.
+  pub fn metadata(&self, path: &str) -> Option<Metadata> {
+    let metadata = self.follow(path).and_then(|(_, file)| file).map(|file| Proc::metadata_of(&file));
+    self.record(|| Call::Metadata { path: path.to_string(), follow: true, ok: metadata.is_some() });
+    metadata
+  }
+
+  // The metadata of whatever's at `path`, or of the symbolic link itself if
+  // that's what's there.
+  pub fn symlink_metadata(&self, path: &str) -> Option<Metadata> {
+    let metadata = self.lookup(path).map(|file| Proc::metadata_of(&file));
+    self.record(|| Call::Metadata { path: path.to_string(), follow: false, ok: metadata.is_some() });
+    metadata
+  }
+
+  fn metadata_of(file: &File<'r>) -> Metadata {
+    match *file {
+      DataFile(ref inode) => {
+        let inode = inode.borrow();
+        let (created, accessed, modified) = inode.stat();
+        let (uid, gid) = inode.owner();
+        Metadata {
+          mode: inode.mode(),
+          size: inode.size(),
+          uid,
+          gid,
+          created,
+          accessed,
+          modified,
+          xattrs: inode.xattrs().cloned().unwrap_or_default()
+        }
+      }
+      Lower(ref lower) => lower.metadata(),
+      _ => {
+        let zero = Timespec::new(0, 0);
+        Metadata {
+          mode: DEFAULT_DIR_MODE,
+          size: 0,
+          uid: 0,
+          gid: 0,
+          created: zero,
+          accessed: zero,
+          modified: zero,
+          xattrs: Xattrs::new()
+        }
+      }
+    }
   }
 }
 
@@ -104,22 +619,22 @@ mod proc_tests {
   // extern crate test;
   extern crate rand;
 
-  use super::{Proc, O_RDWR, O_CREAT};
+  use super::{Proc, AllocKind, MAX_SYMLINKS, O_RDWR, O_CREAT};
   use file::Whence::SeekSet;
   use inode::Inode;
   use self::rand::random;
+  use std::cell::Cell;
+  use std::io::ErrorKind;
 
-  static mut test_inode_drop: bool = false;
+  // Thread local so that tests running in parallel don't trip each other.
+  thread_local!(static TEST_INODE_DROP: Cell<bool> = const { Cell::new(false) });
 
   impl Drop for Inode {
     fn drop(&mut self) {
-      unsafe {
-        if test_inode_drop {
-          test_inode_drop = false;
-          panic!("Dropping.");
-        } else {
-          println!("Dropping, but no flag.");
-        }
+      if TEST_INODE_DROP.with(|flag| flag.replace(false)) {
+        panic!("Dropping.");
+      } else {
+        println!("Dropping, but no flag.");
       }
     }
   }
@@ -138,8 +653,13 @@ mod proc_tests {
 
   #[test]
   fn simple_test() {
+    simple_test_with(AllocKind::Slab);
+    simple_test_with(AllocKind::Boxed);
+  }
+
+  fn simple_test_with(kind: AllocKind) {
     const SIZE: usize = 4096 * 8 + 3434;
-    let mut p = Proc::new();
+    let mut p = Proc::with_allocator(4096, kind);
     let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
@@ -177,15 +697,15 @@ mod proc_tests {
   #[should_panic]
   fn test_proc_drop_inode_dealloc() {
     // Variable is used to make sure that the Drop implemented is only valid for
-    // tests that set that test_inode_drop global variable to true.
-    unsafe { test_inode_drop = true; }
+    // tests that set the TEST_INODE_DROP flag to true.
+    TEST_INODE_DROP.with(|flag| flag.set(true));
 
     const SIZE: usize = 4096 * 3 + 3498;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
 
     let fd = p.open("file", O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
+    p.write(fd, &data);
   }
 
   /**
@@ -202,16 +722,16 @@ mod proc_tests {
   #[should_panic]
   fn test_inode_dealloc() {
     // Make sure flag is set to detect drop.
-    unsafe { test_inode_drop = true; }
+    TEST_INODE_DROP.with(|flag| flag.set(true));
 
     const SIZE: usize = 4096 * 3 + 3498;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
+    p.write(fd, &data);
     p.seek(fd, 0, SeekSet);
     p.read(fd, &mut buf);
 
@@ -235,12 +755,12 @@ mod proc_tests {
   fn test_max_singly_file_size() {
     const SIZE: usize = 4096 * 256;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
+    p.write(fd, &data);
     p.seek(fd, 0, SeekSet);
     p.read(fd, &mut buf);
 
@@ -254,18 +774,18 @@ mod proc_tests {
   }
 
   #[test]
-  fn test_max_file_size() {
+  fn test_max_doubly_file_size() {
     const SIZE: usize = 2 * 4096 * 256;
     let mut p = Proc::new();
-    let mut data1 = rand_array(SIZE);
-    let mut data2 = rand_array(SIZE);
+    let data1 = rand_array(SIZE);
+    let data2 = rand_array(SIZE);
     let mut buf = vec![0; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data1);
+    p.write(fd, &data1);
     p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet);
-    p.write(fd, &mut data2);
+    p.write(fd, &data2);
 
     p.seek(fd, 0, SeekSet);
     p.read(fd, &mut buf);
@@ -277,16 +797,167 @@ mod proc_tests {
   }
 
   #[test]
-  #[should_panic]
+  fn test_max_file_size() {
+    const SIZE: usize = 2 * 4096 * 256;
+    const MAX: isize = 4096 * (256 + 256 * 256 + 256 * 256 * 256);
+    let mut p = Proc::new();
+    let data1 = rand_array(SIZE);
+    let data2 = rand_array(SIZE);
+    let mut buf = vec![0; SIZE];
+    let filename = "first_file";
+
+    // Straddle the doubly/triply-indirect boundary, then fill the last pages.
+    let fd = p.open(filename, O_RDWR | O_CREAT);
+    p.seek(fd, 4096 * 257 * 256 - (SIZE / 2) as isize, SeekSet);
+    p.write(fd, &data1);
+    p.seek(fd, MAX - SIZE as isize, SeekSet);
+    p.write(fd, &data2);
+
+    p.seek(fd, 4096 * 257 * 256 - (SIZE / 2) as isize, SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq_buf(&data1, &buf);
+
+    p.seek(fd, MAX - SIZE as isize, SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq_buf(&data2, &buf);
+  }
+
+  #[test]
   fn test_morethan_max_file_size() {
+    // Writes stop at the largest a file can grow.
     const SIZE: usize = 2 * 4096 * 256;
+    const MAX: isize = 4096 * (256 + 256 * 256 + 256 * 256 * 256);
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
+    let mut buf = vec![0; SIZE - 1];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
-    p.seek(fd, 4096 * 257 * 256 + 1 - SIZE as isize, SeekSet);
-    p.write(fd, &mut data);
+    p.write(fd, &data);
+    p.seek(fd, MAX + 1 - SIZE as isize, SeekSet);
+    assert_eq!(p.write(fd, &data), SIZE - 1);
+    assert_eq!(p.write(fd, &data), 0);
+    assert_eq!(p.metadata(filename).unwrap().size, MAX as usize);
+
+    p.seek(fd, MAX + 1 - SIZE as isize, SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq_buf(&data[..SIZE - 1], &buf);
+  }
+
+  #[test]
+  fn test_small_page_size() {
+    const SIZE: usize = 4096 * 8 + 3434;
+    let mut p = Proc::with_page_size(512);
+    let data = rand_array(SIZE);
+    let mut buf = vec![0; SIZE];
+    let max = p.geometry().max_file_size();
+    assert_eq!(max, 512 * (256 + 256 * 256 + 256 * 256 * 256));
+
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &data);
+    p.seek(fd, (max - SIZE) as isize, SeekSet);
+    p.write(fd, &data);
+
+    p.seek(fd, 0, SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq_buf(&data, &buf);
+
+    p.seek(fd, (max - SIZE) as isize, SeekSet);
+    p.read(fd, &mut buf);
+    assert_eq_buf(&data, &buf);
+  }
+
+  #[test]
+  fn test_morethan_max_small_page_size() {
+    let mut p = Proc::with_page_size(512);
+    let max = p.geometry().max_file_size();
+
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.seek(fd, max as isize, SeekSet);
+    assert_eq!(p.write(fd, &[1]), 0);
+    p.seek(fd, isize::MAX, SeekSet);
+    assert_eq!(p.write(fd, &[1]), 0);
+    p.seek(fd, max as isize - 1, SeekSet);
+    assert_eq!(p.write(fd, &[1, 2]), 1);
+    assert_eq!(p.metadata("file").unwrap().size, max);
+    assert_eq!(p.check(), vec![]);
+  }
+
+  #[test]
+  fn test_symlink_loop() {
+    let mut p = Proc::new();
+    assert!(p.symlink("b", "a"));
+    assert!(p.symlink("a", "b"));
+    assert!(p.symlink("self", "self"));
+
+    // Links that never end up anywhere are like missing files, even with
+    // O_CREAT, but they're still there themselves.
+    for name in ["a", "b", "self"] {
+      assert_eq!(p.open(name, O_RDWR), -2);
+      assert_eq!(p.open(name, O_RDWR | O_CREAT), -2);
+      assert!(p.metadata(name).is_none());
+      assert!(p.symlink_metadata(name).unwrap().is_symlink());
+    }
+
+    // A chain of links as long as is allowed still works.
+    let names: Vec<String> = (0..=MAX_SYMLINKS).map(|i| format!("chain{}", i)).collect();
+    let fd = p.open(&names[MAX_SYMLINKS], O_RDWR | O_CREAT);
+    p.close(fd);
+    for pair in names.windows(2) {
+      assert!(p.symlink(&pair[1], &pair[0]));
+    }
+
+    assert!(p.metadata(&names[0]).unwrap().is_file());
+    assert!(p.symlink(&names[0], "chain-too-long"));
+    assert!(p.metadata("chain-too-long").is_none());
+  }
+
+  #[test]
+  fn test_dangling_symlink() {
+    let mut p = Proc::new();
+    assert!(p.symlink("target", "link"));
+    assert!(p.metadata("link").is_none());
+    assert_eq!(p.readlink("link").unwrap(), "target");
+    assert_eq!(p.open("link", O_RDWR), -2);
+
+    // Creating through the link creates the file it points to.
+    let fd = p.open("link", O_RDWR | O_CREAT);
+    assert!(fd >= 0);
+    assert_eq!(p.write(fd, b"data"), 4);
+    p.close(fd);
+    assert_eq!(p.metadata("target").unwrap().size, 4);
+    assert_eq!(p.metadata("link").unwrap().size, 4);
+
+    // Removing the target leaves the link dangling again.
+    p.unlink("target");
+    assert!(p.metadata("link").is_none());
+    assert!(p.symlink_metadata("link").unwrap().is_symlink());
+  }
+
+  #[test]
+  fn test_link_onto_existing() {
+    let mut p = Proc::new();
+    for name in ["a", "b"] {
+      let fd = p.open(name, O_RDWR | O_CREAT);
+      p.write(fd, name.as_bytes());
+      p.close(fd);
+    }
+
+    assert!(p.mkdir("dir"));
+    assert!(p.symlink("a", "to-a"));
+    for existing in ["b", "dir", "to-a", "a"] {
+      assert_eq!(p.link("a", existing).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    // Nothing was replaced.
+    let mut buf = [0; 1];
+    let fd = p.open("b", O_RDWR);
+    assert_eq!(p.read(fd, &mut buf), 1);
+    assert_eq!(&buf, b"b");
+    assert!(p.metadata("dir").unwrap().is_dir());
+    assert_eq!(p.readlink("to-a").unwrap(), "a");
+
+    assert_eq!(p.link("missing", "c").unwrap_err().kind(), ErrorKind::NotFound);
+    assert!(p.metadata("c").is_none());
   }
 }
@@ -0,0 +1,547 @@
+/*!
+ * A mount table: file systems mounted on directories.
+ *
+ * `Proc::mount` puts a file system on a directory, hiding what the directory
+ * had until `Proc::umount` takes it off again. Most are instances of their
+ * own, with their own files, page size, and allocator: an in-memory `Proc`, a
+ * saved image loaded to be read only, a host directory read and written in
+ * place, or a host directory under an in-memory layer. A union is different:
+ * it merges directories of the file system it's mounted in, as the overlay
+ * module describes. Paths cross mount points as they're resolved, symbolic
+ * links' targets included, and a call on a name under a mount point goes to
+ * the file system mounted there, the innermost if mounts are nested.
+ *
+ * As on Unix, links and renames can't cross from one file system to another:
+ * they fail with `io::ErrorKind::CrossesDevices`, EXDEV. A file system can't
+ * be unmounted while it's busy, with files in it still open or something
+ * mounted in it, and `umount` fails with `io::ErrorKind::ResourceBusy`,
+ * EBUSY. Changing a read-only file system fails with
+ * `io::ErrorKind::ReadOnlyFilesystem`, or the way a call fails otherwise
+ * where it doesn't return errors: writes write nothing, and `open` can't
+ * create.
+ *
+ * Only the file system at the top is saved, checked, or exported, and
+ * archives and host trees can't be imported or copied into the others.
+ */
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use alloc::Allocator;
+use archive;
+use file::{File, RcInode};
+use overlay::{self, host_file, LowerFile, Overlay};
+use {FileDescriptor, Proc};
+
+/**
+ * A file system to mount.
+ */
+pub enum Filesystem<'r> {
+  // An in-memory file system, empty or not.
+  Memory(Proc<'r>),
+  // An image saved by `Proc::save`, loaded to be read only.
+  Image(PathBuf),
+  // A host directory, read and written in place.
+  Host(PathBuf),
+  // A host directory under an in-memory layer that takes the changes.
+  Overlay(PathBuf),
+  // Directories in the file system, `lowers`, topmost first, merged under
+  // `upper`, which takes the changes and is made if it isn't there.
+  Union { upper: String, lowers: Vec<String> },
+}
+
+pub enum Mounted<'r> {
+  Memory(Proc<'r>),
+  Host(PathBuf),
+  // Layers looked up through the file system they're mounted in, which
+  // `Proc::mount_of` never returns.
+  Layers(Overlay)
+}
+
+/**
+ * A file system mounted on `point`.
+ */
+pub struct Mount<'r> {
+  pub point: String,
+  pub mounted: Mounted<'r>,
+  pub read_only: bool,
+  // Descriptors open on files in it, which keep it from being unmounted.
+  pub fds: HashSet<FileDescriptor>,
+}
+
+fn read_only() -> io::Error {
+  io::Error::new(io::ErrorKind::ReadOnlyFilesystem, "the file system is read-only")
+}
+
+// Where `relative` is in the host directory `dir`.
+fn host_path(dir: &Path, relative: &str) -> io::Result<PathBuf> {
+  match overlay::plain(relative) {
+    true => Ok(dir.join(relative)),
+    false => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' has no place on the host", relative)))
+  }
+}
+
+#[cfg(unix)]
+fn host_symlink(target: &str, path: &Path) -> io::Result<()> {
+  ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn host_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links aren't supported on this host"))
+}
+
+fn layers() -> ! {
+  unreachable!("layers are looked up through the file system they're in")
+}
+
+// The calls `Proc` passes on to a mounted file system, with names relative to
+// its mount point.
+impl<'r> Mount<'r> {
+  pub fn lookup(&self, allocator: &Allocator, relative: &str) -> Option<File<'r>> {
+    match self.mounted {
+      Mounted::Memory(ref fs) if relative.is_empty() => Some(fs.cwd.clone()),
+      Mounted::Memory(ref fs) => fs.lookup(relative),
+      Mounted::Host(ref dir) => host_file(allocator, relative, host_path(dir, relative).ok()?, !self.read_only),
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn create(&mut self, allocator: &Allocator, relative: Cow<'r, str>) -> Option<File<'r>> {
+    if self.read_only {
+      return None;
+    }
+
+    match self.mounted {
+      Mounted::Memory(ref mut fs) => fs.create_file(relative),
+      Mounted::Host(ref dir) => {
+        let path = host_path(dir, &relative).ok()?;
+        fs::OpenOptions::new().write(true).create_new(true).open(&path).ok()?;
+        host_file(allocator, &relative, path, true)
+      }
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn copy_up(&mut self, lower: &Rc<LowerFile>) -> io::Result<RcInode> {
+    match self.mounted {
+      Mounted::Memory(ref mut fs) if !self.read_only => fs.copy_up(lower),
+      Mounted::Layers(_) => layers(),
+      _ => Err(read_only())
+    }
+  }
+
+  pub fn unlink(&mut self, relative: &'r str) {
+    if self.read_only {
+      return;
+    }
+
+    match self.mounted {
+      Mounted::Memory(ref mut fs) => fs.unlink(relative),
+      Mounted::Host(ref dir) => {
+        if let Ok(path) = host_path(dir, relative) {
+          let _ = match fs::symlink_metadata(&path) {
+            Ok(ref metadata) if metadata.is_dir() => fs::remove_dir(&path),
+            _ => fs::remove_file(&path)
+          };
+        }
+      }
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn symlink(&mut self, target: &str, relative: &'r str) -> bool {
+    match self.mounted {
+      _ if self.read_only => false,
+      Mounted::Memory(ref mut fs) => fs.symlink(target, relative),
+      Mounted::Host(ref dir) => host_path(dir, relative).and_then(|path| host_symlink(target, &path)).is_ok(),
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn mkdir(&mut self, relative: &'r str) -> bool {
+    match self.mounted {
+      _ if self.read_only => false,
+      Mounted::Memory(ref mut fs) => fs.mkdir(relative),
+      Mounted::Host(ref dir) => host_path(dir, relative).and_then(fs::create_dir).is_ok(),
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn link(&mut self, existing: &str, relative: &'r str) -> io::Result<()> {
+    match self.mounted {
+      _ if self.read_only => Err(read_only()),
+      Mounted::Memory(ref mut fs) => fs.link(existing, relative),
+      Mounted::Host(ref dir) => fs::hard_link(host_path(dir, existing)?, host_path(dir, relative)?),
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn rename(&mut self, from: &'r str, to: &'r str) -> io::Result<()> {
+    match self.mounted {
+      _ if self.read_only => Err(read_only()),
+      Mounted::Memory(ref mut fs) => fs.rename(from, to),
+      Mounted::Host(ref dir) => fs::rename(host_path(dir, from)?, host_path(dir, to)?),
+      Mounted::Layers(_) => layers()
+    }
+  }
+
+  pub fn read_dir(&self, relative: &str) -> Option<Vec<String>> {
+    match self.mounted {
+      Mounted::Memory(ref fs) => fs.read_dir(relative),
+      Mounted::Host(ref dir) => {
+        let entries = fs::read_dir(host_path(dir, relative).ok()?).ok()?;
+        let mut names: Vec<String> = entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect();
+        names.sort();
+        Some(names)
+      }
+      Mounted::Layers(_) => layers()
+    }
+  }
+}
+
+impl<'r> Proc<'r> {
+  /**
+   * Mounts `filesystem` on the directory `path`, hiding what's in it until
+   * it's unmounted. Fails with ResourceBusy if something is mounted there
+   * already, and if `path` isn't a directory or the file system can't be
+   * loaded. A union also fails if its directories aren't right for it; see
+   * `Filesystem::Union`.
+   */
+  pub fn mount(&mut self, path: &str, filesystem: Filesystem<'r>) -> io::Result<()> {
+    let point = match archive::clean("", path) {
+      Some(ref point) if point.is_empty() => {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "nothing can be mounted on the top"));
+      }
+      Some(point) => point,
+      None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is outside the top", path)))
+    };
+
+    match self.lookup(&point) {
+      Some(File::Directory(_)) => {}
+      Some(_) => return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("'{}' isn't a directory", point))),
+      None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("there's no directory '{}'", point)))
+    }
+
+    if self.mounts.iter().any(|mount| mount.point == point) {
+      let message = format!("something is already mounted on '{}'", point);
+      return Err(io::Error::new(io::ErrorKind::ResourceBusy, message));
+    }
+
+    let (mounted, read_only) = match filesystem {
+      Filesystem::Memory(fs) => (Mounted::Memory(fs), false),
+      Filesystem::Image(image) => (Mounted::Memory(Proc::load(image)?), true),
+      Filesystem::Host(dir) => {
+        if !fs::metadata(&dir)?.is_dir() {
+          return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} isn't a directory", dir.display())));
+        }
+
+        (Mounted::Host(dir), false)
+      }
+      Filesystem::Overlay(dir) => {
+        if !fs::metadata(&dir)?.is_dir() {
+          return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} isn't a directory", dir.display())));
+        }
+
+        let mut fs = Proc::with_allocator(self.geometry().page_size(), self.alloc_kind());
+        fs.mounts.push(Mount { point: String::new(), mounted: Mounted::Layers(Overlay::host(dir)), read_only: false,
+                               fds: HashSet::new() });
+        (Mounted::Memory(fs), false)
+      }
+      Filesystem::Union { upper, lowers } => {
+        if self.mount_of(&point).is_some() {
+          return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is in another file system", point)));
+        }
+
+        (Mounted::Layers(self.union(&point, &upper, &lowers)?), false)
+      }
+    };
+
+    self.mounts.push(Mount { point, mounted, read_only, fds: HashSet::new() });
+    Ok(())
+  }
+
+  /**
+   * Unmounts the file system mounted on `path`. What a union copied up or
+   * created stays in its upper directory. Fails with ResourceBusy while files
+   * in it are open or while something else is mounted in it, and with
+   * InvalidInput if nothing is mounted there.
+   */
+  pub fn umount(&mut self, path: &str) -> io::Result<()> {
+    let point = archive::clean("", path).unwrap_or_default();
+    let i = match self.mounts.iter().position(|mount| mount.point == point) {
+      Some(i) => i,
+      None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("nothing is mounted on '{}'", point)))
+    };
+
+    let inside = format!("{}/", point);
+    if !self.mounts[i].fds.is_empty() || self.mounts.iter().any(|mount| mount.point.starts_with(&inside)) {
+      return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("'{}' is busy", point)));
+    }
+
+    self.mounts.remove(i);
+    Ok(())
+  }
+
+  // The innermost mount `name` is under, not counting unions, and where
+  // `name` relative to its mount point starts.
+  pub(crate) fn mount_of(&self, name: &str) -> Option<(usize, usize)> {
+    self.mounts.iter().enumerate()
+      .filter(|&(_, mount)| !matches!(mount.mounted, Mounted::Layers(_)))
+      .filter_map(|(i, mount)| match name.strip_prefix(&mount.point)? {
+        "" => Some((i, name.len())),
+        rest if rest.starts_with('/') => Some((i, mount.point.len() + 1)),
+        _ => None
+      })
+      .max_by_key(|&(i, _)| self.mounts[i].point.len())
+  }
+
+  // Fails if `name` is in a mounted file system, or is a union's mount point
+  // or under one, where imports and copies can't write.
+  pub(crate) fn check_unmounted(&self, name: &str) -> io::Result<()> {
+    if self.mount_of(name).is_some() || self.overlaid(name) {
+      return Err(io::Error::new(io::ErrorKind::CrossesDevices, format!("'{}' is in a mounted file system", name)));
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::io::{Cursor, ErrorKind};
+  use std::path::PathBuf;
+  use std::process;
+
+  use host::CopyOptions;
+  use zip::ZipOptions;
+  use {Filesystem, Proc, MIN_PAGE_SIZE, O_CREAT, O_RDWR};
+
+  fn scratch(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("rustfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn create(p: &mut Proc<'static>, name: &'static str, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    assert_eq!(p.write(fd, data), data.len());
+    p.close(fd);
+  }
+
+  fn contents(p: &mut Proc<'static>, name: &'static str) -> Vec<u8> {
+    let size = p.metadata(name).expect("missing").size;
+    let fd = p.open(name, O_RDWR);
+    let mut buf = vec![0; size];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  #[test]
+  fn test_mounts() {
+    let mut p = Proc::new();
+    assert!(p.mkdir("mnt"));
+    create(&mut p, "mnt/hidden", b"underneath");
+    create(&mut p, "top", b"top");
+    let mut inner = Proc::with_page_size(MIN_PAGE_SIZE);
+    create(&mut inner, "a", b"inner");
+
+    assert_eq!(p.mount("top", Filesystem::Memory(Proc::new())).unwrap_err().kind(), ErrorKind::NotADirectory);
+    assert_eq!(p.mount("missing", Filesystem::Memory(Proc::new())).unwrap_err().kind(), ErrorKind::NotFound);
+    p.mount("mnt", Filesystem::Memory(inner)).unwrap();
+    assert_eq!(p.mount("mnt", Filesystem::Memory(Proc::new())).unwrap_err().kind(), ErrorKind::ResourceBusy);
+
+    // The mount hides what was there, and paths cross into it and back out,
+    // symbolic links and all.
+    assert!(p.metadata("mnt/hidden").is_none());
+    assert_eq!(contents(&mut p, "mnt/a"), b"inner");
+    create(&mut p, "mnt/b", b"new");
+    assert_eq!(p.read_dir("mnt").unwrap(), vec!["a", "b"]);
+    assert!(p.symlink("mnt/a", "to-a"));
+    assert!(p.symlink("../top", "mnt/up"));
+    assert_eq!(contents(&mut p, "to-a"), b"inner");
+    assert_eq!(contents(&mut p, "mnt/up"), b"top");
+    assert_eq!(p.readlink("mnt/up").unwrap(), "../top");
+
+    // Links and renames stay on one file system.
+    assert_eq!(p.link("top", "mnt/top").unwrap_err().kind(), ErrorKind::CrossesDevices);
+    assert_eq!(p.link("mnt/a", "a").unwrap_err().kind(), ErrorKind::CrossesDevices);
+    assert_eq!(p.rename("mnt/a", "a").unwrap_err().kind(), ErrorKind::CrossesDevices);
+    p.link("mnt/a", "mnt/c").unwrap();
+    p.rename("mnt/c", "mnt/d").unwrap();
+    assert_eq!(contents(&mut p, "mnt/d"), b"inner");
+
+    // A file system in use can't be unmounted.
+    let fd = p.open("mnt/a", O_RDWR);
+    assert_eq!(p.umount("mnt").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    p.close(fd);
+    assert!(p.mkdir("mnt/sub"));
+    p.mount("mnt/sub", Filesystem::Memory(Proc::new())).unwrap();
+    create(&mut p, "mnt/sub/deep", b"deep");
+    assert_eq!(p.read_dir("mnt/sub").unwrap(), vec!["deep"]);
+    assert_eq!(p.umount("mnt").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    p.umount("mnt/sub").unwrap();
+    assert!(p.metadata("mnt/sub/deep").is_none());
+    p.umount("mnt").unwrap();
+    assert_eq!(p.umount("mnt").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(contents(&mut p, "mnt/hidden"), b"underneath");
+    assert!(p.metadata("to-a").is_none());
+  }
+
+  #[test]
+  fn test_images_and_hosts() {
+    let dir = scratch("mount");
+    let mut saved = Proc::new();
+    create(&mut saved, "etc", b"saved");
+    saved.save(dir.join("fs.img")).unwrap();
+    fs::create_dir(dir.join("host")).unwrap();
+    fs::write(dir.join("host/file"), b"on the host").unwrap();
+
+    let mut p = Proc::new();
+    for point in ["img", "host", "overlay"].iter() {
+      assert!(p.mkdir(point));
+    }
+
+    // Images are read only.
+    p.mount("img", Filesystem::Image(dir.join("fs.img"))).unwrap();
+    assert_eq!(contents(&mut p, "img/etc"), b"saved");
+    let fd = p.open("img/etc", O_RDWR);
+    assert_eq!(p.write(fd, b"changed"), 0);
+    p.close(fd);
+    assert_eq!(p.open("img/new", O_CREAT | O_RDWR), -2);
+    assert!(!p.mkdir("img/dir"));
+    assert_eq!(p.rename("img/etc", "img/other").unwrap_err().kind(), ErrorKind::ReadOnlyFilesystem);
+    assert_eq!(contents(&mut p, "img/etc"), b"saved");
+
+    // Host directories are read and written in place.
+    p.mount("host", Filesystem::Host(dir.join("host"))).unwrap();
+    assert_eq!(contents(&mut p, "host/file"), b"on the host");
+    let fd = p.open("host/file", O_RDWR);
+    assert_eq!(p.write(fd, b"ON"), 2);
+    p.close(fd);
+    assert_eq!(fs::read(dir.join("host/file")).unwrap(), b"ON the host");
+    create(&mut p, "host/new", b"made here");
+    assert!(p.mkdir("host/dir"));
+    p.rename("host/new", "host/dir/moved").unwrap();
+    assert_eq!(fs::read(dir.join("host/dir/moved")).unwrap(), b"made here");
+    assert_eq!(p.read_dir("host").unwrap(), vec!["dir", "file"]);
+    p.unlink("host/dir/moved");
+    assert!(!dir.join("host/dir/moved").exists());
+
+    // Overlays copy up instead.
+    p.mount("overlay", Filesystem::Overlay(dir.join("host"))).unwrap();
+    let fd = p.open("overlay/file", O_RDWR);
+    assert_eq!(p.write(fd, b"on"), 2);
+    p.close(fd);
+    assert_eq!(contents(&mut p, "overlay/file"), b"on the host");
+    assert_eq!(fs::read(dir.join("host/file")).unwrap(), b"ON the host");
+    for point in ["img", "host", "overlay"].iter() {
+      p.umount(point).unwrap();
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_rename() {
+    let mut p = Proc::new();
+    assert!(p.mkdir("a"));
+    assert!(p.mkdir("a/b"));
+    create(&mut p, "a/x", b"x");
+    create(&mut p, "a/b/y", b"y");
+    p.rename("a", "c").unwrap();
+    assert!(p.metadata("a").is_none());
+    assert_eq!(p.read_dir("c").unwrap(), vec!["b", "x"]);
+    assert_eq!(contents(&mut p, "c/b/y"), b"y");
+
+    create(&mut p, "f", b"f");
+    create(&mut p, "g", b"g");
+    p.rename("f", "g").unwrap();
+    assert!(p.metadata("f").is_none());
+    assert_eq!(contents(&mut p, "g"), b"f");
+
+    assert!(p.mkdir("e"));
+    create(&mut p, "e/z", b"z");
+    assert_eq!(p.rename("g", "c").unwrap_err().kind(), ErrorKind::IsADirectory);
+    assert_eq!(p.rename("c", "g").unwrap_err().kind(), ErrorKind::NotADirectory);
+    assert_eq!(p.rename("c", "c/d").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(p.rename("c", "e").unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+    assert_eq!(p.rename("missing", "q").unwrap_err().kind(), ErrorKind::NotFound);
+
+    // Renaming in a union copies up and leaves a whiteout; directories with
+    // lower layers can't be renamed, as with overlayfs.
+    assert!(p.mkdir("low"));
+    assert!(p.mkdir("low/dir"));
+    assert!(p.mkdir("merged"));
+    create(&mut p, "low/file", b"lower");
+    p.mount("merged", Filesystem::Union { upper: "up".to_string(), lowers: vec!["low".to_string()] }).unwrap();
+    p.rename("merged/file", "merged/moved").unwrap();
+    assert_eq!(contents(&mut p, "merged/moved"), b"lower");
+    assert!(p.metadata("merged/file").is_none());
+    assert!(p.metadata("up/.wh.file").is_some());
+    assert_eq!(contents(&mut p, "low/file"), b"lower");
+    assert_eq!(p.rename("merged/dir", "merged/other").unwrap_err().kind(), ErrorKind::CrossesDevices);
+    assert_eq!(p.check(), vec![]);
+
+    // Directories with something mounted under them stay where they are.
+    for dir in ["a", "a/m", "a/u"].iter() {
+      assert!(p.mkdir(dir));
+    }
+
+    p.mount("a/m", Filesystem::Memory(Proc::new())).unwrap();
+    create(&mut p, "a/m/x", b"x");
+    assert_eq!(p.rename("a", "b").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    assert_eq!(contents(&mut p, "a/m/x"), b"x");
+    assert!(p.metadata("b").is_none());
+    p.umount("a/m").unwrap();
+    p.mount("a/u", Filesystem::Union { upper: "u-up".to_string(), lowers: vec!["low".to_string()] }).unwrap();
+    assert_eq!(p.rename("a", "b").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    p.umount("a/u").unwrap();
+    p.rename("a", "b").unwrap();
+    assert!(p.metadata("b/m").unwrap().is_dir());
+  }
+
+  #[test]
+  fn test_imports() {
+    let mut archive = Proc::new();
+    assert!(archive.mkdir("dir"));
+    create(&mut archive, "dir/file", b"file");
+    let mut tar = Vec::new();
+    archive.export_tar("", &mut tar).unwrap();
+    let mut zip = Vec::new();
+    archive.export_zip("", &mut zip, ZipOptions::default()).unwrap();
+    let host = scratch("mount-imports");
+    fs::write(host.join("file"), b"file").unwrap();
+
+    // Nothing's imported or copied into a mounted file system or a union.
+    let mut p = Proc::new();
+    for dir in ["mnt", "mnt/dir", "merged", "low"].iter() {
+      assert!(p.mkdir(dir));
+    }
+
+    p.mount("mnt", Filesystem::Memory(Proc::new())).unwrap();
+    p.mount("merged", Filesystem::Union { upper: "up".to_string(), lowers: vec!["low".to_string()] }).unwrap();
+    for dest in ["mnt", "mnt/dir", "merged"].iter() {
+      assert_eq!(p.import_tar(&tar[..], dest).unwrap_err().kind(), ErrorKind::CrossesDevices);
+      assert_eq!(p.import_zip(Cursor::new(&zip), dest).unwrap_err().kind(), ErrorKind::CrossesDevices);
+    }
+
+    for dest in ["mnt/dir", "merged/dir"].iter() {
+      let error = p.copy_in(&host, dest, &CopyOptions::default()).unwrap_err();
+      assert_eq!(error.kind(), ErrorKind::CrossesDevices);
+    }
+
+    assert!(p.read_dir("mnt").unwrap().is_empty());
+    assert!(p.read_dir("up").unwrap().is_empty());
+    p.import_tar(&tar[..], "elsewhere").unwrap();
+    assert_eq!(contents(&mut p, "elsewhere/dir/file"), b"file");
+    fs::remove_dir_all(&host).unwrap();
+  }
+}
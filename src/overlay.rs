@@ -0,0 +1,932 @@
+/*!
+ * Layers mounted underneath the file system, read through lazily.
+ *
+ * `Filesystem::Overlay` mounts a host directory as a read-only lower layer,
+ * with an in-memory file system of its own on top. `Filesystem::Union` does
+ * the same with directories in the file system it's mounted in, like Linux's
+ * overlayfs: one upper directory that's written to, over any number of lower
+ * ones that are only read, all seen merged under the mount point.
+ *
+ * Names that aren't in the upper layer are looked up in the lower ones when
+ * they're used, topmost first. Files there are read in place until something
+ * writes to them, when they're copied up first; directories are markers, like
+ * `Proc::mkdir`'s, and `Proc::read_dir` lists what every layer has in them;
+ * and symbolic links are copied whole. New files always go in the upper layer,
+ * and unlinking a name a lower layer has leaves a whiteout that hides it from
+ * then on. Lower layers are never changed.
+ *
+ * The host's whiteouts are kept in memory. A union's are kept in its upper
+ * directory the way container image layers keep them, as empty files: `.wh.`
+ * followed by the name hides that name in the layers below, and `.wh..wh..opq`
+ * in a directory hides everything the layers below have in it. Making a
+ * directory where there was a whiteout makes it opaque. Lower directories can
+ * have whiteouts too, for the layers under them.
+ *
+ * Only what's in memory is saved, checked, or exported; files on the host
+ * that haven't been copied up are left out.
+ */
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloc::{Allocator, InodeBox};
+use archive;
+use directory::DirectoryHandle;
+use file::{File, RcInode};
+use time::Timespec;
+use mount::Mounted;
+use {Metadata, Proc, DEFAULT_LINK_MODE, S_IFREG, Xattrs};
+
+// What a whiteout's name starts with, and the whiteout making its directory
+// opaque.
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+// `name` in `directory`; either can be the top.
+fn join(directory: &str, name: &str) -> String {
+  match (directory, name) {
+    ("", _) => name.to_string(),
+    (_, "") => directory.to_string(),
+    _ => format!("{}/{}", directory, name)
+  }
+}
+
+// The whiteout for `name`, next to it.
+fn whiteout_of(name: &str) -> String {
+  match name.rsplit_once('/') {
+    Some((directory, base)) => format!("{}/{}{}", directory, WHITEOUT, base),
+    None => format!("{}{}", WHITEOUT, name)
+  }
+}
+
+// Whether `relative` is the top or made of plain components, so it has a
+// place in a directory on the host.
+pub fn plain(relative: &str) -> bool {
+  relative.is_empty() || relative.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+// The directories `name` is in, outermost first, not counting the top.
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+  name.match_indices('/').map(move |(i, _)| &name[..i])
+}
+
+enum Layers {
+  // A host directory, and the names unlinked from it, which the host's files
+  // are hidden behind.
+  Host { dir: PathBuf, whiteouts: HashSet<String> },
+  // Directories in the file system: the one written to, then the ones only
+  // read, topmost first.
+  Trees { upper: String, lowers: Vec<String> }
+}
+
+/**
+ * Layers mounted in the mount table, and the files looked up in them.
+ */
+pub struct Overlay {
+  layers: Layers,
+  // Files looked up and not yet copied up, so every handle to one shares its
+  // copy once there is one. Only the handles keep them.
+  files: RefCell<HashMap<String, Weak<LowerFile>>>,
+}
+
+impl Overlay {
+  fn new(layers: Layers) -> Overlay {
+    Overlay { layers, files: RefCell::new(HashMap::new()) }
+  }
+
+  // The host directory `dir` under what's in memory.
+  pub fn host(dir: PathBuf) -> Overlay {
+    Overlay::new(Layers::Host { dir, whiteouts: HashSet::new() })
+  }
+
+  // Where what's made at `relative` goes, if the overlay is mounted at
+  // `point`.
+  fn upper_name(&self, point: &str, relative: &str) -> String {
+    match self.layers {
+      Layers::Host { .. } => join(point, relative),
+      Layers::Trees { ref upper, .. } => join(upper, relative)
+    }
+  }
+}
+
+// `name` relative to `point`, where an overlay is mounted, if it's under it.
+// Names are flat, so only ones made of plain components have a place in the
+// layers.
+fn relative<'n>(point: &str, name: &'n str) -> Option<&'n str> {
+  let relative = match point {
+    "" => name,
+    point => match name.strip_prefix(point)? {
+      "" => "",
+      rest => rest.strip_prefix('/')?
+    }
+  };
+
+  match plain(relative) {
+    true => Some(relative),
+    false => None
+  }
+}
+
+enum Source {
+  // `metadata` is what the file was when it was looked up, in case it can't
+  // be looked at again. Only files in host directories mounted to be written
+  // in place are `writable`. The file is opened for each read and write, so
+  // no more are open on the host than are being used.
+  Host { path: PathBuf, metadata: Metadata, writable: bool },
+  Tree(RcInode)
+}
+
+/**
+ * A regular file in a lower layer, as it's seen from the file system.
+ */
+pub struct LowerFile {
+  name: String,
+  source: Source,
+  // The copy in the upper layer, once something has written to the file.
+  upper: RefCell<Weak<RefCell<InodeBox>>>,
+}
+
+impl LowerFile {
+  fn upper(&self) -> Option<RcInode> {
+    self.upper.borrow().upgrade()
+  }
+
+  // Whether writes go to the host in place, rather than to a copy.
+  pub fn writes_in_place(&self) -> bool {
+    match self.source {
+      Source::Host { writable, .. } => writable,
+      Source::Tree(_) => false
+    }
+  }
+
+  pub fn size(&self) -> usize {
+    self.metadata().size
+  }
+
+  // The inode it's read from, if it's in a union's lower layer. It holds a
+  // reference to it, copied up or not.
+  pub fn tree_inode(&self) -> Option<&RcInode> {
+    match self.source {
+      Source::Tree(ref inode) => Some(inode),
+      Source::Host { .. } => None
+    }
+  }
+
+  pub fn metadata(&self) -> Metadata {
+    match (self.upper(), &self.source) {
+      (Some(inode), _) => Proc::metadata_of(&File::DataFile(inode)),
+      (None, Source::Tree(inode)) => Proc::metadata_of(&File::DataFile(inode.clone())),
+      (None, Source::Host { path, metadata, .. }) => match fs::metadata(path) {
+        Ok(metadata) => host_metadata(&metadata),
+        Err(_) => metadata.clone()
+      }
+    }
+  }
+
+  // Reads like an inode does: past the end of the file are zeroes. Returns 0
+  // if the host can't be read.
+  pub fn read(&self, offset: usize, dst: &mut [u8]) -> usize {
+    let path = match (self.upper(), &self.source) {
+      (Some(inode), _) => return inode.borrow().read(offset, dst),
+      (None, Source::Tree(inode)) => return inode.borrow().read(offset, dst),
+      (None, Source::Host { path, .. }) => path
+    };
+
+    let result = open_host(path, false).ok_or(()).and_then(|mut file| {
+      file.seek(SeekFrom::Start(offset as u64)).map_err(|_| ())?;
+      let mut read = 0;
+      while read < dst.len() {
+        match file.read(&mut dst[read..]) {
+          Ok(0) => break,
+          Ok(n) => read += n,
+          Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+          Err(_) => return Err(())
+        }
+      }
+
+      Ok(read)
+    });
+
+    match result {
+      Ok(read) => {
+        for byte in dst[read..].iter_mut() {
+          *byte = 0;
+        }
+
+        dst.len()
+      }
+      Err(()) => 0
+    }
+  }
+
+  // Writes to the host in place, if the file is writable there. Returns how
+  // much was written.
+  pub fn write(&self, offset: usize, src: &[u8]) -> usize {
+    let path = match self.source {
+      Source::Host { ref path, writable: true, .. } => path,
+      _ => return 0
+    };
+
+    let result = open_host(path, true).ok_or(()).and_then(|mut file| {
+      file.seek(SeekFrom::Start(offset as u64)).map_err(|_| ())?;
+      file.write_all(src).map_err(|_| ())
+    });
+
+    match result {
+      Ok(()) => src.len(),
+      Err(()) => 0
+    }
+  }
+}
+
+fn open_host(path: &Path, writable: bool) -> Option<fs::File> {
+  fs::OpenOptions::new().read(true).write(writable).open(path).ok()
+}
+
+fn to_timespec(time: io::Result<SystemTime>) -> Timespec {
+  match time.map(|time| time.duration_since(UNIX_EPOCH)) {
+    Ok(Ok(since)) => Timespec::new(since.as_secs() as i64, since.subsec_nanos() as i32),
+    Ok(Err(before)) => {
+      let before = before.duration();
+      let nsec = before.subsec_nanos() as i32;
+      match nsec {
+        0 => Timespec::new(-(before.as_secs() as i64), 0),
+        _ => Timespec::new(-(before.as_secs() as i64) - 1, 1_000_000_000 - nsec)
+      }
+    }
+    Err(_) => Timespec::new(0, 0)
+  }
+}
+
+#[cfg(unix)]
+fn mode_and_owner(metadata: &fs::Metadata) -> (u32, u32, u32) {
+  use std::os::unix::fs::MetadataExt;
+  (metadata.mode(), metadata.uid(), metadata.gid())
+}
+
+#[cfg(not(unix))]
+fn mode_and_owner(metadata: &fs::Metadata) -> (u32, u32, u32) {
+  (S_IFREG | if metadata.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0)
+}
+
+fn host_metadata(metadata: &fs::Metadata) -> Metadata {
+  let (mode, uid, gid) = mode_and_owner(metadata);
+  Metadata {
+    mode,
+    size: metadata.len() as usize,
+    uid,
+    gid,
+    created: to_timespec(metadata.created()),
+    accessed: to_timespec(metadata.accessed()),
+    modified: to_timespec(metadata.modified()),
+    xattrs: Xattrs::new()
+  }
+}
+
+// A symbolic link to `target` that's in no directory, for a lower layer's.
+fn detached_link<'r>(allocator: &Allocator, target: &[u8]) -> File<'r> {
+  let mut inode = allocator.alloc_inode();
+  inode.set_mode(DEFAULT_LINK_MODE);
+  inode.write(0, target);
+  File::new_data_file(Rc::new(RefCell::new(inode)))
+}
+
+/**
+ * What's at `path` on the host, seen from the file system at `name`: a
+ * directory marker, a symbolic link in no directory, or a lower file, read in
+ * place and written in place too if `writable`. None for anything else.
+ */
+pub fn host_file<'r>(allocator: &Allocator, name: &str, path: PathBuf, writable: bool) -> Option<File<'r>> {
+  let metadata = fs::symlink_metadata(&path).ok()?;
+  let kind = metadata.file_type();
+  if kind.is_dir() {
+    Some(File::new_dir(None))
+  } else if kind.is_symlink() {
+    let target = fs::read_link(&path).ok()?;
+    Some(detached_link(allocator, target.to_str()?.as_bytes()))
+  } else if kind.is_file() {
+    let metadata = host_metadata(&metadata);
+    let source = Source::Host { path, metadata, writable };
+    Some(File::Lower(Rc::new(LowerFile { name: name.to_string(), source, upper: RefCell::new(Weak::new()) })))
+  } else {
+    None
+  }
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl<'r> Proc<'r> {
+  // Checks the directories of a union to be mounted at `point` and makes
+  // `upper` if it isn't there. Fails if a lower directory isn't there, a
+  // directory is under `point` or `point` under one, or one is in another
+  // file system.
+  pub(crate) fn union(&mut self, point: &str, upper: &str, lowers: &[String]) -> io::Result<Overlay> {
+    let clean = |dir: &str| archive::clean("", dir).ok_or_else(|| invalid(format!("'{}' is outside the top", dir)));
+    let upper = clean(upper)?;
+    let lowers = lowers.iter().map(|dir| clean(dir)).collect::<io::Result<Vec<_>>>()?;
+
+    let under = |a: &str, b: &str| b.is_empty() || a == b || a.starts_with(&format!("{}/", b));
+    for dir in iter::once(&upper).chain(lowers.iter()) {
+      if under(dir, point) || under(point, dir) {
+        return Err(invalid(format!("'{}' and the mount point '{}' overlap", dir, point)));
+      } else if self.mount_of(dir).is_some() || self.union_of(dir).is_some() {
+        return Err(invalid(format!("'{}' is in another file system", dir)));
+      }
+    }
+
+    for dir in lowers.iter() {
+      match self.cwd.get(dir) {
+        Some(File::Directory(_)) => {}
+        _ => return Err(invalid(format!("'{}' isn't a directory", dir)))
+      }
+    }
+
+    for dir in ancestors(&upper).chain(iter::once(upper.as_str())) {
+      if self.cwd.get(dir).is_none() {
+        self.cwd.insert(dir.to_string(), File::new_dir(None));
+      }
+    }
+
+    Ok(Overlay::new(Layers::Trees { upper, lowers }))
+  }
+
+  // Whether `name` is under an overlay.
+  pub(crate) fn overlaid(&self, name: &str) -> bool {
+    self.overlay_of(name).is_some()
+  }
+
+  // Whether `name` is in a union, where what's in memory under the mount
+  // point is hidden and only the layers are seen.
+  pub(crate) fn in_union(&self, name: &str) -> bool {
+    matches!(self.overlay_of(name), Some((&Overlay { layers: Layers::Trees { .. }, .. }, _, _)))
+  }
+
+  // The mount of the overlay `name` is under, the innermost if there's more
+  // than one.
+  pub(crate) fn union_of(&self, name: &str) -> Option<usize> {
+    self.mounts.iter().enumerate()
+      .filter(|&(_, mount)| matches!(mount.mounted, Mounted::Layers(_)) && relative(&mount.point, name).is_some())
+      .max_by_key(|&(_, mount)| mount.point.len())
+      .map(|(i, _)| i)
+  }
+
+  // The overlay `name` is under, where it's mounted, and `name` relative to
+  // it.
+  fn overlay_of<'n>(&self, name: &'n str) -> Option<(&Overlay, &str, &'n str)> {
+    let mount = &self.mounts[self.union_of(name)?];
+    match mount.mounted {
+      Mounted::Layers(ref overlay) => Some((overlay, mount.point.as_str(), relative(&mount.point, name)?)),
+      _ => None
+    }
+  }
+
+  // Whether `layer` hides what the layers under it have at `relative`: it
+  // has a whiteout for it or a directory it's in, something other than a
+  // directory in its place, or an opaque directory above it.
+  fn hides(&self, layer: &str, relative: &str) -> bool {
+    let name = join(layer, relative);
+    if self.cwd.get(&whiteout_of(&name)).is_some() {
+      return true;
+    }
+
+    ancestors(relative).any(|directory| {
+      let directory = join(layer, directory);
+      match self.cwd.get(&directory) {
+        Some(File::Directory(_)) => self.cwd.get(&join(&directory, OPAQUE)).is_some(),
+        Some(_) => true,
+        None => self.cwd.get(&whiteout_of(&directory)).is_some()
+      }
+    })
+  }
+
+  // What the topmost of `layers` with something at `relative` has there,
+  // and whether that's the first layer.
+  fn lookup_trees(&self, layers: &[&str], relative: &str) -> Option<(bool, File<'r>)> {
+    if relative.split('/').any(|part| part.starts_with(WHITEOUT)) {
+      return None;
+    }
+
+    for (i, layer) in layers.iter().enumerate() {
+      if let Some(file) = self.cwd.get(&join(layer, relative)) {
+        return Some((i == 0, file));
+      }
+
+      if self.hides(layer, relative) {
+        return None;
+      }
+    }
+
+    None
+  }
+
+  // What's at `name` in the lower layers of an overlay, or in a union's upper
+  // layer, if it hasn't been unlinked.
+  pub(crate) fn lookup_lower(&self, name: &str) -> Option<File<'r>> {
+    let (overlay, _, relative) = self.overlay_of(name)?;
+    if let Some(lower) = overlay.files.borrow().get(name).and_then(Weak::upgrade) {
+      return Some(File::Lower(lower));
+    }
+
+    let lower = match overlay.layers {
+      Layers::Host { ref dir, ref whiteouts } => {
+        if whiteouts.contains(name) {
+          return None;
+        }
+
+        match host_file(&self.allocator, name, dir.join(relative), false)? {
+          File::Lower(lower) => lower,
+          file => return Some(file)
+        }
+      }
+      Layers::Trees { ref upper, ref lowers } => {
+        let layers: Vec<&str> = iter::once(upper).chain(lowers.iter()).map(|dir| dir.as_str()).collect();
+        match self.lookup_trees(&layers, relative)? {
+          (true, file) | (false, file @ File::Directory(_)) => return Some(file),
+          (false, File::DataFile(inode)) => {
+            if inode.borrow().is_symlink() {
+              let target = inode.borrow().link_target().unwrap_or_default();
+              return Some(detached_link(&self.allocator, target.as_bytes()));
+            }
+
+            let source = Source::Tree(inode);
+            Rc::new(LowerFile { name: name.to_string(), source, upper: RefCell::new(Weak::new()) })
+          }
+          (false, _) => return None
+        }
+      }
+    };
+
+    // Forget the files nothing has open any more before the table grows, so
+    // it's never more than twice as big as it has to be.
+    let mut files = overlay.files.borrow_mut();
+    if files.len() == files.capacity() {
+      files.retain(|_, file| file.strong_count() > 0);
+    }
+
+    files.insert(name.to_string(), Rc::downgrade(&lower));
+    Some(File::Lower(lower))
+  }
+
+  // Makes the directories `relative` is in in a union's upper layer, as
+  // overlayfs does before copying something up into them.
+  fn make_parents(&mut self, upper: &str, relative: &str) {
+    for directory in ancestors(relative) {
+      let directory = join(upper, directory);
+      if self.cwd.get(&directory).is_none() {
+        self.cwd.insert(directory, File::new_dir(None));
+      }
+    }
+  }
+
+  // Where something new at `name` goes: in a union's upper layer if it's
+  // under one, in place of any whiteout there, and as an opaque directory if
+  // `directory` and there was one.
+  pub(crate) fn upper_name(&mut self, name: Cow<'r, str>, directory: bool) -> Cow<'r, str> {
+    let (upper, relative) = match self.overlay_of(&name) {
+      Some((&Overlay { layers: Layers::Trees { ref upper, .. }, .. }, _, relative)) => (upper.clone(), relative.to_string()),
+      _ => return name
+    };
+
+    let upper_name = join(&upper, &relative);
+    let whiteout = whiteout_of(&upper_name);
+    let whited_out = self.cwd.get(&whiteout).is_some();
+    self.cwd.remove(&whiteout);
+    self.make_parents(&upper, &relative);
+    if directory && whited_out {
+      let opaque = self.allocator.alloc_inode();
+      self.cwd.insert(join(&upper_name, OPAQUE), File::new_data_file(Rc::new(RefCell::new(opaque))));
+    }
+
+    upper_name.into()
+  }
+
+  // Hides whatever the lower layers have at `name` from now on, after it's
+  // unlinked; in a union, unlinks it from the upper layer too.
+  pub(crate) fn whiteout(&mut self, name: &str) {
+    let (point, upper, relative) = match self.overlay_of(name) {
+      Some((overlay, point, relative)) => {
+        overlay.files.borrow_mut().remove(name);
+        match overlay.layers {
+          Layers::Trees { ref upper, .. } => (point.to_string(), Some(upper.clone()), relative),
+          Layers::Host { .. } => (point.to_string(), None, relative)
+        }
+      }
+      None => return
+    };
+
+    let upper = match upper {
+      Some(upper) => upper,
+      None => {
+        for mount in self.mounts.iter_mut().filter(|mount| mount.point == point) {
+          if let Mounted::Layers(Overlay { layers: Layers::Host { ref mut whiteouts, .. }, .. }) = mount.mounted {
+            whiteouts.insert(name.to_string());
+          }
+        }
+
+        return;
+      }
+    };
+
+    if relative.is_empty() {
+      return;
+    }
+
+    self.cwd.remove(&join(&upper, relative));
+    if self.lookup_lower(name).is_some() {
+      if let Some((overlay, _, _)) = self.overlay_of(name) {
+        overlay.files.borrow_mut().remove(name);
+      }
+
+      self.make_parents(&upper, relative);
+      let whiteout = self.allocator.alloc_inode();
+      self.cwd.insert(whiteout_of(&join(&upper, relative)), File::new_data_file(Rc::new(RefCell::new(whiteout))));
+    }
+  }
+
+  // The names the lower layers of an overlay might have in the directory
+  // `name`, if it's under one; `read_dir` keeps the ones that are there.
+  pub(crate) fn lower_children(&self, name: &str) -> Vec<String> {
+    let (overlay, _, relative) = match self.overlay_of(name) {
+      Some(found) => found,
+      None => return Vec::new()
+    };
+
+    match overlay.layers {
+      Layers::Host { ref dir, .. } => match fs::read_dir(dir.join(relative)) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect(),
+        Err(_) => Vec::new()
+      },
+      Layers::Trees { ref upper, ref lowers } => {
+        iter::once(upper).chain(lowers.iter())
+          .flat_map(|layer| self.children(&join(layer, relative)))
+          .filter(|child| !child.starts_with(WHITEOUT))
+          .collect()
+      }
+    }
+  }
+
+  /**
+   * Copies a lower file up into the upper layer, in place of the lower one,
+   * unless that's been done already. Pages of zeroes are left as holes. If
+   * the file has been unlinked since it was looked up, the copy has no name,
+   * like any unlinked file still open.
+   */
+  pub(crate) fn copy_up(&mut self, lower: &Rc<LowerFile>) -> io::Result<RcInode> {
+    if let Some(inode) = lower.upper() {
+      return Ok(inode);
+    }
+
+    let metadata = lower.metadata();
+    if metadata.size > self.geometry().max_file_size() {
+      let message = format!("'{}' is too large to copy up", lower.name);
+      return Err(io::Error::new(io::ErrorKind::FileTooLarge, message));
+    }
+
+    let mut inode = self.allocator.alloc_inode();
+    let mut page = vec![0u8; self.geometry().page_size()];
+    let mut offset = 0;
+    while offset < metadata.size {
+      let n = page.len().min(metadata.size - offset);
+      if lower.read(offset, &mut page[..n]) != n {
+        return Err(io::Error::other(format!("couldn't read '{}' to copy it up", lower.name)));
+      }
+
+      if page[..n].iter().any(|&byte| byte != 0) {
+        inode.write(offset, &page[..n]);
+      }
+
+      offset += n;
+    }
+
+    inode.set_mode(S_IFREG | (metadata.mode & 0o7777));
+    inode.set_owner(metadata.uid, metadata.gid);
+    inode.set_stat(metadata.size, (metadata.created, metadata.accessed, metadata.modified));
+    for (name, value) in metadata.xattrs.iter() {
+      inode.set_xattr(name, value);
+    }
+
+    let inode = Rc::new(RefCell::new(inode));
+    *lower.upper.borrow_mut() = Rc::downgrade(&inode);
+
+    let destination = match self.overlay_of(&lower.name) {
+      Some((overlay, point, relative)) => {
+        let mut files = overlay.files.borrow_mut();
+        match files.get(&lower.name) {
+          Some(file) if ptr::eq(file.as_ptr(), Rc::as_ptr(lower)) => {
+            files.remove(&lower.name);
+            let upper = match overlay.layers {
+              Layers::Trees { ref upper, .. } => Some(upper.clone()),
+              Layers::Host { .. } => None
+            };
+
+            Some((overlay.upper_name(point, relative), upper, relative.to_string()))
+          }
+          _ => None
+        }
+      }
+      None => None
+    };
+
+    if let Some((destination, upper, relative)) = destination {
+      if let Some(upper) = upper {
+        self.make_parents(&upper, &relative);
+      }
+
+      self.cwd.insert(destination, File::new_data_file(inode.clone()));
+    }
+
+    Ok(inode)
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use std::fs;
+  use std::io::ErrorKind;
+  use std::os::unix::fs::symlink;
+  use std::path::PathBuf;
+  use std::process;
+
+  use mount::Mounted;
+  use super::Overlay;
+  use {Filesystem, Proc, Whence, O_CREAT, O_RDWR};
+
+  fn scratch(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("rustfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn contents(p: &mut Proc<'static>, name: &'static str) -> Vec<u8> {
+    let size = p.metadata(name).expect("missing").size;
+    let fd = p.open(name, O_RDWR);
+    let mut buf = vec![0; size];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  // The layers of the host overlay mounted first.
+  fn host_layers<'a, 'r>(p: &'a Proc<'r>) -> &'a Overlay {
+    match p.mounts[0].mounted {
+      Mounted::Memory(ref fs) => match fs.mounts[0].mounted {
+        Mounted::Layers(ref overlay) => overlay,
+        _ => panic!("no layers")
+      },
+      _ => panic!("not an overlay")
+    }
+  }
+
+  #[test]
+  fn test_read_through() {
+    let host = scratch("overlay");
+    fs::create_dir_all(host.join("src/empty")).unwrap();
+    fs::write(host.join("src/main.rs"), b"fn main() {}\n").unwrap();
+    fs::write(host.join("README"), b"read me").unwrap();
+    symlink("src/main.rs", host.join("main")).unwrap();
+
+    let mut p = Proc::new();
+    assert!(p.mkdir("repo"));
+    assert!(p.mkdir("file"));
+    p.mount("repo", Filesystem::Overlay(host.clone())).unwrap();
+    assert_eq!(p.mount("./repo/", Filesystem::Overlay(host.clone())).unwrap_err().kind(), ErrorKind::ResourceBusy);
+    assert_eq!(p.mount("file", Filesystem::Overlay(host.join("README"))).unwrap_err().kind(), ErrorKind::NotADirectory);
+    assert!(p.metadata("repo").unwrap().is_dir());
+    assert!(p.metadata("repo/src/empty").unwrap().is_dir());
+    assert_eq!(p.metadata("repo/README").unwrap().size, 7);
+    assert!(p.metadata("repo/missing").is_none());
+    assert!(p.metadata("repo/src/../README").is_none());
+    assert_eq!(p.read_dir("repo").unwrap(), vec!["README", "main", "src"]);
+
+    // Reads come from the host, symbolic links and all, and nothing's copied.
+    assert_eq!(contents(&mut p, "repo/main"), b"fn main() {}\n");
+    assert_eq!(p.readlink("repo/main").unwrap(), "src/main.rs");
+    assert_eq!(p.cwd.get_dir_rc().borrow().entries.len(), 2);
+
+    // Writing copies up; the host isn't touched, and other handles see it.
+    let reader = p.open("repo/src/main.rs", O_RDWR);
+    let writer = p.open("repo/src/main.rs", O_RDWR);
+    p.seek(writer, 0, Whence::SeekEnd);
+    assert_eq!(p.write(writer, b"// more\n"), 8);
+    let mut buf = [0u8; 21];
+    p.read(reader, &mut buf);
+    assert_eq!(&buf, b"fn main() {}\n// more\n");
+    p.close(reader);
+    p.close(writer);
+    assert_eq!(fs::read(host.join("src/main.rs")).unwrap(), b"fn main() {}\n");
+    assert_eq!(p.metadata("repo/src/main.rs").unwrap().size, 21);
+    assert_eq!(p.check(), vec![]);
+
+    // Unlinking hides the host's file, until something new takes its place.
+    let open = p.open("repo/README", O_RDWR);
+    p.unlink("repo/README");
+    assert!(p.metadata("repo/README").is_none());
+    assert_eq!(p.open("repo/README", O_RDWR), -2);
+    let mut buf = [0u8; 7];
+    p.read(open, &mut buf);
+    assert_eq!(&buf, b"read me");
+    let fd = p.open("repo/README", O_CREAT | O_RDWR);
+    p.write(fd, b"new");
+    p.close(fd);
+    assert_eq!(contents(&mut p, "repo/README"), b"new");
+    assert!(host.join("README").exists());
+    assert_eq!(p.read_dir("repo/src").unwrap(), vec!["empty", "main.rs"]);
+
+    // Linking a lower file copies it up first.
+    assert!(p.link("repo/src/main.rs", "repo/copy").is_ok());
+    assert!(p.link("repo/missing", "repo/other").is_err());
+    assert_eq!(p.link("repo/README", "copy").unwrap_err().kind(), ErrorKind::CrossesDevices);
+    assert_eq!(contents(&mut p, "repo/copy"), b"fn main() {}\n// more\n");
+
+    // It stays mounted while the unlinked file is open.
+    assert_eq!(p.umount("repo").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    p.close(open);
+    p.umount("repo").unwrap();
+    assert_eq!(p.umount("repo").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(p.metadata("repo/src/empty").is_none());
+    assert_eq!(p.read_dir("repo").unwrap(), Vec::<String>::new());
+    fs::remove_dir_all(&host).unwrap();
+  }
+
+  #[test]
+  fn test_lower_files_held() {
+    let host = scratch("held");
+    let names: Vec<String> = (0..200).map(|i| format!("host/dir/{}", i)).collect();
+    fs::create_dir_all(host.join("dir")).unwrap();
+    for name in names.iter() {
+      fs::write(host.join(&name["host/".len()..]), name.as_bytes()).unwrap();
+    }
+
+    // Looking files up doesn't keep them.
+    let mut p = Proc::new();
+    assert!(p.mkdir("host"));
+    p.mount("host", Filesystem::Overlay(host.clone())).unwrap();
+    for name in names.iter() {
+      assert!(p.metadata(name).is_some());
+    }
+
+    assert!(host_layers(&p).files.borrow().len() < 10);
+
+    // Open files don't hold files open on the host.
+    let open_on_host = || fs::read_dir("/proc/self/fd").map(|fds| fds.count()).unwrap_or(0);
+    let before = open_on_host();
+    let fds: Vec<_> = names.iter().map(|name| p.open(name, O_RDWR)).collect();
+    for (&fd, name) in fds.iter().zip(names.iter()) {
+      let mut buf = vec![0u8; name.len()];
+      p.read(fd, &mut buf);
+      assert_eq!(buf, name.as_bytes());
+    }
+
+    assert!(open_on_host() < before + 50);
+    assert_eq!(host_layers(&p).files.borrow().len(), names.len());
+    for fd in fds {
+      p.close(fd);
+    }
+
+    fs::remove_dir_all(&host).unwrap();
+  }
+
+  #[test]
+  fn test_copy_up_too_large() {
+    let host = scratch("copy-up");
+    let mut p = Proc::with_page_size(512);
+    let large = fs::File::create(host.join("large")).unwrap();
+    large.set_len(p.geometry().max_file_size() as u64 + 1).unwrap();
+
+    assert!(p.mkdir("host"));
+    p.mount("host", Filesystem::Overlay(host.clone())).unwrap();
+    let fd = p.open("host/large", O_RDWR);
+    assert_eq!(p.write(fd, b"data"), 0);
+    p.close(fd);
+    assert_eq!(p.link("host/large", "host/copy").unwrap_err().kind(), ErrorKind::FileTooLarge);
+    assert!(p.metadata("host/copy").is_none());
+    assert_eq!(p.metadata("host/large").unwrap().size, p.geometry().max_file_size() + 1);
+    fs::remove_dir_all(&host).unwrap();
+  }
+
+  fn create(p: &mut Proc<'static>, name: &'static str, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    p.write(fd, data);
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_union() {
+    let mut p = Proc::new();
+    for dir in ["base", "base/etc", "base/opt", "base/usr", "mid", "mid/etc"].iter() {
+      assert!(p.mkdir(dir));
+    }
+
+    create(&mut p, "base/etc/passwd", b"root:x:0:0");
+    create(&mut p, "base/etc/hosts", b"old");
+    create(&mut p, "base/opt/tool", b"tool");
+    create(&mut p, "base/usr/lib", b"lib");
+    create(&mut p, "mid/etc/hosts", b"127.0.0.1 localhost");
+    create(&mut p, "mid/.wh.opt", b"");
+    assert!(p.symlink("etc/hosts", "mid/hosts"));
+
+    let union = |upper: &str, lowers: &[&str]| Filesystem::Union {
+      upper: upper.to_string(),
+      lowers: lowers.iter().map(|dir| dir.to_string()).collect()
+    };
+    for dir in ["merged", "other"].iter() {
+      assert!(p.mkdir(dir));
+    }
+
+    p.mount("merged", union("up", &["mid", "base"])).unwrap();
+    assert_eq!(p.mount("merged", union("other", &["base"])).unwrap_err().kind(), ErrorKind::ResourceBusy);
+    assert_eq!(p.mount("other", union("up", &["missing"])).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(p.mount("base/etc", union("up", &["base"])).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // Listings merge the layers, the topmost winning, with whiteouts hiding
+    // what's below them and never listed themselves.
+    assert_eq!(p.read_dir("merged").unwrap(), vec!["etc", "hosts", "usr"]);
+    assert_eq!(p.read_dir("merged/etc").unwrap(), vec!["hosts", "passwd"]);
+    assert!(p.read_dir("merged/opt").is_none());
+    assert!(p.metadata("merged/.wh.opt").is_none());
+    assert_eq!(contents(&mut p, "merged/hosts"), b"127.0.0.1 localhost");
+    assert_eq!(p.read_dir("up").unwrap(), Vec::<String>::new());
+
+    // Writing copies up into the upper directory, and the lowers stay as
+    // they were.
+    let reader = p.open("merged/etc/passwd", O_RDWR);
+    let writer = p.open("merged/etc/passwd", O_RDWR);
+    p.seek(writer, 0, Whence::SeekEnd);
+    assert_eq!(p.write(writer, b":root"), 5);
+    let mut buf = [0u8; 15];
+    p.read(reader, &mut buf);
+    assert_eq!(&buf, b"root:x:0:0:root");
+    p.close(reader);
+    p.close(writer);
+    assert_eq!(contents(&mut p, "up/etc/passwd"), b"root:x:0:0:root");
+    assert_eq!(contents(&mut p, "base/etc/passwd"), b"root:x:0:0");
+
+    // Unlinking leaves a whiteout in the upper directory.
+    p.unlink("merged/etc/hosts");
+    assert!(p.metadata("merged/etc/hosts").is_none());
+    assert_eq!(p.read_dir("merged/etc").unwrap(), vec!["passwd"]);
+    assert_eq!(p.read_dir("up/etc").unwrap(), vec![".wh.hosts", "passwd"]);
+    assert_eq!(contents(&mut p, "mid/etc/hosts"), b"127.0.0.1 localhost");
+    create(&mut p, "merged/etc/hosts", b"new");
+    assert_eq!(p.read_dir("up/etc").unwrap(), vec!["hosts", "passwd"]);
+    assert_eq!(contents(&mut p, "merged/etc/hosts"), b"new");
+
+    // A directory made where one was unlinked is opaque.
+    p.unlink("merged/usr");
+    assert!(p.mkdir("merged/usr"));
+    assert_eq!(p.read_dir("merged/usr").unwrap(), Vec::<String>::new());
+    assert_eq!(p.read_dir("up/usr").unwrap(), vec![".wh..wh..opq"]);
+    assert_eq!(p.read_dir("merged").unwrap(), vec!["etc", "hosts", "usr"]);
+    assert_eq!(p.check(), vec![]);
+
+    // Open files keep it mounted, and its directories can't be layers of
+    // another union.
+    let fd = p.open("merged/usr/new", O_CREAT | O_RDWR);
+    assert_eq!(p.umount("merged").unwrap_err().kind(), ErrorKind::ResourceBusy);
+    p.close(fd);
+    assert_eq!(p.mount("other", union("other-up", &["merged/etc"])).unwrap_err().kind(), ErrorKind::InvalidInput);
+    p.umount("merged").unwrap();
+    assert_eq!(p.read_dir("merged").unwrap(), Vec::<String>::new());
+    assert_eq!(p.read_dir("up/usr").unwrap(), vec![".wh..wh..opq", "new"]);
+    assert_eq!(contents(&mut p, "up/etc/hosts"), b"new");
+  }
+
+  #[test]
+  fn test_union_hides_mount_point() {
+    // What the mount point had is hidden while the union is mounted, and
+    // left as it was.
+    let mut p = Proc::new();
+    for dir in ["low", "merged"].iter() {
+      assert!(p.mkdir(dir));
+    }
+
+    create(&mut p, "low/file", b"lower");
+    create(&mut p, "merged/old", b"old");
+    p.mount("merged", Filesystem::Union { upper: "up".to_string(), lowers: vec!["low".to_string()] }).unwrap();
+    assert!(p.metadata("merged/old").is_none());
+    assert_eq!(p.open("merged/old", O_RDWR), -2);
+    assert_eq!(p.read_dir("merged").unwrap(), vec!["file"]);
+
+    create(&mut p, "merged/old", b"new");
+    assert_eq!(contents(&mut p, "up/old"), b"new");
+    p.unlink("merged/old");
+    assert!(p.metadata("merged/old").is_none());
+    p.rename("merged/file", "merged/old").unwrap();
+    assert_eq!(contents(&mut p, "merged/old"), b"lower");
+
+    p.umount("merged").unwrap();
+    assert_eq!(p.read_dir("merged").unwrap(), vec!["old"]);
+    assert_eq!(contents(&mut p, "merged/old"), b"old");
+  }
+}
@@ -0,0 +1,493 @@
+/*!
+ * Converting `strace` output to traces.
+ *
+ * Takes the output of something like
+ *
+ *   strace -f -tt -e trace=open,openat,creat,read,write,pread64,pwrite64,lseek,close,unlink,unlinkat
+ *
+ * and turns the calls on files it opened into a trace that `trace::replay`
+ * can run. Calls on descriptors opened some other way (pipes, sockets,
+ * inherited ones) are left out, as are calls this file system has no
+ * equivalent for. Paths are kept as they are: RustFS has one flat directory,
+ * so `a/b` is just a name.
+ *
+ * With `-f`, each line starts with the pid of the process or thread making the
+ * call. Descriptors are looked up per pid first, then by number alone, since
+ * threads share their process's descriptors. Timestamps from `-t`, `-tt`, or
+ * `-ttt` are kept; without them every call is at time 0.
+ */
+
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+use file::Whence;
+use trace::{Call, Event};
+use {FileDescriptor, O_APPEND, O_CREAT, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY};
+
+/**
+ * What became of the lines of an strace log.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConvertStats {
+  pub lines: usize,
+  pub calls: usize,     // calls converted
+  pub ignored: usize,   // calls on other descriptors or of other kinds
+}
+
+// A traced descriptor: its number in the trace, and its position.
+#[derive(Clone, Copy)]
+struct Open {
+  fd: FileDescriptor,
+  position: usize,
+}
+
+struct Converter {
+  events: Vec<Event>,
+  stats: ConvertStats,
+  by_pid: HashMap<(u32, i64), Open>,
+  by_fd: HashMap<i64, (u32, i64)>,
+  next_fd: FileDescriptor,
+  start_ns: Option<u64>,
+  time_ns: u64,
+  unfinished: HashMap<u32, String>,
+}
+
+// Splits the pid and timestamp off the front of a line.
+fn split_prefix(line: &str) -> (u32, Option<u64>, &str) {
+  let mut rest = line.trim_start();
+  let mut pid = 0;
+  if let Some(stripped) = rest.strip_prefix("[pid ") {
+    if let Some(end) = stripped.find(']') {
+      pid = stripped[..end].trim().parse().unwrap_or(0);
+      rest = stripped[end + 1..].trim_start();
+    }
+  } else if let Some((first, after)) = rest.split_once(' ') {
+    if !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit()) {
+      pid = first.parse().unwrap_or(0);
+      rest = after.trim_start();
+    }
+  }
+
+  let mut time = None;
+  if let Some((first, after)) = rest.split_once(' ') {
+    if let Some(ns) = parse_time(first) {
+      time = Some(ns);
+      rest = after.trim_start();
+    }
+  }
+
+  (pid, time, rest)
+}
+
+// Parses HH:MM:SS[.ffffff] or seconds.ffffff into ns.
+fn parse_time(s: &str) -> Option<u64> {
+  let (whole, fraction) = match s.split_once('.') {
+    Some((whole, fraction)) => (whole, fraction),
+    None => (s, "")
+  };
+
+  if !fraction.bytes().all(|b| b.is_ascii_digit()) || fraction.len() > 9 {
+    return None;
+  }
+
+  let fraction_ns = format!("{:0<9}", fraction).parse::<u64>().ok()?;
+  let parts: Vec<&str> = whole.split(':').collect();
+  if parts.len() != 1 && parts.len() != 3 || (parts.len() == 1 && fraction.is_empty()) {
+    return None;
+  }
+
+  let mut seconds = 0u64;
+  for part in parts {
+    if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+
+    seconds = seconds * 60 + part.parse::<u64>().ok()?;
+  }
+
+  Some(seconds * 1_000_000_000 + fraction_ns)
+}
+
+// Splits a call's arguments at top-level commas, minding quotes and brackets.
+fn split_args(args: &str) -> Vec<String> {
+  let (mut fields, mut field) = (Vec::new(), String::new());
+  let (mut depth, mut quoted, mut escaped) = (0, false, false);
+  for c in args.chars() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if quoted => escaped = true,
+      '"' => quoted = !quoted,
+      '(' | '[' | '{' if !quoted => depth += 1,
+      ')' | ']' | '}' if !quoted => depth -= 1,
+      ',' if !quoted && depth == 0 => {
+        fields.push(field.trim().to_string());
+        field.clear();
+        continue;
+      }
+      _ => {}
+    }
+
+    field.push(c);
+  }
+
+  if !field.trim().is_empty() {
+    fields.push(field.trim().to_string());
+  }
+
+  fields
+}
+
+// The string in a quoted strace argument, with its escapes undone.
+fn unquote(arg: &str) -> Option<String> {
+  let inner = arg.strip_prefix('"')?;
+  let inner = &inner[..inner.rfind('"')?];
+  let (mut bytes, mut chars) = (Vec::new(), inner.chars());
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      let mut buf = [0u8; 4];
+      bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+      continue;
+    }
+
+    match chars.next()? {
+      'n' => bytes.push(b'\n'),
+      't' => bytes.push(b'\t'),
+      'r' => bytes.push(b'\r'),
+      'x' => {
+        let hex: String = chars.by_ref().take(2).collect();
+        bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+      }
+      c @ '0'..='7' => {
+        let mut value = c as u32 - '0' as u32;
+        let mut rest = chars.clone();
+        for _ in 0..2 {
+          match rest.next() {
+            Some(d @ '0'..='7') => { value = value * 8 + (d as u32 - '0' as u32); chars.next(); }
+            _ => break
+          }
+        }
+
+        bytes.push(value as u8);
+      }
+      c => bytes.push(c as u8)
+    }
+  }
+
+  String::from_utf8(bytes).ok()
+}
+
+fn parse_flags(arg: &str) -> u32 {
+  arg.split('|').fold(0, |flags, flag| flags | match flag.trim() {
+    "O_RDONLY" => O_RDONLY,
+    "O_WRONLY" => O_WRONLY,
+    "O_RDWR" => O_RDWR,
+    "O_CREAT" => O_CREAT,
+    "O_APPEND" => O_APPEND,
+    "O_NONBLOCK" => O_NONBLOCK,
+    _ => 0
+  })
+}
+
+fn parse_int(arg: &str) -> Option<i64> {
+  let arg = arg.trim();
+  match arg.strip_prefix("0x") {
+    Some(hex) => i64::from_str_radix(hex, 16).ok(),
+    None => arg.parse().ok()
+  }
+}
+
+// Splits `name(args) = result ...` into its parts.
+fn split_call(text: &str) -> Option<(&str, &str, i64)> {
+  // strace pads short calls out to line their results up.
+  let equals = text.rfind(" = ")?;
+  let call = text[..equals].trim_end().strip_suffix(')')?;
+  let open = call.find('(')?;
+  let name = &call[..open];
+  if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+    return None;
+  }
+
+  let result = text[equals + 3..].split_whitespace().next()?;
+  Some((name, &call[open + 1..], parse_int(result)?))
+}
+
+impl Converter {
+  fn new() -> Converter {
+    Converter {
+      events: Vec::new(),
+      stats: ConvertStats::default(),
+      by_pid: HashMap::new(),
+      by_fd: HashMap::new(),
+      next_fd: 3,
+      start_ns: None,
+      time_ns: 0,
+      unfinished: HashMap::new()
+    }
+  }
+
+  fn push(&mut self, call: Call) {
+    self.events.push(Event { time_ns: self.time_ns, call });
+    self.stats.calls += 1;
+  }
+
+  fn lookup(&self, pid: u32, fd: i64) -> Option<(u32, i64)> {
+    match self.by_pid.contains_key(&(pid, fd)) {
+      true => Some((pid, fd)),
+      false => self.by_fd.get(&fd).cloned()
+    }
+  }
+
+  fn line(&mut self, line: &str) {
+    self.stats.lines += 1;
+    let (pid, time, rest) = split_prefix(line);
+    if let Some(time) = time {
+      let start = *self.start_ns.get_or_insert(time);
+      // Keep time moving forward, even across midnight or out-of-order lines.
+      self.time_ns = self.time_ns.max(time.saturating_sub(start));
+    }
+
+    let text = if let Some(before) = rest.strip_suffix("<unfinished ...>") {
+      self.unfinished.insert(pid, before.to_string());
+      return;
+    } else if rest.starts_with("<... ") {
+      let resumed = match rest.find("resumed>") {
+        Some(i) => &rest[i + "resumed>".len()..],
+        None => return
+      };
+
+      match self.unfinished.remove(&pid) {
+        Some(before) => before + resumed,
+        None => return
+      }
+    } else {
+      rest.to_string()
+    };
+
+    match split_call(&text) {
+      Some((name, args, result)) => {
+        if !self.call(pid, name, &split_args(args), result) {
+          self.stats.ignored += 1;
+        }
+      }
+      None => {
+        // Signals, exits, and anything else that isn't a finished call.
+        if text.contains('(') && !text.starts_with("---") && !text.starts_with("+++") {
+          self.stats.ignored += 1;
+        }
+      }
+    }
+  }
+
+  // Converts one call. Returns whether it was one to keep.
+  fn call(&mut self, pid: u32, name: &str, args: &[String], result: i64) -> bool {
+    let arg = |i: usize| args.get(i).map(String::as_str).unwrap_or("");
+    match name {
+      "open" | "openat" | "creat" => {
+        let (path, flags) = match name {
+          "open" => (unquote(arg(0)), parse_flags(arg(1))),
+          "openat" => (unquote(arg(1)), parse_flags(arg(2))),
+          _ => (unquote(arg(0)), O_CREAT | O_WRONLY)
+        };
+
+        let path = match path {
+          Some(path) => path,
+          None => return false
+        };
+
+        if result < 0 {
+          self.push(Call::Open { path, flags, fd: -2 });
+          return true;
+        }
+
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.by_pid.insert((pid, result), Open { fd, position: 0 });
+        self.by_fd.insert(result, (pid, result));
+        self.push(Call::Open { path, flags, fd });
+        true
+      }
+      "unlink" | "unlinkat" => {
+        let path = match name {
+          "unlink" => unquote(arg(0)),
+          _ if arg(2).contains("AT_REMOVEDIR") => None,
+          _ => unquote(arg(1))
+        };
+
+        match path {
+          Some(path) if result == 0 => { self.push(Call::Unlink { path }); true }
+          _ => false
+        }
+      }
+      "read" | "write" | "pread64" | "pwrite64" | "lseek" | "close" => {
+        let key = match parse_int(arg(0)).and_then(|fd| self.lookup(pid, fd)) {
+          Some(key) => key,
+          None => return false
+        };
+
+        let open = self.by_pid[&key];
+        let fd = open.fd;
+        match name {
+          "close" => {
+            self.by_pid.remove(&key);
+            if self.by_fd.get(&key.1) == Some(&key) {
+              self.by_fd.remove(&key.1);
+            }
+
+            self.push(Call::Close { fd });
+          }
+          "lseek" => {
+            let whence = match arg(2) {
+              "SEEK_SET" => Whence::SeekSet,
+              "SEEK_CUR" => Whence::SeekCur,
+              "SEEK_END" => Whence::SeekEnd,
+              _ => return false
+            };
+
+            if result < 0 { return false }
+            let offset = parse_int(arg(1)).unwrap_or(0) as isize;
+            self.by_pid.get_mut(&key).unwrap().position = result as usize;
+            self.push(Call::Seek { fd, offset, whence, result: result as usize });
+          }
+          _ => {
+            // Reads and writes of the count asked for, returning what they
+            // returned; positioned ones are a seek there, the call, and a
+            // seek back.
+            let len = parse_int(arg(2)).unwrap_or(0).max(0) as usize;
+            let result = result.max(0) as usize;
+            let at = match name {
+              "pread64" | "pwrite64" => Some(parse_int(arg(3)).unwrap_or(0).max(0) as usize),
+              _ => None
+            };
+
+            let offset = at.unwrap_or(open.position);
+            if let Some(at) = at {
+              self.push(Call::Seek { fd, offset: at as isize, whence: Whence::SeekSet, result: at });
+            }
+
+            match name {
+              "read" | "pread64" => self.push(Call::Read { fd, offset, len, result }),
+              _ => self.push(Call::Write { fd, offset, len, result })
+            }
+
+            match at {
+              Some(_) => {
+                let position = open.position;
+                self.push(Call::Seek { fd, offset: position as isize, whence: Whence::SeekSet, result: position });
+              }
+              None => self.by_pid.get_mut(&key).unwrap().position = offset + result
+            }
+          }
+        }
+
+        true
+      }
+      _ => false
+    }
+  }
+}
+
+/**
+ * Converts strace output to the events of a trace.
+ */
+pub fn convert<R: BufRead>(input: R) -> io::Result<(Vec<Event>, ConvertStats)> {
+  let mut converter = Converter::new();
+  for line in input.lines() {
+    converter.line(&line?);
+  }
+
+  Ok((converter.events, converter.stats))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{convert, parse_time, split_args, unquote};
+  use file::Whence;
+  use trace::{replay, Call, Timing};
+  use {Proc, O_CREAT, O_RDONLY, O_WRONLY};
+
+  const LOG: &str = r#"1200  10:00:00.000100 openat(AT_FDCWD, "src/main.rs", O_RDONLY|O_CLOEXEC) = 3
+1200  10:00:00.000200 read(3, "fn main() {\n    println!(\"hi\")"..., 8192) = 40
+1200  10:00:00.000300 read(3, "", 8152) = 0
+1200  10:00:00.000400 close(3)           = 0
+1200  10:00:00.000500 openat(AT_FDCWD, "missing.rs", O_RDONLY) = -1 ENOENT (No such file or directory)
+1200  10:00:00.000600 read(0, "x", 1)     = 1
+1201  10:00:00.000700 openat(AT_FDCWD, "target/out.o", O_WRONLY|O_CREAT|O_TRUNC, 0644) = 3
+1202  10:00:00.000800 write(3, "\177ELF"..., 4096 <unfinished ...>
+1201  10:00:00.000900 --- SIGCHLD {si_signo=SIGCHLD} ---
+1202  10:00:00.001000 <... write resumed>) = 4096
+1201  10:00:00.001100 pwrite64(3, "\0\0", 2, 100) = 2
+1201  10:00:00.001200 lseek(3, 0, SEEK_END) = 4096
+1201  10:00:00.001300 close(3) = 0
+1201  10:00:00.001400 unlinkat(AT_FDCWD, "target/old.o", 0) = 0
+1201  10:00:00.001500 unlinkat(AT_FDCWD, "target/dir", AT_REMOVEDIR) = 0
+1201  10:00:00.001600 mmap(NULL, 8192, PROT_READ, MAP_PRIVATE, 3, 0) = 0x7f0000000000
+1201  10:00:00.001700 +++ exited with 0 +++
+"#;
+
+  #[test]
+  fn test_convert() {
+    let (events, stats) = convert(LOG.as_bytes()).unwrap();
+    let calls: Vec<_> = events.iter().map(|event| event.call.clone()).collect();
+    assert_eq!(calls, vec![
+      Call::Open { path: "src/main.rs".to_string(), flags: O_RDONLY, fd: 3 },
+      Call::Read { fd: 3, offset: 0, len: 8192, result: 40 },
+      Call::Read { fd: 3, offset: 40, len: 8152, result: 0 },
+      Call::Close { fd: 3 },
+      Call::Open { path: "missing.rs".to_string(), flags: O_RDONLY, fd: -2 },
+      Call::Open { path: "target/out.o".to_string(), flags: O_WRONLY | O_CREAT, fd: 4 },
+      Call::Write { fd: 4, offset: 0, len: 4096, result: 4096 },
+      Call::Seek { fd: 4, offset: 100, whence: Whence::SeekSet, result: 100 },
+      Call::Write { fd: 4, offset: 100, len: 2, result: 2 },
+      Call::Seek { fd: 4, offset: 4096, whence: Whence::SeekSet, result: 4096 },
+      Call::Seek { fd: 4, offset: 0, whence: Whence::SeekEnd, result: 4096 },
+      Call::Close { fd: 4 },
+      Call::Unlink { path: "target/old.o".to_string() },
+    ]);
+
+    assert_eq!(stats.lines, 17);
+    assert_eq!((stats.calls, stats.ignored), (13, 3));
+    assert_eq!(events[0].time_ns, 0);
+    assert_eq!(events[6].time_ns, 900_000);
+
+    // The converted trace replays cleanly, apart from the source file, which
+    // existed before the trace and so is empty in the replay: RustFS reads
+    // past the end of a file, so the reads return more than they did.
+    let stats = replay(&mut Proc::new(), &events, Timing::Fast);
+    assert_eq!((stats.calls, stats.skipped), (13, 0));
+    assert_eq!(stats.mismatches, 2);
+  }
+
+  #[test]
+  fn test_convert_far_write() {
+    // A sparse write further along than any file can go replays as a
+    // mismatch.
+    let log = r#"1200  10:00:00.000100 openat(AT_FDCWD, "db", O_WRONLY|O_CREAT, 0644) = 3
+1200  10:00:00.000200 pwrite64(3, "x", 1, 1099511627776) = 1
+1200  10:00:00.000300 close(3) = 0
+"#;
+    let (events, _) = convert(log.as_bytes()).unwrap();
+    let mut p = Proc::new();
+    let stats = replay(&mut p, &events, Timing::Fast);
+    assert_eq!((stats.calls, stats.mismatches, stats.skipped), (events.len(), 1, 0));
+    assert_eq!(p.metadata("db").unwrap().size, 0);
+  }
+
+  #[test]
+  fn test_parse_time() {
+    assert_eq!(parse_time("10:00:01.5"), Some(36_001_500_000_000));
+    assert_eq!(parse_time("00:00:02"), Some(2_000_000_000));
+    assert_eq!(parse_time("1712345678.000001"), Some(1_712_345_678_000_001_000));
+    assert_eq!(parse_time("1200"), None);
+    assert_eq!(parse_time("openat(AT_FDCWD,"), None);
+  }
+
+  #[test]
+  fn test_args() {
+    assert_eq!(split_args(r#"AT_FDCWD, "a, \"b\"", O_RDWR|O_CREAT, 0644"#),
+               vec!["AT_FDCWD", r#""a, \"b\"""#, "O_RDWR|O_CREAT", "0644"]);
+    assert_eq!(split_args("3, {st_mode=S_IFREG|0644, st_size=10, ...}"), vec!["3", "{st_mode=S_IFREG|0644, st_size=10, ...}"]);
+    assert_eq!(unquote(r#""a\"b\\c\n\x41\101""#).unwrap(), "a\"b\\c\nAA");
+    assert_eq!(unquote("\"caf\u{e9}\"").unwrap(), "caf\u{e9}");
+    assert_eq!(unquote("NULL"), None);
+  }
+}
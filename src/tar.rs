@@ -0,0 +1,753 @@
+/*!
+ * Importing and exporting tar archives.
+ *
+ * `Proc::import_tar` unpacks an archive into the file system and
+ * `Proc::export_tar` packs files into one, without going through the host's
+ * disk. Archives are POSIX ustar, with PAX extended headers for what ustar
+ * can't hold: long names and link targets, large sizes and ids, times to the
+ * nanosecond, and extended attributes (as `SCHILY.xattr.NAME`, like GNU tar
+ * and libarchive). GNU long names are read too.
+ *
+ * Regular files, directories, symbolic links, and hard links are supported,
+ * with their modes, owners, and modification times; other kinds of entry are
+ * skipped. Names are flat, so entry `a/b` becomes the file named `a/b`, and
+ * a directory entry a marker (see `Proc::mkdir`) with no metadata of its own.
+ * Runs of zeroes a page long or more in files' data are left as holes.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use archive;
+use directory::DirectoryHandle;
+use file::File;
+use time::{self, Timespec};
+use {Proc, DEFAULT_DIR_MODE, S_IFLNK, S_IFREG};
+
+const BLOCK: usize = 512;
+
+// Where each field of a header is, and how long it is.
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const MTIME: (usize, usize) = (136, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE: usize = 156;
+const LINKNAME: (usize, usize) = (157, 100);
+const MAGIC: (usize, usize) = (257, 8);
+const PREFIX: (usize, usize) = (345, 155);
+
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/**
+ * How many of each kind of entry an archive had.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TarStats {
+  pub files: usize,
+  pub directories: usize,
+  pub symlinks: usize,
+  pub hard_links: usize,
+  // Devices, FIFOs, and anything else there's no equivalent for.
+  pub skipped: usize,
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad tar archive: {}", what))
+}
+
+// The bytes of a field, up to the first NUL.
+fn field(header: &[u8], (start, len): (usize, usize)) -> &[u8] {
+  let bytes = &header[start..start + len];
+  let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(len);
+  &bytes[..end]
+}
+
+fn text(bytes: &[u8]) -> io::Result<String> {
+  String::from_utf8(bytes.to_vec()).map_err(|_| invalid("name isn't UTF-8"))
+}
+
+// A number field: octal, or big-endian base-256 if the high bit is set.
+fn number(header: &[u8], (start, len): (usize, usize)) -> io::Result<u64> {
+  let bytes = &header[start..start + len];
+  if bytes[0] & 0x80 != 0 {
+    // The rest of the first byte is the top of the number; negative ones
+    // have its next bit set.
+    if bytes[0] & 0x40 != 0 {
+      return Err(invalid("number out of range"));
+    }
+
+    let first = (bytes[0] & 0x7f) as u64;
+    return bytes[1..].iter().try_fold(first, |n, &byte| match n >> 56 {
+      0 => Ok((n << 8) | byte as u64),
+      _ => Err(invalid("number out of range"))
+    });
+  }
+
+  let digits = field(header, (start, len));
+  let digits = std::str::from_utf8(digits).map_err(|_| invalid("bad number"))?.trim_matches(' ');
+  match digits {
+    "" => Ok(0),
+    _ => u64::from_str_radix(digits, 8).map_err(|_| invalid("bad number"))
+  }
+}
+
+// The header's checksum: the sum of its bytes, with the checksum's own as
+// spaces.
+fn checksum(header: &[u8]) -> u64 {
+  let (start, len) = CHECKSUM;
+  header.iter().enumerate()
+    .map(|(i, &byte)| if (start..start + len).contains(&i) { b' ' as u64 } else { byte as u64 })
+    .sum()
+}
+
+// Seconds with an optional fraction, as PAX writes times.
+fn parse_time(value: &str) -> io::Result<Timespec> {
+  let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+  let sec: i64 = whole.parse().map_err(|_| invalid("bad time"))?;
+  if fraction.len() > 9 && !fraction[9..].bytes().all(|b| b.is_ascii_digit()) {
+    return Err(invalid("bad time"));
+  }
+
+  let fraction = &fraction[..fraction.len().min(9)];
+  let mut nsec: i32 = match fraction {
+    "" => 0,
+    _ => format!("{:0<9}", fraction).parse().map_err(|_| invalid("bad time"))?
+  };
+
+  // -1.25 is 1.25 seconds before the epoch: -2 seconds plus 0.75.
+  let sec = if whole.starts_with('-') && nsec > 0 { nsec = 1_000_000_000 - nsec; sec - 1 } else { sec };
+  Ok(Timespec::new(sec, nsec))
+}
+
+fn format_time(time: Timespec) -> String {
+  let (mut sec, mut nsec) = (time.sec, time.nsec);
+  match nsec {
+    0 => sec.to_string(),
+    _ => {
+      let sign = if sec < 0 { sec += 1; nsec = 1_000_000_000 - nsec; "-" } else { "" };
+      let fraction = format!("{:09}", nsec);
+      format!("{}{}.{}", sign, sec.abs(), fraction.trim_end_matches('0'))
+    }
+  }
+}
+
+// Keys and values from PAX extended headers.
+type Records = Vec<(String, Vec<u8>)>;
+
+// The records of a PAX extended header: `LENGTH KEY=VALUE\n`, where LENGTH
+// counts the whole record.
+fn parse_pax(mut data: &[u8]) -> io::Result<Records> {
+  let mut records = Vec::new();
+  while !data.is_empty() {
+    let space = data.iter().position(|&byte| byte == b' ').ok_or_else(|| invalid("bad PAX record"))?;
+    let len: usize = std::str::from_utf8(&data[..space]).ok().and_then(|len| len.parse().ok())
+      .filter(|&len| len > space + 1 && len <= data.len() && data[len - 1] == b'\n')
+      .ok_or_else(|| invalid("bad PAX record"))?;
+
+    let record = &data[space + 1..len - 1];
+    let equals = record.iter().position(|&byte| byte == b'=').ok_or_else(|| invalid("bad PAX record"))?;
+    records.push((text(&record[..equals])?, record[equals + 1..].to_vec()));
+    data = &data[len..];
+  }
+
+  Ok(records)
+}
+
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+  // The length includes its own digits, so it may need one more of them.
+  let rest = 1 + key.len() + 1 + value.len() + 1;
+  let mut len = rest + rest.to_string().len();
+  if len.to_string().len() + rest > len {
+    len += 1;
+  }
+
+  out.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+  out.extend_from_slice(value);
+  out.push(b'\n');
+}
+
+// `name` under `dest`, or an error if it would lead outside.
+fn clean(dest: &str, name: &str) -> io::Result<String> {
+  archive::clean(dest, name).ok_or_else(|| invalid(&format!("'{}' is outside the destination", name)))
+}
+
+fn read_block<R: Read>(input: &mut R, block: &mut [u8; BLOCK]) -> io::Result<bool> {
+  let mut read = 0;
+  while read < BLOCK {
+    match input.read(&mut block[read..]) {
+      Ok(0) if read == 0 => return Ok(false),
+      Ok(0) => return Err(invalid("truncated")),
+      Ok(n) => read += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e)
+    }
+  }
+
+  Ok(true)
+}
+
+fn padding(size: u64) -> u64 {
+  (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64
+}
+
+// Reads `size` bytes of entry data, and the padding after them.
+fn read_data<R: Read>(input: &mut R, size: u64) -> io::Result<Vec<u8>> {
+  let mut data = Vec::new();
+  input.take(size).read_to_end(&mut data)?;
+  if (data.len() as u64) < size {
+    return Err(invalid("truncated"));
+  }
+
+  skip(input, padding(size))?;
+  Ok(data)
+}
+
+fn skip<R: Read>(input: &mut R, len: u64) -> io::Result<()> {
+  if io::copy(&mut input.take(len), &mut io::sink())? < len {
+    return Err(invalid("truncated"));
+  }
+
+  Ok(())
+}
+
+// An entry's header, with any extended headers before it applied.
+struct Entry {
+  kind: u8,
+  name: String,
+  link: String,
+  mode: u32,
+  uid: u32,
+  gid: u32,
+  size: u64,
+  mtime: Timespec,
+  atime: Option<Timespec>,
+  xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Entry {
+  fn new(header: &[u8; BLOCK]) -> io::Result<Entry> {
+    let mut name = text(field(header, NAME))?;
+    if field(header, MAGIC).starts_with(b"ustar") && !field(header, PREFIX).is_empty() {
+      name = format!("{}/{}", text(field(header, PREFIX))?, name);
+    }
+
+    Ok(Entry {
+      kind: header[TYPE],
+      name,
+      link: text(field(header, LINKNAME))?,
+      mode: number(header, MODE)? as u32,
+      uid: number(header, UID)? as u32,
+      gid: number(header, GID)? as u32,
+      size: number(header, SIZE)?,
+      mtime: Timespec::new(number(header, MTIME)? as i64, 0),
+      atime: None,
+      xattrs: Vec::new()
+    })
+  }
+
+  fn apply(&mut self, records: &[(String, Vec<u8>)]) -> io::Result<()> {
+    for (key, value) in records.iter() {
+      let number = || text(value)?.parse::<u64>().map_err(|_| invalid(&format!("bad PAX {}", key)));
+      match key.as_str() {
+        "path" => self.name = text(value)?,
+        "linkpath" => self.link = text(value)?,
+        "size" => self.size = number()?,
+        "uid" => self.uid = number()? as u32,
+        "gid" => self.gid = number()? as u32,
+        "mtime" => self.mtime = parse_time(&text(value)?)?,
+        "atime" => self.atime = Some(parse_time(&text(value)?)?),
+        _ if key.starts_with(XATTR_PREFIX) => {
+          self.xattrs.push((key[XATTR_PREFIX.len()..].to_string(), value.clone()));
+        }
+        _ => {}
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl<'r> Proc<'r> {
+  /**
+   * Unpacks the tar archive in `input` into `dest_dir`, which is "" for the
+   * top. Entries replace any files already there with the same names. Fails
+   * on entries whose names lead outside `dest_dir` or into a mounted file
+   * system, hard links to files that aren't there, and malformed archives;
+   * whatever was unpacked before then stays.
+   */
+  pub fn import_tar<R: Read>(&mut self, mut input: R, dest_dir: &str) -> io::Result<TarStats> {
+    let mut stats = TarStats::default();
+    let mut block = [0u8; BLOCK];
+    let (mut global, mut local): (Records, Records) = (Vec::new(), Vec::new());
+    let (mut long_name, mut long_link) = (None, None);
+    while read_block(&mut input, &mut block)? {
+      if block.iter().all(|&byte| byte == 0) {
+        break;
+      }
+
+      let expected = number(&block, CHECKSUM)?;
+      let signed: i64 = block.iter().enumerate()
+        .map(|(i, &byte)| if (CHECKSUM.0..CHECKSUM.0 + CHECKSUM.1).contains(&i) { 32 } else { byte as i8 as i64 })
+        .sum();
+      if expected != checksum(&block) && expected as i64 != signed {
+        return Err(invalid("header checksum mismatch"));
+      }
+
+      let mut entry = Entry::new(&block)?;
+      match entry.kind {
+        b'x' | b'g' | b'L' | b'K' => {
+          let data = read_data(&mut input, entry.size)?;
+          match entry.kind {
+            b'x' => local = parse_pax(&data)?,
+            b'g' => global.extend(parse_pax(&data)?),
+            _ => {
+              let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+              let value = Some(text(&data[..end])?);
+              if entry.kind == b'L' { long_name = value } else { long_link = value }
+            }
+          }
+
+          continue;
+        }
+        _ => {}
+      }
+
+      entry.apply(&global)?;
+      entry.apply(&local)?;
+      if let Some(name) = long_name.take() { entry.name = name }
+      if let Some(link) = long_link.take() { entry.link = link }
+      local.clear();
+
+      let name = clean(dest_dir, &entry.name)?;
+      let has_data = matches!(entry.kind, b'0' | b'\0' | b'7');
+      if name.is_empty() && entry.kind != b'5' {
+        return Err(invalid(&format!("'{}' has no name", entry.name)));
+      } else if !name.is_empty() {
+        self.check_unmounted(&name)?;
+      }
+
+      if has_data && entry.size > self.geometry().max_file_size() as u64 {
+        return Err(invalid(&format!("'{}' is too large for the page size", entry.name)));
+      }
+
+      match entry.kind {
+        b'0' | b'\0' | b'7' | b'2' => {
+          let mut inode = self.allocator.alloc_inode();
+          if entry.kind == b'2' {
+            inode.set_mode(S_IFLNK | (entry.mode & 0o7777));
+            inode.write(0, entry.link.as_bytes());
+            stats.symlinks += 1;
+          } else {
+            inode.set_mode(S_IFREG | (entry.mode & 0o7777));
+            let page_size = self.geometry().page_size();
+            archive::fill(&mut input, &mut inode, entry.size, page_size).map_err(|_| invalid("truncated"))?;
+            skip(&mut input, padding(entry.size))?;
+            stats.files += 1;
+          }
+
+          let (created, _, _) = inode.stat();
+          let size = inode.size().max(if has_data { entry.size as usize } else { 0 });
+          inode.set_stat(size, (created, entry.atime.unwrap_or(entry.mtime), entry.mtime));
+          inode.set_owner(entry.uid, entry.gid);
+          for (name, value) in entry.xattrs.iter() {
+            inode.set_xattr(name, value);
+          }
+
+          self.cwd.insert(name, File::new_data_file(Rc::new(RefCell::new(inode))));
+        }
+        b'1' => {
+          let target = clean(dest_dir, &entry.link)?;
+          match self.cwd.get(&target) {
+            Some(file @ File::DataFile(_)) => self.cwd.insert(name, file),
+            _ => return Err(invalid(&format!("hard link to missing file '{}'", entry.link)))
+          }
+
+          stats.hard_links += 1;
+        }
+        b'5' => {
+          if !name.is_empty() && self.cwd.get(&name).is_none() {
+            self.cwd.insert(name, File::new_dir(None));
+          }
+
+          stats.directories += 1;
+        }
+        _ => stats.skipped += 1
+      }
+
+      // Only regular files' data has been read; anything else's is skipped.
+      if !has_data {
+        skip(&mut input, entry.size + padding(entry.size))?;
+      }
+    }
+
+    Ok(stats)
+  }
+
+  /**
+   * Packs `src_dir`, and everything under it, into a tar archive written to
+   * `out`. `src_dir` is "" for everything. Names in the archive are relative
+   * to `src_dir`. Directories that only exist as part of files' names get
+   * entries too, so the archive unpacks the same anywhere.
+   */
+  pub fn export_tar<W: Write>(&self, src_dir: &str, mut out: W) -> io::Result<TarStats> {
+    let src = clean("", src_dir)?;
+    let mut stats = TarStats::default();
+    let content = self.cwd.get_dir_rc().borrow();
+
+    let names = archive::entries(&content, &src);
+    let mut linked: HashMap<*const RefCell<_>, &str> = HashMap::new();
+    let now = Timespec::new(time::get_time().sec, 0);
+    for &(ref relative, file) in names.iter() {
+      match file {
+        Some(File::DataFile(inode)) => {
+          if let Some(first) = linked.get(&Rc::as_ptr(inode)) {
+            let inode = inode.borrow();
+            Header { kind: b'1', link: first, ..Header::new(relative, &inode) }.write(&mut out)?;
+            stats.hard_links += 1;
+            continue;
+          }
+
+          linked.insert(Rc::as_ptr(inode), relative);
+          let inode = inode.borrow();
+          match inode.link_target() {
+            Some(target) => {
+              Header { kind: b'2', link: &target, ..Header::new(relative, &inode) }.write(&mut out)?;
+              stats.symlinks += 1;
+            }
+            None => {
+              Header { kind: b'0', size: inode.size() as u64, ..Header::new(relative, &inode) }.write(&mut out)?;
+              let mut page = vec![0u8; self.geometry().page_size()];
+              let mut offset = 0;
+              while offset < inode.size() {
+                let len = (inode.size() - offset).min(page.len());
+                inode.read(offset, &mut page[..len]);
+                out.write_all(&page[..len])?;
+                offset += len;
+              }
+
+              out.write_all(&[0; BLOCK][..padding(inode.size() as u64) as usize])?;
+              stats.files += 1;
+            }
+          }
+        }
+        _ => {
+          let name = format!("{}/", relative);
+          let header = Header {
+            name: &name, kind: b'5', mode: DEFAULT_DIR_MODE, uid: 0, gid: 0, size: 0, mtime: now, link: "",
+            xattrs: None
+          };
+
+          header.write(&mut out)?;
+          stats.directories += 1;
+        }
+      }
+    }
+
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.flush()?;
+    Ok(stats)
+  }
+}
+
+// An entry to write.
+struct Header<'a> {
+  name: &'a str,
+  kind: u8,
+  mode: u32,
+  uid: u32,
+  gid: u32,
+  size: u64,
+  mtime: Timespec,
+  link: &'a str,
+  xattrs: Option<&'a ::inode::Xattrs>,
+}
+
+// Puts `value` in octal in a field, if it fits.
+fn put_number(header: &mut [u8; BLOCK], (start, len): (usize, usize), value: u64) -> bool {
+  let digits = format!("{:0width$o}", value, width = len - 1);
+  if digits.len() > len - 1 {
+    return false;
+  }
+
+  header[start..start + len - 1].copy_from_slice(digits.as_bytes());
+  true
+}
+
+fn put_text(header: &mut [u8; BLOCK], (start, len): (usize, usize), value: &str) -> bool {
+  if value.len() > len {
+    return false;
+  }
+
+  header[start..start + value.len()].copy_from_slice(value.as_bytes());
+  true
+}
+
+impl<'a> Header<'a> {
+  fn new(name: &'a str, inode: &'a ::inode::Inode) -> Header<'a> {
+    let (uid, gid) = inode.owner();
+    let (_, _, mtime) = inode.stat();
+    Header {
+      name, kind: b'0', mode: inode.mode(), uid, gid, size: 0, mtime, link: "", xattrs: inode.xattrs()
+    }
+  }
+
+  // Writes the header, after a PAX header if there's anything ustar can't
+  // hold.
+  fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    let mut pax = Vec::new();
+    let mut header = [0u8; BLOCK];
+
+    // A long name can be split between the prefix and name fields at a '/'.
+    let split = self.name.char_indices().filter(|&(i, c)| c == '/' && i <= PREFIX.1)
+      .map(|(i, _)| i).rfind(|&i| self.name.len() - i - 1 <= NAME.1 && i > 0);
+    if !put_text(&mut header, NAME, self.name) {
+      match split {
+        Some(i) => {
+          put_text(&mut header, PREFIX, &self.name[..i]);
+          put_text(&mut header, NAME, &self.name[i + 1..]);
+        }
+        None => pax_record(&mut pax, "path", self.name.as_bytes())
+      }
+    }
+
+    if !put_text(&mut header, LINKNAME, self.link) {
+      pax_record(&mut pax, "linkpath", self.link.as_bytes());
+    }
+
+    put_number(&mut header, MODE, (self.mode & 0o7777) as u64);
+    for &(field, key, value) in [(UID, "uid", self.uid as u64), (GID, "gid", self.gid as u64),
+                                  (SIZE, "size", self.size)].iter() {
+      if !put_number(&mut header, field, value) {
+        pax_record(&mut pax, key, value.to_string().as_bytes());
+      }
+    }
+
+    if self.mtime.sec < 0 || self.mtime.nsec != 0 || !put_number(&mut header, MTIME, self.mtime.sec as u64) {
+      pax_record(&mut pax, "mtime", format_time(self.mtime).as_bytes());
+    }
+
+    for (name, value) in self.xattrs.into_iter().flatten() {
+      pax_record(&mut pax, &format!("{}{}", XATTR_PREFIX, name), value);
+    }
+
+    header[TYPE] = self.kind;
+    header[MAGIC.0..MAGIC.0 + 8].copy_from_slice(b"ustar\x0000");
+
+    if !pax.is_empty() {
+      let base = self.name.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+      let mut pax_name = format!("PaxHeaders/{}", base);
+      while pax_name.len() > NAME.1 {
+        pax_name.pop();
+      }
+
+      let pax_header = Header {
+        name: &pax_name, kind: b'x', mode: 0o644, uid: 0, gid: 0, size: pax.len() as u64, mtime: Timespec::new(0, 0),
+        link: "", xattrs: None
+      };
+
+      pax_header.write(out)?;
+      out.write_all(&pax)?;
+      out.write_all(&[0; BLOCK][..padding(pax.len() as u64) as usize])?;
+    }
+
+    let sum = checksum(&header);
+    header[CHECKSUM.0..CHECKSUM.0 + 8].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    out.write_all(&header)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+  use super::{format_time, parse_time, TarStats};
+  use file::File;
+  use time::Timespec;
+  use {Proc, Whence, O_CREAT, O_RDWR};
+
+  fn write_file(p: &mut Proc<'static>, name: &'static str, offset: usize, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    p.seek(fd, offset as isize, Whence::SeekSet);
+    p.write(fd, data);
+    p.close(fd);
+  }
+
+  fn contents(p: &mut Proc, name: &'static str) -> Vec<u8> {
+    let size = p.metadata(name).expect("missing").size;
+    let fd = p.open(name, O_RDWR);
+    let mut buf = vec![0; size];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  fn set<F: FnOnce(&mut ::inode::Inode)>(p: &Proc, name: &str, f: F) {
+    match p.cwd.get_dir_rc().borrow().entries[name] {
+      File::DataFile(ref inode) => f(&mut inode.borrow_mut()),
+      _ => panic!("not a file")
+    }
+  }
+
+  // Too long for a ustar name, even split between the name and prefix fields.
+  const LONG: &str = "a/very/long/path/that/goes/on/and/on/past/the/hundred/bytes/a/ustar/name/can/hold/by/itself/\
+                      and/then/on/past/the/hundred/and/fifty/five/bytes/of/the/prefix/field/as/well/so/that/it/\
+                      takes/a/pax/header/to/hold/all/of/it/in/one/piece/without/losing/anything.txt";
+  // Too long for a name, but not split between the name and prefix fields.
+  const SPLIT: &str = "another/path/longer/than/the/hundred/bytes/ustar/names/can/hold/but/short/enough/to/split.txt";
+
+  fn fixture() -> Proc<'static> {
+    let mut p = Proc::new();
+    write_file(&mut p, "src/main.rs", 0, b"fn main() {}\n");
+    write_file(&mut p, "src/sparse", 1 << 20, b"end");
+    write_file(&mut p, LONG, 0, b"long");
+    write_file(&mut p, SPLIT, 0, b"split");
+    assert!(p.symlink("main.rs", "src/link"));
+    assert!(p.link("src/main.rs", "src/hard").is_ok());
+    assert!(p.mkdir("empty"));
+    set(&p, "src/main.rs", |inode| {
+      inode.set_mode(0o100755);
+      inode.set_owner(1000, 2000000000);
+      inode.set_xattr("user.origin", b"\x00binary\n");
+      let (created, accessed, _) = inode.stat();
+      let size = inode.size();
+      inode.set_stat(size, (created, accessed, Timespec::new(1700000000, 123456789)));
+    });
+
+    p
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let original = fixture();
+    let mut archive = Vec::new();
+    let stats = original.export_tar("", &mut archive).unwrap();
+    // Every directory above the long names gets an entry, with "src" and "empty".
+    let directories = LONG.matches('/').count() + SPLIT.matches('/').count() + 2;
+    assert_eq!(stats, TarStats { files: 4, directories, symlinks: 1, hard_links: 1, skipped: 0 });
+    assert_eq!(archive.len() % 512, 0);
+
+    let mut p = Proc::new();
+    assert_eq!(p.import_tar(&archive[..], "dest").unwrap(), stats);
+    for &name in ["src/main.rs", "src/sparse", "src/link", "src/hard", LONG, SPLIT].iter() {
+      let theirs = original.symlink_metadata(name).unwrap();
+      let ours = p.symlink_metadata(&format!("dest/{}", name)).unwrap();
+      assert_eq!((ours.mode, ours.size, ours.uid, ours.gid), (theirs.mode, theirs.size, theirs.uid, theirs.gid));
+      assert_eq!((ours.modified, &ours.xattrs), (theirs.modified, &theirs.xattrs), "{}", name);
+    }
+
+    assert!(p.metadata("dest/empty").unwrap().is_dir());
+    assert!(p.metadata("dest/a/very").unwrap().is_dir());
+    assert_eq!(contents(&mut p, "dest/src/link"), b"fn main() {}\n");
+    assert_eq!(p.readlink("dest/src/link").unwrap(), "main.rs");
+    assert_eq!(&contents(&mut p, "dest/src/sparse")[(1 << 20) - 1..], b"\0end");
+
+    // The zeroes before the end of the sparse file are still a hole.
+    let mut pages = 0;
+    set(&p, "dest/src/sparse", |inode| inode.extents(|_, _| pages += 1));
+    assert_eq!(pages, 1);
+
+    // Hard links share an inode.
+    write_file(&mut p, "dest/src/hard", 0, b"fn");
+    assert_eq!(&contents(&mut p, "dest/src/main.rs")[..2], b"fn");
+    assert_eq!(p.check(), vec![]);
+  }
+
+  #[test]
+  fn test_export_dir() {
+    let mut original = fixture();
+    let mut archive = Vec::new();
+    let stats = original.export_tar("./src/", &mut archive).unwrap();
+    assert_eq!((stats.files, stats.directories), (2, 0));
+
+    original.import_tar(&archive[..], "copy").unwrap();
+    assert_eq!(contents(&mut original, "copy/main.rs"), b"fn main() {}\n");
+    assert!(original.metadata("copy/src").is_none());
+  }
+
+  #[test]
+  fn test_bad_archives() {
+    let mut archive = Vec::new();
+    fixture().export_tar("", &mut archive).unwrap();
+    let error = |archive: &[u8], dest: &str| Proc::new().import_tar(archive, dest).unwrap_err().to_string();
+
+    let mut corrupt = archive.clone();
+    corrupt[1] ^= 1;
+    assert_eq!(error(&corrupt, ""), "bad tar archive: header checksum mismatch");
+    assert_eq!(error(&archive[..1000], ""), "bad tar archive: truncated");
+
+    let mut escaping = Vec::new();
+    let mut p = Proc::new();
+    write_file(&mut p, "x", 0, b"x");
+    set(&p, "x", |inode| {
+      // No sub-second time, so no PAX header before the one to change.
+      let (created, accessed, _) = inode.stat();
+      inode.set_stat(1, (created, accessed, Timespec::new(0, 0)));
+    });
+
+    p.export_tar("", &mut escaping).unwrap();
+    let mut huge = escaping.clone();
+    escaping[..4].copy_from_slice(b"../x");
+    let sum = super::checksum(&escaping[..512]);
+    escaping[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    assert_eq!(error(&escaping, "dest"), "bad tar archive: '../x' is outside the destination");
+
+    // A file bigger than the file system can hold, as a base-256 size.
+    huge[124..128].copy_from_slice(&[0x80, 0, 0, 0]);
+    huge[128..136].copy_from_slice(&(1u64 << 40).to_be_bytes());
+    let sum = super::checksum(&huge[..512]);
+    huge[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    let error = Proc::with_page_size(512).import_tar(&huge[..], "").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "bad tar archive: 'x' is too large for the page size");
+  }
+
+  #[test]
+  fn test_base_256() {
+    // GNU tar writes ids and sizes too big for their octal fields in base
+    // 256, with the first byte's top bit as the marker.
+    let mut p = Proc::new();
+    write_file(&mut p, "x", 0, b"x");
+    set(&p, "x", |inode| {
+      let (created, accessed, _) = inode.stat();
+      inode.set_stat(1, (created, accessed, Timespec::new(0, 0)));
+    });
+
+    let mut archive = Vec::new();
+    p.export_tar("", &mut archive).unwrap();
+    let with = |uid: &[u8], size: &[u8]| {
+      let mut archive = archive.clone();
+      archive[108..116].copy_from_slice(uid);
+      archive[124..136].copy_from_slice(size);
+      let sum = super::checksum(&archive[..512]);
+      archive[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+      archive
+    };
+
+    let mut p = Proc::new();
+    let archive = with(&[0x80, 0, 0, 0, 0x77, 0x35, 0x94, 0], &[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    p.import_tar(&archive[..], "").unwrap();
+    assert_eq!(p.metadata("x").unwrap().uid, 2000000000);
+    assert_eq!(contents(&mut p, "x"), b"x");
+
+    // Numbers that don't fit in 64 bits, or are negative, are errors.
+    let error = |archive: &[u8]| Proc::new().import_tar(archive, "").unwrap_err().to_string();
+    let too_big = with(&[0x80, 0, 0, 0, 0x77, 0x35, 0x94, 0], &[0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(error(&too_big), "bad tar archive: number out of range");
+    let negative = with(&[0xff; 8], &[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(error(&negative), "bad tar archive: number out of range");
+  }
+
+  #[test]
+  fn test_times() {
+    for &(text, sec, nsec) in [("0", 0, 0), ("1700000000.5", 1700000000, 500000000), ("-1.25", -2, 750000000),
+                               ("12.000000001", 12, 1)].iter() {
+      let time = Timespec::new(sec, nsec);
+      assert_eq!(parse_time(text).unwrap(), time);
+      assert_eq!(format_time(time), text);
+    }
+  }
+}
@@ -0,0 +1,724 @@
+/*!
+ * Traces of the calls made on a Proc, and replaying them.
+ *
+ * A Proc records every call made on it, with its arguments, its result, and
+ * when it was made, once `start_recording` is called. Traces are compact:
+ * they don't keep the data read or written, only how much, and every number in
+ * them is a variable-length integer. A trace file is the bytes "RFSTRACE" and
+ * a version byte, followed by one record per call: a tag byte, the ns since
+ * the previous call, then the call's fields.
+ *
+ * `replay` runs a trace against a file system, as fast as possible or with
+ * the original timing. Traces of real programs can be made from `strace`
+ * output; see the `strace` module.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use file::Whence;
+use {FileDescriptor, Proc, O_CREAT};
+
+const MAGIC: &[u8; 8] = b"RFSTRACE";
+const VERSION: u8 = 1;
+
+const OPEN: u8 = 1;
+const READ: u8 = 2;
+const WRITE: u8 = 3;
+const SEEK: u8 = 4;
+const CLOSE: u8 = 5;
+const UNLINK: u8 = 6;
+const SYMLINK: u8 = 7;
+const LINK: u8 = 8;
+const MKDIR: u8 = 9;
+const METADATA: u8 = 10;
+const READLINK: u8 = 11;
+const RENAME: u8 = 12;
+
+// Replay reads and writes at most this many bytes at a time, so a trace can't
+// make it allocate a buffer as big as it likes.
+const REPLAY_CHUNK: usize = 1 << 20;
+
+/**
+ * A call on a Proc and its result. `offset` is where the file's position
+ * was before a read or write. Calls that can fail only record whether they
+ * succeeded. `follow` tells `metadata` from `symlink_metadata`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+  Open { path: String, flags: u32, fd: FileDescriptor },
+  Read { fd: FileDescriptor, offset: usize, len: usize, result: usize },
+  Write { fd: FileDescriptor, offset: usize, len: usize, result: usize },
+  Seek { fd: FileDescriptor, offset: isize, whence: Whence, result: usize },
+  Close { fd: FileDescriptor },
+  Unlink { path: String },
+  Symlink { target: String, path: String, ok: bool },
+  Link { existing: String, path: String, ok: bool },
+  Mkdir { path: String, ok: bool },
+  Metadata { path: String, follow: bool, ok: bool },
+  Readlink { path: String, ok: bool },
+  Rename { from: String, to: String, ok: bool },
+}
+
+/**
+ * A call, made `time_ns` ns after the trace started.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+  pub time_ns: u64,
+  pub call: Call,
+}
+
+impl fmt::Display for Event {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:.6} ", self.time_ns as f64 / 1e9)?;
+    match self.call {
+      Call::Open { ref path, flags, fd } => write!(f, "open({:?}, {:#x}) = {}", path, flags, fd),
+      Call::Read { fd, offset, len, result } => write!(f, "read({}, @{}, {}) = {}", fd, offset, len, result),
+      Call::Write { fd, offset, len, result } => write!(f, "write({}, @{}, {}) = {}", fd, offset, len, result),
+      Call::Seek { fd, offset, whence, result } => write!(f, "seek({}, {}, {:?}) = {}", fd, offset, whence, result),
+      Call::Close { fd } => write!(f, "close({})", fd),
+      Call::Unlink { ref path } => write!(f, "unlink({:?})", path),
+      Call::Symlink { ref target, ref path, ok } => write!(f, "symlink({:?}, {:?}) = {}", target, path, ok),
+      Call::Link { ref existing, ref path, ok } => write!(f, "link({:?}, {:?}) = {}", existing, path, ok),
+      Call::Mkdir { ref path, ok } => write!(f, "mkdir({:?}) = {}", path, ok),
+      Call::Metadata { ref path, follow: true, ok } => write!(f, "metadata({:?}) = {}", path, ok),
+      Call::Metadata { ref path, follow: false, ok } => write!(f, "symlink_metadata({:?}) = {}", path, ok),
+      Call::Readlink { ref path, ok } => write!(f, "readlink({:?}) = {}", path, ok),
+      Call::Rename { ref from, ref to, ok } => write!(f, "rename({:?}, {:?}) = {}", from, to, ok)
+    }
+  }
+}
+
+fn write_varint<W: Write>(out: &mut W, mut n: u64) -> io::Result<()> {
+  let mut buf = [0u8; 10];
+  let mut len = 0;
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+    buf[len] = if n == 0 { byte } else { byte | 0x80 };
+    len += 1;
+    if n == 0 { break }
+  }
+
+  out.write_all(&buf[..len])
+}
+
+fn write_signed<W: Write>(out: &mut W, n: i64) -> io::Result<()> {
+  write_varint(out, ((n << 1) ^ (n >> 63)) as u64)
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+  write_varint(out, s.len() as u64)?;
+  out.write_all(s.as_bytes())
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad trace: {}", what))
+}
+
+// Reads one byte, or None at the end of the input.
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+  let mut byte = [0u8];
+  loop {
+    match input.read(&mut byte) {
+      Ok(0) => return Ok(None),
+      Ok(_) => return Ok(Some(byte[0])),
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e)
+    }
+  }
+}
+
+fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+  let (mut n, mut shift) = (0u64, 0);
+  loop {
+    let byte = read_byte(input)?.ok_or_else(|| invalid("truncated record"))?;
+    if shift > 63 {
+      return Err(invalid("integer too long"));
+    }
+
+    n |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 { return Ok(n) }
+    shift += 7;
+  }
+}
+
+fn read_signed<R: Read>(input: &mut R) -> io::Result<i64> {
+  let n = read_varint(input)?;
+  Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+  let len = read_varint(input)?;
+  let mut bytes = Vec::new();
+  input.take(len).read_to_end(&mut bytes)?;
+  if bytes.len() as u64 != len {
+    return Err(invalid("truncated path"));
+  }
+
+  String::from_utf8(bytes).map_err(|_| invalid("path isn't UTF-8"))
+}
+
+fn read_bool<R: Read>(input: &mut R) -> io::Result<bool> {
+  match read_varint(input)? {
+    0 => Ok(false),
+    1 => Ok(true),
+    _ => Err(invalid("bad flag"))
+  }
+}
+
+fn whence_code(whence: Whence) -> u64 {
+  match whence {
+    Whence::SeekSet => 0,
+    Whence::SeekCur => 1,
+    Whence::SeekEnd => 2
+  }
+}
+
+/**
+ * Writes events in the trace format.
+ */
+pub struct TraceWriter<W: Write> {
+  out: W,
+  last_ns: u64,
+}
+
+impl<W: Write> TraceWriter<W> {
+  pub fn new(mut out: W) -> io::Result<TraceWriter<W>> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    Ok(TraceWriter { out, last_ns: 0 })
+  }
+
+  // Writes `event`. Events must be written in time order.
+  pub fn write(&mut self, event: &Event) -> io::Result<()> {
+    let out = &mut self.out;
+    let delta = event.time_ns.checked_sub(self.last_ns).ok_or_else(|| invalid("events out of order"))?;
+    self.last_ns = event.time_ns;
+
+    let tag = match event.call {
+      Call::Open { .. } => OPEN,
+      Call::Read { .. } => READ,
+      Call::Write { .. } => WRITE,
+      Call::Seek { .. } => SEEK,
+      Call::Close { .. } => CLOSE,
+      Call::Unlink { .. } => UNLINK,
+      Call::Symlink { .. } => SYMLINK,
+      Call::Link { .. } => LINK,
+      Call::Mkdir { .. } => MKDIR,
+      Call::Metadata { .. } => METADATA,
+      Call::Readlink { .. } => READLINK,
+      Call::Rename { .. } => RENAME
+    };
+
+    out.write_all(&[tag])?;
+    write_varint(out, delta)?;
+    match event.call {
+      Call::Open { ref path, flags, fd } => {
+        write_string(out, path)?;
+        write_varint(out, flags as u64)?;
+        write_signed(out, fd as i64)
+      }
+      Call::Read { fd, offset, len, result } | Call::Write { fd, offset, len, result } => {
+        write_signed(out, fd as i64)?;
+        write_varint(out, offset as u64)?;
+        write_varint(out, len as u64)?;
+        write_varint(out, result as u64)
+      }
+      Call::Seek { fd, offset, whence, result } => {
+        write_signed(out, fd as i64)?;
+        write_signed(out, offset as i64)?;
+        write_varint(out, whence_code(whence))?;
+        write_varint(out, result as u64)
+      }
+      Call::Close { fd } => write_signed(out, fd as i64),
+      Call::Unlink { ref path } => write_string(out, path),
+      Call::Symlink { target: ref from, ref path, ok } | Call::Link { existing: ref from, ref path, ok }
+      | Call::Rename { ref from, to: ref path, ok } => {
+        write_string(out, from)?;
+        write_string(out, path)?;
+        write_varint(out, ok as u64)
+      }
+      Call::Mkdir { ref path, ok } | Call::Readlink { ref path, ok } => {
+        write_string(out, path)?;
+        write_varint(out, ok as u64)
+      }
+      Call::Metadata { ref path, follow, ok } => {
+        write_string(out, path)?;
+        write_varint(out, follow as u64)?;
+        write_varint(out, ok as u64)
+      }
+    }
+  }
+
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.out.flush()
+  }
+
+  pub fn into_inner(self) -> W {
+    self.out
+  }
+}
+
+/**
+ * Reads the events of a trace, one at a time.
+ */
+pub struct TraceReader<R: Read> {
+  input: R,
+  last_ns: u64,
+}
+
+impl<R: Read> TraceReader<R> {
+  pub fn new(mut input: R) -> io::Result<TraceReader<R>> {
+    let mut header = [0u8; 9];
+    input.read_exact(&mut header).map_err(|_| invalid("not a trace"))?;
+    if &header[..8] != MAGIC {
+      return Err(invalid("not a trace"));
+    } else if header[8] != VERSION {
+      return Err(invalid(&format!("unsupported version {}", header[8])));
+    }
+
+    Ok(TraceReader { input, last_ns: 0 })
+  }
+
+  fn read_call(&mut self, tag: u8) -> io::Result<Call> {
+    let input = &mut self.input;
+    Ok(match tag {
+      OPEN => Call::Open {
+        path: read_string(input)?,
+        flags: read_varint(input)? as u32,
+        fd: read_signed(input)? as FileDescriptor
+      },
+      READ | WRITE => {
+        let (fd, offset) = (read_signed(input)? as FileDescriptor, read_varint(input)? as usize);
+        let (len, result) = (read_varint(input)? as usize, read_varint(input)? as usize);
+        match tag {
+          READ => Call::Read { fd, offset, len, result },
+          _ => Call::Write { fd, offset, len, result }
+        }
+      }
+      SEEK => Call::Seek {
+        fd: read_signed(input)? as FileDescriptor,
+        offset: read_signed(input)? as isize,
+        whence: match read_varint(input)? {
+          0 => Whence::SeekSet,
+          1 => Whence::SeekCur,
+          2 => Whence::SeekEnd,
+          _ => return Err(invalid("bad whence"))
+        },
+        result: read_varint(input)? as usize
+      },
+      CLOSE => Call::Close { fd: read_signed(input)? as FileDescriptor },
+      UNLINK => Call::Unlink { path: read_string(input)? },
+      SYMLINK | LINK | RENAME => {
+        let (from, path, ok) = (read_string(input)?, read_string(input)?, read_bool(input)?);
+        match tag {
+          SYMLINK => Call::Symlink { target: from, path, ok },
+          LINK => Call::Link { existing: from, path, ok },
+          _ => Call::Rename { from, to: path, ok }
+        }
+      }
+      MKDIR => Call::Mkdir { path: read_string(input)?, ok: read_bool(input)? },
+      METADATA => Call::Metadata { path: read_string(input)?, follow: read_bool(input)?, ok: read_bool(input)? },
+      READLINK => Call::Readlink { path: read_string(input)?, ok: read_bool(input)? },
+      _ => return Err(invalid(&format!("unknown call {}", tag)))
+    })
+  }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+  type Item = io::Result<Event>;
+
+  fn next(&mut self) -> Option<io::Result<Event>> {
+    let tag = match read_byte(&mut self.input) {
+      Ok(Some(tag)) => tag,
+      Ok(None) => return None,
+      Err(e) => return Some(Err(e))
+    };
+
+    let event = read_varint(&mut self.input).and_then(|delta| {
+      let call = self.read_call(tag)?;
+      self.last_ns += delta;
+      Ok(Event { time_ns: self.last_ns, call })
+    });
+
+    Some(event)
+  }
+}
+
+// Reads a whole trace.
+pub fn read_trace<R: Read>(input: R) -> io::Result<Vec<Event>> {
+  TraceReader::new(input)?.collect()
+}
+
+/**
+ * What a recording Proc keeps: where the trace goes, when it started, and the
+ * first error writing it, which `Proc::stop_recording` reports.
+ */
+pub struct Recorder {
+  writer: TraceWriter<Box<dyn Write>>,
+  start: Instant,
+  error: Option<io::Error>,
+}
+
+impl Recorder {
+  pub fn new(out: Box<dyn Write>) -> io::Result<Recorder> {
+    Ok(Recorder { writer: TraceWriter::new(out)?, start: Instant::now(), error: None })
+  }
+
+  pub fn record(&mut self, call: Call) {
+    if self.error.is_some() {
+      return;
+    }
+
+    let event = Event { time_ns: self.start.elapsed().as_nanos() as u64, call };
+    if let Err(e) = self.writer.write(&event) {
+      self.error = Some(e);
+    }
+  }
+
+  pub fn finish(mut self) -> io::Result<()> {
+    match self.error.take() {
+      Some(e) => Err(e),
+      None => self.writer.flush()
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+  // One call right after the other.
+  Fast,
+  // Each call as long after the start of the replay as it was after the start
+  // of the trace.
+  Original,
+}
+
+/**
+ * What happened in a replay: the calls made, the calls whose results differed
+ * from the trace's, and the calls skipped because they were on a file
+ * descriptor the replay never opened.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+  pub calls: usize,
+  pub mismatches: usize,
+  pub skipped: usize,
+}
+
+// Does `len` bytes of a read or write as calls of at most REPLAY_CHUNK bytes.
+// Stops at the first short one. Returns how many bytes were done in all.
+fn in_chunks<F: FnMut(usize) -> usize>(len: usize, mut op: F) -> usize {
+  let mut done = 0;
+  while done < len {
+    let chunk = (len - done).min(REPLAY_CHUNK);
+    let n = op(chunk);
+    done += n;
+    if n < chunk { break; }
+  }
+
+  done
+}
+
+/**
+ * Replays `events` on `p`. File descriptors in the trace are mapped to the
+ * ones `p` hands out. Files that the traced program opened successfully are
+ * created if they don't exist, since a trace can start with files already in
+ * place; what they held isn't known, so they start out empty. Writes write
+ * zeroes.
+ */
+pub fn replay<'t>(p: &mut Proc<'t>, events: &'t [Event], timing: Timing) -> ReplayStats {
+  let (start, mut stats) = (Instant::now(), ReplayStats::default());
+  let mut fds: HashMap<FileDescriptor, FileDescriptor> = HashMap::new();
+  let mut buffer = Vec::new();
+
+  for event in events.iter() {
+    if timing == Timing::Original {
+      let due = Duration::from_nanos(event.time_ns);
+      if let Some(wait) = due.checked_sub(start.elapsed()) {
+        thread::sleep(wait);
+      }
+    }
+
+    let (matched, fd) = match event.call {
+      Call::Open { ref path, flags, fd } => {
+        let flags = if fd >= 0 { flags | O_CREAT } else { flags };
+        let new_fd = p.open(path, flags);
+        if fd >= 0 && new_fd >= 0 {
+          fds.insert(fd, new_fd);
+        } else if new_fd >= 0 {
+          p.close(new_fd);
+        }
+
+        stats.calls += 1;
+        if (fd >= 0) != (new_fd >= 0) { stats.mismatches += 1; }
+        continue;
+      }
+      Call::Unlink { ref path } => {
+        p.unlink(path);
+        stats.calls += 1;
+        continue;
+      }
+      Call::Symlink { .. } | Call::Link { .. } | Call::Mkdir { .. } | Call::Metadata { .. } | Call::Readlink { .. }
+      | Call::Rename { .. } => {
+        let (ok, traced) = match event.call {
+          Call::Symlink { ref target, ref path, ok } => (p.symlink(target, path), ok),
+          Call::Link { ref existing, ref path, ok } => (p.link(existing, path).is_ok(), ok),
+          Call::Mkdir { ref path, ok } => (p.mkdir(path), ok),
+          Call::Metadata { ref path, follow: true, ok } => (p.metadata(path).is_some(), ok),
+          Call::Metadata { ref path, ok, .. } => (p.symlink_metadata(path).is_some(), ok),
+          Call::Readlink { ref path, ok } => (p.readlink(path).is_some(), ok),
+          Call::Rename { ref from, ref to, ok } => (p.rename(from, to).is_ok(), ok),
+          _ => unreachable!()
+        };
+
+        stats.calls += 1;
+        if ok != traced { stats.mismatches += 1; }
+        continue;
+      }
+      Call::Read { fd, .. } | Call::Write { fd, .. } | Call::Seek { fd, .. } | Call::Close { fd } => {
+        (fds.get(&fd).cloned(), fd)
+      }
+    };
+
+    let new_fd = match matched {
+      Some(new_fd) => new_fd,
+      None => { stats.skipped += 1; continue; }
+    };
+
+    // No file can hold that much, or go that far, so the call can't have gone
+    // the same way.
+    let max = p.geometry().max_file_size();
+    let position = p.fd_table.get(&new_fd).map_or(0, |handle| handle.position());
+    let same = match event.call {
+      Call::Read { len, .. } if len > max => false,
+      Call::Write { len, .. } if position.checked_add(len).is_none_or(|end| end > max) => false,
+      Call::Read { len, result, .. } => {
+        buffer.resize(len.min(REPLAY_CHUNK), 0);
+        in_chunks(len, |n| p.read(new_fd, &mut buffer[..n])) == result
+      }
+      Call::Write { len, result, .. } => {
+        buffer.resize(len.min(REPLAY_CHUNK), 0);
+        in_chunks(len, |n| p.write(new_fd, &buffer[..n])) == result
+      }
+      Call::Seek { offset, whence, result, .. } => p.seek(new_fd, offset, whence) == result,
+      _ => {
+        p.close(new_fd);
+        fds.remove(&fd);
+        true
+      }
+    };
+
+    stats.calls += 1;
+    if !same { stats.mismatches += 1; }
+  }
+
+  stats
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::io::{self, Write};
+  use std::rc::Rc;
+
+  use super::{read_trace, replay, Call, Event, Timing, TraceWriter, REPLAY_CHUNK};
+  use file::Whence;
+  use {Proc, O_CREAT, O_RDWR};
+
+  // A Vec that's still reachable after it's handed to a Proc.
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn events() -> Vec<Event> {
+    let calls = vec![
+      Call::Open { path: "a/b".to_string(), flags: O_CREAT | O_RDWR, fd: 256 },
+      Call::Write { fd: 256, offset: 0, len: 100_000, result: 100_000 },
+      Call::Seek { fd: 256, offset: -10, whence: Whence::SeekEnd, result: 99_990 },
+      Call::Read { fd: 256, offset: 99_990, len: 10, result: 10 },
+      Call::Close { fd: 256 },
+      Call::Open { path: "missing".to_string(), flags: 0, fd: -2 },
+      Call::Unlink { path: "a/b".to_string() },
+    ];
+
+    calls.into_iter().enumerate().map(|(i, call)| Event { time_ns: i as u64 * 1500, call }).collect()
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut writer = TraceWriter::new(Vec::new()).unwrap();
+    for event in events().iter() {
+      writer.write(event).unwrap();
+    }
+
+    let bytes = writer.into_inner();
+    assert!(bytes.len() < 80, "trace is {} bytes", bytes.len());
+    assert_eq!(read_trace(&bytes[..]).unwrap(), events());
+
+    // Truncated traces and other files are errors.
+    assert!(read_trace(&bytes[..bytes.len() - 1]).is_err());
+    assert!(read_trace(&b"RFSTRACF\x01"[..]).is_err());
+    assert!(read_trace(&b"RFSTRACE\x09"[..]).is_err());
+  }
+
+  #[test]
+  fn test_out_of_order() {
+    let mut writer = TraceWriter::new(Vec::new()).unwrap();
+    let mut events = events();
+    events[1].time_ns = 0;
+    writer.write(&events[0]).unwrap();
+    writer.write(&events[1]).unwrap();
+    events[0].time_ns = 5;
+    writer.write(&events[0]).unwrap();
+    assert!(writer.write(&events[1]).is_err());
+  }
+
+  #[test]
+  fn test_record() {
+    let trace = Shared::default();
+    let mut p = Proc::new();
+    p.start_recording(trace.clone()).unwrap();
+
+    let fd = p.open("file", O_CREAT | O_RDWR);
+    p.write(fd, &[7; 5000]);
+    p.seek(fd, 1000, Whence::SeekSet);
+    let mut buf = [0; 8192];
+    let read = p.read(fd, &mut buf);
+    p.close(fd);
+    p.unlink("file");
+    p.stop_recording().unwrap();
+
+    // Calls after recording stopped aren't in the trace.
+    p.open("other", O_CREAT);
+
+    let events = read_trace(&trace.0.borrow()[..]).unwrap();
+    let calls: Vec<_> = events.iter().map(|event| event.call.clone()).collect();
+    assert_eq!(calls, vec![
+      Call::Open { path: "file".to_string(), flags: O_CREAT | O_RDWR, fd },
+      Call::Write { fd, offset: 0, len: 5000, result: 5000 },
+      Call::Seek { fd, offset: 1000, whence: Whence::SeekSet, result: 1000 },
+      Call::Read { fd, offset: 1000, len: 8192, result: read },
+      Call::Close { fd },
+      Call::Unlink { path: "file".to_string() },
+    ]);
+
+    assert!(events.windows(2).all(|pair| pair[0].time_ns <= pair[1].time_ns));
+  }
+
+  #[test]
+  fn test_record_names() {
+    // Calls on names are recorded with whether they worked, and replay the
+    // same way on an empty Proc.
+    let trace = Shared::default();
+    let mut p = Proc::new();
+    p.start_recording(trace.clone()).unwrap();
+
+    let fd = p.open("file", O_CREAT | O_RDWR);
+    p.close(fd);
+    assert!(p.mkdir("dir"));
+    assert!(!p.mkdir("file"));
+    assert!(p.symlink("file", "to-file"));
+    assert!(p.symlink("nowhere", "dangling"));
+    assert!(p.link("file", "also-file").is_ok());
+    assert!(p.link("file", "dir").is_err());
+    assert!(p.metadata("to-file").is_some());
+    assert!(p.metadata("dangling").is_none());
+    assert!(p.symlink_metadata("dangling").is_some());
+    assert_eq!(p.readlink("to-file").unwrap(), "file");
+    assert!(p.readlink("file").is_none());
+    assert!(p.rename("also-file", "moved").is_ok());
+    assert!(p.rename("also-file", "moved").is_err());
+    p.stop_recording().unwrap();
+
+    let events = read_trace(&trace.0.borrow()[..]).unwrap();
+    let calls: Vec<_> = events.iter().skip(2).map(|event| event.call.clone()).collect();
+    let name = |s: &str| s.to_string();
+    assert_eq!(calls, vec![
+      Call::Mkdir { path: name("dir"), ok: true },
+      Call::Mkdir { path: name("file"), ok: false },
+      Call::Symlink { target: name("file"), path: name("to-file"), ok: true },
+      Call::Symlink { target: name("nowhere"), path: name("dangling"), ok: true },
+      Call::Link { existing: name("file"), path: name("also-file"), ok: true },
+      Call::Link { existing: name("file"), path: name("dir"), ok: false },
+      Call::Metadata { path: name("to-file"), follow: true, ok: true },
+      Call::Metadata { path: name("dangling"), follow: true, ok: false },
+      Call::Metadata { path: name("dangling"), follow: false, ok: true },
+      Call::Readlink { path: name("to-file"), ok: true },
+      Call::Readlink { path: name("file"), ok: false },
+      Call::Rename { from: name("also-file"), to: name("moved"), ok: true },
+      Call::Rename { from: name("also-file"), to: name("moved"), ok: false },
+    ]);
+
+    let stats = replay(&mut Proc::new(), &events, Timing::Fast);
+    assert_eq!((stats.calls, stats.mismatches, stats.skipped), (15, 0, 0));
+
+    // Replaying again where the names already exist doesn't go the same way.
+    let stats = replay(&mut p, &events, Timing::Fast);
+    assert_eq!(stats.mismatches, 3);
+  }
+
+  #[test]
+  fn test_replay() {
+    let events = events();
+    let mut p = Proc::new();
+    let stats = replay(&mut p, &events, Timing::Fast);
+    assert_eq!((stats.calls, stats.mismatches, stats.skipped), (7, 0, 0));
+
+    // The file the trace created and wrote is gone again.
+    assert_eq!(p.open("a/b", O_RDWR), -2);
+  }
+
+  #[test]
+  fn test_replay_missing_files() {
+    // A file that existed before the trace started is created, and a call on
+    // a descriptor the trace never opened is skipped. The file then exists
+    // where the trace says it didn't.
+    let events: Vec<_> = vec![
+      Call::Open { path: "old".to_string(), flags: O_RDWR, fd: 3 },
+      Call::Read { fd: 3, offset: 0, len: 100, result: 100 },
+      Call::Write { fd: 9, offset: 0, len: 100, result: 100 },
+      Call::Close { fd: 3 },
+      Call::Open { path: "old".to_string(), flags: O_RDWR, fd: -2 },
+    ].into_iter().map(|call| Event { time_ns: 0, call }).collect();
+
+    let stats = replay(&mut Proc::new(), &events, Timing::Original);
+    assert_eq!((stats.calls, stats.mismatches, stats.skipped), (4, 1, 1));
+  }
+
+  #[test]
+  fn test_replay_large_calls() {
+    // Big reads and writes are done a chunk at a time, and ones no file could
+    // hold, or writes past the largest file, aren't done at all.
+    let len = 3 * REPLAY_CHUNK + 5;
+    let events: Vec<_> = vec![
+      Call::Open { path: "big".to_string(), flags: O_CREAT | O_RDWR, fd: 3 },
+      Call::Write { fd: 3, offset: 0, len, result: len },
+      Call::Seek { fd: 3, offset: 0, whence: Whence::SeekSet, result: 0 },
+      Call::Read { fd: 3, offset: 0, len, result: len },
+      Call::Write { fd: 3, offset: len, len: usize::MAX, result: 0 },
+      Call::Read { fd: 3, offset: len, len: usize::MAX, result: 0 },
+      Call::Seek { fd: 3, offset: 1 << 40, whence: Whence::SeekSet, result: 1 << 40 },
+      Call::Write { fd: 3, offset: 1 << 40, len: 10, result: 10 },
+    ].into_iter().map(|call| Event { time_ns: 0, call }).collect();
+
+    let mut p = Proc::new();
+    let stats = replay(&mut p, &events, Timing::Fast);
+    assert_eq!((stats.calls, stats.mismatches, stats.skipped), (8, 3, 0));
+    assert_eq!(p.metadata("big").unwrap().size, len);
+  }
+}
@@ -0,0 +1,851 @@
+/*!
+ * Importing and exporting zip archives.
+ *
+ * `Proc::import_zip` unpacks an archive into the file system and
+ * `Proc::export_zip` packs files into one, without going through the host's
+ * disk. Entries are stored or deflated, and zip64 records are used for
+ * entries and archives past 4 GiB, or with more than 65534 entries.
+ *
+ * Deflated entries are written as they're compressed, a page at a time, so
+ * their CRCs and sizes follow their data in a data descriptor as well as
+ * being in the central directory.
+ *
+ * Unix modes are kept in the upper half of the external attributes, as
+ * Info-ZIP does, so symbolic links survive as entries holding their targets.
+ * Modification times are kept to the second in an extended timestamp field,
+ * besides the two-second DOS time every entry has. Zips have no owners,
+ * extended attributes, or hard links; hard links are packed as copies. As
+ * with tar, a directory entry becomes a marker (see `Proc::mkdir`), and runs
+ * of zeroes a page long or more in files' data are left as holes.
+ */
+
+use std::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use archive;
+use deflate::{self, Deflater};
+use directory::DirectoryHandle;
+use file::File;
+use image::Checksummed;
+use time::{self, Timespec};
+use {Proc, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END: u32 = 0x06054b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+// General purpose flags: encrypted, sizes in a data descriptor, and names
+// in UTF-8.
+const ENCRYPTED: u16 = 1 << 0;
+const DESCRIPTOR: u16 = 1 << 3;
+const UTF8: u16 = 1 << 11;
+
+// Extra fields: zip64 sizes and offsets, and extended timestamps.
+const ZIP64_EXTRA: u16 = 0x0001;
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+
+// Versions needed to extract, and the Unix host in the version made by.
+const VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+const UNIX: u16 = 3 << 8;
+
+// What a 16- or 32-bit field holds when the value is in a zip64 record.
+const MAX_16: u64 = 0xffff;
+const MAX_32: u64 = 0xffff_ffff;
+
+// The end of central directory record, without a comment, and how long a
+// comment can be.
+const END_LEN: usize = 22;
+const MAX_COMMENT: usize = 0xffff;
+const ZIP64_LOCATOR_LEN: usize = 20;
+
+// Caps how much is reserved up front from sizes read from an archive, which
+// may be garbage.
+const MAX_RESERVE: u64 = 1 << 20;
+
+/**
+ * How entries' data is compressed.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+  Stored,
+  // Deflated, with any parts that wouldn't get smaller stored.
+  Deflated,
+}
+
+/**
+ * How `Proc::export_zip` writes an archive.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZipOptions {
+  pub compression: Compression,
+  // Whether to write zip64 records even where nothing needs them.
+  pub zip64: bool,
+}
+
+impl Default for ZipOptions {
+  fn default() -> ZipOptions {
+    ZipOptions { compression: Compression::Deflated, zip64: false }
+  }
+}
+
+/**
+ * How many of each kind of entry an archive had.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ZipStats {
+  pub files: usize,
+  pub directories: usize,
+  pub symlinks: usize,
+  // Encrypted entries, and ones compressed some other way.
+  pub skipped: usize,
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad zip archive: {}", what))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = Checksummed::new(());
+  crc.update(data);
+  crc.checksum()
+}
+
+// Little-endian fields read from the front of a slice.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+  fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+    if self.0.len() < len {
+      return Err(invalid("truncated"));
+    }
+
+    let (bytes, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> io::Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u16(&mut self) -> io::Result<u16> {
+    let bytes = self.bytes(2)?;
+    Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+  }
+
+  fn u32(&mut self) -> io::Result<u32> {
+    Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+  }
+
+  fn u64(&mut self) -> io::Result<u64> {
+    Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+  }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+  out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+  out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+  out.extend_from_slice(&value.to_le_bytes());
+}
+
+// Days since 1970-01-01 to a (year, month, day) in the proleptic Gregorian
+// calendar, and back.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let days = days + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days - era * 146097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+  let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+  (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let year_of_era = year - era * 400;
+  let month = month as i64;
+  let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+  era * 146097 + year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year - 719468
+}
+
+// A time as a DOS (time, date), in UTC, clamped to the years DOS times can
+// hold: 1980 to 2107.
+fn to_dos(time: Timespec) -> (u16, u16) {
+  let min = days_from_civil(1980, 1, 1) * 86400;
+  let max = days_from_civil(2107, 12, 31) * 86400 + 86399;
+  let sec = time.sec.clamp(min, max);
+  let (year, month, day) = civil_from_days(sec.div_euclid(86400));
+  let second = sec.rem_euclid(86400);
+  let time = ((second / 3600) << 11) | ((second / 60 % 60) << 5) | ((second % 60) / 2);
+  let date = ((year - 1980) << 9) | ((month as i64) << 5) | day as i64;
+  (time as u16, date as u16)
+}
+
+fn from_dos(time: u16, date: u16) -> Timespec {
+  let (year, month, day) = (1980 + (date >> 9) as i64, (date >> 5 & 0xf) as u32, (date & 0x1f) as u32);
+  let second = (time >> 11) as i64 * 3600 + (time >> 5 & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+  Timespec::new(days_from_civil(year, month.max(1), day.max(1)) * 86400 + second, 0)
+}
+
+// An entry in the central directory.
+struct Entry {
+  name: String,
+  flags: u16,
+  method: u16,
+  crc: u32,
+  compressed: u64,
+  size: u64,
+  offset: u64,
+  mode: Option<u32>,
+  mtime: Timespec,
+}
+
+impl Entry {
+  fn read(fields: &mut Fields) -> io::Result<Entry> {
+    if fields.u32()? != CENTRAL_HEADER {
+      return Err(invalid("bad central directory entry"));
+    }
+
+    let made_by = fields.u16()?;
+    let _needed = fields.u16()?;
+    let flags = fields.u16()?;
+    let method = fields.u16()?;
+    let (time, date) = (fields.u16()?, fields.u16()?);
+    let crc = fields.u32()?;
+    let (mut compressed, mut size) = (fields.u32()? as u64, fields.u32()? as u64);
+    let (name_len, extra_len, comment_len) = (fields.u16()?, fields.u16()?, fields.u16()?);
+    let _disk = fields.u16()?;
+    let _internal = fields.u16()?;
+    let external = fields.u32()?;
+    let mut offset = fields.u32()? as u64;
+    let name = String::from_utf8_lossy(fields.bytes(name_len as usize)?).into_owned();
+    let mut extra = Fields(fields.bytes(extra_len as usize)?);
+    fields.bytes(comment_len as usize)?;
+
+    let mut mtime = from_dos(time, date);
+    while !extra.0.is_empty() {
+      let (id, len) = (extra.u16()?, extra.u16()?);
+      let mut data = Fields(extra.bytes(len as usize)?);
+      match id {
+        // Only the fields that overflowed are there, in this order.
+        ZIP64_EXTRA => {
+          for value in [&mut size, &mut compressed, &mut offset].iter_mut() {
+            if **value == MAX_32 {
+              **value = data.u64()?;
+            }
+          }
+        }
+        TIMESTAMP_EXTRA if len >= 5 && data.u8()? & 1 != 0 => {
+          mtime = Timespec::new(data.u32()? as i32 as i64, 0);
+        }
+        _ => {}
+      }
+    }
+
+    let mode = match (made_by & 0xff00, external >> 16) {
+      (UNIX, mode) if mode != 0 => Some(mode),
+      _ => None
+    };
+
+    Ok(Entry { name, flags, method, crc, compressed, size, offset, mode, mtime })
+  }
+}
+
+// Finds and reads the central directory.
+fn read_central_directory<R: Read + Seek>(input: &mut R) -> io::Result<Vec<Entry>> {
+  let len = input.seek(SeekFrom::End(0))?;
+  let tail_len = len.min((END_LEN + MAX_COMMENT + ZIP64_LOCATOR_LEN) as u64);
+  input.seek(SeekFrom::Start(len - tail_len))?;
+  let mut tail = vec![0; tail_len as usize];
+  input.read_exact(&mut tail)?;
+
+  let end = (0..tail.len().saturating_sub(END_LEN - 1)).rev()
+    .find(|&i| tail[i..i + 4] == END.to_le_bytes())
+    .ok_or_else(|| invalid("no end of central directory record"))?;
+
+  let mut fields = Fields(&tail[end + 4..]);
+  let (disk, directory_disk) = (fields.u16()?, fields.u16()?);
+  let _disk_entries = fields.u16()?;
+  let mut count = fields.u16()? as u64;
+  let mut directory_len = fields.u32()? as u64;
+  let mut directory_offset = fields.u32()? as u64;
+  let mut disks = (disk, directory_disk) != (0, 0);
+
+  // A zip64 end record, if there is one, is found through the locator just
+  // before the ordinary one.
+  if end >= ZIP64_LOCATOR_LEN && tail[end - ZIP64_LOCATOR_LEN..][..4] == ZIP64_LOCATOR.to_le_bytes() {
+    let mut locator = Fields(&tail[end - ZIP64_LOCATOR_LEN + 4..end]);
+    let _disk = locator.u32()?;
+    let offset = locator.u64()?;
+    input.seek(SeekFrom::Start(offset))?;
+    let mut record = [0u8; 56];
+    input.read_exact(&mut record)?;
+
+    let mut fields = Fields(&record);
+    if fields.u32()? != ZIP64_END {
+      return Err(invalid("bad zip64 end of central directory record"));
+    }
+
+    fields.bytes(8 + 2 + 2)?;
+    disks = (fields.u32()?, fields.u32()?) != (0, 0);
+    let _disk_entries = fields.u64()?;
+    count = fields.u64()?;
+    directory_len = fields.u64()?;
+    directory_offset = fields.u64()?;
+  }
+
+  if disks {
+    return Err(invalid("archives split across disks aren't supported"));
+  }
+
+  input.seek(SeekFrom::Start(directory_offset))?;
+  let mut directory = Vec::with_capacity(directory_len.min(MAX_RESERVE) as usize);
+  input.take(directory_len).read_to_end(&mut directory)?;
+
+  let mut fields = Fields(&directory);
+  let mut entries = Vec::with_capacity(count.min(MAX_RESERVE) as usize);
+  for _ in 0..count {
+    entries.push(Entry::read(&mut fields)?);
+  }
+
+  Ok(entries)
+}
+
+// Reads an entry's data, decompressed and checked against its CRC.
+fn read_data<R: Read + Seek>(input: &mut R, entry: &Entry) -> io::Result<Vec<u8>> {
+  input.seek(SeekFrom::Start(entry.offset))?;
+  let mut header = [0u8; 30];
+  input.read_exact(&mut header)?;
+
+  let mut fields = Fields(&header);
+  if fields.u32()? != LOCAL_HEADER {
+    return Err(invalid(&format!("no local header for '{}'", entry.name)));
+  }
+
+  fields.bytes(22)?;
+  let skip = fields.u16()? as i64 + fields.u16()? as i64;
+  input.seek(SeekFrom::Current(skip))?;
+
+  let mut compressed = Vec::with_capacity(entry.compressed.min(MAX_RESERVE) as usize);
+  input.take(entry.compressed).read_to_end(&mut compressed)?;
+  if (compressed.len() as u64) < entry.compressed {
+    return Err(invalid("truncated"));
+  }
+
+  let data = match entry.method {
+    DEFLATED => {
+      let mut data = Vec::with_capacity(entry.size.min(MAX_RESERVE) as usize);
+      deflate::inflate(&compressed, &mut data, entry.size.min(usize::MAX as u64) as usize)?;
+      data
+    }
+    _ => compressed
+  };
+
+  if data.len() as u64 != entry.size || crc32(&data) != entry.crc {
+    return Err(invalid(&format!("'{}' is corrupt", entry.name)));
+  }
+
+  Ok(data)
+}
+
+// A writer that counts what's been through it, for offsets in the archive.
+struct Counting<W> {
+  inner: W,
+  written: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.written += n as u64;
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+// What the central directory needs to know about an entry written.
+struct Written {
+  name: String,
+  method: u16,
+  dos: (u16, u16),
+  mtime: Timespec,
+  crc: u32,
+  compressed: u64,
+  size: u64,
+  offset: u64,
+  mode: u32,
+}
+
+impl Written {
+  // Deflated entries' sizes aren't known until after they're written.
+  fn streamed(&self) -> bool {
+    self.method == DEFLATED
+  }
+
+  fn flags(&self) -> u16 {
+    let descriptor = if self.streamed() { DESCRIPTOR } else { 0 };
+    descriptor | if self.name.is_ascii() { 0 } else { UTF8 }
+  }
+
+  // The extended timestamp field, if the time fits in one.
+  fn timestamp(&self, out: &mut Vec<u8>) {
+    if self.mtime.sec >= i32::MIN as i64 && self.mtime.sec <= i32::MAX as i64 {
+      put_u16(out, TIMESTAMP_EXTRA);
+      put_u16(out, 5);
+      out.push(1);
+      put_u32(out, self.mtime.sec as i32 as u32);
+    }
+  }
+
+  // The local header, with zeroes for the CRC and sizes if they're in a
+  // data descriptor.
+  fn local_header(&self, zip64: bool) -> Vec<u8> {
+    let (crc, compressed, size) = if self.streamed() { (0, 0, 0) } else { (self.crc, self.compressed, self.size) };
+    let mut extra = Vec::new();
+    if zip64 {
+      put_u16(&mut extra, ZIP64_EXTRA);
+      put_u16(&mut extra, 16);
+      put_u64(&mut extra, size);
+      put_u64(&mut extra, compressed);
+    }
+
+    self.timestamp(&mut extra);
+
+    let mut out = Vec::with_capacity(30 + self.name.len() + extra.len());
+    put_u32(&mut out, LOCAL_HEADER);
+    put_u16(&mut out, if zip64 { ZIP64_VERSION } else { VERSION });
+    put_u16(&mut out, self.flags());
+    put_u16(&mut out, self.method);
+    put_u16(&mut out, self.dos.0);
+    put_u16(&mut out, self.dos.1);
+    put_u32(&mut out, crc);
+    put_u32(&mut out, if zip64 { MAX_32 } else { compressed } as u32);
+    put_u32(&mut out, if zip64 { MAX_32 } else { size } as u32);
+    put_u16(&mut out, self.name.len() as u16);
+    put_u16(&mut out, extra.len() as u16);
+    out.extend_from_slice(self.name.as_bytes());
+    out.extend_from_slice(&extra);
+    out
+  }
+
+  // The data descriptor after a streamed entry's data. Its sizes are 64-bit
+  // if the local header had a zip64 field.
+  fn data_descriptor(&self, zip64: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(24);
+    put_u32(&mut out, DATA_DESCRIPTOR);
+    put_u32(&mut out, self.crc);
+    if zip64 {
+      put_u64(&mut out, self.compressed);
+      put_u64(&mut out, self.size);
+    } else {
+      put_u32(&mut out, self.compressed as u32);
+      put_u32(&mut out, self.size as u32);
+    }
+
+    out
+  }
+
+  fn central_header(&self, force_zip64: bool) -> Vec<u8> {
+    let sizes_overflow = force_zip64 || self.size >= MAX_32 || self.compressed >= MAX_32;
+    let offset_overflows = force_zip64 || self.offset >= MAX_32;
+
+    let mut extra = Vec::new();
+    if sizes_overflow || offset_overflows {
+      let fields: Vec<u64> = [(sizes_overflow, self.size), (sizes_overflow, self.compressed),
+                              (offset_overflows, self.offset)].iter()
+        .filter(|&&(overflows, _)| overflows).map(|&(_, value)| value).collect();
+      put_u16(&mut extra, ZIP64_EXTRA);
+      put_u16(&mut extra, 8 * fields.len() as u16);
+      for &value in fields.iter() {
+        put_u64(&mut extra, value);
+      }
+    }
+
+    self.timestamp(&mut extra);
+
+    let zip64 = sizes_overflow || offset_overflows;
+    let version = if zip64 { ZIP64_VERSION } else { VERSION };
+    let is_dir = self.mode & S_IFMT == S_IFDIR;
+    let mut out = Vec::with_capacity(46 + self.name.len() + extra.len());
+    put_u32(&mut out, CENTRAL_HEADER);
+    put_u16(&mut out, UNIX | version);
+    put_u16(&mut out, version);
+    put_u16(&mut out, self.flags());
+    put_u16(&mut out, self.method);
+    put_u16(&mut out, self.dos.0);
+    put_u16(&mut out, self.dos.1);
+    put_u32(&mut out, self.crc);
+    put_u32(&mut out, if sizes_overflow { MAX_32 } else { self.compressed } as u32);
+    put_u32(&mut out, if sizes_overflow { MAX_32 } else { self.size } as u32);
+    put_u16(&mut out, self.name.len() as u16);
+    put_u16(&mut out, extra.len() as u16);
+    put_u16(&mut out, 0);
+    put_u16(&mut out, 0);
+    put_u16(&mut out, 0);
+    // The mode, and MS-DOS's directory attribute for those that look there.
+    put_u32(&mut out, self.mode << 16 | if is_dir { 0x10 } else { 0 });
+    put_u32(&mut out, if offset_overflows { MAX_32 } else { self.offset } as u32);
+    out.extend_from_slice(self.name.as_bytes());
+    out.extend_from_slice(&extra);
+    out
+  }
+}
+
+impl<'r> Proc<'r> {
+  /**
+   * Unpacks the zip archive in `input` into `dest_dir`, which is "" for the
+   * top. Entries replace any files already there with the same names. Fails
+   * on entries whose names lead outside `dest_dir` or into a mounted file
+   * system, entries too large for a file, entries whose data doesn't match
+   * its CRC, and malformed archives; whatever was unpacked before then stays.
+   */
+  pub fn import_zip<R: Read + Seek>(&mut self, mut input: R, dest_dir: &str) -> io::Result<ZipStats> {
+    let mut stats = ZipStats::default();
+    for entry in read_central_directory(&mut input)? {
+      let name = archive::clean(dest_dir, &entry.name)
+        .ok_or_else(|| invalid(&format!("'{}' is outside the destination", entry.name)))?;
+      if !name.is_empty() {
+        self.check_unmounted(&name)?;
+      }
+
+      let kind = entry.mode.map(|mode| mode & S_IFMT);
+      if entry.name.ends_with('/') || kind == Some(S_IFDIR) {
+        if !name.is_empty() && self.cwd.get(&name).is_none() {
+          self.cwd.insert(name, File::new_dir(None));
+        }
+
+        stats.directories += 1;
+        continue;
+      }
+
+      if name.is_empty() {
+        return Err(invalid(&format!("'{}' has no name", entry.name)));
+      }
+
+      if entry.flags & ENCRYPTED != 0 || (entry.method != STORED && entry.method != DEFLATED) {
+        stats.skipped += 1;
+        continue;
+      }
+
+      if entry.size > self.geometry().max_file_size() as u64 {
+        return Err(invalid(&format!("'{}' is too large for the page size", entry.name)));
+      }
+
+      let data = read_data(&mut input, &entry)?;
+      let mut inode = self.allocator.alloc_inode();
+      let permissions = entry.mode.map(|mode| mode & 0o7777);
+      if kind == Some(S_IFLNK) {
+        inode.set_mode(S_IFLNK | permissions.unwrap_or(0o777));
+        inode.write(0, &data);
+        stats.symlinks += 1;
+      } else {
+        inode.set_mode(permissions.map(|mode| S_IFREG | mode).unwrap_or(DEFAULT_FILE_MODE));
+        let page_size = self.geometry().page_size();
+        archive::fill(&mut &data[..], &mut inode, data.len() as u64, page_size)?;
+        stats.files += 1;
+      }
+
+      let (created, _, _) = inode.stat();
+      inode.set_stat(data.len(), (created, entry.mtime, entry.mtime));
+      self.cwd.insert(name, File::new_data_file(Rc::new(RefCell::new(inode))));
+    }
+
+    Ok(stats)
+  }
+
+  /**
+   * Packs `src_dir`, and everything under it, into a zip archive written to
+   * `out`. `src_dir` is "" for everything. Names in the archive are relative
+   * to `src_dir`. Directories that only exist as part of files' names get
+   * entries too.
+   */
+  pub fn export_zip<W: Write>(&self, src_dir: &str, out: W, options: ZipOptions) -> io::Result<ZipStats> {
+    let src = archive::clean("", src_dir).ok_or_else(|| invalid(&format!("'{}' is outside the top", src_dir)))?;
+    let mut out = Counting { inner: out, written: 0 };
+    let mut stats = ZipStats::default();
+    let mut written = Vec::new();
+    let content = self.cwd.get_dir_rc().borrow();
+    let now = Timespec::new(time::get_time().sec, 0);
+    let mut page = vec![0u8; self.geometry().page_size()];
+    for (relative, file) in archive::entries(&content, &src) {
+      let inode = match file {
+        Some(File::DataFile(inode)) => inode.borrow(),
+        _ => {
+          stats.directories += 1;
+          let entry = Written {
+            name: format!("{}/", relative),
+            method: STORED,
+            dos: to_dos(now),
+            mtime: now,
+            crc: 0,
+            compressed: 0,
+            size: 0,
+            offset: out.written,
+            mode: DEFAULT_DIR_MODE
+          };
+
+          out.write_all(&entry.local_header(options.zip64))?;
+          written.push(entry);
+          continue;
+        }
+      };
+
+      if inode.is_symlink() { stats.symlinks += 1 } else { stats.files += 1 }
+      let (mode, mtime, size) = (inode.mode(), inode.stat().2, inode.size() as u64);
+      let deflated = options.compression == Compression::Deflated && mode & S_IFMT == S_IFREG && size > 0;
+      let mut entry = Written {
+        name: relative, mode, mtime,
+        method: if deflated { DEFLATED } else { STORED },
+        dos: to_dos(mtime),
+        crc: 0,
+        compressed: size,
+        size,
+        offset: out.written
+      };
+
+      // Stored entries' CRCs go before their data, so they're read twice.
+      let mut pages = |f: &mut dyn FnMut(&[u8]) -> io::Result<()>| -> io::Result<()> {
+        let mut offset = 0;
+        while offset < inode.size() {
+          let len = (inode.size() - offset).min(page.len());
+          inode.read(offset, &mut page[..len]);
+          f(&page[..len])?;
+          offset += len;
+        }
+
+        Ok(())
+      };
+
+      let mut crc = Checksummed::new(());
+      if !entry.streamed() {
+        pages(&mut |data| { crc.update(data); Ok(()) })?;
+        entry.crc = crc.checksum();
+      }
+
+      let zip64 = options.zip64 || if entry.streamed() { deflate::max_len(size) } else { size } >= MAX_32;
+      out.write_all(&entry.local_header(zip64))?;
+      if entry.streamed() {
+        let mut deflater = Deflater::new();
+        let start = out.written;
+        pages(&mut |data| {
+          crc.update(data);
+          deflater.write(data);
+          out.write_all(&deflater.take())
+        })?;
+
+        out.write_all(&deflater.finish())?;
+        entry.crc = crc.checksum();
+        entry.compressed = out.written - start;
+        out.write_all(&entry.data_descriptor(zip64))?;
+      } else {
+        pages(&mut |data| out.write_all(data))?;
+      }
+
+      written.push(entry);
+    }
+
+    let directory_offset = out.written;
+    for entry in written.iter() {
+      out.write_all(&entry.central_header(options.zip64))?;
+    }
+
+    let directory_len = out.written - directory_offset;
+    let count = written.len() as u64;
+    let mut end = Vec::new();
+    if options.zip64 || count >= MAX_16 || directory_offset >= MAX_32 || directory_len >= MAX_32 {
+      let zip64_end = out.written;
+      put_u32(&mut end, ZIP64_END);
+      put_u64(&mut end, 44);
+      put_u16(&mut end, UNIX | ZIP64_VERSION);
+      put_u16(&mut end, ZIP64_VERSION);
+      put_u32(&mut end, 0);
+      put_u32(&mut end, 0);
+      put_u64(&mut end, count);
+      put_u64(&mut end, count);
+      put_u64(&mut end, directory_len);
+      put_u64(&mut end, directory_offset);
+
+      put_u32(&mut end, ZIP64_LOCATOR);
+      put_u32(&mut end, 0);
+      put_u64(&mut end, zip64_end);
+      put_u32(&mut end, 1);
+    }
+
+    put_u32(&mut end, END);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, count.min(MAX_16) as u16);
+    put_u16(&mut end, count.min(MAX_16) as u16);
+    put_u32(&mut end, directory_len.min(MAX_32) as u32);
+    put_u32(&mut end, directory_offset.min(MAX_32) as u32);
+    put_u16(&mut end, 0);
+    out.write_all(&end)?;
+    out.flush()?;
+    Ok(stats)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::{from_dos, to_dos, Compression, ZipOptions, ZipStats};
+  use file::File;
+  use time::Timespec;
+  use {Proc, Whence, O_CREAT, O_RDWR};
+
+  fn write_file(p: &mut Proc<'static>, name: &'static str, offset: usize, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    p.seek(fd, offset as isize, Whence::SeekSet);
+    p.write(fd, data);
+    p.close(fd);
+  }
+
+  fn contents(p: &mut Proc, name: &'static str) -> Vec<u8> {
+    let size = p.metadata(name).expect("missing").size;
+    let fd = p.open(name, O_RDWR);
+    let mut buf = vec![0; size];
+    p.read(fd, &mut buf);
+    p.close(fd);
+    buf
+  }
+
+  fn set<F: FnOnce(&mut ::inode::Inode)>(p: &Proc, name: &str, f: F) {
+    match p.cwd.get_dir_rc().borrow().entries[name] {
+      File::DataFile(ref inode) => f(&mut inode.borrow_mut()),
+      _ => panic!("not a file")
+    }
+  }
+
+  fn fixture() -> Proc<'static> {
+    let mut p = Proc::new();
+    write_file(&mut p, "src/main.rs", 0, "fn main() {}\n".repeat(100).as_bytes());
+    write_file(&mut p, "src/sparse", 1 << 20, b"end");
+    write_file(&mut p, "src/empty", 0, b"");
+    write_file(&mut p, "ünïcödé", 0, b"names");
+    assert!(p.symlink("main.rs", "src/link"));
+    assert!(p.link("src/main.rs", "src/hard").is_ok());
+    assert!(p.mkdir("docs"));
+    set(&p, "src/main.rs", |inode| {
+      inode.set_mode(0o100755);
+      let (created, accessed, _) = inode.stat();
+      let size = inode.size();
+      inode.set_stat(size, (created, accessed, Timespec::new(1700000001, 0)));
+    });
+
+    p
+  }
+
+  fn round_trip(options: ZipOptions) -> (Vec<u8>, Proc<'static>) {
+    let original = fixture();
+    let mut archive = Vec::new();
+    let stats = original.export_zip("", &mut archive, options).unwrap();
+    assert_eq!(stats, ZipStats { files: 5, directories: 2, symlinks: 1, skipped: 0 });
+
+    let mut p = Proc::new();
+    assert_eq!(p.import_zip(Cursor::new(&archive), "dest").unwrap(), stats);
+    for &name in ["src/main.rs", "src/sparse", "src/empty", "src/link", "src/hard", "ünïcödé"].iter() {
+      let theirs = original.symlink_metadata(name).unwrap();
+      let ours = p.symlink_metadata(&format!("dest/{}", name)).unwrap();
+      assert_eq!((ours.mode, ours.size, ours.modified.sec), (theirs.mode, theirs.size, theirs.modified.sec), "{}", name);
+    }
+
+    assert!(p.metadata("dest/docs").unwrap().is_dir());
+    assert!(p.metadata("dest/src").unwrap().is_dir());
+    assert_eq!(p.readlink("dest/src/link").unwrap(), "main.rs");
+    assert_eq!(contents(&mut p, "dest/src/link"), "fn main() {}\n".repeat(100).as_bytes());
+    assert_eq!(&contents(&mut p, "dest/src/sparse")[(1 << 20) - 1..], b"\0end");
+
+    // The zeroes before the end of the sparse file are still a hole.
+    let mut pages = 0;
+    set(&p, "dest/src/sparse", |inode| inode.extents(|_, _| pages += 1));
+    assert_eq!(pages, 1);
+    assert_eq!(p.check(), vec![]);
+    (archive, p)
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let (deflated, _) = round_trip(ZipOptions::default());
+    let (stored, _) = round_trip(ZipOptions { compression: Compression::Stored, zip64: false });
+    assert!(deflated.len() < stored.len() / 10);
+
+    // Hard links are copies.
+    let (_, mut p) = round_trip(ZipOptions::default());
+    write_file(&mut p, "dest/src/hard", 0, b"//");
+    assert_eq!(&contents(&mut p, "dest/src/main.rs")[..2], b"fn");
+  }
+
+  #[test]
+  fn test_zip64() {
+    let (archive, _) = round_trip(ZipOptions { zip64: true, ..ZipOptions::default() });
+    let end = archive.len() - 22 - 20 - 56;
+    assert_eq!(archive[end..end + 4], super::ZIP64_END.to_le_bytes());
+  }
+
+  #[test]
+  fn test_bad_archives() {
+    let mut p = Proc::new();
+    write_file(&mut p, "file", 0, b"some data");
+    let mut archive = Vec::new();
+    p.export_zip("", &mut archive, ZipOptions { compression: Compression::Stored, zip64: false }).unwrap();
+    let error = |archive: &[u8], dest: &str| Proc::new().import_zip(Cursor::new(archive), dest).unwrap_err().to_string();
+
+    // The data starts after the 30-byte header, the name, and the timestamp.
+    let mut corrupt = archive.clone();
+    corrupt[30 + 4 + 9] ^= 1;
+    assert_eq!(error(&corrupt, ""), "bad zip archive: 'file' is corrupt");
+    assert_eq!(error(&archive[..archive.len() - 1], ""), "bad zip archive: no end of central directory record");
+    assert_eq!(error(b"not a zip", ""), "bad zip archive: no end of central directory record");
+
+    // Names are in the local and central headers; only the central one counts.
+    let mut escaping = archive.clone();
+    let central = archive.len() - 22 - (46 + 4 + 9);
+    escaping[central + 46..central + 50].copy_from_slice(b"../x");
+    assert_eq!(error(&escaping, "dest"), "bad zip archive: '../x' is outside the destination");
+
+    // Deflated data that holds more than the entry's size says.
+    let mut deflated = Vec::new();
+    p.export_zip("", &mut deflated, ZipOptions::default()).unwrap();
+    let central = deflated.len() - 22 - (46 + 4 + 9);
+    deflated[central + 24..central + 28].copy_from_slice(&4u32.to_le_bytes());
+    assert_eq!(error(&deflated, ""), "bad deflate data: longer than expected");
+
+    // An entry bigger than a file can be, by its zip64 size.
+    let mut huge = Vec::new();
+    p.export_zip("", &mut huge, ZipOptions { compression: Compression::Stored, zip64: true }).unwrap();
+    let central = huge.len() - 22 - 20 - 56 - (46 + 4 + 28 + 9);
+    huge[central + 54..central + 62].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    let error = Proc::with_page_size(512).import_zip(Cursor::new(&huge), "").unwrap_err();
+    assert_eq!(error.to_string(), "bad zip archive: 'file' is too large for the page size");
+  }
+
+  #[test]
+  fn test_dos_times() {
+    for &(sec, expected) in [(315532800, 315532800), (1700000001, 1700000000), (0, 315532800),
+                             (951782400 + 86399, 951782400 + 86398), (i64::MAX, 4354819198)].iter() {
+      let (time, date) = to_dos(Timespec::new(sec, 0));
+      assert_eq!(from_dos(time, date), Timespec::new(expected, 0), "{}", sec);
+    }
+  }
+}